@@ -40,8 +40,28 @@ impl FilterTarget {
             &FilterTarget::ForwardOutput | &FilterTarget::ForwardInput => "FORWARD",
         }
     }
+
+    /// The nftables hook the counting chain is attached to
+    pub fn nft_hook(&self) -> &str {
+        match self {
+            &FilterTarget::Input => "input",
+            &FilterTarget::Output => "output",
+            &FilterTarget::ForwardOutput | &FilterTarget::ForwardInput => "forward",
+        }
+    }
+
+    /// The nftables equivalent of `interface`
+    pub fn nft_interface(&self) -> &str {
+        match self {
+            &FilterTarget::Input | &FilterTarget::ForwardInput => "iifname",
+            &FilterTarget::Output | &FilterTarget::ForwardOutput => "oifname",
+        }
+    }
 }
 
+/// The nftables table all counting chains and sets live in
+const NFT_TABLE: &str = "rita";
+
 #[test]
 fn test_filter_target_interface() {
     assert_eq!(FilterTarget::Input.interface(), "src");
@@ -66,15 +86,28 @@ fn test_filter_table_table() {
     assert_eq!(FilterTarget::ForwardInput.table(), "FORWARD");
 }
 
+#[test]
+fn test_filter_target_nft() {
+    assert_eq!(FilterTarget::Input.nft_hook(), "input");
+    assert_eq!(FilterTarget::Output.nft_hook(), "output");
+    assert_eq!(FilterTarget::ForwardInput.nft_hook(), "forward");
+    assert_eq!(FilterTarget::ForwardOutput.nft_hook(), "forward");
+    assert_eq!(FilterTarget::Input.nft_interface(), "iifname");
+    assert_eq!(FilterTarget::ForwardInput.nft_interface(), "iifname");
+    assert_eq!(FilterTarget::Output.nft_interface(), "oifname");
+    assert_eq!(FilterTarget::ForwardOutput.nft_interface(), "oifname");
+}
+
 fn parse_ipset(input: &str) -> Result<HashMap<(IpAddr, String), u64>, Error> {
     lazy_static! {
         static ref RE: Regex =
-            Regex::new(r"(?m)^add \S+ ([a-f0-9:]+),(wg\d+) packets (\d+) bytes (\d+)")
+            Regex::new(r"(?m)^add \S+ ([a-f0-9:.]+),(wg\d+) packets (\d+) bytes (\d+)")
                 .expect("Unable to compile regular expression");
     }
     let mut map = HashMap::new();
 
-    // example line `add aa fd00::1,wg0 packets 28 bytes 2212`
+    // example lines `add aa fd00::1,wg0 packets 28 bytes 2212` and
+    // `add aa_v4 10.0.0.1,wg0 packets 28 bytes 2212`
 
     for caps in RE.captures_iter(input) {
         map.insert(
@@ -118,21 +151,180 @@ add zxcv 1234:5678:9801:2345:6789:0123:4567:8902,wg0 packets 123456789 bytes 987
     }
 }
 
+#[test]
+fn test_parse_ipset_mixed_families() {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    let data = r#"
+create rita_fwd_output_v4 hash:net,iface family inet hashsize 1024 maxelem 65536 counters
+add rita_fwd_output_v4 10.0.0.1,wg0 packets 10 bytes 1000
+add rita_fwd_output_v4 192.168.10.254,wg3 packets 1 bytes 60
+add rita_fwd_output fd00::1,wg0 packets 2 bytes 200
+add rita_fwd_output ::ffff:10.0.0.1,wg1 packets 3 bytes 300
+"#;
+    let result = parse_ipset(data).expect("Unable to parse ipset");
+    assert_eq!(result.len(), 4);
+    assert_eq!(
+        result.get(&(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), "wg0".into())),
+        Some(&(1000u64 + 10 * 40))
+    );
+    assert_eq!(
+        result.get(&(IpAddr::V4(Ipv4Addr::new(192, 168, 10, 254)), "wg3".into())),
+        Some(&(60u64 + 40))
+    );
+    assert_eq!(
+        result.get(&(
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)),
+            "wg0".into()
+        )),
+        Some(&(200u64 + 2 * 40))
+    );
+    // an IPv4 mapped IPv6 address is still an IPv6 destination
+    assert_eq!(
+        result.get(&(
+            IpAddr::V6(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped()),
+            "wg1".into()
+        )),
+        Some(&(300u64 + 3 * 40))
+    );
+}
+
+/// Parses the elements of an nftables set keyed by destination and interface, as printed by
+/// `nft list set`. Counts are the same as `parse_ipset` would give for the same traffic.
+fn parse_nft_set(input: &str) -> Result<HashMap<(IpAddr, String), u64>, Error> {
+    lazy_static! {
+        static ref RE: Regex =
+            Regex::new(r#"([a-f0-9:.]+) \. "?(wg\d+)"? counter packets (\d+) bytes (\d+)"#)
+                .expect("Unable to compile regular expression");
+    }
+    let mut map = HashMap::new();
+
+    // example element `fd00::1 . "wg0" counter packets 28 bytes 2212`, elements are comma
+    // separated and may be spread over several lines
+
+    for caps in RE.captures_iter(input) {
+        map.insert(
+            (IpAddr::from_str(&caps[1])?, String::from(&caps[2])),
+            caps[4].parse::<u64>()? + caps[3].parse::<u64>()? * 40,
+        );
+    }
+    Ok(map)
+}
+
+#[test]
+fn test_parse_nft_set() {
+    use std::net::Ipv6Addr;
+    let data = r#"
+table ip6 rita {
+	set rita_input {
+		type ipv6_addr . ifname
+		size 65535
+		flags dynamic
+		elements = { 1234:5678:9801:2345:6789:123:4567:8901 . "wg42" counter packets 123456789 bytes 987654321,
+			     fd00::dead:beef . "wg0" counter packets 1 bytes 80 }
+	}
+}
+"#;
+    let result = parse_nft_set(data).expect("Unable to parse set");
+    assert_eq!(result.len(), 2);
+
+    let addr1 = Ipv6Addr::new(
+        0x1234, 0x5678, 0x9801, 0x2345, 0x6789, 0x0123, 0x4567, 0x8901,
+    );
+    assert_eq!(
+        result.get(&(IpAddr::V6(addr1), "wg42".into())),
+        Some(&(987654321u64 + 123456789u64 * 40))
+    );
+    let addr2 = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0xdead, 0xbeef);
+    assert_eq!(
+        result.get(&(IpAddr::V6(addr2), "wg0".into())),
+        Some(&(80u64 + 40))
+    );
+
+    let empty = r#"
+table ip6 rita {
+	set rita_input {
+		type ipv6_addr . ifname
+		size 65535
+		flags dynamic
+	}
+}
+"#;
+    assert!(parse_nft_set(empty).unwrap().is_empty());
+}
+
+/// IPv4 and IPv6 traffic are counted separately, ipsets and nftables sets are both typed by
+/// address family so each family gets its own set and rule
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Family {
+    V6,
+    V4,
+}
+
+const FAMILIES: [Family; 2] = [Family::V6, Family::V4];
+
+impl Family {
+    /// The IPv6 sets keep their original names so existing installs keep counting
+    fn ipset_name(self, target: &FilterTarget) -> String {
+        match self {
+            Family::V6 => target.set_name().to_string(),
+            Family::V4 => format!("{}_v4", target.set_name()),
+        }
+    }
+
+    fn ipset_family(self) -> &'static str {
+        match self {
+            Family::V6 => "inet6",
+            Family::V4 => "inet",
+        }
+    }
+
+    fn iptables(self) -> &'static str {
+        match self {
+            Family::V6 => "ip6tables",
+            Family::V4 => "iptables",
+        }
+    }
+
+    /// Used both as the nftables table family and the payload protocol in rules
+    fn nft_family(self) -> &'static str {
+        match self {
+            Family::V6 => "ip6",
+            Family::V4 => "ip",
+        }
+    }
+
+    fn nft_addr_type(self) -> &'static str {
+        match self {
+            Family::V6 => "ipv6_addr",
+            Family::V4 => "ipv4_addr",
+        }
+    }
+}
+
 impl KernelInterface {
+    /// Creates the counting sets and rules for both address families
     pub fn init_counter(&self, target: &FilterTarget) -> Result<(), Error> {
+        for family in FAMILIES.iter() {
+            self.init_family_counter(target, *family)?;
+        }
+        Ok(())
+    }
+
+    fn init_family_counter(&self, target: &FilterTarget, family: Family) -> Result<(), Error> {
+        let set_name = family.ipset_name(target);
         self.run_command(
             "ipset",
             &[
                 "create",
-                target.set_name(),
+                &set_name,
                 "hash:net,iface",
                 "family",
-                "inet6",
+                family.ipset_family(),
                 "counters",
             ],
         )?;
         self.add_iptables_rule(
-            "ip6tables",
+            family.iptables(),
             &[
                 "-w",
                 "-A",
@@ -141,48 +333,176 @@ impl KernelInterface {
                 "set",
                 "!",
                 "--match-set",
-                target.set_name(),
+                &set_name,
                 &format!("dst,{}", target.interface()),
                 "-j",
                 "SET",
                 "--add-set",
-                target.set_name(),
+                &set_name,
                 &format!("dst,{}", target.interface()),
             ],
         )?;
         Ok(())
     }
 
+    /// Reads and zeroes the counters of both address families, IPv4 and IPv6 destinations
+    /// can't collide so the results are simply merged
     pub fn read_counters(
         &self,
         target: &FilterTarget,
     ) -> Result<HashMap<(IpAddr, String), u64>, Error> {
+        let mut res = HashMap::new();
+        for family in FAMILIES.iter() {
+            res.extend(self.read_family_counters(target, *family)?);
+        }
+        Ok(res)
+    }
+
+    fn read_family_counters(
+        &self,
+        target: &FilterTarget,
+        family: Family,
+    ) -> Result<HashMap<(IpAddr, String), u64>, Error> {
+        let set_name = family.ipset_name(target);
+        let tmp_name = format!("tmp_{}", set_name);
         self.run_command(
             "ipset",
             &[
                 "create",
-                &format!("tmp_{}", target.set_name()),
+                &tmp_name,
                 "hash:net,iface",
                 "family",
-                "inet6",
+                family.ipset_family(),
                 "counters",
             ],
         )?;
 
+        self.run_command("ipset", &["swap", &tmp_name, &set_name])?;
+
+        let output = self.run_command("ipset", &["save", &tmp_name])?;
+        let res = parse_ipset(&String::from_utf8(output.stdout)?);
+        trace!("ipset parsed into {:?}", res);
+
+        self.run_command("ipset", &["destroy", &tmp_name])?;
+        res
+    }
+
+    /// The nftables version of `init_counter`, every packet adds its destination and interface to
+    /// a dynamic set with a counter on each element. Safe to run again, the chain is flushed
+    /// before the rule is added. There is one table per address family, both named `rita`.
+    pub fn init_nft_counter(&self, target: &FilterTarget) -> Result<(), Error> {
+        for family in FAMILIES.iter() {
+            self.init_family_nft_counter(target, *family)?;
+        }
+        Ok(())
+    }
+
+    fn init_family_nft_counter(&self, target: &FilterTarget, family: Family) -> Result<(), Error> {
+        let nft_family = family.nft_family();
+        self.run_command("nft", &["add", "table", nft_family, NFT_TABLE])?;
         self.run_command(
-            "ipset",
+            "nft",
+            &[
+                "add",
+                "set",
+                nft_family,
+                NFT_TABLE,
+                target.set_name(),
+                "{",
+                "type",
+                family.nft_addr_type(),
+                ".",
+                "ifname",
+                ";",
+                "flags",
+                "dynamic",
+                ";",
+                "}",
+            ],
+        )?;
+        self.run_command(
+            "nft",
+            &[
+                "add",
+                "chain",
+                nft_family,
+                NFT_TABLE,
+                target.set_name(),
+                "{",
+                "type",
+                "filter",
+                "hook",
+                target.nft_hook(),
+                "priority",
+                "0",
+                ";",
+                "}",
+            ],
+        )?;
+        self.run_command(
+            "nft",
+            &["flush", "chain", nft_family, NFT_TABLE, target.set_name()],
+        )?;
+        self.run_command(
+            "nft",
             &[
-                "swap",
-                &format!("tmp_{}", target.set_name()),
+                "add",
+                "rule",
+                nft_family,
+                NFT_TABLE,
                 target.set_name(),
+                "update",
+                &format!("@{}", target.set_name()),
+                "{",
+                nft_family,
+                "daddr",
+                ".",
+                target.nft_interface(),
+                "counter",
+                "}",
             ],
         )?;
+        Ok(())
+    }
 
-        let output = self.run_command("ipset", &["save", &format!("tmp_{}", target.set_name())])?;
-        let res = parse_ipset(&String::from_utf8(output.stdout)?);
-        trace!("ipset parsed into {:?}", res);
+    /// The nftables version of `read_counters`. Sets can't be swapped like ipsets can, so the set
+    /// is listed and then flushed, packets counted in between the two commands are lost.
+    pub fn read_nft_counters(
+        &self,
+        target: &FilterTarget,
+    ) -> Result<HashMap<(IpAddr, String), u64>, Error> {
+        let mut res = HashMap::new();
+        for family in FAMILIES.iter() {
+            res.extend(self.read_family_nft_counters(target, *family)?);
+        }
+        Ok(res)
+    }
+
+    fn read_family_nft_counters(
+        &self,
+        target: &FilterTarget,
+        family: Family,
+    ) -> Result<HashMap<(IpAddr, String), u64>, Error> {
+        let nft_family = family.nft_family();
+        let output = self.run_command(
+            "nft",
+            &["list", "set", nft_family, NFT_TABLE, target.set_name()],
+        )?;
+        if !output.status.success() {
+            bail!(
+                "Listing nftables set {} {} failed: {}",
+                nft_family,
+                target.set_name(),
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        let res = parse_nft_set(&String::from_utf8(output.stdout)?);
+        trace!("nft set parsed into {:?}", res);
 
-        self.run_command("ipset", &["destroy", &format!("tmp_{}", target.set_name())])?;
+        self.run_command(
+            "nft",
+            &["flush", "set", nft_family, NFT_TABLE, target.set_name()],
+        )?;
         res
     }
 }
@@ -246,6 +566,52 @@ fn test_init_counter() {
                     status: ExitStatus::from_raw(0),
                 })
             }
+            3 => {
+                assert_eq!(program, "ipset");
+                assert_eq!(
+                    args,
+                    vec![
+                        "create",
+                        "rita_input_v4",
+                        "hash:net,iface",
+                        "family",
+                        "inet",
+                        "counters",
+                    ]
+                );
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            4 => {
+                assert_eq!(program, "iptables");
+                assert_eq!(
+                    args,
+                    vec![
+                        "-w",
+                        "-C",
+                        "INPUT",
+                        "-m",
+                        "set",
+                        "!",
+                        "--match-set",
+                        "rita_input_v4",
+                        "dst,src",
+                        "-j",
+                        "SET",
+                        "--add-set",
+                        "rita_input_v4",
+                        "dst,src",
+                    ]
+                );
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
 
             _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
         }
@@ -255,7 +621,7 @@ fn test_init_counter() {
 }
 #[test]
 fn test_read_counters() {
-    use std::net::Ipv6Addr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
     use std::os::unix::process::ExitStatusExt;
     use std::process::ExitStatus;
     use std::process::Output;
@@ -315,13 +681,284 @@ add xxx fd00::dead:beef,wg42 packets 111 bytes 222
                     status: ExitStatus::from_raw(0),
                 })
             }
+            5 => {
+                assert_eq!(program, "ipset");
+                assert_eq!(
+                    args,
+                    vec![
+                        "create",
+                        "tmp_rita_input_v4",
+                        "hash:net,iface",
+                        "family",
+                        "inet",
+                        "counters",
+                    ]
+                );
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            6 => {
+                assert_eq!(program, "ipset");
+                assert_eq!(args, vec!["swap", "tmp_rita_input_v4", "rita_input_v4"]);
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            7 => {
+                assert_eq!(program, "ipset");
+                assert_eq!(args, vec!["save", "tmp_rita_input_v4"]);
+                Ok(Output {
+                    stdout: b"
+add xxx 10.0.0.1,wg42 packets 5 bytes 600
+".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            8 => {
+                assert_eq!(program, "ipset");
+                assert_eq!(args, vec!["destroy", "tmp_rita_input_v4"]);
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
             _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
         }
     }));
     let result = KI
         .read_counters(&FilterTarget::Input)
         .expect("Unable to read values");
-    assert_eq!(result.len(), 1);
+    assert_eq!(result.len(), 2);
+
+    let value = result
+        .get(&(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), "wg42".into()))
+        .expect("Unable to find key");
+    assert_eq!(value, &(600u64 + 5u64 * 40));
+
+    let value = result
+        .get(&(
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0xdead, 0xbeef)),
+            "wg42".into(),
+        )).expect("Unable to find key");
+    assert_eq!(value, &(222u64 + 111u64 * 40));
+}
+
+#[test]
+fn test_init_nft_counter() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    use KI;
+
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        assert_eq!(program, "nft");
+        let expected: Vec<&str> = match counter {
+            1 => vec!["add", "table", "ip6", "rita"],
+            2 => vec![
+                "add",
+                "set",
+                "ip6",
+                "rita",
+                "rita_fwd_output",
+                "{",
+                "type",
+                "ipv6_addr",
+                ".",
+                "ifname",
+                ";",
+                "flags",
+                "dynamic",
+                ";",
+                "}",
+            ],
+            3 => vec![
+                "add",
+                "chain",
+                "ip6",
+                "rita",
+                "rita_fwd_output",
+                "{",
+                "type",
+                "filter",
+                "hook",
+                "forward",
+                "priority",
+                "0",
+                ";",
+                "}",
+            ],
+            4 => vec!["flush", "chain", "ip6", "rita", "rita_fwd_output"],
+            5 => vec![
+                "add",
+                "rule",
+                "ip6",
+                "rita",
+                "rita_fwd_output",
+                "update",
+                "@rita_fwd_output",
+                "{",
+                "ip6",
+                "daddr",
+                ".",
+                "oifname",
+                "counter",
+                "}",
+            ],
+            6 => vec!["add", "table", "ip", "rita"],
+            7 => vec![
+                "add",
+                "set",
+                "ip",
+                "rita",
+                "rita_fwd_output",
+                "{",
+                "type",
+                "ipv4_addr",
+                ".",
+                "ifname",
+                ";",
+                "flags",
+                "dynamic",
+                ";",
+                "}",
+            ],
+            8 => vec![
+                "add",
+                "chain",
+                "ip",
+                "rita",
+                "rita_fwd_output",
+                "{",
+                "type",
+                "filter",
+                "hook",
+                "forward",
+                "priority",
+                "0",
+                ";",
+                "}",
+            ],
+            9 => vec!["flush", "chain", "ip", "rita", "rita_fwd_output"],
+            10 => vec![
+                "add",
+                "rule",
+                "ip",
+                "rita",
+                "rita_fwd_output",
+                "update",
+                "@rita_fwd_output",
+                "{",
+                "ip",
+                "daddr",
+                ".",
+                "oifname",
+                "counter",
+                "}",
+            ],
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        };
+        assert_eq!(args, expected);
+        Ok(Output {
+            stdout: b"".to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+    KI.init_nft_counter(&FilterTarget::ForwardOutput)
+        .expect("Unable to init counter");
+}
+
+#[test]
+fn test_read_nft_counters() {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    use KI;
+
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        match counter {
+            1 => {
+                assert_eq!(program, "nft");
+                assert_eq!(args, vec!["list", "set", "ip6", "rita", "rita_input"]);
+                Ok(Output {
+                    stdout: b"
+table ip6 rita {
+	set rita_input {
+		type ipv6_addr . ifname
+		size 65535
+		flags dynamic
+		elements = { fd00::dead:beef . \"wg42\" counter packets 111 bytes 222 }
+	}
+}
+".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            2 => {
+                assert_eq!(program, "nft");
+                assert_eq!(args, vec!["flush", "set", "ip6", "rita", "rita_input"]);
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            3 => {
+                assert_eq!(program, "nft");
+                assert_eq!(args, vec!["list", "set", "ip", "rita", "rita_input"]);
+                Ok(Output {
+                    stdout: b"
+table ip rita {
+	set rita_input {
+		type ipv4_addr . ifname
+		size 65535
+		flags dynamic
+		elements = { 10.0.0.1 . \"wg42\" counter packets 5 bytes 600 }
+	}
+}
+".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            4 => {
+                assert_eq!(program, "nft");
+                assert_eq!(args, vec!["flush", "set", "ip", "rita", "rita_input"]);
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        }
+    }));
+    let result = KI
+        .read_nft_counters(&FilterTarget::Input)
+        .expect("Unable to read values");
+    assert_eq!(result.len(), 2);
+
+    let value = result
+        .get(&(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), "wg42".into()))
+        .expect("Unable to find key");
+    assert_eq!(value, &(600u64 + 5u64 * 40));
 
     let value = result
         .get(&(
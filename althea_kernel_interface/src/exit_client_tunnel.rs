@@ -198,4 +198,25 @@ impl KernelInterface {
 
         Ok(())
     }
+
+    /// Stops forwarding traffic from `lan_nic` out through the exit tunnel, the rule is inserted
+    /// ahead of the ones from `add_client_nat_rules` so it takes precedence over them
+    pub fn block_client_exit_traffic(&self, lan_nic: &str) -> Result<(), Error> {
+        self.add_iptables_rule(
+            "iptables",
+            &[
+                "-w", "-I", "FORWARD", "-i", &lan_nic, "-o", "wg_exit", "-j", "DROP",
+            ],
+        )
+    }
+
+    /// Undoes `block_client_exit_traffic`
+    pub fn unblock_client_exit_traffic(&self, lan_nic: &str) -> Result<(), Error> {
+        self.delete_iptables_rule(
+            "iptables",
+            &[
+                "-w", "-D", "FORWARD", "-i", &lan_nic, "-o", "wg_exit", "-j", "DROP",
+            ],
+        )
+    }
 }
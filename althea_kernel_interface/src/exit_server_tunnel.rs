@@ -1,10 +1,14 @@
+use super::wg_netlink::{read_private_key, WgDeviceConfig, WgPeerConfig};
 use super::{KernelInterface, KernelInterfaceError};
 
+use althea_types::WgKey;
+
 use std::collections::HashSet;
 
 use failure::Error;
 
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 
 #[derive(Debug)]
 pub struct ExitClient {
@@ -22,6 +26,94 @@ impl KernelInterface {
         private_key_path: &str,
         local_ip: &IpAddr,
         netmask: u8,
+    ) -> Result<(), Error> {
+        let configured = self.use_netlink()
+            && match self.netlink_set_exit_wg_config(&clients, listen_port, private_key_path) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Failed to configure wg_exit over netlink {:?}", e);
+                    false
+                }
+            };
+        if !configured {
+            self.wg_set_exit_wg_config(clients, listen_port, private_key_path)?;
+        }
+
+        let _output = self.run_command(
+            "ip",
+            &[
+                "address",
+                "add",
+                &format!("{}/{}", local_ip, netmask),
+                "dev",
+                "wg_exit",
+            ],
+        )?;
+
+        let output = self.run_command("ip", &["link", "set", "dev", "wg_exit", "mtu", "1340"])?;
+        if !output.stderr.is_empty() {
+            return Err(KernelInterfaceError::RuntimeError(format!(
+                "received error adding wg link: {}",
+                String::from_utf8(output.stderr)?
+            )).into());
+        }
+
+        let output = self.run_command("ip", &["link", "set", "dev", "wg_exit", "up"])?;
+        if !output.stderr.is_empty() {
+            return Err(KernelInterfaceError::RuntimeError(format!(
+                "received error setting wg interface up: {}",
+                String::from_utf8(output.stderr)?
+            )).into());
+        }
+
+        Ok(())
+    }
+
+    /// Adds or updates every client and removes peers that are no longer clients in a
+    /// single netlink request, leaving existing sessions and counters untouched
+    fn netlink_set_exit_wg_config(
+        &self,
+        clients: &[ExitClient],
+        listen_port: u16,
+        private_key_path: &str,
+    ) -> Result<(), Error> {
+        let mut peers = Vec::new();
+        let mut client_pubkeys = HashSet::new();
+        for c in clients {
+            let key: WgKey = c.public_key.parse()?;
+            peers.push(WgPeerConfig {
+                public_key: key.clone(),
+                endpoint: Some(SocketAddr::new(c.mesh_ip, c.port)),
+                allowed_ips: vec![(
+                    c.internal_ip,
+                    if c.internal_ip.is_ipv4() { 32 } else { 128 },
+                )],
+                persistent_keepalive: Some(5),
+                remove: false,
+            });
+            client_pubkeys.insert(key);
+        }
+
+        for peer in self.netlink_get_wg_device("wg_exit")?.peers {
+            if !client_pubkeys.contains(&peer.public_key) {
+                peers.push(WgPeerConfig::remove(peer.public_key));
+            }
+        }
+
+        self.netlink_set_wg_device(&WgDeviceConfig {
+            ifname: "wg_exit".to_string(),
+            private_key: Some(read_private_key(Path::new(private_key_path))?),
+            listen_port: Some(listen_port),
+            replace_peers: false,
+            peers,
+        })
+    }
+
+    fn wg_set_exit_wg_config(
+        &self,
+        clients: Vec<ExitClient>,
+        listen_port: u16,
+        private_key_path: &str,
     ) -> Result<(), Error> {
         let command = "wg".to_string();
 
@@ -61,33 +153,6 @@ impl KernelInterface {
             }
         }
 
-        let _output = self.run_command(
-            "ip",
-            &[
-                "address",
-                "add",
-                &format!("{}/{}", local_ip, netmask),
-                "dev",
-                "wg_exit",
-            ],
-        )?;
-
-        let output = self.run_command("ip", &["link", "set", "dev", "wg_exit", "mtu", "1340"])?;
-        if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error adding wg link: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
-        }
-
-        let output = self.run_command("ip", &["link", "set", "dev", "wg_exit", "up"])?;
-        if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error setting wg interface up: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
-        }
-
         Ok(())
     }
 
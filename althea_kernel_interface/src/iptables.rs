@@ -19,4 +19,22 @@ impl KernelInterface {
 
         Ok(())
     }
+
+    /// Deletes a rule if it's present, the counterpart of `add_iptables_rule`
+    pub fn delete_iptables_rule(&self, command: &str, rule: &[&str]) -> Result<(), Error> {
+        assert!(rule.contains(&"-D"));
+
+        let check_rule: Vec<&str> = rule
+            .iter()
+            .map(|x| if x == &"-D" { "-C" } else { x })
+            .collect();
+
+        let check = self.run_command(command, &check_rule)?;
+
+        if check.status.success() {
+            self.run_command(command, rule)?;
+        }
+
+        Ok(())
+    }
 }
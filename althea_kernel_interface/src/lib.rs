@@ -7,6 +7,7 @@ extern crate log;
 
 extern crate eui48;
 extern crate itertools;
+extern crate libc;
 extern crate regex;
 
 extern crate althea_types;
@@ -37,12 +38,15 @@ mod open_tunnel;
 mod openwrt_ubus;
 mod ping_check;
 mod setup_wg_if;
+mod tunnel_suspension;
 mod udp_socket_table;
 pub mod wg_iface_counter;
+mod wg_netlink;
 
 pub use counter::FilterTarget;
 pub use create_wg_key::WgKeypair;
 pub use exit_server_tunnel::ExitClient;
+pub use wg_netlink::{WgDevice, WgPeer};
 
 use failure::Error;
 
@@ -69,6 +73,10 @@ lazy_static! {
 pub trait CommandRunner {
     fn run_command(&self, program: &str, args: &[&str]) -> Result<Output, Error>;
     fn set_mock(&self, mock: Box<FnMut(String, Vec<String>) -> Result<Output, Error> + Send>);
+    /// Whether WireGuard should be configured over netlink rather than by running `wg`
+    fn use_netlink(&self) -> bool {
+        false
+    }
 }
 
 pub struct LinuxCommandRunner;
@@ -106,6 +114,10 @@ impl CommandRunner for LinuxCommandRunner {
     fn set_mock(&self, _mock: Box<FnMut(String, Vec<String>) -> Result<Output, Error> + Send>) {
         unimplemented!()
     }
+
+    fn use_netlink(&self) -> bool {
+        true
+    }
 }
 
 pub struct TestCommandRunner {
@@ -1,6 +1,7 @@
+use super::wg_netlink::{interface_index, read_private_key, WgDeviceConfig, WgPeerConfig};
 use super::{KernelInterface, KernelInterfaceError};
 
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::path::Path;
 
 use althea_types::WgKey;
@@ -54,7 +55,52 @@ fn socket_to_string(endpoint: &SocketAddr, interface_name: Option<String>) -> St
     }
 }
 
+/// Link local endpoints need the index of the interface they are reachable over, the
+/// netlink equivalent of the `%iface` suffix produced by `socket_to_string`
+fn endpoint_with_scope(
+    endpoint: &SocketAddr,
+    interface_name: &Option<String>,
+) -> Result<SocketAddr, Error> {
+    match (endpoint, interface_name) {
+        (&SocketAddr::V6(endpoint), &Some(ref name))
+            if is_link_local(IpAddr::V6(*endpoint.ip())) =>
+        {
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                *endpoint.ip(),
+                endpoint.port(),
+                0,
+                interface_index(name)?,
+            )))
+        }
+        _ => Ok(*endpoint),
+    }
+}
+
 impl KernelInterface {
+    /// Sets our key and port and the single peer of a tunnel in one netlink request
+    fn netlink_open_tunnel(
+        &self,
+        interface: &str,
+        port: u16,
+        endpoint: Option<SocketAddr>,
+        remote_pub_key: &WgKey,
+        private_key_path: &Path,
+    ) -> Result<(), Error> {
+        self.netlink_set_wg_device(&WgDeviceConfig {
+            ifname: interface.to_string(),
+            private_key: Some(read_private_key(private_key_path)?),
+            listen_port: Some(port),
+            replace_peers: false,
+            peers: vec![WgPeerConfig {
+                public_key: remote_pub_key.clone(),
+                endpoint,
+                allowed_ips: vec![(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)],
+                persistent_keepalive: Some(5),
+                remove: false,
+            }],
+        })
+    }
+
     pub fn open_tunnel(
         &self,
         interface: &String,
@@ -78,32 +124,51 @@ impl KernelInterface {
                 external_nic
             }
         };
-        let socket_connect_str = socket_to_string(endpoint, phy_name);
-        trace!("socket conenct string: {}", socket_connect_str);
-        let output = self.run_command(
-            "wg",
-            &[
-                "set",
-                &interface,
-                "listen-port",
-                &format!("{}", port),
-                "private-key",
-                &format!("{}", private_key_path.to_str().unwrap()),
-                "peer",
-                &format!("{}", remote_pub_key),
-                "endpoint",
-                &socket_connect_str,
-                "allowed-ips",
-                "::/0",
-                "persistent-keepalive",
-                "5",
-            ],
-        )?;
-        if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error from wg command: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+        let configured = self.use_netlink()
+            && match endpoint_with_scope(endpoint, &phy_name).and_then(|endpoint| {
+                self.netlink_open_tunnel(
+                    interface,
+                    port,
+                    Some(endpoint),
+                    remote_pub_key,
+                    private_key_path,
+                )
+            }) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Failed to configure {} over netlink {:?}", interface, e);
+                    false
+                }
+            };
+
+        if !configured {
+            let socket_connect_str = socket_to_string(endpoint, phy_name);
+            trace!("socket conenct string: {}", socket_connect_str);
+            let output = self.run_command(
+                "wg",
+                &[
+                    "set",
+                    &interface,
+                    "listen-port",
+                    &format!("{}", port),
+                    "private-key",
+                    &format!("{}", private_key_path.to_str().unwrap()),
+                    "peer",
+                    &format!("{}", remote_pub_key),
+                    "endpoint",
+                    &socket_connect_str,
+                    "allowed-ips",
+                    "::/0",
+                    "persistent-keepalive",
+                    "5",
+                ],
+            )?;
+            if !output.stderr.is_empty() {
+                return Err(KernelInterfaceError::RuntimeError(format!(
+                    "received error from wg command: {}",
+                    String::from_utf8(output.stderr)?
+                )).into());
+            }
         }
         let _output = self.run_command(
             "ip",
@@ -143,28 +208,44 @@ impl KernelInterface {
         private_key_path: &Path,
         own_ip: &IpAddr,
     ) -> Result<(), Error> {
-        let output = self.run_command(
-            "wg",
-            &[
-                "set",
-                &interface,
-                "listen-port",
-                &format!("{}", port),
-                "private-key",
-                &format!("{}", private_key_path.to_str().unwrap()),
-                "peer",
-                &format!("{}", remote_pub_key),
-                "allowed-ips",
-                "::/0",
-                "persistent-keepalive",
-                "5",
-            ],
-        )?;
-        if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error from wg command: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+        let configured = self.use_netlink()
+            && match remote_pub_key
+                .parse::<WgKey>()
+                .map_err(Error::from)
+                .and_then(|key| {
+                    self.netlink_open_tunnel(interface, port, None, &key, private_key_path)
+                }) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Failed to configure {} over netlink {:?}", interface, e);
+                    false
+                }
+            };
+
+        if !configured {
+            let output = self.run_command(
+                "wg",
+                &[
+                    "set",
+                    &interface,
+                    "listen-port",
+                    &format!("{}", port),
+                    "private-key",
+                    &format!("{}", private_key_path.to_str().unwrap()),
+                    "peer",
+                    &format!("{}", remote_pub_key),
+                    "allowed-ips",
+                    "::/0",
+                    "persistent-keepalive",
+                    "5",
+                ],
+            )?;
+            if !output.stderr.is_empty() {
+                return Err(KernelInterfaceError::RuntimeError(format!(
+                    "received error from wg command: {}",
+                    String::from_utf8(output.stderr)?
+                )).into());
+            }
         }
         let _output = self.run_command(
             "ip",
@@ -197,7 +278,6 @@ impl KernelInterface {
 fn test_open_tunnel_linux() {
     use KI;
 
-    use std::net::SocketAddrV6;
     use std::os::unix::process::ExitStatusExt;
     use std::process::ExitStatus;
     use std::process::Output;
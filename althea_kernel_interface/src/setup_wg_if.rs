@@ -8,6 +8,13 @@ use failure::Error;
 
 impl KernelInterface {
     pub fn get_peers(&self, iface_name: &str) -> Result<Vec<WgKey>, Error> {
+        if self.use_netlink() {
+            match self.netlink_get_wg_device(iface_name) {
+                Ok(device) => return Ok(device.peers.into_iter().map(|p| p.public_key).collect()),
+                Err(e) => warn!("Failed to get {} peers over netlink {:?}", iface_name, e),
+            }
+        }
+
         let output = self.run_command("wg", &["show", iface_name, "peers"])?;
 
         let output = from_utf8(&output.stdout)?;
@@ -43,6 +50,13 @@ impl KernelInterface {
 
     /// calls iproute2 to set up a new interface with a given name.
     pub fn setup_wg_if_named(&self, name: &str) -> Result<(), Error> {
+        if self.use_netlink() {
+            match self.netlink_create_wg_if(name) {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!("Failed to create {} over netlink {:?}", name, e),
+            }
+        }
+
         let output = self.run_command("ip", &["link", "add", &name, "type", "wireguard"])?;
         let stderr = String::from_utf8(output.stderr)?;
         if !stderr.is_empty() {
@@ -60,6 +74,24 @@ impl KernelInterface {
 
     /// Returns the number of clients that are active on the wg_exit tunnel
     pub fn get_wg_exit_clients_online(&self) -> Result<u32, Error> {
+        if self.use_netlink() {
+            match self.netlink_get_wg_device("wg_exit") {
+                Ok(device) => {
+                    let now = SystemTime::now();
+                    return Ok(device
+                        .peers
+                        .iter()
+                        .filter_map(|p| p.last_handshake)
+                        .filter(|d| match now.duration_since(*d) {
+                            Ok(elapsed) => elapsed < Duration::new(600, 0),
+                            // handshake in the future, clock skew, count it as online
+                            Err(_) => true,
+                        }).count() as u32);
+                }
+                Err(e) => warn!("Failed to get wg_exit handshakes over netlink {:?}", e),
+            }
+        }
+
         let output = self.run_command("wg", &["show", "wg_exit", "latest-handshakes"])?;
         let mut num: u32 = 0;
         let out = String::from_utf8(output.stdout)?;
@@ -0,0 +1,150 @@
+//! Rules for suspending forwarding over a tunnel when the neighbor on the other end has fallen too
+//! far behind on payments. Only forwarded traffic is dropped, the neighbor can still reach us
+//! directly so babel keeps running and payments can still come in to clear the debt.
+
+use super::KernelInterface;
+
+use failure::Error;
+
+/// All suspension rules live in this chain so that stale rules for interfaces that have since
+/// been deleted (and may be reused for another neighbor) can be flushed on startup
+static SUSPENDED_CHAIN: &'static str = "rita_suspended";
+
+/// Traffic of both address families is billed, so forwarding is suspended for both
+static IPTABLES_COMMANDS: [&'static str; 2] = ["ip6tables", "iptables"];
+
+impl KernelInterface {
+    /// Creates the suspension chain if it doesn't exist yet, hooks it into FORWARD and clears out
+    /// any leftovers from a previous run
+    pub fn init_tunnel_suspension(&self) -> Result<(), Error> {
+        for command in IPTABLES_COMMANDS.iter() {
+            // fails harmlessly if the chain already exists
+            self.run_command(command, &["-w", "-N", SUSPENDED_CHAIN])?;
+            self.add_iptables_rule(command, &["-w", "-I", "FORWARD", "-j", SUSPENDED_CHAIN])?;
+            self.run_command(command, &["-w", "-F", SUSPENDED_CHAIN])?;
+        }
+        Ok(())
+    }
+
+    /// Drops any traffic forwarded through us to or from this interface
+    pub fn suspend_tunnel(&self, iface_name: &str) -> Result<(), Error> {
+        for command in IPTABLES_COMMANDS.iter() {
+            self.add_iptables_rule(
+                command,
+                &["-w", "-A", SUSPENDED_CHAIN, "-i", iface_name, "-j", "DROP"],
+            )?;
+            self.add_iptables_rule(
+                command,
+                &["-w", "-A", SUSPENDED_CHAIN, "-o", iface_name, "-j", "DROP"],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Undoes `suspend_tunnel`
+    pub fn resume_tunnel(&self, iface_name: &str) -> Result<(), Error> {
+        for command in IPTABLES_COMMANDS.iter() {
+            self.delete_iptables_rule(
+                command,
+                &["-w", "-D", SUSPENDED_CHAIN, "-i", iface_name, "-j", "DROP"],
+            )?;
+            self.delete_iptables_rule(
+                command,
+                &["-w", "-D", SUSPENDED_CHAIN, "-o", iface_name, "-j", "DROP"],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_suspend_tunnel() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    use KI;
+
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        assert_eq!(
+            program,
+            if counter <= 3 {
+                "ip6tables"
+            } else {
+                "iptables"
+            }
+        );
+        let (expected, status) = match counter {
+            1 | 4 => (
+                vec!["-w", "-C", "rita_suspended", "-i", "wg0", "-j", "DROP"],
+                1,
+            ),
+            2 | 5 => (
+                vec!["-w", "-A", "rita_suspended", "-i", "wg0", "-j", "DROP"],
+                0,
+            ),
+            // the outgoing rule is already in place so it's not added twice
+            3 | 6 => (
+                vec!["-w", "-C", "rita_suspended", "-o", "wg0", "-j", "DROP"],
+                0,
+            ),
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        };
+        assert_eq!(args, expected);
+        Ok(Output {
+            stdout: b"".to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(status),
+        })
+    }));
+    KI.suspend_tunnel("wg0").expect("Unable to suspend tunnel");
+}
+
+#[test]
+fn test_resume_tunnel() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    use KI;
+
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        assert_eq!(
+            program,
+            if counter <= 3 {
+                "ip6tables"
+            } else {
+                "iptables"
+            }
+        );
+        let (expected, status) = match counter {
+            1 | 4 => (
+                vec!["-w", "-C", "rita_suspended", "-i", "wg0", "-j", "DROP"],
+                0,
+            ),
+            2 | 5 => (
+                vec!["-w", "-D", "rita_suspended", "-i", "wg0", "-j", "DROP"],
+                0,
+            ),
+            // nothing to delete
+            3 | 6 => (
+                vec!["-w", "-C", "rita_suspended", "-o", "wg0", "-j", "DROP"],
+                1,
+            ),
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        };
+        assert_eq!(args, expected);
+        Ok(Output {
+            stdout: b"".to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(status),
+        })
+    }));
+    KI.resume_tunnel("wg0").expect("Unable to resume tunnel");
+}
@@ -21,6 +21,24 @@ impl KernelInterface {
     /// Takes a wg interface name and provides upload and download since creation in bytes
     /// in a hashmap indexed by peer WireGuard key
     pub fn read_wg_counters(&self, wg_name: &str) -> Result<HashMap<WgKey, WgUsage>, Error> {
+        if self.use_netlink() {
+            match self.netlink_get_wg_device(wg_name) {
+                Ok(device) => {
+                    return Ok(device
+                        .peers
+                        .into_iter()
+                        .map(|peer| {
+                            let usage = WgUsage {
+                                upload: peer.tx_bytes,
+                                download: peer.rx_bytes,
+                            };
+                            (peer.public_key, usage)
+                        }).collect())
+                }
+                Err(e) => warn!("Failed to read {} counters over netlink {:?}", wg_name, e),
+            }
+        }
+
         let output = self.run_command("wg", &["show", wg_name, "transfer"])?;
         if !output.stderr.is_empty() {
             return Err(KernelInterfaceError::RuntimeError(format!(
@@ -0,0 +1,804 @@
+//! A small generic netlink client for the in kernel WireGuard module, this lets us create
+//! interfaces, configure keys, ports and peers and read per peer counters and handshake times
+//! without spawning the `wg` binary and parsing its text output. The command based code paths
+//! remain as a fallback for when netlink is not available, see `CommandRunner::use_netlink`
+
+use super::{KernelInterface, KernelInterfaceError};
+
+use althea_types::WgKey;
+
+use failure::Error;
+
+use libc;
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const NETLINK_ROUTE: i32 = 0;
+const NETLINK_GENERIC: i32 = 16;
+
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_DUMP: u16 = 0x300;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+const NLA_HDRLEN: usize = 4;
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_TYPE_MASK: u16 = !(3 << 14);
+
+const GENL_HDRLEN: usize = 4;
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+const RTM_NEWLINK: u16 = 16;
+const IFINFOMSG_LEN: usize = 16;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_INFO_KIND: u16 = 1;
+
+const WG_GENL_NAME: &str = "wireguard";
+const WG_GENL_VERSION: u8 = 1;
+const WG_CMD_GET_DEVICE: u8 = 0;
+const WG_CMD_SET_DEVICE: u8 = 1;
+
+const WGDEVICE_A_IFNAME: u16 = 2;
+const WGDEVICE_A_PRIVATE_KEY: u16 = 3;
+const WGDEVICE_A_PUBLIC_KEY: u16 = 4;
+const WGDEVICE_A_FLAGS: u16 = 5;
+const WGDEVICE_A_LISTEN_PORT: u16 = 6;
+const WGDEVICE_A_PEERS: u16 = 8;
+const WGDEVICE_F_REPLACE_PEERS: u32 = 1;
+
+const WGPEER_A_PUBLIC_KEY: u16 = 1;
+const WGPEER_A_FLAGS: u16 = 3;
+const WGPEER_A_ENDPOINT: u16 = 4;
+const WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL: u16 = 5;
+const WGPEER_A_LAST_HANDSHAKE_TIME: u16 = 6;
+const WGPEER_A_RX_BYTES: u16 = 7;
+const WGPEER_A_TX_BYTES: u16 = 8;
+const WGPEER_A_ALLOWEDIPS: u16 = 9;
+const WGPEER_F_REMOVE_ME: u32 = 1;
+const WGPEER_F_REPLACE_ALLOWEDIPS: u32 = 2;
+
+const WGALLOWEDIP_A_FAMILY: u16 = 1;
+const WGALLOWEDIP_A_IPADDR: u16 = 2;
+const WGALLOWEDIP_A_CIDR_MASK: u16 = 3;
+
+const AF_INET: u16 = 2;
+const AF_INET6: u16 = 10;
+
+/// The state of a WireGuard interface as reported by the kernel
+#[derive(Debug, Clone, PartialEq)]
+pub struct WgDevice {
+    pub ifname: String,
+    pub public_key: Option<WgKey>,
+    pub listen_port: u16,
+    pub peers: Vec<WgPeer>,
+}
+
+/// A single peer of a WireGuard interface, the byte counters are totals since the peer
+/// was added to the interface
+#[derive(Debug, Clone, PartialEq)]
+pub struct WgPeer {
+    pub public_key: WgKey,
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<(IpAddr, u8)>,
+    pub persistent_keepalive: u16,
+    /// None if no handshake has ever completed
+    pub last_handshake: Option<SystemTime>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// Changes to apply to a WireGuard interface, anything left as None is not touched
+#[derive(Debug, Clone, Default)]
+pub struct WgDeviceConfig {
+    pub ifname: String,
+    pub private_key: Option<WgKey>,
+    pub listen_port: Option<u16>,
+    pub replace_peers: bool,
+    pub peers: Vec<WgPeerConfig>,
+}
+
+/// Changes to apply to a single peer, `allowed_ips` always replaces the existing set
+#[derive(Debug, Clone)]
+pub struct WgPeerConfig {
+    pub public_key: WgKey,
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<(IpAddr, u8)>,
+    pub persistent_keepalive: Option<u16>,
+    pub remove: bool,
+}
+
+impl WgPeerConfig {
+    pub fn remove(public_key: WgKey) -> WgPeerConfig {
+        WgPeerConfig {
+            public_key,
+            endpoint: None,
+            allowed_ips: Vec::new(),
+            persistent_keepalive: None,
+            remove: true,
+        }
+    }
+}
+
+/// Netlink uses host byte order for everything but addresses and ports, these write
+/// and read unsigned integers of `width` bytes in host order
+fn put_ne(buf: &mut Vec<u8>, value: u64, width: usize) {
+    let mut bytes: Vec<u8> = (0..width)
+        .map(|i| (value >> (8 * (width - 1 - i))) as u8)
+        .collect();
+    if cfg!(target_endian = "little") {
+        bytes.reverse();
+    }
+    buf.extend_from_slice(&bytes);
+}
+
+fn get_ne(data: &[u8], width: usize) -> Result<u64, Error> {
+    if data.len() < width {
+        bail!(
+            "netlink value too short, expected {} got {}",
+            width,
+            data.len()
+        );
+    }
+    let mut bytes = data[..width].to_vec();
+    if cfg!(target_endian = "little") {
+        bytes.reverse();
+    }
+    Ok(bytes.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b)))
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    let len = align(buf.len());
+    buf.resize(len, 0);
+}
+
+fn put_attr(buf: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    put_ne(buf, (NLA_HDRLEN + data.len()) as u64, 2);
+    put_ne(buf, u64::from(kind), 2);
+    buf.extend_from_slice(data);
+    pad(buf);
+}
+
+fn put_attr_ne(buf: &mut Vec<u8>, kind: u16, value: u64, width: usize) {
+    let mut data = Vec::new();
+    put_ne(&mut data, value, width);
+    put_attr(buf, kind, &data);
+}
+
+fn put_attr_str(buf: &mut Vec<u8>, kind: u16, value: &str) {
+    let mut data = value.as_bytes().to_vec();
+    data.push(0);
+    put_attr(buf, kind, &data);
+}
+
+/// Opens a nested attribute, returning the offset to pass to `end_nested` once the
+/// contents have been written
+fn begin_nested(buf: &mut Vec<u8>, kind: u16) -> usize {
+    let start = buf.len();
+    put_ne(buf, 0, 2);
+    put_ne(buf, u64::from(kind | NLA_F_NESTED), 2);
+    start
+}
+
+fn end_nested(buf: &mut Vec<u8>, start: usize) {
+    let mut len = Vec::new();
+    put_ne(&mut len, (buf.len() - start) as u64, 2);
+    buf[start] = len[0];
+    buf[start + 1] = len[1];
+}
+
+/// Splits a buffer of netlink attributes into (type, payload) pairs
+fn parse_attrs(mut data: &[u8]) -> Result<Vec<(u16, &[u8])>, Error> {
+    let mut attrs = Vec::new();
+    while data.len() >= NLA_HDRLEN {
+        let len = get_ne(data, 2)? as usize;
+        let kind = get_ne(&data[2..], 2)? as u16 & NLA_TYPE_MASK;
+        if len < NLA_HDRLEN || len > data.len() {
+            bail!("malformed netlink attribute of length {}", len);
+        }
+        attrs.push((kind, &data[NLA_HDRLEN..len]));
+        data = &data[align(len).min(data.len())..];
+    }
+    Ok(attrs)
+}
+
+fn parse_str(data: &[u8]) -> Result<String, Error> {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    Ok(String::from_utf8(data[..end].to_vec())?)
+}
+
+fn parse_key(data: &[u8]) -> Result<WgKey, Error> {
+    if data.len() != 32 {
+        bail!("WireGuard key of length {}", data.len());
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(data);
+    Ok(WgKey::from(key))
+}
+
+/// Prepends a netlink header to a message body
+fn nl_message(kind: u16, flags: u16, seq: u32, body: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(NLMSG_HDRLEN + body.len());
+    put_ne(&mut msg, (NLMSG_HDRLEN + body.len()) as u64, 4);
+    put_ne(&mut msg, u64::from(kind), 2);
+    put_ne(&mut msg, u64::from(flags), 2);
+    put_ne(&mut msg, u64::from(seq), 4);
+    put_ne(&mut msg, 0, 4);
+    msg.extend_from_slice(body);
+    msg
+}
+
+fn genl_header(cmd: u8, version: u8) -> Vec<u8> {
+    vec![cmd, version, 0, 0]
+}
+
+fn encode_sockaddr(addr: &SocketAddr) -> Vec<u8> {
+    let mut buf = Vec::new();
+    match addr {
+        SocketAddr::V4(addr) => {
+            put_ne(&mut buf, u64::from(AF_INET), 2);
+            buf.extend_from_slice(&[(addr.port() >> 8) as u8, addr.port() as u8]);
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&[0u8; 8]);
+        }
+        SocketAddr::V6(addr) => {
+            put_ne(&mut buf, u64::from(AF_INET6), 2);
+            buf.extend_from_slice(&[(addr.port() >> 8) as u8, addr.port() as u8]);
+            buf.extend_from_slice(&[0u8; 4]);
+            buf.extend_from_slice(&addr.ip().octets());
+            put_ne(&mut buf, u64::from(addr.scope_id()), 4);
+        }
+    }
+    buf
+}
+
+fn parse_sockaddr(data: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    let family = get_ne(data, 2)? as u16;
+    if data.len() >= 16 && family == AF_INET {
+        let port = (u16::from(data[2]) << 8) | u16::from(data[3]);
+        let ip = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
+        Ok(Some(SocketAddr::V4(SocketAddrV4::new(ip, port))))
+    } else if data.len() >= 28 && family == AF_INET6 {
+        let port = (u16::from(data[2]) << 8) | u16::from(data[3]);
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&data[8..24]);
+        let scope_id = get_ne(&data[24..], 4)? as u32;
+        Ok(Some(SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::from(octets),
+            port,
+            0,
+            scope_id,
+        ))))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Builds the body (everything after the netlink header) of a WG_CMD_SET_DEVICE request
+fn encode_set_device(config: &WgDeviceConfig) -> Vec<u8> {
+    let mut body = genl_header(WG_CMD_SET_DEVICE, WG_GENL_VERSION);
+    put_attr_str(&mut body, WGDEVICE_A_IFNAME, &config.ifname);
+    if let Some(ref key) = config.private_key {
+        put_attr(&mut body, WGDEVICE_A_PRIVATE_KEY, key.as_ref());
+    }
+    if let Some(port) = config.listen_port {
+        put_attr_ne(&mut body, WGDEVICE_A_LISTEN_PORT, u64::from(port), 2);
+    }
+    if config.replace_peers {
+        put_attr_ne(
+            &mut body,
+            WGDEVICE_A_FLAGS,
+            u64::from(WGDEVICE_F_REPLACE_PEERS),
+            4,
+        );
+    }
+    if !config.peers.is_empty() {
+        let peers = begin_nested(&mut body, WGDEVICE_A_PEERS);
+        for (i, peer) in config.peers.iter().enumerate() {
+            let entry = begin_nested(&mut body, i as u16);
+            put_attr(&mut body, WGPEER_A_PUBLIC_KEY, peer.public_key.as_ref());
+            if peer.remove {
+                put_attr_ne(&mut body, WGPEER_A_FLAGS, u64::from(WGPEER_F_REMOVE_ME), 4);
+                end_nested(&mut body, entry);
+                continue;
+            }
+            put_attr_ne(
+                &mut body,
+                WGPEER_A_FLAGS,
+                u64::from(WGPEER_F_REPLACE_ALLOWEDIPS),
+                4,
+            );
+            if let Some(ref endpoint) = peer.endpoint {
+                put_attr(&mut body, WGPEER_A_ENDPOINT, &encode_sockaddr(endpoint));
+            }
+            if let Some(keepalive) = peer.persistent_keepalive {
+                put_attr_ne(
+                    &mut body,
+                    WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL,
+                    u64::from(keepalive),
+                    2,
+                );
+            }
+            let ips = begin_nested(&mut body, WGPEER_A_ALLOWEDIPS);
+            for (j, &(ip, cidr)) in peer.allowed_ips.iter().enumerate() {
+                let ip_entry = begin_nested(&mut body, j as u16);
+                match ip {
+                    IpAddr::V4(ip) => {
+                        put_attr_ne(&mut body, WGALLOWEDIP_A_FAMILY, u64::from(AF_INET), 2);
+                        put_attr(&mut body, WGALLOWEDIP_A_IPADDR, &ip.octets());
+                    }
+                    IpAddr::V6(ip) => {
+                        put_attr_ne(&mut body, WGALLOWEDIP_A_FAMILY, u64::from(AF_INET6), 2);
+                        put_attr(&mut body, WGALLOWEDIP_A_IPADDR, &ip.octets());
+                    }
+                }
+                put_attr(&mut body, WGALLOWEDIP_A_CIDR_MASK, &[cidr]);
+                end_nested(&mut body, ip_entry);
+            }
+            end_nested(&mut body, ips);
+            end_nested(&mut body, entry);
+        }
+        end_nested(&mut body, peers);
+    }
+    body
+}
+
+fn parse_allowed_ip(data: &[u8]) -> Result<Option<(IpAddr, u8)>, Error> {
+    let mut family = 0;
+    let mut addr = None;
+    let mut cidr = 0;
+    for (kind, value) in parse_attrs(data)? {
+        match kind {
+            WGALLOWEDIP_A_FAMILY => family = get_ne(value, 2)? as u16,
+            WGALLOWEDIP_A_IPADDR => addr = Some(value),
+            WGALLOWEDIP_A_CIDR_MASK => cidr = get_ne(value, 1)? as u8,
+            _ => {}
+        }
+    }
+    Ok(match (family, addr) {
+        (AF_INET, Some(a)) if a.len() == 4 => {
+            Some((IpAddr::V4(Ipv4Addr::new(a[0], a[1], a[2], a[3])), cidr))
+        }
+        (AF_INET6, Some(a)) if a.len() == 16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(a);
+            Some((IpAddr::V6(Ipv6Addr::from(octets)), cidr))
+        }
+        _ => None,
+    })
+}
+
+fn parse_peer(data: &[u8]) -> Result<WgPeer, Error> {
+    let mut public_key = None;
+    let mut peer = WgPeer {
+        public_key: WgKey::from([0u8; 32]),
+        endpoint: None,
+        allowed_ips: Vec::new(),
+        persistent_keepalive: 0,
+        last_handshake: None,
+        rx_bytes: 0,
+        tx_bytes: 0,
+    };
+    for (kind, value) in parse_attrs(data)? {
+        match kind {
+            WGPEER_A_PUBLIC_KEY => public_key = Some(parse_key(value)?),
+            WGPEER_A_ENDPOINT => peer.endpoint = parse_sockaddr(value)?,
+            WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL => {
+                peer.persistent_keepalive = get_ne(value, 2)? as u16
+            }
+            WGPEER_A_LAST_HANDSHAKE_TIME => {
+                let secs = get_ne(value, 8)?;
+                let nanos = get_ne(&value[value.len().min(8)..], 8)?;
+                if secs != 0 || nanos != 0 {
+                    peer.last_handshake = Some(UNIX_EPOCH + Duration::new(secs, nanos as u32));
+                }
+            }
+            WGPEER_A_RX_BYTES => peer.rx_bytes = get_ne(value, 8)?,
+            WGPEER_A_TX_BYTES => peer.tx_bytes = get_ne(value, 8)?,
+            WGPEER_A_ALLOWEDIPS => {
+                for (_, ip) in parse_attrs(value)? {
+                    if let Some(ip) = parse_allowed_ip(ip)? {
+                        peer.allowed_ips.push(ip);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    match public_key {
+        Some(key) => {
+            peer.public_key = key;
+            Ok(peer)
+        }
+        None => bail!("WireGuard peer without a public key"),
+    }
+}
+
+/// Folds the messages of a WG_CMD_GET_DEVICE dump into a single device, the kernel splits
+/// large devices across several messages and may split a single peer's allowed ips between
+/// two of them, in which case the peer is repeated with only its key and the remaining ips
+fn parse_device(messages: &[Vec<u8>]) -> Result<WgDevice, Error> {
+    let mut device = WgDevice {
+        ifname: String::new(),
+        public_key: None,
+        listen_port: 0,
+        peers: Vec::new(),
+    };
+    for msg in messages {
+        if msg.len() < GENL_HDRLEN {
+            bail!("truncated generic netlink message");
+        }
+        for (kind, value) in parse_attrs(&msg[GENL_HDRLEN..])? {
+            match kind {
+                WGDEVICE_A_IFNAME => device.ifname = parse_str(value)?,
+                WGDEVICE_A_PUBLIC_KEY => device.public_key = Some(parse_key(value)?),
+                WGDEVICE_A_LISTEN_PORT => device.listen_port = get_ne(value, 2)? as u16,
+                WGDEVICE_A_PEERS => {
+                    for (_, peer) in parse_attrs(value)? {
+                        let peer = parse_peer(peer)?;
+                        if let Some(last) = device.peers.last_mut() {
+                            if last.public_key == peer.public_key {
+                                last.allowed_ips.extend(peer.allowed_ips);
+                                continue;
+                            }
+                        }
+                        device.peers.push(peer);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(device)
+}
+
+/// A raw netlink socket, closed on drop
+struct NetlinkSocket {
+    fd: libc::c_int,
+    seq: u32,
+}
+
+impl NetlinkSocket {
+    fn open(protocol: i32) -> Result<NetlinkSocket, Error> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                protocol,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let socket = NetlinkSocket { fd, seq: 0 };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let res = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(socket)
+    }
+
+    /// Sends a request and collects the payloads of every reply up to the final ack or
+    /// end of dump, a negative errno from the kernel is returned as an io::Error
+    fn request(&mut self, kind: u16, flags: u16, body: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        self.seq = self.seq.wrapping_add(1);
+        let seq = self.seq;
+        let msg = nl_message(kind, flags | NLM_F_REQUEST | NLM_F_ACK, seq, body);
+        let sent =
+            unsafe { libc::send(self.fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut replies = Vec::new();
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let len =
+                unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if len < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            let mut data = &buf[..len as usize];
+            while data.len() >= NLMSG_HDRLEN {
+                let msg_len = get_ne(data, 4)? as usize;
+                let msg_kind = get_ne(&data[4..], 2)? as u16;
+                let msg_seq = get_ne(&data[8..], 4)? as u32;
+                if msg_len < NLMSG_HDRLEN || msg_len > data.len() {
+                    bail!("malformed netlink message of length {}", msg_len);
+                }
+                let payload = &data[NLMSG_HDRLEN..msg_len];
+                data = &data[align(msg_len).min(data.len())..];
+
+                if msg_seq != seq {
+                    continue;
+                }
+                match msg_kind {
+                    NLMSG_DONE => return Ok(replies),
+                    NLMSG_ERROR => {
+                        let errno = get_ne(payload, 4)? as u32 as i32;
+                        if errno == 0 {
+                            return Ok(replies);
+                        }
+                        return Err(io::Error::from_raw_os_error(-errno).into());
+                    }
+                    _ => replies.push(payload.to_vec()),
+                }
+            }
+        }
+    }
+
+    /// Looks up the id the kernel assigned to a generic netlink family
+    fn resolve_family(&mut self, name: &str) -> Result<u16, Error> {
+        let mut body = genl_header(CTRL_CMD_GETFAMILY, 1);
+        put_attr_str(&mut body, CTRL_ATTR_FAMILY_NAME, name);
+        for reply in self.request(GENL_ID_CTRL, 0, &body)? {
+            if reply.len() < GENL_HDRLEN {
+                continue;
+            }
+            for (kind, value) in parse_attrs(&reply[GENL_HDRLEN..])? {
+                if kind == CTRL_ATTR_FAMILY_ID {
+                    return Ok(get_ne(value, 2)? as u16);
+                }
+            }
+        }
+        bail!("generic netlink family {} not found", name)
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Reads a base64 private key file as written by `wg genkey`
+pub fn read_private_key(path: &Path) -> Result<WgKey, Error> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    let contents = contents.trim();
+    if contents.len() != 44 {
+        bail!("{:?} does not contain a WireGuard key", path);
+    }
+    Ok(contents.parse()?)
+}
+
+/// Returns the index of a network interface, used as the scope id of link local endpoints
+pub fn interface_index(name: &str) -> Result<u32, Error> {
+    let c_name = CString::new(name)?;
+    let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if index == 0 {
+        return Err(
+            KernelInterfaceError::RuntimeError(format!("no interface named {}", name)).into(),
+        );
+    }
+    Ok(index)
+}
+
+impl KernelInterface {
+    /// Creates a WireGuard interface with rtnetlink, an existing interface of the same name
+    /// is not an error
+    pub fn netlink_create_wg_if(&self, name: &str) -> Result<(), Error> {
+        let mut socket = NetlinkSocket::open(NETLINK_ROUTE)?;
+        let mut body = vec![0u8; IFINFOMSG_LEN];
+        put_attr_str(&mut body, IFLA_IFNAME, name);
+        let linkinfo = begin_nested(&mut body, IFLA_LINKINFO);
+        put_attr(&mut body, IFLA_INFO_KIND, WG_GENL_NAME.as_bytes());
+        end_nested(&mut body, linkinfo);
+
+        match socket.request(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL, &body) {
+            Ok(_) => Ok(()),
+            Err(e) => match e.downcast_ref::<io::Error>() {
+                Some(io_err) if io_err.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// Reads the full state of a WireGuard interface, including per peer counters
+    pub fn netlink_get_wg_device(&self, name: &str) -> Result<WgDevice, Error> {
+        let mut socket = NetlinkSocket::open(NETLINK_GENERIC)?;
+        let family = socket.resolve_family(WG_GENL_NAME)?;
+        let mut body = genl_header(WG_CMD_GET_DEVICE, WG_GENL_VERSION);
+        put_attr_str(&mut body, WGDEVICE_A_IFNAME, name);
+        let replies = socket.request(family, NLM_F_DUMP, &body)?;
+        parse_device(&replies)
+    }
+
+    /// Applies a configuration to a WireGuard interface in a single request
+    pub fn netlink_set_wg_device(&self, config: &WgDeviceConfig) -> Result<(), Error> {
+        let mut socket = NetlinkSocket::open(NETLINK_GENERIC)?;
+        let family = socket.resolve_family(WG_GENL_NAME)?;
+        socket.request(family, 0, &encode_set_device(config))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_ne_round_trip() {
+    let mut buf = Vec::new();
+    put_ne(&mut buf, 0x1234, 2);
+    put_ne(&mut buf, 0xdead_beef, 4);
+    put_ne(&mut buf, 13592616000, 8);
+    assert_eq!(buf.len(), 14);
+    assert_eq!(get_ne(&buf, 2).unwrap(), 0x1234);
+    assert_eq!(get_ne(&buf[2..], 4).unwrap(), 0xdead_beef);
+    assert_eq!(get_ne(&buf[6..], 8).unwrap(), 13592616000);
+    assert!(get_ne(&buf[12..], 4).is_err());
+}
+
+#[test]
+fn test_attrs_are_padded_and_nested() {
+    let mut buf = Vec::new();
+    put_attr_str(&mut buf, WGDEVICE_A_IFNAME, "wg0");
+    let nested = begin_nested(&mut buf, WGDEVICE_A_PEERS);
+    put_attr(&mut buf, 0, &[1, 2, 3, 4, 5]);
+    end_nested(&mut buf, nested);
+    // 4 header + "wg0\0", then 4 header + (4 header + 5 data + 3 pad)
+    assert_eq!(buf.len(), 8 + 16);
+
+    let attrs = parse_attrs(&buf).unwrap();
+    assert_eq!(attrs.len(), 2);
+    assert_eq!(attrs[0].0, WGDEVICE_A_IFNAME);
+    assert_eq!(parse_str(attrs[0].1).unwrap(), "wg0");
+    assert_eq!(attrs[1].0, WGDEVICE_A_PEERS);
+    let inner = parse_attrs(attrs[1].1).unwrap();
+    assert_eq!(inner, vec![(0, &[1u8, 2, 3, 4, 5][..])]);
+}
+
+#[test]
+fn test_malformed_attr_rejected() {
+    let mut buf = Vec::new();
+    put_ne(&mut buf, 64, 2);
+    put_ne(&mut buf, 1, 2);
+    buf.extend_from_slice(&[0u8; 4]);
+    assert!(parse_attrs(&buf).is_err());
+}
+
+#[test]
+fn test_sockaddr_round_trip() {
+    let v4: SocketAddr = "192.168.1.1:51820".parse().unwrap();
+    let encoded = encode_sockaddr(&v4);
+    assert_eq!(encoded.len(), 16);
+    assert_eq!(&encoded[2..4], &[0xca, 0x6c]);
+    assert_eq!(parse_sockaddr(&encoded).unwrap(), Some(v4));
+
+    let v6 = SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 8088, 0, 3));
+    let encoded = encode_sockaddr(&v6);
+    assert_eq!(encoded.len(), 28);
+    assert_eq!(parse_sockaddr(&encoded).unwrap(), Some(v6));
+}
+
+#[test]
+fn test_set_device_parses_back() {
+    let key: WgKey = "x8AcR9wI4t97aowYFlis077BDBk9SLdq6khMiixuTsQ="
+        .parse()
+        .unwrap();
+    let stale: WgKey = "jkIodvXKgij/rAEQXFEPJpls6ooxXJEC5XlWA1uUPUg="
+        .parse()
+        .unwrap();
+    let config = WgDeviceConfig {
+        ifname: "wg1".to_string(),
+        private_key: Some(stale.clone()),
+        listen_port: Some(8088),
+        replace_peers: false,
+        peers: vec![
+            WgPeerConfig {
+                public_key: key.clone(),
+                endpoint: Some("[fd00::2]:8088".parse::<SocketAddr>().unwrap()),
+                allowed_ips: vec![("::".parse::<IpAddr>().unwrap(), 0)],
+                persistent_keepalive: Some(5),
+                remove: false,
+            },
+            WgPeerConfig::remove(stale.clone()),
+        ],
+    };
+    let body = encode_set_device(&config);
+    assert_eq!(
+        &body[..GENL_HDRLEN],
+        &[WG_CMD_SET_DEVICE, WG_GENL_VERSION, 0, 0]
+    );
+
+    // the set and get layouts share attribute ids, so our parser can read our own request
+    let device = parse_device(&[body]).unwrap();
+    assert_eq!(device.ifname, "wg1");
+    assert_eq!(device.listen_port, 8088);
+    assert_eq!(device.peers.len(), 2);
+    assert_eq!(device.peers[0].public_key, key);
+    assert_eq!(
+        device.peers[0].endpoint,
+        Some("[fd00::2]:8088".parse::<SocketAddr>().unwrap())
+    );
+    assert_eq!(device.peers[0].persistent_keepalive, 5);
+    assert_eq!(
+        device.peers[0].allowed_ips,
+        vec![("::".parse::<IpAddr>().unwrap(), 0)]
+    );
+    assert_eq!(device.peers[1].public_key, stale);
+    assert!(device.peers[1].allowed_ips.is_empty());
+}
+
+#[test]
+fn test_get_device_merges_split_peers() {
+    let key: WgKey = "x8AcR9wI4t97aowYFlis077BDBk9SLdq6khMiixuTsQ="
+        .parse()
+        .unwrap();
+
+    let peer_msg = |ip: &str, rx: u64, handshake: u64| {
+        let mut body = genl_header(WG_CMD_GET_DEVICE, WG_GENL_VERSION);
+        put_attr_str(&mut body, WGDEVICE_A_IFNAME, "wg_exit");
+        put_attr_ne(&mut body, WGDEVICE_A_LISTEN_PORT, 59999, 2);
+        let peers = begin_nested(&mut body, WGDEVICE_A_PEERS);
+        let entry = begin_nested(&mut body, 0);
+        put_attr(&mut body, WGPEER_A_PUBLIC_KEY, key.as_ref());
+        put_attr_ne(&mut body, WGPEER_A_RX_BYTES, rx, 8);
+        put_attr_ne(&mut body, WGPEER_A_TX_BYTES, 13592616000, 8);
+        let mut time = Vec::new();
+        put_ne(&mut time, handshake, 8);
+        put_ne(&mut time, 0, 8);
+        put_attr(&mut body, WGPEER_A_LAST_HANDSHAKE_TIME, &time);
+        let ips = begin_nested(&mut body, WGPEER_A_ALLOWEDIPS);
+        let ip_entry = begin_nested(&mut body, 0);
+        put_attr_ne(&mut body, WGALLOWEDIP_A_FAMILY, u64::from(AF_INET), 2);
+        let ip: Ipv4Addr = ip.parse().unwrap();
+        put_attr(&mut body, WGALLOWEDIP_A_IPADDR, &ip.octets());
+        put_attr(&mut body, WGALLOWEDIP_A_CIDR_MASK, &[32]);
+        end_nested(&mut body, ip_entry);
+        end_nested(&mut body, ips);
+        end_nested(&mut body, entry);
+        end_nested(&mut body, peers);
+        body
+    };
+
+    let device = parse_device(&[
+        peer_msg("172.168.1.2", 821519724, 1536936247),
+        peer_msg("172.168.1.3", 0, 0),
+    ])
+    .unwrap();
+    assert_eq!(device.ifname, "wg_exit");
+    assert_eq!(device.listen_port, 59999);
+    assert_eq!(device.peers.len(), 1);
+    let peer = &device.peers[0];
+    assert_eq!(peer.rx_bytes, 821519724);
+    assert_eq!(peer.tx_bytes, 13592616000);
+    assert_eq!(
+        peer.last_handshake,
+        Some(UNIX_EPOCH + Duration::from_secs(1536936247))
+    );
+    assert_eq!(
+        peer.allowed_ips,
+        vec![
+            ("172.168.1.2".parse::<IpAddr>().unwrap(), 32),
+            ("172.168.1.3".parse::<IpAddr>().unwrap(), 32)
+        ]
+    );
+}
@@ -0,0 +1,212 @@
+//! Types for off-chain payment channels between neighbors. `ChannelState` is shared with the
+//! bounty hunter so that states signed by Rita can be uploaded to it unchanged.
+
+use clarity::{Address, PrivateKey, Signature};
+use interop::Identity;
+use num256::Uint256;
+use sha3::{Digest, Keccak256};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChannelState {
+    pub channel_id: Uint256,
+    pub address_a: Address,
+    pub address_b: Address,
+    pub nonce: Uint256,
+
+    pub balance_a: Uint256,
+    pub balance_b: Uint256,
+
+    pub signature_a: Option<Signature>,
+    pub signature_b: Option<Signature>,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        ChannelState {
+            channel_id: Uint256::from(0u32),
+            address_a: [0u8; 20].into(),
+            address_b: [0u8; 20].into(),
+            nonce: Uint256::from(0u32),
+            balance_a: Uint256::from(0u32),
+            balance_b: Uint256::from(0u32),
+            signature_a: None,
+            signature_b: None,
+        }
+    }
+}
+
+impl ChannelState {
+    /// The opening state of the channel between two addresses, the lower address is always `a`
+    /// so that both ends arrive at the same state without having to talk to each other first
+    pub fn new(ours: &Address, theirs: &Address) -> ChannelState {
+        let (address_a, address_b) = if ours.as_bytes() <= theirs.as_bytes() {
+            (ours.clone(), theirs.clone())
+        } else {
+            (theirs.clone(), ours.clone())
+        };
+
+        let mut id_data = Vec::new();
+        id_data.extend_from_slice(address_a.as_bytes());
+        id_data.extend_from_slice(address_b.as_bytes());
+
+        ChannelState {
+            channel_id: Uint256::from_bytes_be(&Keccak256::digest(&id_data)),
+            address_a,
+            address_b,
+            nonce: Uint256::from(0u32),
+            balance_a: Uint256::from(0u32),
+            balance_b: Uint256::from(0u32),
+            signature_a: None,
+            signature_b: None,
+        }
+    }
+
+    /// The Keccak256 hash both parties sign. It covers, in order: channel_id (32 bytes),
+    /// address_a (20 bytes), address_b (20 bytes), nonce (32 bytes), balance_a (32 bytes) and
+    /// balance_b (32 bytes), with all numbers big endian and left padded with zeroes.
+    pub fn fingerprint(&self) -> Vec<u8> {
+        let channel_id_fixed: [u8; 32] = self.channel_id.clone().into();
+        let nonce_fixed: [u8; 32] = self.nonce.clone().into();
+        let balance_a_fixed: [u8; 32] = self.balance_a.clone().into();
+        let balance_b_fixed: [u8; 32] = self.balance_b.clone().into();
+
+        let mut data = Vec::with_capacity(168);
+        data.extend_from_slice(&channel_id_fixed);
+        data.extend_from_slice(self.address_a.as_bytes());
+        data.extend_from_slice(self.address_b.as_bytes());
+        data.extend_from_slice(&nonce_fixed);
+        data.extend_from_slice(&balance_a_fixed);
+        data.extend_from_slice(&balance_b_fixed);
+
+        Keccak256::digest(&data).to_vec()
+    }
+
+    /// Whether `address` is one of the two parties of this channel
+    pub fn is_party(&self, address: &Address) -> bool {
+        self.address_a == *address || self.address_b == *address
+    }
+
+    /// The amount credited to one of the parties
+    pub fn balance_of(&self, address: &Address) -> Uint256 {
+        if self.address_a == *address {
+            self.balance_a.clone()
+        } else {
+            self.balance_b.clone()
+        }
+    }
+
+    /// Adds `amount` to the balance of one of the parties
+    pub fn credit(&mut self, address: &Address, amount: &Uint256) {
+        if self.address_a == *address {
+            self.balance_a = self.balance_a.clone() + amount.clone();
+        } else {
+            self.balance_b = self.balance_b.clone() + amount.clone();
+        }
+    }
+
+    /// Signs the state on behalf of `signer`, which has to be the address behind `key`
+    pub fn sign(&mut self, key: &PrivateKey, signer: &Address) {
+        let sig = key.sign_hash(&self.fingerprint());
+        if self.address_a == *signer {
+            self.signature_a = Some(sig);
+        } else {
+            self.signature_b = Some(sig);
+        }
+    }
+
+    /// Checks for a valid signature over this state from one of the parties
+    pub fn is_signed_by(&self, address: &Address) -> bool {
+        let sig = if self.address_a == *address {
+            &self.signature_a
+        } else if self.address_b == *address {
+            &self.signature_b
+        } else {
+            return false;
+        };
+
+        match *sig {
+            Some(ref sig) => match sig.recover(&self.fingerprint()) {
+                Ok(signer) => signer == *address,
+                Err(_) => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Both parties have signed, such a state can be enforced on chain
+    pub fn is_fully_signed(&self) -> bool {
+        self.is_signed_by(&self.address_a) && self.is_signed_by(&self.address_b)
+    }
+}
+
+/// A channel state proposed and signed by a neighbor that is paying us
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelUpdate {
+    pub from: Identity,
+    pub state: ChannelState,
+    /// The last state signed by both of us as far as the sender knows, the proposal follows it.
+    /// Lets us catch up if we lost that state.
+    #[serde(default)]
+    pub latest: Option<ChannelState>,
+}
+
+/// The reply to a `ChannelUpdate`, if the update was accepted `state` is the proposal with our
+/// signature added, otherwise it's the latest state we have so the proposer can catch up
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelUpdateResponse {
+    pub accepted: bool,
+    pub state: ChannelState,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_keys() -> (PrivateKey, PrivateKey) {
+        (
+            "0xfe1b2d1a4f8b0b8a3c1e2f3d4c5b6a79887766554433221100ffeeddccbbaa99"
+                .parse()
+                .unwrap(),
+            "0x1111111111111111111111111111111111111111111111111111111111111111"
+                .parse()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_new_channel_is_symmetric() {
+        let (key_a, key_b) = get_test_keys();
+        let a = key_a.to_public_key().unwrap();
+        let b = key_b.to_public_key().unwrap();
+
+        assert_eq!(ChannelState::new(&a, &b), ChannelState::new(&b, &a));
+    }
+
+    #[test]
+    fn test_sign_both_parties() {
+        let (key_a, key_b) = get_test_keys();
+        let a = key_a.to_public_key().unwrap();
+        let b = key_b.to_public_key().unwrap();
+
+        let mut state = ChannelState::new(&a, &b);
+        state.credit(&b, &Uint256::from(10u32));
+        state.nonce = Uint256::from(1u32);
+
+        state.sign(&key_a, &a);
+        assert!(state.is_signed_by(&a));
+        assert!(!state.is_signed_by(&b));
+        assert!(!state.is_fully_signed());
+
+        state.sign(&key_b, &b);
+        assert!(state.is_fully_signed());
+        assert_eq!(state.balance_of(&b), Uint256::from(10u32));
+        assert_eq!(state.balance_of(&a), Uint256::from(0u32));
+
+        // changing anything invalidates both signatures
+        state.credit(&a, &Uint256::from(1u32));
+        assert!(!state.is_signed_by(&a));
+        assert!(!state.is_signed_by(&b));
+    }
+}
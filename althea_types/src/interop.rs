@@ -1,5 +1,6 @@
-use clarity::Address;
+use clarity::{Address, PrivateKey, Signature};
 use num256::Uint256;
+use sha3::{Digest, Keccak256};
 use std::net::IpAddr;
 use wg_key::WgKey;
 
@@ -168,11 +169,153 @@ impl Message for LocalIdentity {
     type Result = ();
 }
 
-/// This is a stand-in for channel updates. Completely insecure, but allows us to
-/// track how much people would be paying each other if channels were implemented.
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+/// This is a stand-in for channel updates. Signed by the sender so it can't be forged, but
+/// nothing stops a node from claiming payments it never backs with real money.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct PaymentTx {
     pub to: Identity,
     pub from: Identity,
     pub amount: Uint256,
+    /// Picked at random by the sender, a retried payment keeps its txid so the receiver can tell
+    /// it apart from a new payment for the same amount
+    #[serde(default)]
+    pub txid: Option<u64>,
+    /// Strictly increasing for every payment made by a sender, lets the receiver reject replays
+    pub nonce: Uint256,
+    /// Signature over `fingerprint()` by the key behind `from.eth_address`
+    #[serde(default)]
+    pub signature: Option<Signature>,
+}
+
+impl PaymentTx {
+    /// Keccak256 hash of every field except the signature, this is what gets signed. Layout:
+    /// from identity, to identity, amount (32 bytes big endian), nonce (32 bytes big endian),
+    /// txid (1 byte presence flag, 8 bytes big endian). An identity is its eth address (20 bytes),
+    /// mesh ip (1 byte family tag, 4 or 16 bytes) and wireguard key (32 bytes).
+    pub fn fingerprint(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        push_identity_bytes(&mut data, &self.from);
+        push_identity_bytes(&mut data, &self.to);
+
+        let amount: [u8; 32] = self.amount.clone().into();
+        data.extend_from_slice(&amount);
+        let nonce: [u8; 32] = self.nonce.clone().into();
+        data.extend_from_slice(&nonce);
+
+        match self.txid {
+            Some(txid) => {
+                data.push(1);
+                for i in (0..8).rev() {
+                    data.push((txid >> (i * 8)) as u8);
+                }
+            }
+            None => data.extend_from_slice(&[0u8; 9]),
+        }
+
+        Keccak256::digest(&data).to_vec()
+    }
+
+    /// Signs the payment, the key should be the one behind `from.eth_address`
+    pub fn sign(&mut self, key: &PrivateKey) {
+        self.signature = Some(key.sign_hash(&self.fingerprint()));
+    }
+
+    /// Checks that the payment carries a valid signature made by `from.eth_address`
+    pub fn verify(&self) -> bool {
+        match self.signature {
+            Some(ref sig) => match sig.recover(&self.fingerprint()) {
+                Ok(signer) => signer == self.from.eth_address,
+                Err(_) => false,
+            },
+            None => false,
+        }
+    }
+}
+
+fn push_identity_bytes(data: &mut Vec<u8>, id: &Identity) {
+    data.extend_from_slice(id.eth_address.as_bytes());
+    match id.mesh_ip {
+        IpAddr::V4(ip) => {
+            data.push(4);
+            data.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            data.push(6);
+            data.extend_from_slice(&ip.octets());
+        }
+    }
+    data.extend_from_slice(id.wg_public_key.as_ref());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_payment() -> (PaymentTx, PrivateKey) {
+        let key: PrivateKey = "0xfe1b2d1a4f8b0b8a3c1e2f3d4c5b6a79887766554433221100ffeeddccbbaa99"
+            .parse()
+            .unwrap();
+        let from = Identity {
+            eth_address: key.to_public_key().unwrap(),
+            mesh_ip: "2001::3".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+        let to = Identity {
+            eth_address: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            mesh_ip: "2001::4".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+        let pmt = PaymentTx {
+            to,
+            from,
+            amount: Uint256::from(100u32),
+            txid: Some(42),
+            nonce: Uint256::from(1u32),
+            signature: None,
+        };
+        (pmt, key)
+    }
+
+    #[test]
+    fn test_payment_sign_verify() {
+        let (mut pmt, key) = get_test_payment();
+        assert!(!pmt.verify());
+        pmt.sign(&key);
+        assert!(pmt.verify());
+    }
+
+    #[test]
+    fn test_payment_tampered() {
+        let (mut pmt, key) = get_test_payment();
+        pmt.sign(&key);
+
+        let mut tampered = pmt.clone();
+        tampered.amount = Uint256::from(1000u32);
+        assert!(!tampered.verify());
+
+        let mut tampered = pmt.clone();
+        tampered.nonce = Uint256::from(2u32);
+        assert!(!tampered.verify());
+
+        let mut tampered = pmt.clone();
+        tampered.to.mesh_ip = "2001::5".parse().unwrap();
+        assert!(!tampered.verify());
+    }
+
+    #[test]
+    fn test_payment_wrong_signer() {
+        let (mut pmt, _key) = get_test_payment();
+        let other_key: PrivateKey =
+            "0x1111111111111111111111111111111111111111111111111111111111111111"
+                .parse()
+                .unwrap();
+        pmt.sign(&other_key);
+        assert!(!pmt.verify());
+    }
 }
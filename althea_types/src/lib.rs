@@ -5,6 +5,7 @@ extern crate hex;
 extern crate num256;
 extern crate serde;
 extern crate serde_json;
+extern crate sha3;
 
 #[macro_use]
 extern crate serde_derive;
@@ -12,10 +13,12 @@ extern crate serde_derive;
 #[cfg(feature = "actix")]
 extern crate actix;
 
+pub mod channel_state;
 pub mod interop;
 pub mod rtt;
 pub mod wg_key;
 
+pub use channel_state::{ChannelState, ChannelUpdate, ChannelUpdateResponse};
 pub use interop::*;
 pub use rtt::RTTimestamps;
 pub use std::str::FromStr;
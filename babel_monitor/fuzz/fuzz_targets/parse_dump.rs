@@ -0,0 +1,15 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate babel_monitor;
+
+use std::str;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(output) = str::from_utf8(data) {
+        let _ = babel_monitor::parse_dump(output);
+        for line in output.lines() {
+            let _ = babel_monitor::parse_line(line);
+        }
+    }
+});
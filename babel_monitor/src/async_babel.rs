@@ -0,0 +1,463 @@
+//! A non blocking babel client for use inside of an event loop. Every command is bounded by a
+//! timeout so a slow or wedged babeld results in an error rather than a stalled caller, and
+//! `BabelPool` keeps a connection open between calls so we don't pay for a new connection and
+//! preamble on every query.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use failure::Error;
+use futures::future::{self, loop_fn, Loop};
+use futures::{Future, IntoFuture, Sink, Stream};
+use tokio::codec::{Framed, LinesCodec};
+use tokio::net::TcpStream;
+use tokio::timer::Timeout;
+
+use super::{
+    check_preamble, parse_dump, parse_local_fee, parse_neighs_from_dump, parse_routes_from_dump,
+    Dump, Neighbor, Route,
+};
+use events::{parse_event, BabelEvent};
+use BabelMonitorError::*;
+
+/// Bounds `fut` by `timeout`, `what` names the operation in the resulting error
+fn with_timeout<F>(
+    fut: F,
+    timeout: Duration,
+    what: String,
+) -> Box<Future<Item = F::Item, Error = Error>>
+where
+    F: Future<Error = Error> + 'static,
+{
+    Box::new(Timeout::new(fut, timeout).map_err(move |e| {
+        if e.is_elapsed() {
+            warn!("Babel did not answer '{}' within {:?}", what, timeout);
+            TimedOut(what).into()
+        } else if e.is_inner() {
+            e.into_inner().unwrap()
+        } else {
+            format_err!("Timer failed while waiting on babel: {:?}", e)
+        }
+    }))
+}
+
+/// A single connection to babeld. Commands consume the connection and hand it back alongside
+/// their result so it can be used for the next command.
+pub struct AsyncBabel {
+    stream: Framed<TcpStream, LinesCodec>,
+    timeout: Duration,
+}
+
+impl AsyncBabel {
+    /// Opens a connection and validates the preamble, `timeout` applies to this and every
+    /// command sent afterwards
+    pub fn connect(
+        addr: &SocketAddr,
+        timeout: Duration,
+    ) -> Box<Future<Item = AsyncBabel, Error = Error>> {
+        let connection = TcpStream::connect(addr)
+            .from_err()
+            .and_then(move |stream| {
+                AsyncBabel {
+                    stream: Framed::new(stream, LinesCodec::new()),
+                    timeout,
+                }
+                .read_babel()
+            })
+            .and_then(|(babel, preamble)| check_preamble(preamble).map(|_| babel));
+        with_timeout(connection, timeout, format!("connect to {}", addr))
+    }
+
+    fn read_babel(self) -> Box<Future<Item = (AsyncBabel, String), Error = Error>> {
+        let timeout = self.timeout;
+        Box::new(
+            loop_fn((self.stream, String::new()), |(stream, mut ret)| {
+                stream
+                    .into_future()
+                    .map_err(|(e, _)| Error::from(e))
+                    .and_then(move |(line, stream)| {
+                        let line = match line {
+                            Some(line) => line,
+                            None => {
+                                warn!(
+                                    "Terminator was never found; full output:\n{:?}\nEND OF BABEL OUTPUT",
+                                    ret
+                                );
+                                return Err(NoTerminator(ret).into());
+                            }
+                        };
+                        ret.push_str(&line);
+                        ret.push_str("\n");
+                        match line.as_str().trim() {
+                            "ok" => {
+                                trace!(
+                                    "Babel returned ok; full output:\n{}\nEND OF BABEL OUTPUT",
+                                    ret
+                                );
+                                Ok(Loop::Break((stream, ret)))
+                            }
+                            "bad" | "no" => {
+                                warn!(
+                                    "Babel returned bad/no; full output:\n{}\nEND OF BABEL OUTPUT",
+                                    ret
+                                );
+                                Err(ReadFailed(ret).into())
+                            }
+                            _ => Ok(Loop::Continue((stream, ret))),
+                        }
+                    })
+            }).map(move |(stream, ret)| (AsyncBabel { stream, timeout }, ret)),
+        )
+    }
+
+    pub fn command(self, cmd: &str) -> Box<Future<Item = (AsyncBabel, String), Error = Error>> {
+        let timeout = self.timeout;
+        let cmd = cmd.to_string();
+        let failed_cmd = cmd.clone();
+
+        trace!("Sending '{}' to babel", cmd);
+        let response = self
+            .stream
+            .send(cmd.clone())
+            .from_err()
+            .and_then(move |stream| AsyncBabel { stream, timeout }.read_babel())
+            .map_err(move |e| CommandFailed(failed_cmd, e.to_string()).into());
+        with_timeout(response, timeout, cmd)
+    }
+
+    pub fn get_local_fee(self) -> Box<Future<Item = (AsyncBabel, u32), Error = Error>> {
+        Box::new(
+            self.command("dump")
+                .and_then(|(babel, babel_output)| Ok((babel, parse_local_fee(&babel_output)?))),
+        )
+    }
+
+    pub fn set_local_fee(
+        self,
+        new_fee: u32,
+    ) -> Box<Future<Item = (AsyncBabel, ()), Error = Error>> {
+        Box::new(
+            self.command(&format!("fee {}", new_fee))
+                .map(|(babel, _babel_output)| (babel, ())),
+        )
+    }
+
+    pub fn set_metric_factor(
+        self,
+        new_factor: u32,
+    ) -> Box<Future<Item = (AsyncBabel, ()), Error = Error>> {
+        Box::new(
+            self.command(&format!("metric-factor {}", new_factor))
+                .map(|(babel, _babel_output)| (babel, ())),
+        )
+    }
+
+    pub fn monitor(self, iface: &str) -> Box<Future<Item = (AsyncBabel, ()), Error = Error>> {
+        Box::new(
+            self.command(&format!("interface {} enable-timestamps true", iface))
+                .map(|(babel, _babel_output)| (babel, ())),
+        )
+    }
+
+    pub fn unmonitor(self, iface: &str) -> Box<Future<Item = (AsyncBabel, ()), Error = Error>> {
+        Box::new(
+            self.command(&format!("flush interface {}", iface))
+                .map(|(babel, _babel_output)| (babel, ())),
+        )
+    }
+
+    pub fn parse_neighs(
+        self,
+    ) -> Box<Future<Item = (AsyncBabel, VecDeque<Neighbor>), Error = Error>> {
+        Box::new(
+            self.command("dump").and_then(|(babel, babel_output)| {
+                Ok((babel, parse_neighs_from_dump(&babel_output)?))
+            }),
+        )
+    }
+
+    pub fn parse_routes(self) -> Box<Future<Item = (AsyncBabel, VecDeque<Route>), Error = Error>> {
+        Box::new(
+            self.command("dump").and_then(|(babel, babel_output)| {
+                Ok((babel, parse_routes_from_dump(&babel_output)?))
+            }),
+        )
+    }
+
+    /// Everything babel reports in a dump, entries that fail to parse are left out
+    pub fn dump(self) -> Box<Future<Item = (AsyncBabel, Dump), Error = Error>> {
+        Box::new(
+            self.command("dump")
+                .map(|(babel, babel_output)| (babel, parse_dump(&babel_output))),
+        )
+    }
+
+    /// Puts this connection into monitoring mode. The stream starts with the current table as
+    /// updates followed by `BabelEvent::Synced` and then carries every change babel makes until
+    /// the connection is closed, no timeout applies once the command has been sent.
+    pub fn subscribe(self) -> Box<Stream<Item = BabelEvent, Error = Error>> {
+        Box::new(
+            self.stream
+                .send("monitor".to_string())
+                .from_err::<Error>()
+                .map(|stream| {
+                    stream
+                        .from_err::<Error>()
+                        .and_then(|line| match line.as_str().trim() {
+                            "bad" | "no" => {
+                                Err(CommandFailed("monitor".to_string(), line.clone()).into())
+                            }
+                            _ => Ok(parse_event(&line)),
+                        })
+                        .filter_map(|event| event)
+                })
+                .flatten_stream(),
+        )
+    }
+}
+
+/// Hands out a babel connection that is kept open between uses. Clones share the same
+/// connection. A connection that errors or times out is dropped and the next use reconnects.
+#[derive(Clone)]
+pub struct BabelPool {
+    addr: SocketAddr,
+    timeout: Duration,
+    idle: Arc<Mutex<Option<AsyncBabel>>>,
+}
+
+impl BabelPool {
+    pub fn new(addr: SocketAddr, timeout: Duration) -> BabelPool {
+        BabelPool {
+            addr,
+            timeout,
+            idle: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Runs `f` against the idle connection, or a new one if the idle connection is missing or
+    /// already in use. The connection is returned to the pool only if `f` succeeds.
+    pub fn run<F, R, T>(&self, f: F) -> Box<Future<Item = T, Error = Error>>
+    where
+        F: FnOnce(AsyncBabel) -> R + 'static,
+        R: IntoFuture<Item = (AsyncBabel, T), Error = Error>,
+        R::Future: 'static,
+        T: 'static,
+    {
+        let idle = self.idle.clone();
+        let babel = match self.idle.lock() {
+            Ok(mut idle) => idle.take(),
+            Err(_) => None,
+        };
+        let babel: Box<Future<Item = AsyncBabel, Error = Error>> = match babel {
+            Some(babel) => Box::new(future::ok(babel)),
+            None => AsyncBabel::connect(&self.addr, self.timeout),
+        };
+
+        Box::new(babel.and_then(f).map(move |(babel, ret)| {
+            if let Ok(mut idle) = idle.lock() {
+                // if another connection was opened while this one was busy keep that one
+                if idle.is_none() {
+                    *idle = Some(babel);
+                }
+            }
+            ret
+        }))
+    }
+
+    /// Opens a dedicated monitoring connection, see `AsyncBabel::subscribe`
+    pub fn subscribe(&self) -> Box<Stream<Item = BabelEvent, Error = Error>> {
+        Box::new(
+            AsyncBabel::connect(&self.addr, self.timeout)
+                .map(|babel| babel.subscribe())
+                .flatten_stream(),
+        )
+    }
+
+    pub fn get_local_fee(&self) -> Box<Future<Item = u32, Error = Error>> {
+        self.run(|babel| babel.get_local_fee())
+    }
+
+    pub fn set_local_fee(&self, new_fee: u32) -> Box<Future<Item = (), Error = Error>> {
+        self.run(move |babel| babel.set_local_fee(new_fee))
+    }
+
+    pub fn set_metric_factor(&self, new_factor: u32) -> Box<Future<Item = (), Error = Error>> {
+        self.run(move |babel| babel.set_metric_factor(new_factor))
+    }
+
+    pub fn monitor(&self, iface: &str) -> Box<Future<Item = (), Error = Error>> {
+        let iface = iface.to_string();
+        self.run(move |babel| babel.monitor(&iface))
+    }
+
+    pub fn unmonitor(&self, iface: &str) -> Box<Future<Item = (), Error = Error>> {
+        let iface = iface.to_string();
+        self.run(move |babel| babel.unmonitor(&iface))
+    }
+
+    pub fn parse_neighs(&self) -> Box<Future<Item = VecDeque<Neighbor>, Error = Error>> {
+        self.run(|babel| babel.parse_neighs())
+    }
+
+    pub fn parse_routes(&self) -> Box<Future<Item = VecDeque<Route>, Error = Error>> {
+        self.run(|babel| babel.parse_routes())
+    }
+
+    pub fn dump(&self) -> Box<Future<Item = Dump, Error = Error>> {
+        self.run(|babel| babel.dump())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+    use tokio::runtime::current_thread::Runtime;
+    use BabelMonitorError;
+    use BabelTable;
+
+    static PREAMBLE: &'static str =
+        "ALTHEA 0.1\nversion babeld-1.8.0-24-g6335378\nhost raspberrypi\nmy-id \
+         ba:27:eb:ff:fe:09:06:dd\nok\n";
+
+    static DUMP: &'static str = "local fee 1024\n\
+add neighbour 14f05f0 address fe80::e9d0:498f:6c61:be29 if wlan0 reach feff rxcost 258 txcost 341 \
+rtt 18.674 rttcost 473 cost 817\n\
+add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:c1:2d:d5 \
+metric 817 price 4008 fee 4008 refmetric 0 full-path-rtt 18.674 via fe80::e9d0:498f:6c61:be29 if wlan0\n\
+ok\n";
+
+    /// Accepts a single connection and answers each command it receives with `reply(command)`,
+    /// the commands received are sent back to the test. A `None` reply leaves the command
+    /// unanswered.
+    fn serve_one<F>(reply: F) -> (SocketAddr, Receiver<String>)
+    where
+        F: Fn(&str) -> Option<String> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(PREAMBLE.as_bytes()).unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            for line in reader.lines() {
+                let line = line.unwrap();
+                let out = reply(&line);
+                if tx.send(line).is_err() {
+                    return;
+                }
+                if let Some(out) = out {
+                    stream.write_all(out.as_bytes()).unwrap();
+                }
+            }
+        });
+        (addr, rx)
+    }
+
+    #[test]
+    fn test_pool_reuses_connection() {
+        let (addr, commands) = serve_one(|cmd| match cmd {
+            "dump" => Some(DUMP.to_string()),
+            _ => Some("ok\n".to_string()),
+        });
+        let pool = BabelPool::new(addr, Duration::from_secs(5));
+        let mut runtime = Runtime::new().unwrap();
+
+        let routes = runtime.block_on(pool.parse_routes()).unwrap();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].price, 4008);
+
+        // the server only accepts once, so these only succeed over the same connection
+        let neighs = runtime.block_on(pool.clone().parse_neighs()).unwrap();
+        assert_eq!(neighs.len(), 1);
+        assert_eq!(neighs[0].id, "14f05f0");
+        assert_eq!(runtime.block_on(pool.get_local_fee()).unwrap(), 1024);
+        runtime.block_on(pool.set_local_fee(20)).unwrap();
+        runtime.block_on(pool.monitor("wg0")).unwrap();
+        runtime.block_on(pool.unmonitor("wg0")).unwrap();
+
+        let sent: Vec<String> = commands.try_iter().collect();
+        assert_eq!(
+            sent,
+            vec![
+                "dump",
+                "dump",
+                "dump",
+                "fee 20",
+                "interface wg0 enable-timestamps true",
+                "flush interface wg0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_command_timeout() {
+        let (addr, _commands) = serve_one(|_| None);
+        let pool = BabelPool::new(addr, Duration::from_millis(200));
+        let mut runtime = Runtime::new().unwrap();
+
+        let res = runtime.block_on(pool.parse_routes());
+        match res.unwrap_err().downcast::<BabelMonitorError>() {
+            Ok(TimedOut(cmd)) => assert_eq!(cmd, "dump"),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        // the timed out connection must not be handed out again
+        assert!(pool.idle.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let (addr, commands) = serve_one(|cmd| match cmd {
+            "monitor" => Some(format!(
+                "{}flush neighbour 14f05f0 address fe80::e9d0:498f:6c61:be29 if wlan0\n",
+                DUMP
+            )),
+            _ => None,
+        });
+        let pool = BabelPool::new(addr, Duration::from_secs(5));
+        let mut runtime = Runtime::new().unwrap();
+
+        let events = runtime
+            .block_on(pool.subscribe().take(5).collect())
+            .unwrap();
+        assert_eq!(
+            commands.try_iter().collect::<Vec<String>>(),
+            vec!["monitor"]
+        );
+
+        let mut table = BabelTable::new();
+        for event in events.iter().take(4) {
+            table.apply(event);
+        }
+        match events[3] {
+            BabelEvent::Synced => {}
+            ref other => panic!("expected the table to be synced, got {:?}", other),
+        }
+        assert_eq!(table.local_fee(), Some(1024));
+        assert_eq!(table.routes().len(), 1);
+        assert_eq!(table.neighs().len(), 1);
+
+        table.apply(&events[4]);
+        assert!(table.neighs().is_empty());
+    }
+
+    #[test]
+    fn test_bad_preamble() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"BABEL 1.0\nok\n").unwrap();
+        });
+        let mut runtime = Runtime::new().unwrap();
+
+        let res = runtime.block_on(AsyncBabel::connect(&addr, Duration::from_secs(5)));
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,626 @@
+//! Parsing for the lines babel prints in `dump` and `monitor` output. Every entry line has the
+//! form `<kind> <object> <id> (<key> <value>)*` where kind is `add`, `change` or `flush` and
+//! object is `interface`, `neighbour`, `route` or `xroute`. The althea fork of babeld also prints
+//! `local fee <fee>` at the start of a dump.
+//!
+//! Lines are first split into tokens and then the values are looked up by their exact key, so a
+//! key that happens to be part of another key or value is never mistaken for it.
+
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use failure::Error;
+use ipnetwork::IpNetwork;
+
+use super::{Neighbor, Route};
+use BabelMonitorError::InvalidEntry;
+
+/// A line of babel output split into its parts
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tokens<'a> {
+    pub line: &'a str,
+    pub kind: &'a str,
+    pub object: &'a str,
+    pub id: &'a str,
+    pairs: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Tokens<'a> {
+    /// The value for `key`, or the first one if babel printed the key more than once
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.pairs
+            .iter()
+            .find(|&&(k, _)| k == key)
+            .map(|&(_, value)| value)
+    }
+
+    fn invalid(&self, reason: String) -> Error {
+        InvalidEntry(self.line.to_string(), reason).into()
+    }
+
+    fn required(&self, key: &str) -> Result<&'a str, Error> {
+        self.get(key)
+            .ok_or_else(|| self.invalid(format!("missing '{}'", key)))
+    }
+
+    fn parse<T>(&self, key: &str) -> Result<T, Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = self.required(key)?;
+        value
+            .parse()
+            .map_err(|e| self.invalid(format!("bad '{}' {}: {}", key, value, e)))
+    }
+
+    fn parse_optional<T>(&self, key: &str) -> Result<Option<T>, Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.get(key) {
+            Some(_) => self.parse(key).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_hex(&self, key: &str) -> Result<u16, Error> {
+        let value = self.required(key)?;
+        u16::from_str_radix(value, 16)
+            .map_err(|e| self.invalid(format!("bad '{}' {}: {}", key, value, e)))
+    }
+
+    fn parse_bool(&self, key: &str) -> Result<bool, Error> {
+        match self.required(key)? {
+            "yes" | "true" => Ok(true),
+            "no" | "false" => Ok(false),
+            value => Err(self.invalid(format!("bad '{}' {}", key, value))),
+        }
+    }
+}
+
+/// Splits a line into its kind, object, id and key value pairs
+pub fn tokenize(line: &str) -> Result<Tokens, Error> {
+    let mut words = line.split_whitespace();
+    let (kind, object, id) = match (words.next(), words.next(), words.next()) {
+        (Some(kind), Some(object), Some(id)) => (kind, object, id),
+        _ => return Err(InvalidEntry(line.to_string(), "too short".to_string()).into()),
+    };
+
+    let mut pairs = Vec::new();
+    while let Some(key) = words.next() {
+        match words.next() {
+            Some(value) => pairs.push((key, value)),
+            None => {
+                return Err(
+                    InvalidEntry(line.to_string(), format!("no value for '{}'", key)).into(),
+                )
+            }
+        }
+    }
+
+    Ok(Tokens {
+        line,
+        kind,
+        object,
+        id,
+        pairs,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interface {
+    pub name: String,
+    pub up: bool,
+    pub ipv6: Option<IpAddr>,
+    pub ipv4: Option<IpAddr>,
+}
+
+/// A route we export into babel
+#[derive(Debug, Clone, PartialEq)]
+pub struct XRoute {
+    pub prefix: IpNetwork,
+    pub from: IpNetwork,
+    pub metric: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    Interface(Interface),
+    Neighbour(Neighbor),
+    Route(Route),
+    XRoute(XRoute),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    LocalFee(u32),
+    Add(Entry),
+    Change(Entry),
+    /// Flushes only identify what's being removed, the rest of the line isn't parsed
+    Flush {
+        object: String,
+        id: String,
+    },
+}
+
+fn parse_interface(tokens: &Tokens) -> Result<Interface, Error> {
+    Ok(Interface {
+        name: tokens.id.to_string(),
+        up: tokens.parse_bool("up")?,
+        ipv6: tokens.parse_optional("ipv6")?,
+        ipv4: tokens.parse_optional("ipv4")?,
+    })
+}
+
+fn parse_neighbour(tokens: &Tokens) -> Result<Neighbor, Error> {
+    Ok(Neighbor {
+        id: tokens.id.to_string(),
+        address: tokens.parse("address")?,
+        iface: tokens.required("if")?.to_string(),
+        reach: tokens.parse_hex("reach")?,
+        ureach: match tokens.get("ureach") {
+            Some(_) => Some(tokens.parse_hex("ureach")?),
+            None => None,
+        },
+        txcost: tokens.parse("txcost")?,
+        rxcost: tokens.parse("rxcost")?,
+        // it's possible that our neigh does not have rtt enabled
+        rtt: tokens.parse_optional("rtt")?.unwrap_or(0.0),
+        rttcost: tokens.parse_optional("rttcost")?.unwrap_or(0),
+        cost: tokens.parse("cost")?,
+    })
+}
+
+fn parse_route(tokens: &Tokens) -> Result<Route, Error> {
+    let prefix: IpNetwork = tokens.parse("prefix")?;
+    let from = tokens
+        .parse_optional("from")?
+        .unwrap_or_else(|| default_from(&prefix));
+    let price: u32 = tokens.parse("price")?;
+    Ok(Route {
+        id: tokens.id.to_string(),
+        iface: tokens.required("if")?.to_string(),
+        xroute: false,
+        installed: tokens.parse_bool("installed")?,
+        neigh_ip: tokens.parse("via")?,
+        prefix,
+        from,
+        router_id: tokens.get("id").unwrap_or("").to_string(),
+        seqno: tokens.parse_optional("seqno")?,
+        metric: tokens.parse("metric")?,
+        refmetric: tokens.parse("refmetric")?,
+        full_path_rtt: tokens.parse("full-path-rtt")?,
+        price,
+        fee: tokens.parse_optional("fee")?.unwrap_or(price),
+    })
+}
+
+fn parse_xroute(tokens: &Tokens) -> Result<XRoute, Error> {
+    let prefix: IpNetwork = tokens.parse("prefix")?;
+    let from = tokens
+        .parse_optional("from")?
+        .unwrap_or_else(|| default_from(&prefix));
+    Ok(XRoute {
+        prefix,
+        from,
+        metric: tokens.parse("metric")?,
+    })
+}
+
+/// Parses one line of `dump` or `monitor` output. Terminators (`ok`, `bad`, `no`) aren't
+/// entries and are rejected like any other malformed line.
+pub fn parse_line(line: &str) -> Result<Line, Error> {
+    let tokens = tokenize(line)?;
+
+    if tokens.kind == "local" {
+        if tokens.object == "fee" && tokens.pairs.is_empty() {
+            return tokens
+                .id
+                .parse()
+                .map(Line::LocalFee)
+                .map_err(|e| tokens.invalid(format!("bad fee {}: {}", tokens.id, e)));
+        }
+        return Err(tokens.invalid(format!("unknown local value '{}'", tokens.object)));
+    }
+
+    if tokens.kind == "flush" {
+        return match tokens.object {
+            "interface" | "neighbour" | "route" | "xroute" => Ok(Line::Flush {
+                object: tokens.object.to_string(),
+                id: tokens.id.to_string(),
+            }),
+            object => Err(tokens.invalid(format!("unknown object '{}'", object))),
+        };
+    }
+
+    let entry = match tokens.object {
+        "interface" => Entry::Interface(parse_interface(&tokens)?),
+        "neighbour" => Entry::Neighbour(parse_neighbour(&tokens)?),
+        "route" => Entry::Route(parse_route(&tokens)?),
+        "xroute" => Entry::XRoute(parse_xroute(&tokens)?),
+        object => return Err(tokens.invalid(format!("unknown object '{}'", object))),
+    };
+    match tokens.kind {
+        "add" => Ok(Line::Add(entry)),
+        "change" => Ok(Line::Change(entry)),
+        kind => Err(tokens.invalid(format!("unknown kind '{}'", kind))),
+    }
+}
+
+/// Everything in the output of a babel `dump`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dump {
+    pub local_fee: Option<u32>,
+    pub interfaces: Vec<Interface>,
+    pub neighs: VecDeque<Neighbor>,
+    pub xroutes: Vec<XRoute>,
+    pub routes: VecDeque<Route>,
+}
+
+/// Parses the output of a babel `dump`, lines that fail to parse are logged and skipped
+pub fn parse_dump(babel_output: &str) -> Dump {
+    let mut dump = Dump::default();
+    for line in babel_output.lines() {
+        match line.trim() {
+            "" | "ok" | "bad" | "no" => continue,
+            _ => {}
+        }
+        match parse_line(line) {
+            Ok(Line::LocalFee(fee)) => dump.local_fee = Some(fee),
+            Ok(Line::Add(entry)) | Ok(Line::Change(entry)) => match entry {
+                Entry::Interface(iface) => dump.interfaces.push(iface),
+                Entry::Neighbour(neigh) => dump.neighs.push_back(neigh),
+                Entry::Route(route) => dump.routes.push_back(route),
+                Entry::XRoute(xroute) => dump.xroutes.push(xroute),
+            },
+            Ok(Line::Flush { .. }) => trace!("Ignoring flush in dump {}", line),
+            Err(e) => warn!("Failed to parse babel output {}", e),
+        }
+    }
+    dump
+}
+
+/// The source prefix babel reports for routes that aren't source specific
+pub fn default_from(prefix: &IpNetwork) -> IpNetwork {
+    let any = match *prefix {
+        IpNetwork::V4(_) => "0.0.0.0/0",
+        IpNetwork::V6(_) => "::/0",
+    };
+    any.parse().unwrap()
+}
+
+/// Formats a route the way babel prints it, `kind` is `add`, `change` or `flush`
+pub fn route_line(kind: &str, route: &Route) -> String {
+    let seqno = match route.seqno {
+        Some(seqno) => format!(" seqno {}", seqno),
+        None => String::new(),
+    };
+    // babel leaves the id out for routes it hasn't learned a router id for
+    let router_id = if route.router_id.is_empty() {
+        String::new()
+    } else {
+        format!(" id {}", route.router_id)
+    };
+    format!(
+        "{} route {} prefix {} from {} installed {}{}{} metric {} price {} fee {} \
+         refmetric {} full-path-rtt {} via {} if {}",
+        kind,
+        route.id,
+        route.prefix,
+        route.from,
+        if route.installed { "yes" } else { "no" },
+        router_id,
+        seqno,
+        route.metric,
+        route.price,
+        route.fee,
+        route.refmetric,
+        route.full_path_rtt,
+        route.neigh_ip,
+        route.iface
+    )
+}
+
+/// Formats a neighbour the way babel prints it
+pub fn neigh_line(kind: &str, neigh: &Neighbor) -> String {
+    let ureach = match neigh.ureach {
+        Some(ureach) => format!(" ureach {:04x}", ureach),
+        None => String::new(),
+    };
+    format!(
+        "{} neighbour {} address {} if {} reach {:04x}{} rxcost {} txcost {} rtt {} rttcost {} \
+         cost {}",
+        kind,
+        neigh.id,
+        neigh.address,
+        neigh.iface,
+        neigh.reach,
+        ureach,
+        neigh.rxcost,
+        neigh.txcost,
+        neigh.rtt,
+        neigh.rttcost,
+        neigh.cost
+    )
+}
+
+/// Formats an exported route the way babel prints it
+pub fn xroute_line(kind: &str, xroute: &XRoute) -> String {
+    format!(
+        "{} xroute {}-{} prefix {} from {} metric {}",
+        kind, xroute.prefix, xroute.from, xroute.prefix, xroute.from, xroute.metric
+    )
+}
+
+/// Formats an interface the way babel prints it
+pub fn interface_line(kind: &str, iface: &Interface) -> String {
+    let mut line = format!("{} interface {} up {}", kind, iface.name, iface.up);
+    if let Some(ip) = iface.ipv6 {
+        line.push_str(&format!(" ipv6 {}", ip));
+    }
+    if let Some(ip) = iface.ipv4 {
+        line.push_str(&format!(" ipv4 {}", ip));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    static ROUTE_LINE: &'static str =
+        "add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed yes id \
+         ba:27:eb:ff:fe:c1:2d:d5 seqno 7 metric 1306 price 4008 fee 4008 refmetric 0 \
+         full-path-rtt 18.674 via fe80::e9d0:498f:6c61:be29 if wlan0";
+
+    static NEIGH_LINE: &'static str =
+        "add neighbour 14f05f0 address fe80::e9d0:498f:6c61:be29 if wlan0 reach ffff ureach 0000 \
+         rxcost 256 txcost 341 rtt 29.264 rttcost 1050 cost 1306";
+
+    #[test]
+    fn test_tokenize() {
+        let tokens = tokenize("add interface wlan0 up true ipv4 10.28.119.131").unwrap();
+        assert_eq!(tokens.kind, "add");
+        assert_eq!(tokens.object, "interface");
+        assert_eq!(tokens.id, "wlan0");
+        assert_eq!(tokens.get("up"), Some("true"));
+        assert_eq!(tokens.get("ipv4"), Some("10.28.119.131"));
+        assert_eq!(tokens.get("ipv6"), None);
+
+        assert!(tokenize("ok").is_err());
+        assert!(tokenize("add route 14f06d8 prefix").is_err());
+    }
+
+    #[test]
+    fn test_keys_match_exactly() {
+        let route = match parse_line(ROUTE_LINE).unwrap() {
+            Line::Add(Entry::Route(route)) => route,
+            other => panic!("unexpected {:?}", other),
+        };
+        // 'metric' is part of 'refmetric' and 'id' of 'if', neither may be confused
+        assert_eq!(route.metric, 1306);
+        assert_eq!(route.refmetric, 0);
+        assert_eq!(route.router_id, "ba:27:eb:ff:fe:c1:2d:d5");
+        assert_eq!(route.iface, "wlan0");
+        assert_eq!(route.seqno, Some(7));
+        assert_eq!(route.from, "0.0.0.0/0".parse::<IpNetwork>().unwrap());
+
+        let neigh = match parse_line(NEIGH_LINE).unwrap() {
+            Line::Add(Entry::Neighbour(neigh)) => neigh,
+            other => panic!("unexpected {:?}", other),
+        };
+        // 'cost' is part of 'rxcost', 'txcost' and 'rttcost'
+        assert_eq!(neigh.cost, 1306);
+        assert_eq!(neigh.rxcost, 256);
+        assert_eq!(neigh.txcost, 341);
+        assert_eq!(neigh.reach, 0xffff);
+        assert_eq!(neigh.ureach, Some(0));
+    }
+
+    #[test]
+    fn test_parse_dump() {
+        let dump = parse_dump(
+            "local fee 1024\n\
+             add interface wlan0 up true ipv6 fe80::1a8b:ec1:8542:1bd8 ipv4 10.28.119.131\n\
+             add interface wg0 up false\n\
+             add xroute 10.28.119.131/32-::/0 prefix 10.28.119.131/32 from ::/0 metric 0\n\
+             add route 14f06d8 prefix garbage\n\
+             ok\n",
+        );
+        assert_eq!(dump.local_fee, Some(1024));
+        assert_eq!(dump.interfaces.len(), 2);
+        assert_eq!(
+            dump.interfaces[0].ipv4,
+            Some("10.28.119.131".parse().unwrap())
+        );
+        assert!(!dump.interfaces[1].up);
+        assert_eq!(
+            dump.xroutes,
+            vec![XRoute {
+                prefix: "10.28.119.131/32".parse().unwrap(),
+                from: "::/0".parse().unwrap(),
+                metric: 0,
+            }]
+        );
+        assert!(dump.routes.is_empty());
+    }
+
+    #[test]
+    fn test_real_babeld_output() {
+        // captured from a router running an older version of the althea fork, which prints no fee
+        let route = match parse_line(
+            "add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed yes id \
+             ba:27:eb:ff:fe:c1:2d:d5 metric 1306 price 4008 refmetric 0 full-path-rtt 18.674 via \
+             fe80::e9d0:498f:6c61:be29 if wlan0",
+        ) {
+            Ok(Line::Add(Entry::Route(route))) => route,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(route.price, 4008);
+        assert_eq!(route.fee, 4008);
+        assert_eq!(route.seqno, None);
+
+        // babel versions without source specific routing print neither the source nor the router
+        let dump = parse_dump(
+            "add xroute 10.28.119.131/32 prefix 10.28.119.131/32 metric 0\n\
+             add route 14f0548 prefix fd00::5/128 installed yes metric 958 price 2048 fee 2048 \
+             refmetric 0 full-path-rtt 56.805 via fe80::e914:2335:a76:bda3 if wlan0\n\
+             ok\n",
+        );
+        assert_eq!(dump.routes.len(), 1);
+        assert_eq!(dump.routes[0].from, "::/0".parse::<IpNetwork>().unwrap());
+        assert_eq!(dump.routes[0].router_id, "");
+        assert_eq!(
+            dump.xroutes[0].from,
+            "0.0.0.0/0".parse::<IpNetwork>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_flush_only_needs_id() {
+        assert_eq!(
+            parse_line("flush neighbour 14f05f0 address").unwrap(),
+            Line::Flush {
+                object: "neighbour".to_string(),
+                id: "14f05f0".to_string(),
+            }
+        );
+        assert!(parse_line("flush gateway 14f05f0").is_err());
+    }
+
+    fn arb_ip() -> BoxedStrategy<IpAddr> {
+        prop_oneof![
+            any::<u32>().prop_map(|ip| IpAddr::V4(Ipv4Addr::from(ip))),
+            any::<[u8; 16]>().prop_map(|ip| IpAddr::V6(Ipv6Addr::from(ip))),
+        ]
+        .boxed()
+    }
+
+    fn arb_prefix() -> BoxedStrategy<IpNetwork> {
+        prop_oneof![
+            (any::<u32>(), 0u8..33).prop_map(|(ip, len)| IpNetwork::new(
+                IpAddr::V4(Ipv4Addr::from(ip)),
+                len
+            )
+            .unwrap()),
+            (any::<[u8; 16]>(), 0u8..129).prop_map(|(ip, len)| IpNetwork::new(
+                IpAddr::V6(Ipv6Addr::from(ip)),
+                len
+            )
+            .unwrap()),
+        ]
+        .boxed()
+    }
+
+    /// Names like babel's ids and interface names, never empty and without whitespace
+    fn arb_name() -> BoxedStrategy<String> {
+        "[a-z0-9:.-]{1,17}".boxed()
+    }
+
+    prop_compose! {
+        fn arb_route()(
+            id in arb_name(),
+            iface in arb_name(),
+            installed in any::<bool>(),
+            neigh_ip in arb_ip(),
+            prefix in arb_prefix(),
+            router_id in prop_oneof![Just(String::new()), arb_name()],
+            seqno in any::<Option<u16>>(),
+            metric in any::<u16>(),
+            refmetric in any::<u16>(),
+            full_path_rtt in 0f32..100000f32,
+            price in any::<u32>(),
+            fee in any::<u32>(),
+        ) -> Route {
+            Route {
+                id,
+                iface,
+                xroute: false,
+                installed,
+                neigh_ip,
+                from: default_from(&prefix),
+                prefix,
+                router_id,
+                seqno,
+                metric,
+                refmetric,
+                full_path_rtt,
+                price,
+                fee,
+            }
+        }
+    }
+
+    prop_compose! {
+        fn arb_neigh()(
+            id in arb_name(),
+            address in arb_ip(),
+            iface in arb_name(),
+            reach in any::<u16>(),
+            ureach in any::<Option<u16>>(),
+            txcost in any::<u16>(),
+            rxcost in any::<u16>(),
+            rtt in 0f32..100000f32,
+            rttcost in any::<u16>(),
+            cost in any::<u16>(),
+        ) -> Neighbor {
+            Neighbor { id, address, iface, reach, ureach, txcost, rxcost, rtt, rttcost, cost }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_route_round_trips(route in arb_route(), change in any::<bool>()) {
+            let kind = if change { "change" } else { "add" };
+            let line = parse_line(&route_line(kind, &route)).unwrap();
+            if change {
+                prop_assert_eq!(line, Line::Change(Entry::Route(route)));
+            } else {
+                prop_assert_eq!(line, Line::Add(Entry::Route(route)));
+            }
+        }
+
+        #[test]
+        fn prop_neigh_round_trips(neigh in arb_neigh()) {
+            prop_assert_eq!(
+                parse_line(&neigh_line("add", &neigh)).unwrap(),
+                Line::Add(Entry::Neighbour(neigh))
+            );
+        }
+
+        #[test]
+        fn prop_dump_keeps_every_entry(
+            routes in proptest::collection::vec(arb_route(), 0..10),
+            neighs in proptest::collection::vec(arb_neigh(), 0..10),
+            fee in any::<u32>(),
+        ) {
+            let mut output = format!("local fee {}\n", fee);
+            for neigh in neighs.iter() {
+                output.push_str(&neigh_line("add", neigh));
+                output.push('\n');
+            }
+            for route in routes.iter() {
+                output.push_str(&route_line("add", route));
+                output.push('\n');
+            }
+            output.push_str("ok\n");
+
+            let dump = parse_dump(&output);
+            prop_assert_eq!(dump.local_fee, Some(fee));
+            prop_assert_eq!(dump.routes.into_iter().collect::<Vec<Route>>(), routes);
+            prop_assert_eq!(dump.neighs.into_iter().collect::<Vec<Neighbor>>(), neighs);
+        }
+
+        #[test]
+        fn prop_parse_never_panics(line in "\\PC*") {
+            let _ = parse_line(&line);
+            let _ = parse_dump(&line);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Babel's `monitor` command first replays the whole table as `add` lines followed by `ok`, then
+//! keeps the connection open and writes an `add`, `change` or `flush` line whenever a route,
+//! neighbour, interface or xroute changes. This module turns those lines into events and keeps
+//! a table of routes and neighbours up to date with them.
+
+use std::collections::{HashMap, VecDeque};
+
+use super::{parse_line, Entry, Line, Neighbor, Route};
+
+#[derive(Debug, Clone)]
+pub enum BabelEvent {
+    /// The initial replay of the table is complete, everything after this is a live update
+    Synced,
+    LocalFee(u32),
+    /// A route was added or changed
+    RouteUpdated(Route),
+    /// The route with the given id was removed
+    RouteFlushed(String),
+    /// A neighbour was added or changed
+    NeighUpdated(Neighbor),
+    /// The neighbour with the given id was removed
+    NeighFlushed(String),
+}
+
+/// Parses a line from a monitoring connection, lines for things we don't track (interfaces,
+/// xroutes) and lines that fail to parse return None.
+pub fn parse_event(line: &str) -> Option<BabelEvent> {
+    let line = line.trim();
+    if line == "ok" {
+        return Some(BabelEvent::Synced);
+    }
+
+    match parse_line(line) {
+        Ok(Line::LocalFee(fee)) => Some(BabelEvent::LocalFee(fee)),
+        Ok(Line::Add(Entry::Route(route))) | Ok(Line::Change(Entry::Route(route))) => {
+            Some(BabelEvent::RouteUpdated(route))
+        }
+        Ok(Line::Add(Entry::Neighbour(neigh))) | Ok(Line::Change(Entry::Neighbour(neigh))) => {
+            Some(BabelEvent::NeighUpdated(neigh))
+        }
+        Ok(Line::Flush { ref object, ref id }) if object == "route" => {
+            Some(BabelEvent::RouteFlushed(id.clone()))
+        }
+        Ok(Line::Flush { ref object, ref id }) if object == "neighbour" => {
+            Some(BabelEvent::NeighFlushed(id.clone()))
+        }
+        Ok(_) => {
+            trace!("Ignoring babel event {}", line);
+            None
+        }
+        Err(e) => {
+            warn!("Unable to parse babel event {}", e);
+            None
+        }
+    }
+}
+
+/// Babel's routes and neighbours as last reported over a monitoring connection, keyed by the
+/// ids babel gives them.
+#[derive(Debug, Clone, Default)]
+pub struct BabelTable {
+    local_fee: Option<u32>,
+    routes: HashMap<String, Route>,
+    neighs: HashMap<String, Neighbor>,
+}
+
+impl BabelTable {
+    pub fn new() -> BabelTable {
+        BabelTable::default()
+    }
+
+    pub fn apply(&mut self, event: &BabelEvent) {
+        match *event {
+            BabelEvent::Synced => {}
+            BabelEvent::LocalFee(fee) => self.local_fee = Some(fee),
+            BabelEvent::RouteUpdated(ref route) => {
+                self.routes.insert(route.id.clone(), route.clone());
+            }
+            BabelEvent::RouteFlushed(ref id) => {
+                self.routes.remove(id);
+            }
+            BabelEvent::NeighUpdated(ref neigh) => {
+                self.neighs.insert(neigh.id.clone(), neigh.clone());
+            }
+            BabelEvent::NeighFlushed(ref id) => {
+                self.neighs.remove(id);
+            }
+        }
+    }
+
+    /// The local fee, if babel has reported one or it has been set with a `LocalFee` event
+    pub fn local_fee(&self) -> Option<u32> {
+        self.local_fee
+    }
+
+    pub fn route(&self, id: &str) -> Option<&Route> {
+        self.routes.get(id)
+    }
+
+    pub fn routes(&self) -> VecDeque<Route> {
+        self.routes.values().cloned().collect()
+    }
+
+    pub fn neighs(&self) -> VecDeque<Neighbor> {
+        self.neighs.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ROUTE_ADD: &'static str =
+        "add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed yes id \
+         ba:27:eb:ff:fe:c1:2d:d5 metric 817 price 4008 fee 4008 refmetric 0 full-path-rtt 18.674 \
+         via fe80::e9d0:498f:6c61:be29 if wlan0";
+
+    static ROUTE_CHANGE: &'static str =
+        "change route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed no id \
+         ba:27:eb:ff:fe:c1:2d:d5 metric 1306 price 5000 fee 5000 refmetric 0 full-path-rtt 18.674 \
+         via fe80::e9d0:498f:6c61:be29 if wlan0";
+
+    static ROUTE_FLUSH: &'static str =
+        "flush route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed no id \
+         ba:27:eb:ff:fe:c1:2d:d5 metric 65535 price 5000 fee 5000 refmetric 0 full-path-rtt \
+         18.674 via fe80::e9d0:498f:6c61:be29 if wlan0";
+
+    static NEIGH_ADD: &'static str =
+        "add neighbour 14f05f0 address fe80::e9d0:498f:6c61:be29 if wlan0 reach ffff rxcost \
+         256 txcost 256 rtt 29.264 rttcost 1050 cost 1306";
+
+    #[test]
+    fn test_parse_events() {
+        match parse_event(ROUTE_ADD) {
+            Some(BabelEvent::RouteUpdated(route)) => {
+                assert_eq!(route.id, "14f06d8");
+                assert!(route.installed);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_event(ROUTE_FLUSH) {
+            Some(BabelEvent::RouteFlushed(id)) => assert_eq!(id, "14f06d8"),
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_event("flush neighbour 14f05f0 address fe80::e9d0:498f:6c61:be29") {
+            Some(BabelEvent::NeighFlushed(id)) => assert_eq!(id, "14f05f0"),
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_event("local fee 1024") {
+            Some(BabelEvent::LocalFee(fee)) => assert_eq!(fee, 1024),
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_event("ok") {
+            Some(BabelEvent::Synced) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(
+            parse_event("add xroute 10.28.119.131/32-::/0 prefix 10.28.119.131/32 metric 0")
+                .is_none()
+        );
+        assert!(parse_event("add interface wlan0 up true").is_none());
+        assert!(parse_event("add route 14f06d8 prefix garbage").is_none());
+    }
+
+    #[test]
+    fn test_table_follows_events() {
+        let mut table = BabelTable::new();
+        for line in &[ROUTE_ADD, NEIGH_ADD, "local fee 20", "ok"] {
+            table.apply(&parse_event(line).unwrap());
+        }
+        assert_eq!(table.routes().len(), 1);
+        assert_eq!(table.neighs().len(), 1);
+        assert_eq!(table.local_fee(), Some(20));
+
+        table.apply(&parse_event(ROUTE_CHANGE).unwrap());
+        let routes = table.routes();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].price, 5000);
+        assert!(!routes[0].installed);
+
+        table.apply(&parse_event(ROUTE_FLUSH).unwrap());
+        assert!(table.routes().is_empty());
+        table.apply(&BabelEvent::NeighFlushed("14f05f0".to_string()));
+        assert!(table.neighs().is_empty());
+    }
+}
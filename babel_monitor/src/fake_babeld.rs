@@ -0,0 +1,529 @@
+//! An in process stand in for babeld's local configuration interface, for testing code that
+//! talks to babel without running babeld. It listens on a local TCP port, greets every
+//! connection with the same preamble babeld does and answers `dump`, `monitor`, `unmonitor`,
+//! `fee`, `metric-factor`, `interface`, `flush interface` and `redistribute`. Anything else is
+//! answered with `bad`.
+//!
+//! The route, neighbour and xroute table is set from the test, changes are pushed to connections
+//! that sent `monitor` the same way babeld announces them. Settings changed over the protocol can
+//! be read back to check what the code under test asked for.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use failure::Error;
+use ipnetwork::IpNetwork;
+
+use super::dump::default_from;
+use super::{
+    interface_line, neigh_line, route_line, xroute_line, Interface, Neighbor, Route, XRoute,
+};
+
+const PREAMBLE: &'static str = "ALTHEA 0.1\nversion babeld-1.8.0-fake\nhost fake-babeld\nmy-id \
+                                fe:80:00:00:00:00:00:01\nok\n";
+
+/// Interfaces are reported up without addresses
+fn up_interface(name: &str) -> Interface {
+    Interface {
+        name: name.to_string(),
+        up: true,
+        ipv6: None,
+        ipv4: None,
+    }
+}
+
+struct State {
+    local_fee: u32,
+    metric_factor: u32,
+    interfaces: Vec<String>,
+    redistributions: Vec<String>,
+    commands: Vec<String>,
+    routes: BTreeMap<String, Route>,
+    neighs: BTreeMap<String, Neighbor>,
+    xroutes: BTreeMap<String, XRoute>,
+    /// Write halves of the connections that sent `monitor`
+    monitors: Vec<TcpStream>,
+    unresponsive: bool,
+}
+
+impl State {
+    /// The whole table as babeld prints it for `dump` and at the start of `monitor`
+    fn dump(&self) -> String {
+        let mut out = format!("local fee {}\n", self.local_fee);
+        for name in self.interfaces.iter() {
+            out.push_str(&interface_line("add", &up_interface(name)));
+            out.push('\n');
+        }
+        for neigh in self.neighs.values() {
+            out.push_str(&neigh_line("add", neigh));
+            out.push('\n');
+        }
+        for xroute in self.xroutes.values() {
+            out.push_str(&xroute_line("add", xroute));
+            out.push('\n');
+        }
+        for route in self.routes.values() {
+            out.push_str(&route_line("add", route));
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Sends a change to every monitoring connection, dropping the ones that have gone away
+    fn notify(&mut self, line: String) {
+        let line = format!("{}\n", line);
+        self.monitors
+            .retain(|mut stream| stream.write_all(line.as_bytes()).is_ok());
+    }
+
+    /// Runs a single command and returns the reply, `monitor` is dealt with by the caller
+    fn command(&mut self, cmd: &str) -> String {
+        let mut words = cmd.split_whitespace();
+        let ok = match (words.next(), words.next()) {
+            (Some("dump"), None) => return format!("{}ok\n", self.dump()),
+            (Some("fee"), Some(fee)) => match fee.parse() {
+                Ok(fee) => {
+                    self.local_fee = fee;
+                    true
+                }
+                Err(_) => false,
+            },
+            (Some("metric-factor"), Some(factor)) => match factor.parse() {
+                Ok(factor) => {
+                    self.metric_factor = factor;
+                    true
+                }
+                Err(_) => false,
+            },
+            (Some("interface"), Some(name)) => {
+                if !self.interfaces.iter().any(|iface| iface == name) {
+                    self.interfaces.push(name.to_string());
+                    self.notify(interface_line("add", &up_interface(name)));
+                }
+                true
+            }
+            (Some("flush"), Some("interface")) => match words.next() {
+                Some(name) => {
+                    let before = self.interfaces.len();
+                    self.interfaces.retain(|iface| iface != name);
+                    if self.interfaces.len() != before {
+                        self.notify(interface_line("flush", &up_interface(name)));
+                    }
+                    true
+                }
+                None => false,
+            },
+            (Some("redistribute"), Some(_)) => {
+                let rule = cmd["redistribute".len()..].trim().to_string();
+                self.redistributions.push(rule);
+                true
+            }
+            _ => false,
+        };
+        if ok {
+            "ok\n".to_string()
+        } else {
+            "bad\n".to_string()
+        }
+    }
+}
+
+/// A fake babeld, it stops accepting connections when dropped
+pub struct FakeBabeld {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl FakeBabeld {
+    /// Starts a fake babeld on a free port of the IPv4 loopback
+    pub fn new() -> Result<FakeBabeld, Error> {
+        FakeBabeld::bind(&"127.0.0.1:0".parse()?)
+    }
+
+    pub fn bind(addr: &SocketAddr) -> Result<FakeBabeld, Error> {
+        let listener = TcpListener::bind(addr)?;
+        let fake = FakeBabeld {
+            addr: listener.local_addr()?,
+            state: Arc::new(Mutex::new(State {
+                local_fee: 0,
+                metric_factor: 0,
+                interfaces: Vec::new(),
+                redistributions: Vec::new(),
+                commands: Vec::new(),
+                routes: BTreeMap::new(),
+                neighs: BTreeMap::new(),
+                xroutes: BTreeMap::new(),
+                monitors: Vec::new(),
+                unresponsive: false,
+            })),
+            closed: Arc::new(AtomicBool::new(false)),
+        };
+
+        let state = fake.state.clone();
+        let closed = fake.closed.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if closed.load(Ordering::SeqCst) {
+                    return;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let state = state.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = serve(stream, state) {
+                                trace!("Fake babeld connection ended {:?}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Fake babeld failed to accept {:?}", e),
+                }
+            }
+        });
+
+        Ok(fake)
+    }
+
+    /// The address to connect to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn local_fee(&self) -> u32 {
+        self.state.lock().unwrap().local_fee
+    }
+
+    pub fn set_local_fee(&self, fee: u32) {
+        self.state.lock().unwrap().local_fee = fee;
+    }
+
+    pub fn metric_factor(&self) -> u32 {
+        self.state.lock().unwrap().metric_factor
+    }
+
+    /// Interfaces added with `interface` and not flushed since
+    pub fn interfaces(&self) -> Vec<String> {
+        self.state.lock().unwrap().interfaces.clone()
+    }
+
+    /// The arguments of every `redistribute` command, in order
+    pub fn redistributions(&self) -> Vec<String> {
+        self.state.lock().unwrap().redistributions.clone()
+    }
+
+    /// Every command received over any connection, in order
+    pub fn commands(&self) -> Vec<String> {
+        self.state.lock().unwrap().commands.clone()
+    }
+
+    /// Adds a route or replaces the route with the same id
+    pub fn set_route(&self, route: Route) {
+        let mut state = self.state.lock().unwrap();
+        let kind = if state.routes.contains_key(&route.id) {
+            "change"
+        } else {
+            "add"
+        };
+        let line = route_line(kind, &route);
+        state.routes.insert(route.id.clone(), route);
+        state.notify(line);
+    }
+
+    pub fn flush_route(&self, id: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(route) = state.routes.remove(id) {
+            let line = route_line("flush", &route);
+            state.notify(line);
+        }
+    }
+
+    /// Adds a neighbour or replaces the neighbour with the same id
+    pub fn set_neigh(&self, neigh: Neighbor) {
+        let mut state = self.state.lock().unwrap();
+        let kind = if state.neighs.contains_key(&neigh.id) {
+            "change"
+        } else {
+            "add"
+        };
+        let line = neigh_line(kind, &neigh);
+        state.neighs.insert(neigh.id.clone(), neigh);
+        state.notify(line);
+    }
+
+    pub fn flush_neigh(&self, id: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(neigh) = state.neighs.remove(id) {
+            let line = neigh_line("flush", &neigh);
+            state.notify(line);
+        }
+    }
+
+    /// Adds a route we export or changes the metric of an existing one
+    pub fn set_xroute(&self, prefix: IpNetwork, metric: u16) {
+        let mut state = self.state.lock().unwrap();
+        let kind = if state.xroutes.contains_key(&prefix.to_string()) {
+            "change"
+        } else {
+            "add"
+        };
+        let xroute = XRoute {
+            prefix,
+            from: default_from(&prefix),
+            metric,
+        };
+        state.notify(xroute_line(kind, &xroute));
+        state.xroutes.insert(prefix.to_string(), xroute);
+    }
+
+    pub fn flush_xroute(&self, prefix: IpNetwork) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(xroute) = state.xroutes.remove(&prefix.to_string()) {
+            state.notify(xroute_line("flush", &xroute));
+        }
+    }
+
+    /// While set commands are read but never answered, like a babeld that has hung
+    pub fn set_unresponsive(&self, unresponsive: bool) {
+        self.state.lock().unwrap().unresponsive = unresponsive;
+    }
+
+    /// Closes every monitoring connection, like babeld restarting
+    pub fn drop_monitors(&self) {
+        for stream in self.state.lock().unwrap().monitors.drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl Drop for FakeBabeld {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        // wake the accept loop up so it sees the flag
+        let _ = TcpStream::connect(self.addr);
+        self.drop_monitors();
+    }
+}
+
+fn serve(mut stream: TcpStream, state: Arc<Mutex<State>>) -> Result<(), Error> {
+    stream.write_all(PREAMBLE.as_bytes())?;
+    let reader = BufReader::new(stream.try_clone()?);
+    for line in reader.lines() {
+        let line = line?;
+        let cmd = line.trim();
+        if cmd.is_empty() {
+            continue;
+        }
+        if cmd == "quit" {
+            break;
+        }
+
+        let mut state = state.lock().unwrap();
+        state.commands.push(cmd.to_string());
+        if state.unresponsive {
+            continue;
+        }
+
+        match cmd {
+            "monitor" => {
+                let reply = format!("{}ok\n", state.dump());
+                stream.write_all(reply.as_bytes())?;
+                state.monitors.push(stream.try_clone()?);
+            }
+            "unmonitor" => {
+                let addr = stream.peer_addr()?;
+                state
+                    .monitors
+                    .retain(|monitor| monitor.peer_addr().ok() != Some(addr));
+                stream.write_all(b"ok\n")?;
+            }
+            _ => {
+                let reply = state.command(cmd);
+                stream.write_all(reply.as_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A route with made up values for the fields tests rarely care about
+pub fn fake_route(id: &str, prefix: IpNetwork, neigh_ip: IpAddr, price: u32) -> Route {
+    Route {
+        id: id.to_string(),
+        iface: "wg0".to_string(),
+        xroute: false,
+        installed: true,
+        neigh_ip,
+        from: default_from(&prefix),
+        prefix,
+        router_id: "fe:80:00:00:00:00:00:02".to_string(),
+        seqno: Some(1),
+        metric: 256,
+        refmetric: 0,
+        full_path_rtt: 10.0,
+        price,
+        fee: price,
+    }
+}
+
+/// A neighbour with made up values for the fields tests rarely care about
+pub fn fake_neigh(id: &str, address: IpAddr, iface: &str) -> Neighbor {
+    Neighbor {
+        id: id.to_string(),
+        address,
+        iface: iface.to_string(),
+        reach: 0xffff,
+        ureach: None,
+        txcost: 256,
+        rxcost: 256,
+        rtt: 10.0,
+        rttcost: 0,
+        cost: 256,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{AsyncBabel, Babel, BabelEvent, BabelPool, BabelTable};
+    use super::*;
+    use futures::{Future, Stream};
+    use std::time::Duration;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn populated() -> FakeBabeld {
+        let babeld = FakeBabeld::new().unwrap();
+        babeld.set_local_fee(1024);
+        babeld.set_neigh(fake_neigh(
+            "14f05f0",
+            "fe80::e9d0:498f:6c61:be29".parse().unwrap(),
+            "wg0",
+        ));
+        babeld.set_route(fake_route(
+            "14f06d8",
+            "fd00::5/128".parse().unwrap(),
+            "fe80::e9d0:498f:6c61:be29".parse().unwrap(),
+            4008,
+        ));
+        babeld.set_xroute("fd00::1/128".parse().unwrap(), 0);
+        babeld
+    }
+
+    #[test]
+    fn test_blocking_client() {
+        let babeld = populated();
+        let mut babel = Babel::new(TcpStream::connect(babeld.addr()).unwrap());
+        babel.start_connection().unwrap();
+
+        assert_eq!(babel.get_local_fee().unwrap(), 1024);
+        let routes = babel.parse_routes().unwrap();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].price, 4008);
+        assert_eq!(routes[0].iface, "wg0");
+        assert_eq!(babel.parse_neighs().unwrap()[0].id, "14f05f0");
+
+        babel.set_local_fee(20).unwrap();
+        babel.set_metric_factor(1900).unwrap();
+        babel.monitor("wg1").unwrap();
+        assert_eq!(babeld.local_fee(), 20);
+        assert_eq!(babeld.metric_factor(), 1900);
+        assert_eq!(babeld.interfaces(), vec!["wg1"]);
+
+        babel.unmonitor("wg1").unwrap();
+        assert!(babeld.interfaces().is_empty());
+    }
+
+    #[test]
+    fn test_async_client() {
+        let babeld = populated();
+        let pool = BabelPool::new(babeld.addr(), Duration::from_secs(5));
+        let mut runtime = Runtime::new().unwrap();
+
+        assert_eq!(runtime.block_on(pool.parse_routes()).unwrap().len(), 1);
+        let dump = runtime.block_on(pool.dump()).unwrap();
+        assert_eq!(dump.local_fee, Some(1024));
+        assert_eq!(
+            dump.routes[0],
+            fake_route(
+                "14f06d8",
+                "fd00::5/128".parse().unwrap(),
+                "fe80::e9d0:498f:6c61:be29".parse().unwrap(),
+                4008,
+            )
+        );
+        assert_eq!(dump.xroutes[0].prefix, "fd00::1/128".parse().unwrap());
+        runtime.block_on(pool.set_local_fee(7)).unwrap();
+        assert_eq!(babeld.local_fee(), 7);
+        runtime
+            .block_on(pool.run(|babel| babel.command("redistribute ip fd00::1/128 allow")))
+            .unwrap();
+        assert_eq!(babeld.redistributions(), vec!["ip fd00::1/128 allow"]);
+        assert!(runtime
+            .block_on(pool.run(|babel| babel.command("not-a-command")))
+            .is_err());
+
+        babeld.set_unresponsive(true);
+        let babel = runtime
+            .block_on(AsyncBabel::connect(
+                &babeld.addr(),
+                Duration::from_millis(200),
+            ))
+            .unwrap();
+        assert!(runtime.block_on(babel.get_local_fee()).is_err());
+    }
+
+    fn next_event(
+        runtime: &mut Runtime,
+        events: Box<Stream<Item = BabelEvent, Error = Error>>,
+    ) -> (
+        Option<BabelEvent>,
+        Box<Stream<Item = BabelEvent, Error = Error>>,
+    ) {
+        runtime
+            .block_on(events.into_future().map_err(|(e, _)| e))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_monitor_follows_changes() {
+        let babeld = populated();
+        let pool = BabelPool::new(babeld.addr(), Duration::from_secs(5));
+        let mut runtime = Runtime::new().unwrap();
+
+        let mut table = BabelTable::new();
+        let mut events = pool.subscribe();
+        loop {
+            let (event, rest) = next_event(&mut runtime, events);
+            events = rest;
+            match event.unwrap() {
+                BabelEvent::Synced => break,
+                event => table.apply(&event),
+            }
+        }
+        assert_eq!(table.local_fee(), Some(1024));
+        assert_eq!(table.routes().len(), 1);
+        assert_eq!(table.neighs().len(), 1);
+
+        let mut route = table.routes()[0].clone();
+        route.price = 5000;
+        babeld.set_route(route);
+        let (event, events) = next_event(&mut runtime, events);
+        match event {
+            Some(BabelEvent::RouteUpdated(ref route)) => assert_eq!(route.price, 5000),
+            ref other => panic!("expected a route change, got {:?}", other),
+        }
+
+        babeld.flush_neigh("14f05f0");
+        let (event, events) = next_event(&mut runtime, events);
+        match event {
+            Some(BabelEvent::NeighFlushed(ref id)) => assert_eq!(id, "14f05f0"),
+            ref other => panic!("expected a neighbour flush, got {:?}", other),
+        }
+
+        babeld.drop_monitors();
+        let (event, _) = next_event(&mut runtime, events);
+        assert!(event.is_none());
+    }
+}
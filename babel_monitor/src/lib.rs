@@ -1,10 +1,21 @@
 extern crate bufstream;
 #[macro_use]
 extern crate failure;
+extern crate futures;
 extern crate ipnetwork;
 #[macro_use]
 extern crate log;
 extern crate mockstream;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
+extern crate tokio;
+
+mod async_babel;
+mod dump;
+mod events;
+#[cfg(any(test, feature = "fake-babeld"))]
+pub mod fake_babeld;
 
 use std::collections::VecDeque;
 use std::io::{BufRead, Read, Write};
@@ -16,10 +27,15 @@ use bufstream::BufStream;
 use failure::Error;
 use ipnetwork::IpNetwork;
 
+pub use async_babel::{AsyncBabel, BabelPool};
+pub use dump::{
+    interface_line, neigh_line, parse_dump, parse_line, route_line, tokenize, xroute_line, Dump,
+    Entry, Interface, Line, Tokens, XRoute,
+};
+pub use events::{parse_event, BabelEvent, BabelTable};
+
 #[derive(Debug, Fail)]
 pub enum BabelMonitorError {
-    #[fail(display = "variable '{}' not found in '{}'", _0, _1)]
-    VariableNotFound(String, String),
     #[fail(display = "Invalid preamble: {}", _0)]
     InvalidPreamble(String),
     #[fail(display = "Could not find local fee in '{}'", _0)]
@@ -32,27 +48,15 @@ pub enum BabelMonitorError {
     NoTerminator(String),
     #[fail(display = "No Neighbor was found matching address:\n{}", _0)]
     NoNeighbor(String),
+    #[fail(display = "Babel did not answer '{}' in time", _0)]
+    TimedOut(String),
+    #[fail(display = "Invalid entry '{}': {}", _0, _1)]
+    InvalidEntry(String, String),
 }
 
 use BabelMonitorError::*;
 
-// If a function doesn't need internal state of the Babel object
-// we don't want to place it as a member function.
-fn find_babel_val(val: &str, line: &str) -> Result<String, Error> {
-    let mut iter = line.split(" ");
-    while let Some(entry) = iter.next() {
-        if entry.to_string().contains(val) {
-            match iter.next() {
-                Some(v) => return Ok(v.to_string()),
-                None => continue,
-            }
-        }
-    }
-    warn!("find_babel_val warn! Can not find {} in {}", val, line);
-    return Err(VariableNotFound(String::from(val), String::from(line)).into());
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Route {
     pub id: String,
     pub iface: String,
@@ -60,19 +64,29 @@ pub struct Route {
     pub installed: bool,
     pub neigh_ip: IpAddr,
     pub prefix: IpNetwork,
+    /// The source prefix, only narrower than the default route for source specific routes. Babel
+    /// versions without source specific routing don't print it, it's the default route then.
+    pub from: IpNetwork,
+    /// The router that originated the route, empty if babel didn't print it
+    pub router_id: String,
+    /// Only printed by babel versions that report it
+    pub seqno: Option<u16>,
     pub metric: u16,
     pub refmetric: u16,
     pub full_path_rtt: f32,
     pub price: u32,
+    /// Older versions of the althea fork only print the price, the fee is the same as the price then
     pub fee: u32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Neighbor {
     pub id: String,
     pub address: IpAddr,
     pub iface: String,
     pub reach: u16,
+    /// Unicast reachability, only printed by babel versions that report it
+    pub ureach: Option<u16>,
     pub txcost: u16,
     pub rxcost: u16,
     pub rtt: f32,
@@ -80,6 +94,118 @@ pub struct Neighbor {
     pub cost: u16,
 }
 
+/// Reads the local fee from the first line of the output of a babel `dump`
+pub fn parse_local_fee(babel_output: &str) -> Result<u32, Error> {
+    let fee_entry = match babel_output.split("\n").nth(0) {
+        Some(entry) => entry,
+        // Even an empty string wouldn't yield None
+        None => return Err(LocalFeeNotFound(String::from("<Babel output is None>")).into()),
+    };
+
+    match parse_line(fee_entry) {
+        Ok(Line::LocalFee(fee)) => {
+            trace!("Retrieved a local fee of {}", fee);
+            Ok(fee)
+        }
+        _ => Err(LocalFeeNotFound(String::from(fee_entry)).into()),
+    }
+}
+
+/// Validates the configuration api version babel sends when a connection is opened
+fn check_preamble(preamble: String) -> Result<(), Error> {
+    // Note you have changed the config interface, bump to 1.1 in babel
+    if preamble.contains("ALTHEA 0.1") {
+        trace!("Attached OK to Babel with preamble: {}", preamble);
+        return Ok(());
+    } else {
+        return Err(InvalidPreamble(preamble).into());
+    }
+}
+
+/// Parses the neighbours out of the output of a babel `dump`
+pub fn parse_neighs_from_dump(babel_output: &str) -> Result<VecDeque<Neighbor>, Error> {
+    let mut vector: VecDeque<Neighbor> = VecDeque::with_capacity(5);
+    let mut found_neigh = false;
+    for entry in babel_output.lines() {
+        if entry.starts_with("add neighbour") {
+            found_neigh = true;
+            match parse_line(entry) {
+                Ok(Line::Add(Entry::Neighbour(neigh))) => vector.push_back(neigh),
+                Ok(_) => {}
+                Err(e) => warn!("Failed to parse neighbour {}", e),
+            }
+        }
+    }
+    if vector.len() == 0 && found_neigh {
+        bail!("All Babel neigh parsing failed!")
+    }
+    Ok(vector)
+}
+
+/// Parses the routes out of the output of a babel `dump`
+pub fn parse_routes_from_dump(babel_out: &str) -> Result<VecDeque<Route>, Error> {
+    let mut vector: VecDeque<Route> = VecDeque::with_capacity(20);
+    let mut found_route = false;
+    trace!("Got from babel dump: {}", babel_out);
+
+    for entry in babel_out.lines() {
+        if entry.starts_with("add route") {
+            trace!("Parsing 'add route' entry: {}", entry);
+            found_route = true;
+            match parse_line(entry) {
+                Ok(Line::Add(Entry::Route(route))) => vector.push_back(route),
+                Ok(_) => {}
+                Err(e) => warn!("Failed to parse route {}", e),
+            }
+        }
+    }
+    if vector.len() == 0 && found_route {
+        bail!("All Babel route parsing failed!")
+    }
+    Ok(vector)
+}
+
+/// In this function we take a route snapshot then loop over the routes list twice
+/// to find the neighbor local address and then the route to the destination
+/// via that neighbor. This could be dramatically more efficient if we had the neighbors
+/// local ip lying around somewhere.
+pub fn get_route_via_neigh(
+    neigh_mesh_ip: IpAddr,
+    dest_mesh_ip: IpAddr,
+    routes: &VecDeque<Route>,
+) -> Result<Route, Error> {
+    // First find the neighbors route to itself to get the local address
+    for neigh_route in routes.iter() {
+        // This will fail on v4 babel routes etc
+        if let IpNetwork::V6(ref ip) = neigh_route.prefix {
+            if ip.ip() == neigh_mesh_ip {
+                let neigh_local_ip = neigh_route.neigh_ip;
+                // Now we take the neigh_local_ip and search for a route via that
+                for route in routes.iter() {
+                    if let IpNetwork::V6(ref ip) = route.prefix {
+                        if ip.ip() == dest_mesh_ip && route.neigh_ip == neigh_local_ip {
+                            return Ok(route.clone());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Err(NoNeighbor(neigh_mesh_ip.to_string()).into())
+}
+
+/// Checks if Babel has an installed route to the given destination
+pub fn do_we_have_route(mesh_ip: &IpAddr, routes: &VecDeque<Route>) -> Result<bool, Error> {
+    for route in routes.iter() {
+        if let IpNetwork::V6(ref ip) = route.prefix {
+            if ip.ip() == *mesh_ip && route.installed {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
 pub struct Babel<T: Read + Write> {
     stream: BufStream<T>,
 }
@@ -136,30 +262,12 @@ impl<T: Read + Write> Babel<T> {
     // Consumes the automated Preamble and validates configuration api version
     pub fn start_connection(&mut self) -> Result<(), Error> {
         let preamble = self.read_babel()?;
-        // Note you have changed the config interface, bump to 1.1 in babel
-        if preamble.contains("ALTHEA 0.1") {
-            trace!("Attached OK to Babel with preamble: {}", preamble);
-            return Ok(());
-        } else {
-            return Err(InvalidPreamble(preamble).into());
-        }
+        check_preamble(preamble)
     }
 
     pub fn get_local_fee(&mut self) -> Result<u32, Error> {
         let babel_output = self.command("dump")?;
-        let fee_entry = match babel_output.split("\n").nth(0) {
-            Some(entry) => entry,
-            // Even an empty string wouldn't yield None
-            None => return Err(LocalFeeNotFound(String::from("<Babel output is None>")).into()),
-        };
-
-        if fee_entry.contains("local fee") {
-            let fee = find_babel_val("fee", fee_entry)?.parse()?;
-            trace!("Retrieved a local fee of {}", fee);
-            return Ok(fee);
-        }
-
-        Err(LocalFeeNotFound(String::from(fee_entry)).into())
+        parse_local_fee(&babel_output)
     }
 
     pub fn set_local_fee(&mut self, new_fee: u32) -> Result<(), Error> {
@@ -195,251 +303,36 @@ impl<T: Read + Write> Babel<T> {
     }
 
     pub fn parse_neighs(&mut self) -> Result<VecDeque<Neighbor>, Error> {
-        let mut vector: VecDeque<Neighbor> = VecDeque::with_capacity(5);
-        let mut found_neigh = false;
-        for entry in self.command("dump")?.split("\n") {
-            if entry.contains("add neighbour") {
-                found_neigh = true;
-                let neigh = Neighbor {
-                    id: match find_babel_val("neighbour", entry) {
-                        Ok(val) => val,
-                        Err(_) => continue,
-                    },
-                    address: match find_babel_val("address", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing address for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    iface: match find_babel_val("if", entry) {
-                        Ok(val) => val,
-                        Err(_) => continue,
-                    },
-                    reach: match find_babel_val("reach", entry) {
-                        Ok(val) => match u16::from_str_radix(&val, 16) {
-                            Ok(val) => val,
-                            Err(e) => {
-                                warn!("Failed to convert reach {:?} {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    txcost: match find_babel_val("txcost", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing txcost for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    rxcost: match find_babel_val("rxcost", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing rxcost for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    rtt: match find_babel_val("rtt", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing rtt for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        // it's possible that our neigh does not have rtt enabled, handle
-                        Err(_) => 0.0,
-                    },
-                    rttcost: match find_babel_val("rttcost", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing rtt for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        // it's possible that our neigh does not have rtt enabled, handle
-                        Err(_) => 0,
-                    },
-                    cost: match find_babel_val("cost", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing cost for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                };
-                vector.push_back(neigh);
-            }
-        }
-        if vector.len() == 0 && found_neigh {
-            bail!("All Babel neigh parsing failed!")
-        }
-        Ok(vector)
+        let babel_output = self.command("dump")?;
+        parse_neighs_from_dump(&babel_output)
     }
 
     pub fn parse_routes(&mut self) -> Result<VecDeque<Route>, Error> {
-        let mut vector: VecDeque<Route> = VecDeque::with_capacity(20);
-        let babel_out = self.command("dump")?;
-        let mut found_route = false;
-        trace!("Got from babel dump: {}", babel_out);
-
-        for entry in babel_out.split("\n") {
-            if entry.contains("add route") {
-                trace!("Parsing 'add route' entry: {}", entry);
-                found_route = true;
-                let route = Route {
-                    id: match find_babel_val("route", entry) {
-                        Ok(entry) => entry,
-                        Err(_) => continue,
-                    },
-                    iface: match find_babel_val("if", entry) {
-                        Ok(entry) => entry,
-                        Err(_) => continue,
-                    },
-                    xroute: false,
-                    installed: match find_babel_val("installed", entry) {
-                        Ok(entry) => entry.contains("yes"),
-                        Err(_) => continue,
-                    },
-                    neigh_ip: match find_babel_val("via", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing neigh_ip for route {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    prefix: match find_babel_val("prefix", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing prefix for route {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    metric: match find_babel_val("metric", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing metric for route {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    refmetric: match find_babel_val("refmetric", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing refmetric {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    full_path_rtt: match find_babel_val("full-path-rtt", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing full_path_rtt {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    price: match find_babel_val("price", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing price {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    fee: match find_babel_val("fee", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing fee {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                };
-
-                vector.push_back(route);
-            }
-        }
-        if vector.len() == 0 && found_route {
-            bail!("All Babel route parsing failed!")
-        }
-        Ok(vector)
+        let babel_output = self.command("dump")?;
+        parse_routes_from_dump(&babel_output)
+    }
+
+    /// Everything babel reports in a dump, entries that fail to parse are left out
+    pub fn dump(&mut self) -> Result<Dump, Error> {
+        let babel_output = self.command("dump")?;
+        Ok(parse_dump(&babel_output))
     }
 
-    /// In this function we take a route snapshot then loop over the routes list twice
-    /// to find the neighbor local address and then the route to the destination
-    /// via that neighbor. This could be dramatically more efficient if we had the neighbors
-    /// local ip lying around somewhere.
     pub fn get_route_via_neigh(
         &mut self,
         neigh_mesh_ip: IpAddr,
         dest_mesh_ip: IpAddr,
         routes: &VecDeque<Route>,
     ) -> Result<Route, Error> {
-        // First find the neighbors route to itself to get the local address
-        for neigh_route in routes.iter() {
-            // This will fail on v4 babel routes etc
-            if let IpNetwork::V6(ref ip) = neigh_route.prefix {
-                if ip.ip() == neigh_mesh_ip {
-                    let neigh_local_ip = neigh_route.neigh_ip;
-                    // Now we take the neigh_local_ip and search for a route via that
-                    for route in routes.iter() {
-                        if let IpNetwork::V6(ref ip) = route.prefix {
-                            if ip.ip() == dest_mesh_ip && route.neigh_ip == neigh_local_ip {
-                                return Ok(route.clone());
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        Err(NoNeighbor(neigh_mesh_ip.to_string()).into())
+        get_route_via_neigh(neigh_mesh_ip, dest_mesh_ip, routes)
     }
 
-    /// Checks if Babel has an installed route to the given destination
     pub fn do_we_have_route(
         &mut self,
         mesh_ip: &IpAddr,
         routes: &VecDeque<Route>,
     ) -> Result<bool, Error> {
-        for route in routes.iter() {
-            if let IpNetwork::V6(ref ip) = route.prefix {
-                if ip.ip() == *mesh_ip && route.installed {
-                    return Ok(true);
-                }
-            }
-        }
-        Ok(false)
+        do_we_have_route(mesh_ip, routes)
     }
 }
 #[cfg(test)]
@@ -460,13 +353,13 @@ rtt 18.674 rttcost 473 cost 817\n\
 add neighbour 14f0488 address fe80::e914:2335:a76:bda3 if wlan0 reach feff rxcost 258 txcost 256 \
 rtt 22.805 rttcost 698 cost 956\n\
 add xroute 10.28.119.131/32-::/0 prefix 10.28.119.131/32 from ::/0 metric 0\n\
-add route 14f0820 prefix 10.28.7.7/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:5b:fe:c7\
+add route 14f0820 prefix 10.28.7.7/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:5b:fe:c7 \
 metric 1596 price 3072 fee 3072 refmetric 638 full-path-rtt 22.805 via fe80::e914:2335:a76:bda3 if wlan0\n\
-add route 14f07a0 prefix 10.28.7.7/32 from 0.0.0.0/0 installed no id ba:27:eb:ff:fe:5b:fe:c7\
+add route 14f07a0 prefix 10.28.7.7/32 from 0.0.0.0/0 installed no id ba:27:eb:ff:fe:5b:fe:c7 \
 metric 1569 price 5032 fee 5032 refmetric 752 full-path-rtt 42.805 via fe80::e9d0:498f:6c61:be29 if wlan0\n\
-add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:c1:2d:d5\
+add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:c1:2d:d5 \
 metric 817 price 4008 fee 4008 refmetric 0 full-path-rtt 18.674 via fe80::e9d0:498f:6c61:be29 if wlan0\n\
-add route 14f0548 prefix 10.28.244.138/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:d1:3e:ba\
+add route 14f0548 prefix 10.28.244.138/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:d1:3e:ba \
 metric 958 price 2048 fee 2048 refmetric 0 full-path-rtt 56.805 via fe80::e914:2335:a76:bda3 if wlan0\n\
 ok\n";
 
@@ -511,23 +404,23 @@ ok\n";
 
     #[test]
     fn line_parse() {
-        assert_eq!(find_babel_val("metric", XROUTE_LINE).unwrap(), "0");
-        assert_eq!(
-            find_babel_val("prefix", XROUTE_LINE).unwrap(),
-            "10.28.119.131/32"
-        );
-        assert_eq!(find_babel_val("route", ROUTE_LINE).unwrap(), "14f06d8");
-        assert_eq!(find_babel_val("if", ROUTE_LINE).unwrap(), "wlan0");
-        assert_eq!(
-            find_babel_val("via", ROUTE_LINE).unwrap(),
-            "fe80::e9d0:498f:6c61:be29"
-        );
-        assert_eq!(find_babel_val("reach", NEIGH_LINE).unwrap(), "ffff");
-        assert_eq!(find_babel_val("rxcost", NEIGH_LINE).unwrap(), "256");
-        assert_eq!(find_babel_val("rtt", NEIGH_LINE).unwrap(), "29.264");
-        assert_eq!(find_babel_val("interface", IFACE_LINE).unwrap(), "wlan0");
-        assert_eq!(find_babel_val("ipv4", IFACE_LINE).unwrap(), "10.28.119.131");
-        assert_eq!(find_babel_val("price", PRICE_LINE).unwrap(), "1024");
+        let xroute = tokenize(XROUTE_LINE).unwrap();
+        assert_eq!(xroute.get("metric"), Some("0"));
+        assert_eq!(xroute.get("prefix"), Some("10.28.119.131/32"));
+        let route = tokenize(ROUTE_LINE).unwrap();
+        assert_eq!(route.id, "14f06d8");
+        assert_eq!(route.get("if"), Some("wlan0"));
+        assert_eq!(route.get("via"), Some("fe80::e9d0:498f:6c61:be29"));
+        let neigh = tokenize(NEIGH_LINE).unwrap();
+        assert_eq!(neigh.get("reach"), Some("ffff"));
+        assert_eq!(neigh.get("rxcost"), Some("256"));
+        assert_eq!(neigh.get("rtt"), Some("29.264"));
+        let iface = tokenize(IFACE_LINE).unwrap();
+        assert_eq!(iface.id, "wlan0");
+        assert_eq!(iface.get("ipv4"), Some("10.28.119.131"));
+        let price = tokenize(PRICE_LINE).unwrap();
+        assert_eq!(price.object, "price");
+        assert_eq!(price.id, "1024");
     }
 
     #[test]
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX states_address_b_idx;
+DROP INDEX states_address_a_idx;
+DROP TABLE state_history
@@ -0,0 +1,28 @@
+-- Every state ever accepted, `states` only keeps the latest one per channel
+CREATE TABLE state_history (
+  id INTEGER NOT NULL PRIMARY KEY,
+  channel_id BLOB NOT NULL,
+  address_a BLOB NOT NULL,
+  address_b BLOB NOT NULL,
+  nonce BLOB NOT NULL,
+  balance_a BLOB NOT NULL,
+  balance_b BLOB NOT NULL,
+  sig_a_v BLOB,
+  sig_a_r BLOB,
+  sig_a_s BLOB,
+  sig_b_v BLOB,
+  sig_b_r BLOB,
+  sig_b_s BLOB
+);
+
+CREATE UNIQUE INDEX state_history_channel_nonce_idx ON state_history (channel_id, nonce);
+
+-- The composite index on states can't serve lookups by address_b alone
+CREATE INDEX states_address_a_idx ON states (address_a);
+CREATE INDEX states_address_b_idx ON states (address_b);
+
+INSERT INTO state_history (channel_id, address_a, address_b, nonce, balance_a, balance_b,
+                           sig_a_v, sig_a_r, sig_a_s, sig_b_v, sig_b_r, sig_b_s)
+  SELECT channel_id, address_a, address_b, nonce, balance_a, balance_b,
+         sig_a_v, sig_a_r, sig_a_s, sig_b_v, sig_b_r, sig_b_s
+  FROM states;
@@ -10,6 +10,7 @@ extern crate log;
 extern crate serde_derive;
 
 extern crate actix_web;
+extern crate althea_types;
 extern crate clarity;
 extern crate dotenv;
 extern crate env_logger;
@@ -23,7 +24,9 @@ extern crate serde_json;
 
 mod models;
 mod network_endpoints;
+mod queries;
 mod schema;
+mod watchtower;
 
 use actix_web::{http::Method, server, App};
 use diesel::{connection::Connection, sqlite::SqliteConnection};
@@ -34,7 +37,10 @@ use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 
 use std::{env, path, process, sync::Mutex};
 
-use network_endpoints::{handle_get_channel_state, handle_upload_channel_state};
+use network_endpoints::{
+    handle_channel_close_started, handle_get_balances, handle_get_channel_history,
+    handle_get_channel_state, handle_get_channels, handle_upload_channel_state,
+};
 
 lazy_static! {
     static ref DB_CONN: Mutex<SqliteConnection> = {
@@ -84,7 +90,16 @@ fn main() {
                             "/get_channel_state/{address}",
                             Method::GET,
                             handle_get_channel_state,
-                        )
+                        ).route(
+                            "/channel_close_started",
+                            Method::POST,
+                            handle_channel_close_started,
+                        ).route("/channels/{address}", Method::GET, handle_get_channels)
+                        .route(
+                            "/channel_history/{channel_id}",
+                            Method::GET,
+                            handle_get_channel_history,
+                        ).route("/balances", Method::GET, handle_get_balances)
                 }).workers(1)
                 .bind_ssl(format!("[::]:{}", BOUNTY_HUNTER_PORT), builder)
                 .unwrap()
@@ -1,25 +1,12 @@
 use clarity::{Address, Signature};
 use failure::Error;
 use num256::Uint256;
-use num_traits::Zero;
 
 use std::convert::{From, Into};
 
-use schema::states;
+use schema::{state_history, states};
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
-pub struct ChannelState {
-    pub channel_id: Uint256,
-    pub address_a: Address,
-    pub address_b: Address,
-    pub nonce: Uint256,
-
-    pub balance_a: Uint256,
-    pub balance_b: Uint256,
-
-    pub signature_a: Option<Signature>,
-    pub signature_b: Option<Signature>,
-}
+pub use althea_types::ChannelState;
 
 /// A helper type that prepares a [ChannelState](ChannelState) for storage by turning the contents into types
 /// easy to grasp for `diesel`.
@@ -63,19 +50,26 @@ pub struct NewChannelStateRecord {
     pub sig_b_s: Option<Vec<u8>>,
 }
 
-impl Default for ChannelState {
-    fn default() -> Self {
-        Self {
-            channel_id: Uint256::zero(),
-            address_a: [0u8; 20].into(),
-            address_b: [0u8; 20].into(),
-            nonce: Uint256::zero(),
-            balance_a: Uint256::zero(),
-            balance_b: Uint256::zero(),
-            signature_a: None,
-            signature_b: None,
-        }
-    }
+/// The `state_history` counterpart of [NewChannelStateRecord](NewChannelStateRecord), every
+/// accepted state is kept there and not just the latest one
+#[derive(Insertable, Clone, Debug, Eq, PartialEq)]
+#[table_name = "state_history"]
+pub struct NewStateHistoryRecord {
+    pub channel_id: Vec<u8>,
+    pub address_a: Vec<u8>,
+    pub address_b: Vec<u8>,
+    pub nonce: Vec<u8>,
+
+    pub balance_a: Vec<u8>,
+    pub balance_b: Vec<u8>,
+
+    pub sig_a_v: Option<Vec<u8>>,
+    pub sig_a_r: Option<Vec<u8>>,
+    pub sig_a_s: Option<Vec<u8>>,
+
+    pub sig_b_v: Option<Vec<u8>>,
+    pub sig_b_r: Option<Vec<u8>>,
+    pub sig_b_s: Option<Vec<u8>>,
 }
 
 impl From<ChannelStateRecord> for NewChannelStateRecord {
@@ -100,11 +94,31 @@ impl From<ChannelStateRecord> for NewChannelStateRecord {
     }
 }
 
-impl ChannelState {
-    /// Verify the channel state against all available signatures
-    pub fn verify(&self, _sig: &Signature) -> Result<(), Error> {
-        Ok(())
+/// Check that `sig` is a signature over `state` made by `signer`
+fn verify_signature(state: &ChannelState, sig: &Signature, signer: &Address) -> Result<(), Error> {
+    let recovered = sig.recover(&state.fingerprint())?;
+    if recovered != *signer {
+        bail!(
+            "Signature was made by {:#x} instead of {:#x}",
+            recovered,
+            signer
+        );
+    }
+    Ok(())
+}
+
+/// Verify that the channel state is signed by both parties, `signature_a` has to be made by
+/// `address_a` and `signature_b` by `address_b`
+pub fn verify_state(state: &ChannelState) -> Result<(), Error> {
+    match state.signature_a {
+        Some(ref sig_a) => verify_signature(state, sig_a, &state.address_a)?,
+        None => bail!("Signature A is missing"),
+    }
+    match state.signature_b {
+        Some(ref sig_b) => verify_signature(state, sig_b, &state.address_b)?,
+        None => bail!("Signature B is missing"),
     }
+    Ok(())
 }
 
 impl ChannelStateRecord {
@@ -230,9 +244,108 @@ impl From<ChannelState> for NewChannelStateRecord {
     }
 }
 
+impl From<NewChannelStateRecord> for NewStateHistoryRecord {
+    fn from(record: NewChannelStateRecord) -> Self {
+        Self {
+            channel_id: record.channel_id,
+            address_a: record.address_a,
+            address_b: record.address_b,
+            nonce: record.nonce,
+
+            balance_a: record.balance_a,
+            balance_b: record.balance_b,
+
+            sig_a_v: record.sig_a_v,
+            sig_a_r: record.sig_a_r,
+            sig_a_s: record.sig_a_s,
+
+            sig_b_v: record.sig_b_v,
+            sig_b_r: record.sig_b_r,
+            sig_b_s: record.sig_b_s,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use clarity::PrivateKey;
+
+    fn get_test_keys() -> (PrivateKey, PrivateKey) {
+        (
+            "0xfe1b2d1a4f8b0b8a3c1e2f3d4c5b6a79887766554433221100ffeeddccbbaa99"
+                .parse()
+                .unwrap(),
+            "0x1111111111111111111111111111111111111111111111111111111111111111"
+                .parse()
+                .unwrap(),
+        )
+    }
+
+    /// A state between the two test keys, signed by both
+    fn get_signed_state() -> ChannelState {
+        let (key_a, key_b) = get_test_keys();
+        let mut state = ChannelState {
+            channel_id: 1.into(),
+            address_a: key_a.to_public_key().unwrap(),
+            address_b: key_b.to_public_key().unwrap(),
+            nonce: 5.into(),
+            balance_a: 100.into(),
+            balance_b: 200.into(),
+            signature_a: None,
+            signature_b: None,
+        };
+        state.signature_a = Some(key_a.sign_hash(&state.fingerprint()));
+        state.signature_b = Some(key_b.sign_hash(&state.fingerprint()));
+        state
+    }
+
+    #[test]
+    fn test_verify() {
+        let state = get_signed_state();
+        verify_state(&state).unwrap();
+
+        // a state signed by only one party so far can't be enforced
+        let mut half_signed = state.clone();
+        half_signed.signature_b = None;
+        assert!(verify_state(&half_signed).is_err());
+
+        let mut half_signed = state.clone();
+        half_signed.signature_a = None;
+        assert!(verify_state(&half_signed).is_err());
+    }
+
+    #[test]
+    fn test_verify_wrong_signer() {
+        let mut state = get_signed_state();
+        let impostor: PrivateKey =
+            "0x2222222222222222222222222222222222222222222222222222222222222222"
+                .parse()
+                .unwrap();
+        state.signature_b = Some(impostor.sign_hash(&state.fingerprint()));
+        assert!(verify_state(&state).is_err());
+    }
+
+    #[test]
+    fn test_verify_swapped_signatures() {
+        let mut state = get_signed_state();
+        let sig_a = state.signature_a.take();
+        state.signature_a = state.signature_b.take();
+        state.signature_b = sig_a;
+        assert!(verify_state(&state).is_err());
+    }
+
+    #[test]
+    fn test_verify_tampered_balances() {
+        let mut state = get_signed_state();
+        state.balance_a = 200.into();
+        state.balance_b = 100.into();
+        assert!(verify_state(&state).is_err());
+
+        let mut state = get_signed_state();
+        state.nonce = 6.into();
+        assert!(verify_state(&state).is_err());
+    }
 
     #[test]
     fn test_state2record() {
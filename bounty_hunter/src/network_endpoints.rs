@@ -1,4 +1,4 @@
-use actix_web::{http::StatusCode, HttpRequest, HttpResponse, Json};
+use actix_web::{http::StatusCode, HttpRequest, HttpResponse, Json, Path, Query};
 use clarity::Address;
 use diesel::prelude::*;
 use failure::Error;
@@ -7,10 +7,19 @@ use num256::Uint256;
 
 use std::collections::HashMap;
 
-use models::{ChannelState, ChannelStateRecord, NewChannelStateRecord};
+use models::{verify_state, ChannelState, ChannelStateRecord, NewChannelStateRecord};
+use queries::{self, Page, Pagination};
 use schema::states::dsl::*;
+use watchtower::{run_watchtower, ChainEvent, DbStateStore, LOCAL_CHAIN};
 use DB_CONN;
 
+/// A channel close seen on chain, as reported to `/channel_close_started`
+#[derive(Deserialize, Debug)]
+pub struct ChannelCloseStarted {
+    pub channel_id: Uint256,
+    pub nonce: Uint256,
+}
+
 /// Pass channel state to the bounty hunter
 pub fn handle_upload_channel_state(
     state_obj: Json<ChannelState>,
@@ -22,53 +31,24 @@ pub fn handle_upload_channel_state(
     let state = state_obj.into_inner();
     debug!("Got state {:?}", state);
 
-    // Check signatures
-    if let Some(sig_a) = &state.signature_a {
-        match state.verify(sig_a) {
-            Ok(_) => debug!(
-                "Channel {}: Signature A {:?} verification OK",
-                state.channel_id, sig_a
-            ),
-            Err(e) => {
-                let msg = format!(
-                    "Channel {}: Signature A {:?} verification FAILED",
-                    state.channel_id, sig_a
-                );
-
-                warn!("{}: {}", msg, e);
-                ret.insert("error".to_owned(), msg);
-
-                return Box::new(future::ok(
-                    HttpResponse::new(StatusCode::BAD_REQUEST)
-                        .into_builder()
-                        .json(ret),
-                ));
-            }
-        }
-    }
-    if let Some(sig_b) = &state.signature_b {
-        match state.verify(sig_b) {
-            Ok(_) => debug!(
-                "Channel {}: Signature B {:?} verification OK",
-                state.channel_id, sig_b
-            ),
-            Err(e) => {
-                let msg = format!(
-                    "Channel {}: Signature B {:?} verification FAILED",
-                    state.channel_id, sig_b
-                );
-
-                warn!("{}: {}", msg, e);
-                ret.insert("error".to_owned(), msg);
-
-                return Box::new(future::ok(
-                    HttpResponse::new(StatusCode::BAD_REQUEST)
-                        .into_builder()
-                        .json(ret),
-                ));
-            }
-        }
+    // Only a state signed by both parties can be enforced on chain, storing anything less would
+    // let a forged state with a high nonce shadow the real ones
+    if let Err(e) = verify_state(&state) {
+        let msg = format!(
+            "Channel {}: Signature verification FAILED",
+            state.channel_id
+        );
+
+        warn!("{}: {}", msg, e);
+        ret.insert("error".to_owned(), msg);
+
+        return Box::new(future::ok(
+            HttpResponse::new(StatusCode::BAD_REQUEST)
+                .into_builder()
+                .json(ret),
+        ));
     }
+    debug!("Channel {}: Signature verification OK", state.channel_id);
 
     // Multiple queries need to happen without interruption
     let db_conn = DB_CONN.lock().unwrap();
@@ -130,75 +110,58 @@ pub fn handle_upload_channel_state(
     // No better nonce available, carry on
     debug!("Channel {}: Nonce {} OK", state.channel_id, state.nonce);
     let state_new_record = NewChannelStateRecord::from(state.clone());
-    let existing_entry = states.filter(channel_id.eq(&state_new_record.channel_id));
-
-    // Store the result so that db_conn ref is released in time. Would cause deadlock for the
-    // rows_affected == 0 branch otherwise.
-    let update_result = diesel::update(existing_entry)
-        .set(&state_new_record)
-        .execute(&*db_conn);
-
-    match update_result {
-        // The schema has `UNIQUE` on channel_id. This query must affect only one row
-        // at all times.
-        Ok(rows_affected) if rows_affected > 1 => {
-            warn!("Channel {}: Update affected multiple ({}) rows. Make sure that your DB respects the UNIQUE constraint",
-            state.channel_id,
-            rows_affected);
-
-            ret.insert(
-                "error".to_owned(),
-                "A DB integrity problem was detected in your channel's entries".to_owned(),
-            );
 
-            Box::new(future::ok(
-                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                    .into_builder()
-                    .json(ret),
-            ))
-        }
-        Ok(rows_affected) if rows_affected == 1 => {
+    match queries::store_state(&*db_conn, &state_new_record) {
+        Ok(()) => {
             info!("Channel {}: Update OK", state.channel_id);
             Box::new(future::ok(HttpResponse::Ok().json(ret)))
         }
-        Ok(rows_affected) if rows_affected == 0 => {
-            info!("Channel {}: Not in database, inserting", state.channel_id);
-
-            match diesel::insert_into(states)
-                .values(NewChannelStateRecord::from(state.clone()))
-                .execute(&*db_conn)
-            {
-                Ok(_) => {
-                    info!("Insert OK");
-                    Box::new(future::ok(HttpResponse::Ok().json(ret)))
-                }
-                Err(e) => {
-                    let msg = format!("Could not insert new channel into database");
-
-                    warn!("Channel {}: {}: {}", state.channel_id, msg, e);
-
-                    ret.insert("error".to_owned(), msg);
-
-                    Box::new(future::ok(
-                        HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                            .into_builder()
-                            .json(ret),
-                    ))
-                }
-            }
-        }
-        Ok(rows_affected_other) => {
-            error!("Unknown Update result value: {}", rows_affected_other);
+        Err(e) => {
+            let msg = format!("Could not store channel state");
+            warn!("Channel {}: {}: {}", state.channel_id, msg, e);
+            ret.insert("error".to_owned(), msg);
             Box::new(future::ok(
                 HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
                     .into_builder()
                     .json(ret),
             ))
         }
+    }
+}
+
+/// Tell the bounty hunter that a channel close was started on chain, the response lists the
+/// disputes submitted because of it
+pub fn handle_channel_close_started(
+    close: Json<ChannelCloseStarted>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    trace!("Hit /channel_close_started");
+
+    let close = close.into_inner();
+    debug!(
+        "Channel {}: Close started with nonce {}",
+        close.channel_id, close.nonce
+    );
+
+    let mut chain = LOCAL_CHAIN.lock().unwrap();
+    chain.push_event(ChainEvent::ChannelCloseStarted {
+        channel_id: close.channel_id.clone(),
+        nonce: close.nonce,
+    });
+
+    match run_watchtower(&mut *chain, &DbStateStore) {
+        Ok(disputes) => {
+            let mut ret = HashMap::new();
+            ret.insert("disputes".to_owned(), disputes);
+            Box::new(future::ok(HttpResponse::Ok().json(ret)))
+        }
         Err(e) => {
-            let msg = format!("Could not update existing record");
-            warn!("Channel {}: {}: {}", state.channel_id, msg, e);
+            let mut ret = HashMap::new();
+            let msg = format!("Could not check the channel close");
+
+            warn!("Channel {}: {}: {}", close.channel_id, msg, e);
+
             ret.insert("error".to_owned(), msg);
+
             Box::new(future::ok(
                 HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
                     .into_builder()
@@ -256,3 +219,73 @@ pub fn handle_get_channel_state(
 
     return Box::new(future::ok(HttpResponse::Ok().json(ok_ret)));
 }
+
+fn query_error_response(msg: String) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let mut ret = HashMap::new();
+    ret.insert("error".to_owned(), msg);
+    Box::new(future::ok(
+        HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+            .into_builder()
+            .json(ret),
+    ))
+}
+
+/// List the latest states of all channels an address is a party of
+pub fn handle_get_channels(
+    address: Path<Address>,
+    page: Query<Pagination>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let address = address.into_inner();
+    let page = page.into_inner();
+    trace!("Hit /channels/{:#x}", address);
+
+    match queries::channels_for_address(&*DB_CONN.lock().unwrap(), &address, page) {
+        Ok(channels) => Box::new(future::ok(
+            HttpResponse::Ok().json(Page::new(page, channels)),
+        )),
+        Err(e) => {
+            let msg = format!("Could not retrieve channels from database");
+            warn!("Address {:#x}: {}: {}", address, msg, e);
+            query_error_response(msg)
+        }
+    }
+}
+
+/// List every state accepted for a channel in nonce order
+pub fn handle_get_channel_history(
+    channel: Path<Uint256>,
+    page: Query<Pagination>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    // Can't be called `channel_id`, that would shadow the column
+    let channel = channel.into_inner();
+    let page = page.into_inner();
+    trace!("Hit /channel_history/{}", channel);
+
+    match queries::channel_history(&*DB_CONN.lock().unwrap(), &channel, page) {
+        Ok(history) => Box::new(future::ok(
+            HttpResponse::Ok().json(Page::new(page, history)),
+        )),
+        Err(e) => {
+            let msg = format!("Could not retrieve channel history from database");
+            warn!("Channel {}: {}: {}", channel, msg, e);
+            query_error_response(msg)
+        }
+    }
+}
+
+/// List the total balance every address holds across its channels
+pub fn handle_get_balances(
+    page: Query<Pagination>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let page = page.into_inner();
+    trace!("Hit /balances");
+
+    match queries::balances(&*DB_CONN.lock().unwrap(), page) {
+        Ok(totals) => Box::new(future::ok(HttpResponse::Ok().json(Page::new(page, totals)))),
+        Err(e) => {
+            let msg = format!("Could not compute balances");
+            warn!("{}: {}", msg, e);
+            query_error_response(msg)
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! Queries over the stored channel states. Besides storing uploads these back the endpoints
+//! operators use to investigate channels and disputes without opening the database by hand.
+
+use clarity::Address;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use failure::Error;
+use num256::Uint256;
+use num_traits::Zero;
+
+use std::cmp;
+use std::collections::BTreeMap;
+
+use models::{ChannelState, ChannelStateRecord, NewChannelStateRecord, NewStateHistoryRecord};
+use schema::{state_history, states};
+
+/// How many entries a page holds when the request doesn't say
+pub const DEFAULT_PAGE_LIMIT: i64 = 100;
+/// The largest page we're willing to serve
+pub const MAX_PAGE_LIMIT: i64 = 1000;
+
+/// `?offset=<n>&limit=<n>` on the query endpoints
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pagination {
+    #[serde(default)]
+    pub offset: i64,
+    #[serde(default = "default_limit")]
+    pub limit: i64,
+}
+
+fn default_limit() -> i64 {
+    DEFAULT_PAGE_LIMIT
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Pagination {
+            offset: 0,
+            limit: DEFAULT_PAGE_LIMIT,
+        }
+    }
+}
+
+impl Pagination {
+    /// Clamps the requested window into something we're willing to serve
+    pub fn sanitized(self) -> Self {
+        Pagination {
+            offset: cmp::max(self.offset, 0),
+            limit: cmp::min(cmp::max(self.limit, 0), MAX_PAGE_LIMIT),
+        }
+    }
+}
+
+/// One page of query results along with the window that was actually served
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Page<T> {
+    pub offset: i64,
+    pub limit: i64,
+    pub records: Vec<T>,
+}
+
+impl<T> Page<T> {
+    pub fn new(page: Pagination, records: Vec<T>) -> Self {
+        let page = page.sanitized();
+        Page {
+            offset: page.offset,
+            limit: page.limit,
+            records,
+        }
+    }
+}
+
+/// What an address holds across all the channels it's a party of
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AddressBalance {
+    pub address: Address,
+    pub channels: u64,
+    pub balance: Uint256,
+}
+
+/// Appends an accepted state to the channel's history. Recording the same nonce twice is a no-op
+/// so that a retried upload doesn't trip the unique index.
+pub fn record_history(
+    conn: &SqliteConnection,
+    record: &NewChannelStateRecord,
+) -> Result<(), Error> {
+    diesel::insert_or_ignore_into(state_history::table)
+        .values(NewStateHistoryRecord::from(record.clone()))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Makes an accepted state the latest one of its channel and appends it to the channel's history
+/// in a single transaction, so the two tables never disagree
+pub fn store_state(conn: &SqliteConnection, record: &NewChannelStateRecord) -> Result<(), Error> {
+    conn.transaction::<_, Error, _>(|| {
+        record_history(conn, record)?;
+
+        let existing = states::table.filter(states::channel_id.eq(&record.channel_id));
+        // The schema has `UNIQUE` on channel_id, so at most one row is affected
+        match diesel::update(existing).set(record).execute(conn)? {
+            0 => {
+                info!("Not in database, inserting");
+                diesel::insert_into(states::table)
+                    .values(record)
+                    .execute(conn)?;
+            }
+            1 => {}
+            rows_affected => bail!(
+                "Update affected multiple ({}) rows. Make sure that your DB respects the UNIQUE constraint",
+                rows_affected
+            ),
+        }
+        Ok(())
+    })
+}
+
+/// The latest state of every channel `address` is a party of, oldest channels first
+pub fn channels_for_address(
+    conn: &SqliteConnection,
+    address: &Address,
+    page: Pagination,
+) -> Result<Vec<ChannelState>, Error> {
+    let page = page.sanitized();
+    states::table
+        .filter(
+            states::address_a
+                .eq(address.as_bytes())
+                .or(states::address_b.eq(address.as_bytes())),
+        ).order(states::id)
+        .offset(page.offset)
+        .limit(page.limit)
+        .load::<ChannelStateRecord>(conn)?
+        .into_iter()
+        .map(|record| record.to_state())
+        .collect()
+}
+
+/// Every state accepted for `channel_id`, in nonce order
+pub fn channel_history(
+    conn: &SqliteConnection,
+    channel_id: &Uint256,
+    page: Pagination,
+) -> Result<Vec<ChannelState>, Error> {
+    let page = page.sanitized();
+    state_history::table
+        .filter(state_history::channel_id.eq(channel_id.to_bytes_be()))
+        .order(state_history::nonce.asc())
+        .offset(page.offset)
+        .limit(page.limit)
+        .load::<ChannelStateRecord>(conn)?
+        .into_iter()
+        .map(|record| record.to_state())
+        .collect()
+}
+
+/// Sums every address' balances over the latest states of its channels, ordered by address.
+/// Balances are stored as big endian blobs SQLite can't add up, so this is done here.
+pub fn balances(conn: &SqliteConnection, page: Pagination) -> Result<Vec<AddressBalance>, Error> {
+    let page = page.sanitized();
+    let mut totals: BTreeMap<Vec<u8>, AddressBalance> = BTreeMap::new();
+
+    for record in states::table.load::<ChannelStateRecord>(conn)? {
+        let state = record.to_state()?;
+        for (address, balance) in vec![
+            (state.address_a, state.balance_a),
+            (state.address_b, state.balance_b),
+        ] {
+            let total = totals
+                .entry(address.as_bytes().to_vec())
+                .or_insert_with(|| AddressBalance {
+                    address: address.clone(),
+                    channels: 0,
+                    balance: Uint256::zero(),
+                });
+            total.channels += 1;
+            total.balance = total.balance.clone() + balance;
+        }
+    }
+
+    Ok(totals
+        .into_iter()
+        .map(|(_, total)| total)
+        .skip(page.offset as usize)
+        .take(page.limit as usize)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::connection::SimpleConnection;
+
+    fn get_conn() -> SqliteConnection {
+        let conn = SqliteConnection::establish(":memory:").unwrap();
+        conn.batch_execute(include_str!(
+            "../migrations/2018-01-18-004707_add_tables/up.sql"
+        )).unwrap();
+        conn.batch_execute(include_str!(
+            "../migrations/2018-11-26-120000_add_state_history/up.sql"
+        )).unwrap();
+        conn
+    }
+
+    fn get_state(channel_id: u32, a: u8, b: u8, nonce: u32) -> ChannelState {
+        ChannelState {
+            channel_id: channel_id.into(),
+            address_a: [a; 20].into(),
+            address_b: [b; 20].into(),
+            nonce: nonce.into(),
+            balance_a: 100.into(),
+            balance_b: 200.into(),
+            signature_a: None,
+            signature_b: None,
+        }
+    }
+
+    fn store(conn: &SqliteConnection, state: ChannelState) {
+        diesel::insert_into(states::table)
+            .values(NewChannelStateRecord::from(state))
+            .execute(conn)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_pagination_sanitized() {
+        let page = Pagination {
+            offset: -5,
+            limit: MAX_PAGE_LIMIT + 1,
+        };
+        assert_eq!(
+            page.sanitized(),
+            Pagination {
+                offset: 0,
+                limit: MAX_PAGE_LIMIT
+            }
+        );
+    }
+
+    #[test]
+    fn test_channels_for_address() {
+        let conn = get_conn();
+        store(&conn, get_state(1, 1, 2, 1));
+        store(&conn, get_state(2, 3, 1, 1));
+        store(&conn, get_state(3, 2, 3, 1));
+
+        let channels =
+            channels_for_address(&conn, &[1u8; 20].into(), Pagination::default()).unwrap();
+        assert_eq!(channels, vec![get_state(1, 1, 2, 1), get_state(2, 3, 1, 1)]);
+
+        let page = Pagination {
+            offset: 1,
+            limit: 1,
+        };
+        let channels = channels_for_address(&conn, &[1u8; 20].into(), page).unwrap();
+        assert_eq!(channels, vec![get_state(2, 3, 1, 1)]);
+    }
+
+    #[test]
+    fn test_channel_history() {
+        let conn = get_conn();
+        for nonce in &[2, 10, 1] {
+            let record = NewChannelStateRecord::from(get_state(1, 1, 2, *nonce));
+            record_history(&conn, &record).unwrap();
+        }
+        // A retried upload doesn't duplicate the entry
+        record_history(&conn, &NewChannelStateRecord::from(get_state(1, 1, 2, 10))).unwrap();
+        record_history(&conn, &NewChannelStateRecord::from(get_state(2, 1, 2, 5))).unwrap();
+
+        let history = channel_history(&conn, &1u32.into(), Pagination::default()).unwrap();
+        assert_eq!(
+            history,
+            vec![
+                get_state(1, 1, 2, 1),
+                get_state(1, 1, 2, 2),
+                get_state(1, 1, 2, 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_store_state() {
+        let conn = get_conn();
+        store_state(&conn, &NewChannelStateRecord::from(get_state(1, 1, 2, 1))).unwrap();
+        store_state(&conn, &NewChannelStateRecord::from(get_state(1, 1, 2, 2))).unwrap();
+
+        let latest = states::table.load::<ChannelStateRecord>(&conn).unwrap();
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].clone().to_state().unwrap(), get_state(1, 1, 2, 2));
+        assert_eq!(
+            channel_history(&conn, &1u32.into(), Pagination::default()).unwrap(),
+            vec![get_state(1, 1, 2, 1), get_state(1, 1, 2, 2)]
+        );
+    }
+
+    #[test]
+    fn test_balances() {
+        let conn = get_conn();
+        store(&conn, get_state(1, 1, 2, 1));
+        store(&conn, get_state(2, 3, 1, 1));
+
+        let all = balances(&conn, Pagination::default()).unwrap();
+        assert_eq!(
+            all,
+            vec![
+                AddressBalance {
+                    address: [1u8; 20].into(),
+                    channels: 2,
+                    balance: 300u32.into(),
+                },
+                AddressBalance {
+                    address: [2u8; 20].into(),
+                    channels: 1,
+                    balance: 200u32.into(),
+                },
+                AddressBalance {
+                    address: [3u8; 20].into(),
+                    channels: 1,
+                    balance: 100u32.into(),
+                },
+            ]
+        );
+
+        let page = Pagination {
+            offset: 2,
+            limit: 10,
+        };
+        assert_eq!(balances(&conn, page).unwrap(), vec![all[2].clone()]);
+    }
+}
@@ -0,0 +1,365 @@
+//! The watchtower half of the bounty hunter. Stored channel states are only useful if somebody
+//! notices when a channel is being closed with an older state than the latest one both parties
+//! signed, this module watches a chain event source for channel closes and produces disputes
+//! carrying the newer state whenever a stale one is used.
+
+use diesel::prelude::*;
+use failure::Error;
+use num256::Uint256;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use models::{verify_state, ChannelState, ChannelStateRecord};
+use DB_CONN;
+
+lazy_static! {
+    /// Chain events reported through `/channel_close_started`, used until the bounty hunter
+    /// follows a real chain
+    pub static ref LOCAL_CHAIN: Mutex<LocalChain> = Mutex::new(LocalChain::default());
+}
+
+/// Events from the payment channel contract the watchtower cares about
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum ChainEvent {
+    /// One of the parties started closing `channel_id` with the state at `nonce`
+    ChannelCloseStarted { channel_id: Uint256, nonce: Uint256 },
+}
+
+/// A newer, co-signed state to be submitted against a channel close that used a stale one
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Dispute {
+    pub channel_id: Uint256,
+    pub closing_nonce: Uint256,
+    pub state: ChannelState,
+}
+
+/// Where the watchtower learns about channel closes and sends its disputes
+pub trait ChainEventSource {
+    /// Returns every event seen since the last call
+    fn poll_events(&mut self) -> Result<Vec<ChainEvent>, Error>;
+    fn submit_dispute(&mut self, dispute: &Dispute) -> Result<(), Error>;
+}
+
+/// Where the watchtower looks up the states it was given for a channel
+pub trait StateStore {
+    fn latest_state(&self, channel_id: &Uint256) -> Result<Option<ChannelState>, Error>;
+    /// Every state accepted for the channel, newest first
+    fn history(&self, channel_id: &Uint256) -> Result<Vec<ChannelState>, Error>;
+}
+
+/// The states uploaded through `/upload_channel_state`
+pub struct DbStateStore;
+
+impl StateStore for DbStateStore {
+    fn latest_state(&self, id: &Uint256) -> Result<Option<ChannelState>, Error> {
+        use schema::states::dsl::*;
+
+        let mut records = states
+            .filter(channel_id.eq(id.to_bytes_be()))
+            .load::<ChannelStateRecord>(&*DB_CONN.lock().unwrap())?;
+
+        // channel_id is UNIQUE, there's at most one record
+        match records.pop() {
+            Some(record) => Ok(Some(record.to_state()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn history(&self, id: &Uint256) -> Result<Vec<ChannelState>, Error> {
+        use schema::state_history::dsl::*;
+
+        state_history
+            .filter(channel_id.eq(id.to_bytes_be()))
+            .order(nonce.desc())
+            .load::<ChannelStateRecord>(&*DB_CONN.lock().unwrap())?
+            .into_iter()
+            .map(|record| record.to_state())
+            .collect()
+    }
+}
+
+/// A state store kept in memory
+#[derive(Debug, Default)]
+pub struct MemoryStateStore {
+    states: HashMap<Uint256, ChannelState>,
+    history: HashMap<Uint256, Vec<ChannelState>>,
+}
+
+impl MemoryStateStore {
+    /// Keeps `state` if it's newer than the one stored for its channel
+    pub fn insert(&mut self, state: ChannelState) {
+        let newer = match self.states.get(&state.channel_id) {
+            Some(existing) => state.nonce > existing.nonce,
+            None => true,
+        };
+        if newer {
+            self.history
+                .entry(state.channel_id.clone())
+                .or_insert_with(Vec::new)
+                .push(state.clone());
+            self.states.insert(state.channel_id.clone(), state);
+        }
+    }
+}
+
+impl StateStore for MemoryStateStore {
+    fn latest_state(&self, channel_id: &Uint256) -> Result<Option<ChannelState>, Error> {
+        Ok(self.states.get(channel_id).cloned())
+    }
+
+    fn history(&self, channel_id: &Uint256) -> Result<Vec<ChannelState>, Error> {
+        let mut history = self.history.get(channel_id).cloned().unwrap_or_default();
+        history.reverse();
+        Ok(history)
+    }
+}
+
+/// A chain event source fed by hand, events are queued with `push_event` and disputes are only
+/// logged and remembered
+#[derive(Debug, Default)]
+pub struct LocalChain {
+    events: Vec<ChainEvent>,
+    pub disputes: Vec<Dispute>,
+}
+
+impl LocalChain {
+    pub fn push_event(&mut self, event: ChainEvent) {
+        self.events.push(event);
+    }
+}
+
+impl ChainEventSource for LocalChain {
+    fn poll_events(&mut self) -> Result<Vec<ChainEvent>, Error> {
+        Ok(self.events.drain(..).collect())
+    }
+
+    fn submit_dispute(&mut self, dispute: &Dispute) -> Result<(), Error> {
+        info!(
+            "Channel {}: Disputing close at nonce {} with nonce {}",
+            dispute.channel_id, dispute.closing_nonce, dispute.state.nonce
+        );
+        self.disputes.push(dispute.clone());
+        Ok(())
+    }
+}
+
+/// Decides whether a close of `channel_id` at `closing_nonce` has to be disputed. Only states
+/// signed by both parties are worth submitting, the contract won't accept anything else, so if
+/// the latest stored state isn't one the newest co-signed state in the history is used instead.
+pub fn check_close<T: StateStore>(
+    store: &T,
+    channel_id: &Uint256,
+    closing_nonce: &Uint256,
+) -> Result<Option<Dispute>, Error> {
+    let latest = match store.latest_state(channel_id)? {
+        Some(state) => state,
+        None => {
+            debug!("Channel {}: Close started for unknown channel", channel_id);
+            return Ok(None);
+        }
+    };
+
+    let state = match verify_state(&latest) {
+        Ok(()) => latest,
+        Err(e) => {
+            warn!(
+                "Channel {}: Stored nonce {} can't be submitted ({}), looking through the history",
+                channel_id, latest.nonce, e
+            );
+            match store
+                .history(channel_id)?
+                .into_iter()
+                .find(|state| verify_state(state).is_ok())
+            {
+                Some(state) => state,
+                None => {
+                    warn!(
+                        "Channel {}: No state signed by both parties to dispute close at nonce {}",
+                        channel_id, closing_nonce
+                    );
+                    return Ok(None);
+                }
+            }
+        }
+    };
+
+    if state.nonce <= *closing_nonce {
+        debug!(
+            "Channel {}: Close at nonce {} is not stale (ours is {})",
+            channel_id, closing_nonce, state.nonce
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(Dispute {
+        channel_id: channel_id.clone(),
+        closing_nonce: closing_nonce.clone(),
+        state,
+    }))
+}
+
+/// Handles every pending event from `source` and submits the resulting disputes back to it
+pub fn run_watchtower<S: ChainEventSource, T: StateStore>(
+    source: &mut S,
+    store: &T,
+) -> Result<Vec<Dispute>, Error> {
+    let mut disputes = Vec::new();
+    for event in source.poll_events()? {
+        match event {
+            ChainEvent::ChannelCloseStarted { channel_id, nonce } => {
+                if let Some(dispute) = check_close(store, &channel_id, &nonce)? {
+                    source.submit_dispute(&dispute)?;
+                    disputes.push(dispute);
+                }
+            }
+        }
+    }
+    Ok(disputes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clarity::PrivateKey;
+
+    /// Just enough of the payment channel contract to tell whether disputes land: a close sets
+    /// the channel's closing nonce and a dispute replaces it if the disputed state is newer
+    #[derive(Default)]
+    struct FakeChain {
+        events: Vec<ChainEvent>,
+        closing: HashMap<Uint256, Uint256>,
+    }
+
+    impl FakeChain {
+        fn start_close(&mut self, channel_id: Uint256, nonce: Uint256) {
+            self.closing.insert(channel_id.clone(), nonce.clone());
+            self.events
+                .push(ChainEvent::ChannelCloseStarted { channel_id, nonce });
+        }
+    }
+
+    impl ChainEventSource for FakeChain {
+        fn poll_events(&mut self) -> Result<Vec<ChainEvent>, Error> {
+            Ok(self.events.drain(..).collect())
+        }
+
+        fn submit_dispute(&mut self, dispute: &Dispute) -> Result<(), Error> {
+            verify_state(&dispute.state)?;
+            let closing = self
+                .closing
+                .get_mut(&dispute.channel_id)
+                .ok_or(format_err!("Channel is not closing"))?;
+            if dispute.state.nonce <= *closing {
+                bail!("Disputed state is not newer than the closing one");
+            }
+            *closing = dispute.state.nonce.clone();
+            Ok(())
+        }
+    }
+
+    fn get_signed_state(channel_id: u32, nonce: u32) -> ChannelState {
+        let key_a: PrivateKey =
+            "0xfe1b2d1a4f8b0b8a3c1e2f3d4c5b6a79887766554433221100ffeeddccbbaa99"
+                .parse()
+                .unwrap();
+        let key_b: PrivateKey =
+            "0x1111111111111111111111111111111111111111111111111111111111111111"
+                .parse()
+                .unwrap();
+        let mut state = ChannelState {
+            channel_id: channel_id.into(),
+            address_a: key_a.to_public_key().unwrap(),
+            address_b: key_b.to_public_key().unwrap(),
+            nonce: nonce.into(),
+            balance_a: 100.into(),
+            balance_b: 200.into(),
+            signature_a: None,
+            signature_b: None,
+        };
+        state.signature_a = Some(key_a.sign_hash(&state.fingerprint()));
+        state.signature_b = Some(key_b.sign_hash(&state.fingerprint()));
+        state
+    }
+
+    #[test]
+    fn test_stale_close_is_disputed() {
+        let mut store = MemoryStateStore::default();
+        store.insert(get_signed_state(1, 3));
+        store.insert(get_signed_state(1, 7));
+        let mut chain = FakeChain::default();
+
+        chain.start_close(1u32.into(), 3u32.into());
+        let disputes = run_watchtower(&mut chain, &store).unwrap();
+
+        assert_eq!(disputes.len(), 1);
+        assert_eq!(disputes[0].closing_nonce, Uint256::from(3u32));
+        assert_eq!(disputes[0].state, get_signed_state(1, 7));
+        assert_eq!(chain.closing[&Uint256::from(1u32)], Uint256::from(7u32));
+    }
+
+    #[test]
+    fn test_current_close_is_not_disputed() {
+        let mut store = MemoryStateStore::default();
+        store.insert(get_signed_state(1, 7));
+        let mut chain = FakeChain::default();
+
+        chain.start_close(1u32.into(), 7u32.into());
+        chain.start_close(2u32.into(), 1u32.into());
+
+        assert!(run_watchtower(&mut chain, &store).unwrap().is_empty());
+        assert_eq!(chain.closing[&Uint256::from(1u32)], Uint256::from(7u32));
+    }
+
+    #[test]
+    fn test_half_signed_state_is_not_disputed() {
+        let mut state = get_signed_state(1, 7);
+        state.signature_b = None;
+        let mut store = MemoryStateStore::default();
+        store.insert(state);
+        let mut chain = FakeChain::default();
+
+        chain.start_close(1u32.into(), 3u32.into());
+
+        assert!(run_watchtower(&mut chain, &store).unwrap().is_empty());
+        assert_eq!(chain.closing[&Uint256::from(1u32)], Uint256::from(3u32));
+    }
+
+    #[test]
+    fn test_half_signed_state_falls_back_to_history() {
+        let mut state = get_signed_state(1, 7);
+        state.signature_b = None;
+        let mut store = MemoryStateStore::default();
+        store.insert(get_signed_state(1, 3));
+        store.insert(get_signed_state(1, 5));
+        store.insert(state);
+        let mut chain = FakeChain::default();
+
+        chain.start_close(1u32.into(), 3u32.into());
+        let disputes = run_watchtower(&mut chain, &store).unwrap();
+
+        assert_eq!(disputes.len(), 1);
+        assert_eq!(disputes[0].state, get_signed_state(1, 5));
+        assert_eq!(chain.closing[&Uint256::from(1u32)], Uint256::from(5u32));
+
+        // nothing co-signed is newer than the close
+        chain.start_close(1u32.into(), 5u32.into());
+        assert!(run_watchtower(&mut chain, &store).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_local_chain_records_disputes() {
+        let mut store = MemoryStateStore::default();
+        store.insert(get_signed_state(1, 7));
+        let mut chain = LocalChain::default();
+
+        chain.push_event(ChainEvent::ChannelCloseStarted {
+            channel_id: 1u32.into(),
+            nonce: 2u32.into(),
+        });
+        let disputes = run_watchtower(&mut chain, &store).unwrap();
+
+        assert_eq!(chain.disputes, disputes);
+        assert!(chain.poll_events().unwrap().is_empty());
+    }
+}
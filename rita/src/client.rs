@@ -70,6 +70,9 @@ use actix_web::http::Method;
 use actix_web::*;
 
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use babel_monitor::BabelPool;
 
 #[cfg(test)]
 use std::sync::Mutex;
@@ -160,6 +163,17 @@ lazy_static! {
         { Arc::new(RwLock::new(RitaSettingsStruct::default())) };
 }
 
+lazy_static! {
+    /// Connection to babeld shared by everything that queries it, a command that takes longer
+    /// than the timeout fails rather than holding up the actor waiting on it
+    pub static ref BABEL: BabelPool = BabelPool::new(
+        format!("[::1]:{}", SETTING.get_network().babel_port)
+            .parse()
+            .unwrap(),
+        Duration::from_secs(5),
+    );
+}
+
 fn main() {
     // On Linux static builds we need to probe ssl certs path to be able to
     // do TLS stuff.
@@ -194,13 +208,17 @@ fn main() {
 
     let system = actix::System::new(format!("main {:?}", SETTING.get_network().mesh_ip));
 
+    assert!(rita_common::babel_watcher::BabelWatcher::from_registry().connected());
     assert!(rita_common::debt_keeper::DebtKeeper::from_registry().connected());
     assert!(rita_common::payment_controller::PaymentController::from_registry().connected());
     assert!(rita_common::tunnel_manager::TunnelManager::from_registry().connected());
     assert!(rita_common::http_client::HTTPClient::from_registry().connected());
     assert!(rita_common::traffic_watcher::TrafficWatcher::from_registry().connected());
+    assert!(rita_common::traffic_reconciler::TrafficReconciler::from_registry().connected());
+    assert!(rita_common::route_history::RouteHistory::from_registry().connected());
     assert!(rita_common::peer_listener::PeerListener::from_registry().connected());
     assert!(rita_client::exit_manager::ExitManager::from_registry().connected());
+    assert!(rita_client::budget::BudgetKeeper::from_registry().connected());
 
     // rita
     server::new(|| App::new().resource("/hello", |r| r.method(Method::POST).with(hello_response)))
@@ -210,9 +228,14 @@ fn main() {
         .shutdown_timeout(0)
         .start();
     server::new(|| {
-        App::new().resource("/make_payment", |r| {
-            r.method(Method::POST).with(make_payments)
-        })
+        App::new()
+            .resource("/make_payment", |r| {
+                r.method(Method::POST).with(make_payments)
+            }).resource("/channel_update", |r| {
+                r.method(Method::POST).with(channel_update)
+            }).resource("/traffic_report", |r| {
+                r.method(Method::POST).with(traffic_report)
+            })
     })
     .workers(1)
     .bind(format!("[::0]:{}", SETTING.get_network().rita_contact_port))
@@ -224,6 +247,8 @@ fn main() {
     server::new(|| {
         App::new()
             .middleware(middleware::Headers)
+            .route("/budget", Method::GET, get_budget)
+            .route("/budget", Method::POST, set_budget)
             .route("/dao_list", Method::GET, get_dao_list)
             .route("/dao_list/add/{address}", Method::POST, add_to_dao_list)
             .route(
@@ -232,6 +257,22 @@ fn main() {
                 remove_from_dao_list,
             )
             .route("/debts", Method::GET, get_debts)
+            .route("/debts/history", Method::GET, get_debts_history)
+            .route("/debts/adjustments", Method::GET, get_debt_adjustments)
+            .route("/debts/{address}/forgive", Method::POST, forgive_debt)
+            .route("/debts/{address}/adjust", Method::POST, adjust_debt)
+            .route("/debts/{address}/reset", Method::POST, reset_debt)
+            .route("/payment_policy", Method::GET, get_payment_policies)
+            .route("/payment_policy/{address}", Method::POST, set_payment_policy)
+            .route(
+                "/payment_policy/{address}",
+                Method::DELETE,
+                remove_payment_policy,
+            )
+            .route("/traffic_reconciliation", Method::GET, get_traffic_reconciliation)
+            .route("/accounting_gaps", Method::GET, get_accounting_gaps)
+            .route("/routes/history", Method::GET, get_route_history)
+            .route("/routes/history/{ip}", Method::GET, get_destination_history)
             .route("/exits/sync", Method::GET, exits_sync)
             .route("/exits", Method::GET, get_exit_info)
             .route("/exits", Method::POST, add_exits)
@@ -92,6 +92,9 @@ use rita_common::network_endpoints::*;
 use rita_exit::network_endpoints::*;
 
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use babel_monitor::BabelPool;
 
 #[cfg(test)]
 use std::sync::Mutex;
@@ -164,6 +167,17 @@ lazy_static! {
         { Arc::new(RwLock::new(RitaExitSettingsStruct::default())) };
 }
 
+lazy_static! {
+    /// Connection to babeld shared by everything that queries it, a command that takes longer
+    /// than the timeout fails rather than holding up the actor waiting on it
+    pub static ref BABEL: BabelPool = BabelPool::new(
+        format!("[::1]:{}", SETTING.get_network().babel_port)
+            .parse()
+            .unwrap(),
+        Duration::from_secs(5),
+    );
+}
+
 fn main() {
     // On Linux static builds we need to probe ssl certs path to be able to
     // do TLS stuff.
@@ -195,11 +209,14 @@ fn main() {
 
     let system = actix::System::new(format!("main {:?}", SETTING.get_network().mesh_ip));
 
+    assert!(rita_common::babel_watcher::BabelWatcher::from_registry().connected());
     assert!(rita_common::debt_keeper::DebtKeeper::from_registry().connected());
     assert!(rita_common::payment_controller::PaymentController::from_registry().connected());
     assert!(rita_common::tunnel_manager::TunnelManager::from_registry().connected());
     assert!(rita_common::http_client::HTTPClient::from_registry().connected());
     assert!(rita_common::traffic_watcher::TrafficWatcher::from_registry().connected());
+    assert!(rita_common::traffic_reconciler::TrafficReconciler::from_registry().connected());
+    assert!(rita_common::route_history::RouteHistory::from_registry().connected());
     assert!(rita_common::peer_listener::PeerListener::from_registry().connected());
 
     assert!(rita_exit::traffic_watcher::TrafficWatcher::from_registry().connected());
@@ -211,9 +228,14 @@ fn main() {
         .shutdown_timeout(0)
         .start();
     server::new(|| {
-        App::new().resource("/make_payment", |r| {
-            r.method(Method::POST).with(make_payments)
-        })
+        App::new()
+            .resource("/make_payment", |r| {
+                r.method(Method::POST).with(make_payments)
+            }).resource("/channel_update", |r| {
+                r.method(Method::POST).with(channel_update)
+            }).resource("/traffic_report", |r| {
+                r.method(Method::POST).with(traffic_report)
+            })
     }).workers(1)
     .bind(format!("[::0]:{}", SETTING.get_network().rita_contact_port))
     .unwrap()
@@ -255,6 +277,22 @@ fn main() {
             .route("/wipe", Method::POST, wipe)
             .route("/database", Method::DELETE, nuke_db)
             .route("/debts", Method::GET, get_debts)
+            .route("/debts/history", Method::GET, get_debts_history)
+            .route("/debts/adjustments", Method::GET, get_debt_adjustments)
+            .route("/debts/{address}/forgive", Method::POST, forgive_debt)
+            .route("/debts/{address}/adjust", Method::POST, adjust_debt)
+            .route("/debts/{address}/reset", Method::POST, reset_debt)
+            .route("/payment_policy", Method::GET, get_payment_policies)
+            .route("/payment_policy/{address}", Method::POST, set_payment_policy)
+            .route(
+                "/payment_policy/{address}",
+                Method::DELETE,
+                remove_payment_policy,
+            )
+            .route("/traffic_reconciliation", Method::GET, get_traffic_reconciliation)
+            .route("/accounting_gaps", Method::GET, get_accounting_gaps)
+            .route("/routes/history", Method::GET, get_route_history)
+            .route("/routes/history/{ip}", Method::GET, get_destination_history)
             .route("/dao_list", Method::GET, get_dao_list)
             .route("/dao_list/add/{address}", Method::POST, add_to_dao_list)
             .route(
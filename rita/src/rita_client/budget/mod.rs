@@ -0,0 +1,546 @@
+//! Keeps a client's spending within the daily and monthly caps the household has set. Spending is
+//! followed from two sides, what the traffic watcher bills us for the exit and the route to it, and
+//! what the debt keeper actually pays out. Bills are paid some time after they're run up so the two
+//! aren't added together, a period has cost us whichever of the two is larger.
+//!
+//! Crossing one of the `warn_at` percentages of a cap raises a warning, once per period, and with
+//! `cutoff` enabled LAN traffic stops being routed over `wg_exit` until the period is over or the
+//! cap is raised.
+
+use actix::prelude::*;
+
+use failure::Error;
+
+use num256::{Int256, Uint256};
+
+use std::time::Duration;
+
+use settings::{BudgetSettings, RitaClientSettings};
+use KI;
+use SETTING;
+
+use rita_client::rita_loop::Tick;
+use rita_common::debt_keeper::{unix_now, DebtKeeper, GetDebtsList};
+use rita_common::storage;
+
+/// Version of the on disk budget format, bump this whenever `BudgetState` changes in a way older
+/// files can't be read as
+const BUDGET_FILE_VERSION: u32 = 1;
+
+/// How often the spending so far is written out to `budget_file`
+const BUDGET_SAVE_INTERVAL: u64 = 300;
+
+const DAY_SECS: u64 = 86400;
+
+/// Unix time of the start of the UTC day `now` falls into
+fn day_start(now: u64) -> u64 {
+    now - now % DAY_SECS
+}
+
+/// Unix time of the start of the UTC calendar month `now` falls into
+fn month_start(now: u64) -> u64 {
+    // Day of the month from the days since the epoch, see Howard Hinnant's `civil_from_days`
+    let days = now / DAY_SECS + 719468;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * shifted_month + 2) / 5;
+
+    day_start(now) - day_of_month * DAY_SECS
+}
+
+/// What was spent in one day or month
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PeriodSpending {
+    /// Unix time the period started at
+    pub start: u64,
+    /// What the traffic watcher charged us for the exit and the route to it
+    pub billed: Uint256,
+    /// What the debt keeper paid out to the exit and neighbors
+    pub paid: Uint256,
+    /// The `warn_at` percentages that have already been warned about
+    pub warned: Vec<u8>,
+}
+
+impl PeriodSpending {
+    fn new(start: u64) -> PeriodSpending {
+        PeriodSpending {
+            start,
+            billed: Uint256::from(0u32),
+            paid: Uint256::from(0u32),
+            warned: Vec::new(),
+        }
+    }
+
+    /// What the period has cost us so far
+    pub fn spent(&self) -> Uint256 {
+        if self.paid > self.billed {
+            self.paid.clone()
+        } else {
+            self.billed.clone()
+        }
+    }
+
+    /// Whether at least `percent` of `cap` has been spent
+    fn reached(&self, cap: &Int256, percent: u8) -> bool {
+        Int256::from(self.spent()) * Int256::from(100) >= cap.clone() * Int256::from(percent as u64)
+    }
+
+    /// Remembers every threshold in `warn_at` that was reached for the first time this period and
+    /// returns them
+    fn check_warnings(&mut self, cap: &Int256, warn_at: &[u8]) -> Vec<u8> {
+        let mut new_warnings = Vec::new();
+        for percent in warn_at {
+            if !self.warned.contains(percent) && self.reached(cap, *percent) {
+                self.warned.push(*percent);
+                new_warnings.push(*percent);
+            }
+        }
+        new_warnings.sort();
+        new_warnings
+    }
+}
+
+/// A warning threshold that was crossed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BudgetWarning {
+    Daily(u8),
+    Monthly(u8),
+}
+
+/// The spending of the current periods as saved to `budget_file`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct BudgetState {
+    version: u32,
+    daily: PeriodSpending,
+    monthly: PeriodSpending,
+    /// The debt keeper's total of payments sent as of the last look, `None` before the first one
+    payments_seen: Option<Uint256>,
+}
+
+impl BudgetState {
+    fn new(now: u64) -> BudgetState {
+        BudgetState {
+            version: BUDGET_FILE_VERSION,
+            daily: PeriodSpending::new(day_start(now)),
+            monthly: PeriodSpending::new(month_start(now)),
+            payments_seen: None,
+        }
+    }
+
+    /// Starts over any period that ended before `now`
+    fn roll(&mut self, now: u64) {
+        if self.daily.start != day_start(now) {
+            self.daily = PeriodSpending::new(day_start(now));
+        }
+        if self.monthly.start != month_start(now) {
+            self.monthly = PeriodSpending::new(month_start(now));
+        }
+    }
+
+    fn billed(&mut self, now: u64, amount: Uint256) {
+        self.roll(now);
+        self.daily.billed = self.daily.billed.clone() + amount.clone();
+        self.monthly.billed = self.monthly.billed.clone() + amount;
+    }
+
+    /// Takes the debt keeper's running total of payments sent and counts whatever was paid since
+    /// the last look. The total shrinks when a payment fails and is put back onto the debt, that's
+    /// taken back out of the periods again.
+    fn payments_total(&mut self, now: u64, total: Uint256) {
+        self.roll(now);
+        let last = match self.payments_seen.clone() {
+            Some(last) => last,
+            None => {
+                // whatever was paid before we started watching isn't part of any budget
+                self.payments_seen = Some(total);
+                return;
+            }
+        };
+
+        if total >= last {
+            let amount = total.clone() - last;
+            self.daily.paid = self.daily.paid.clone() + amount.clone();
+            self.monthly.paid = self.monthly.paid.clone() + amount;
+        } else {
+            let amount = last - total.clone();
+            for period in vec![&mut self.daily, &mut self.monthly] {
+                period.paid = if period.paid > amount {
+                    period.paid.clone() - amount.clone()
+                } else {
+                    Uint256::from(0u32)
+                };
+            }
+        }
+        self.payments_seen = Some(total);
+    }
+
+    /// Warnings for the thresholds crossed since the last check
+    fn check_warnings(&mut self, settings: &BudgetSettings) -> Vec<BudgetWarning> {
+        let mut warnings = Vec::new();
+        if let Some(ref cap) = settings.daily_cap {
+            for percent in self.daily.check_warnings(cap, &settings.warn_at) {
+                warnings.push(BudgetWarning::Daily(percent));
+            }
+        }
+        if let Some(ref cap) = settings.monthly_cap {
+            for percent in self.monthly.check_warnings(cap, &settings.warn_at) {
+                warnings.push(BudgetWarning::Monthly(percent));
+            }
+        }
+        warnings
+    }
+
+    /// Whether either period has used up its cap
+    fn over_cap(&self, settings: &BudgetSettings) -> bool {
+        let daily = match settings.daily_cap {
+            Some(ref cap) => self.daily.reached(cap, 100),
+            None => false,
+        };
+        let monthly = match settings.monthly_cap {
+            Some(ref cap) => self.monthly.reached(cap, 100),
+            None => false,
+        };
+        daily || monthly
+    }
+}
+
+pub struct BudgetKeeper {
+    state: BudgetState,
+    /// Whether LAN traffic to the exit is currently blocked
+    cut_off: bool,
+}
+
+impl Actor for BudgetKeeper {
+    type Context = Context<Self>;
+}
+
+impl Supervised for BudgetKeeper {}
+impl SystemService for BudgetKeeper {
+    fn service_started(&mut self, ctx: &mut Context<Self>) {
+        if let Err(e) = self.load() {
+            error!("Failed to restore budget, starting empty: {:?}", e);
+        }
+
+        // a cutoff left behind by a previous run is put back in place by the next tick if the cap
+        // is still exceeded
+        for nic in SETTING.get_exit_client().lan_nics.iter() {
+            if let Err(e) = KI.unblock_client_exit_traffic(nic) {
+                error!("Failed to clear budget cutoff for {}: {:?}", nic, e);
+            }
+        }
+
+        ctx.run_interval(Duration::from_secs(BUDGET_SAVE_INTERVAL), |act, _ctx| {
+            if let Err(e) = act.save() {
+                error!("Failed to save budget: {:?}", e);
+            }
+        });
+
+        info!("Budget Keeper started");
+    }
+}
+
+impl Default for BudgetKeeper {
+    fn default() -> BudgetKeeper {
+        BudgetKeeper {
+            state: BudgetState::new(unix_now()),
+            cut_off: false,
+        }
+    }
+}
+
+impl BudgetKeeper {
+    /// Writes the spending of the current periods to `budget_file`
+    pub fn save(&self) -> Result<(), Error> {
+        let path = SETTING.get_budget().budget_file.clone();
+        storage::save_json(&path, &self.state)
+    }
+
+    /// Restores the spending saved in `budget_file`, periods that have ended since are dropped
+    pub fn load(&mut self) -> Result<(), Error> {
+        let path = SETTING.get_budget().budget_file.clone();
+        let state: BudgetState = match storage::load_json(&path)? {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        if state.version != BUDGET_FILE_VERSION {
+            bail!(
+                "Unsupported budget file version {}, expected {}",
+                state.version,
+                BUDGET_FILE_VERSION
+            );
+        }
+        self.state = state;
+        self.state.roll(unix_now());
+        Ok(())
+    }
+
+    /// Raises warnings and blocks or unblocks LAN traffic to the exit to match the spending so far
+    fn enforce(&mut self, now: u64) {
+        let settings = SETTING.get_budget().clone();
+        self.state.roll(now);
+
+        for warning in self.state.check_warnings(&settings) {
+            match warning {
+                BudgetWarning::Daily(percent) => warn!(
+                    "Spent {} today, that's over {}% of the daily budget",
+                    self.state.daily.spent(),
+                    percent
+                ),
+                BudgetWarning::Monthly(percent) => warn!(
+                    "Spent {} this month, that's over {}% of the monthly budget",
+                    self.state.monthly.spent(),
+                    percent
+                ),
+            }
+        }
+
+        let cut_off = settings.cutoff && self.state.over_cap(&settings);
+        if cut_off == self.cut_off {
+            return;
+        }
+        for nic in SETTING.get_exit_client().lan_nics.iter() {
+            let res = if cut_off {
+                KI.block_client_exit_traffic(nic)
+            } else {
+                KI.unblock_client_exit_traffic(nic)
+            };
+            if let Err(e) = res {
+                error!("Failed to change budget cutoff for {}: {:?}", nic, e);
+                return;
+            }
+        }
+        if cut_off {
+            warn!("Budget exhausted, no longer routing LAN traffic to the exit");
+        } else {
+            info!("Routing LAN traffic to the exit again");
+        }
+        self.cut_off = cut_off;
+    }
+}
+
+/// The traffic watcher charged us this much for a round of exit traffic
+pub struct Billed {
+    pub amount: Int256,
+}
+
+impl Message for Billed {
+    type Result = ();
+}
+
+impl Handler<Billed> for BudgetKeeper {
+    type Result = ();
+
+    fn handle(&mut self, msg: Billed, _: &mut Context<Self>) -> Self::Result {
+        if msg.amount > Int256::from(0) {
+            let now = unix_now();
+            self.state.billed(now, Uint256::from(msg.amount));
+            self.enforce(now);
+        }
+    }
+}
+
+/// Catches up with the payments the debt keeper made since the last tick
+impl Handler<Tick> for BudgetKeeper {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: Tick, ctx: &mut Context<Self>) -> Self::Result {
+        ctx.spawn(
+            DebtKeeper::from_registry()
+                .send(GetDebtsList)
+                .into_actor(self)
+                .then(|res, act, _ctx| {
+                    let now = unix_now();
+                    match res {
+                        Ok(Ok(debts)) => {
+                            let total = debts.iter().fold(Uint256::from(0u32), |total, debt| {
+                                total + debt.payment_details.total_payment_sent.clone()
+                            });
+                            act.state.payments_total(now, total);
+                        }
+                        Ok(Err(e)) => warn!("Failed to get debts for the budget: {:?}", e),
+                        Err(e) => warn!("Failed to get debts for the budget: {:?}", e),
+                    }
+                    act.enforce(now);
+                    actix::fut::ok(())
+                }),
+        );
+        Ok(())
+    }
+}
+
+/// One period as shown on the dashboard
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct PeriodStatus {
+    pub start: u64,
+    pub cap: Option<Int256>,
+    pub billed: Uint256,
+    pub paid: Uint256,
+    pub spent: Uint256,
+    /// How much of the cap has been spent, in percent
+    pub used_percent: Option<Int256>,
+    pub warned: Vec<u8>,
+}
+
+impl PeriodStatus {
+    fn new(period: &PeriodSpending, cap: &Option<Int256>) -> PeriodStatus {
+        let used_percent = match *cap {
+            Some(ref cap) if *cap > Int256::from(0) => {
+                Some(Int256::from(period.spent()) * Int256::from(100) / cap.clone())
+            }
+            _ => None,
+        };
+        PeriodStatus {
+            start: period.start,
+            cap: cap.clone(),
+            billed: period.billed.clone(),
+            paid: period.paid.clone(),
+            spent: period.spent(),
+            used_percent,
+            warned: period.warned.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct BudgetStatus {
+    pub daily: PeriodStatus,
+    pub monthly: PeriodStatus,
+    /// Whether LAN traffic to the exit is blocked because a cap was hit
+    pub cut_off: bool,
+    pub settings: BudgetSettings,
+}
+
+pub struct GetBudget;
+
+impl Message for GetBudget {
+    type Result = Result<BudgetStatus, Error>;
+}
+
+impl Handler<GetBudget> for BudgetKeeper {
+    type Result = Result<BudgetStatus, Error>;
+
+    fn handle(&mut self, _msg: GetBudget, _ctx: &mut Context<Self>) -> Self::Result {
+        let settings = SETTING.get_budget().clone();
+        self.state.roll(unix_now());
+        Ok(BudgetStatus {
+            daily: PeriodStatus::new(&self.state.daily, &settings.daily_cap),
+            monthly: PeriodStatus::new(&self.state.monthly, &settings.monthly_cap),
+            cut_off: self.cut_off,
+            settings,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2018-11-26 13:20:00 UTC
+    const NOW: u64 = 1543238400;
+
+    fn get_settings() -> BudgetSettings {
+        BudgetSettings {
+            daily_cap: Some(Int256::from(1000)),
+            monthly_cap: Some(Int256::from(20000)),
+            warn_at: vec![50, 80],
+            cutoff: true,
+            ..BudgetSettings::default()
+        }
+    }
+
+    #[test]
+    fn test_period_starts() {
+        // 2018-11-26 00:00:00 UTC
+        assert_eq!(day_start(NOW), 1543190400);
+        // 2018-11-01 00:00:00 UTC
+        assert_eq!(month_start(NOW), 1541030400);
+        // 2016-02-29 23:59:59 UTC falls into February of a leap year
+        assert_eq!(month_start(1456790399), 1454284800);
+        // 2016-03-01 00:00:00 UTC starts March
+        assert_eq!(month_start(1456790400), 1456790400);
+        assert_eq!(month_start(0), 0);
+    }
+
+    #[test]
+    fn test_billed_and_paid_not_added() {
+        let mut state = BudgetState::new(NOW);
+        state.payments_total(NOW, Uint256::from(5000u32));
+        state.billed(NOW, Uint256::from(300u32));
+        state.billed(NOW + 5, Uint256::from(200u32));
+        assert_eq!(state.daily.spent(), Uint256::from(500u32));
+
+        // the bill gets paid, nothing more was spent
+        state.payments_total(NOW + 10, Uint256::from(5500u32));
+        assert_eq!(state.daily.spent(), Uint256::from(500u32));
+        assert_eq!(state.monthly.spent(), Uint256::from(500u32));
+
+        // a payment failed and went back onto the debt
+        state.payments_total(NOW + 15, Uint256::from(5300u32));
+        assert_eq!(state.daily.paid, Uint256::from(300u32));
+        assert_eq!(state.daily.spent(), Uint256::from(500u32));
+    }
+
+    #[test]
+    fn test_periods_roll_over() {
+        let mut state = BudgetState::new(NOW);
+        state.billed(NOW, Uint256::from(300u32));
+        state.billed(NOW + DAY_SECS, Uint256::from(100u32));
+        assert_eq!(state.daily.spent(), Uint256::from(100u32));
+        assert_eq!(state.monthly.spent(), Uint256::from(400u32));
+
+        // 2018-12-01 00:00:00 UTC
+        state.billed(1543622400, Uint256::from(50u32));
+        assert_eq!(state.monthly.start, 1543622400);
+        assert_eq!(state.monthly.spent(), Uint256::from(50u32));
+    }
+
+    #[test]
+    fn test_warnings_once_per_period() {
+        let settings = get_settings();
+        let mut state = BudgetState::new(NOW);
+
+        state.billed(NOW, Uint256::from(499u32));
+        assert!(state.check_warnings(&settings).is_empty());
+
+        state.billed(NOW, Uint256::from(400u32));
+        assert_eq!(
+            state.check_warnings(&settings),
+            vec![BudgetWarning::Daily(50), BudgetWarning::Daily(80)]
+        );
+        assert!(state.check_warnings(&settings).is_empty());
+
+        state.billed(NOW + DAY_SECS, Uint256::from(500u32));
+        assert_eq!(
+            state.check_warnings(&settings),
+            vec![BudgetWarning::Daily(50)]
+        );
+    }
+
+    #[test]
+    fn test_over_cap() {
+        let mut settings = get_settings();
+        let mut state = BudgetState::new(NOW);
+
+        state.billed(NOW, Uint256::from(999u32));
+        assert!(!state.over_cap(&settings));
+        state.billed(NOW, Uint256::from(1u32));
+        assert!(state.over_cap(&settings));
+
+        settings.daily_cap = None;
+        assert!(!state.over_cap(&settings));
+        settings.monthly_cap = Some(Int256::from(1000));
+        assert!(state.over_cap(&settings));
+    }
+
+    #[test]
+    fn test_first_payments_total_is_baseline() {
+        let mut state = BudgetState::new(NOW);
+        state.payments_total(NOW, Uint256::from(100000u32));
+        assert_eq!(state.daily.paid, Uint256::from(0u32));
+        state.payments_total(NOW, Uint256::from(100010u32));
+        assert_eq!(state.daily.paid, Uint256::from(10u32));
+    }
+}
@@ -4,13 +4,15 @@ The Exit info endpoint gathers infromation about exit status and presents it to
 
 use actix::prelude::*;
 use failure::Error;
-use std::net::{SocketAddr, TcpStream};
+use futures::Future;
+use std::collections::VecDeque;
 
-use babel_monitor::Babel;
+use babel_monitor::{do_we_have_route, Route};
+use rita_common::babel_watcher::table_or_dump;
 use rita_common::dashboard::Dashboard;
 use settings::ExitServer;
 use settings::RitaClientSettings;
-use settings::RitaCommonSettings;
+use BABEL;
 use KI;
 use SETTING;
 
@@ -54,46 +56,46 @@ fn is_tunnel_working(exit: &ExitServer, current_exit: Option<&ExitServer>) -> bo
 }
 
 impl Handler<GetExitInfo> for Dashboard {
-    type Result = Result<Vec<ExitInfo>, Error>;
+    type Result = ResponseFuture<Vec<ExitInfo>, Error>;
 
     fn handle(&mut self, _msg: GetExitInfo, _ctx: &mut Self::Context) -> Self::Result {
-        let stream = TcpStream::connect::<SocketAddr>(
-            format!("[::1]:{}", SETTING.get_network().babel_port).parse()?,
-        )?;
-        let mut babel = Babel::new(stream);
-        babel.start_connection()?;
-        let route_table_sample = babel.parse_routes()?;
-
-        let mut output = Vec::new();
+        Box::new(
+            table_or_dump(|table| Some(table.routes()), || BABEL.parse_routes())
+                .and_then(|route_table_sample| exit_info(&route_table_sample)),
+        )
+    }
+}
 
-        let exit_client = SETTING.get_exit_client();
-        let current_exit = exit_client.get_current_exit();
+fn exit_info(route_table_sample: &VecDeque<Route>) -> Result<Vec<ExitInfo>, Error> {
+    let mut output = Vec::new();
 
-        for exit in exit_client.exits.clone().into_iter() {
-            let selected = is_selected(&exit.1, current_exit);
-            let have_route = babel.do_we_have_route(&exit.1.id.mesh_ip, &route_table_sample)?;
+    let exit_client = SETTING.get_exit_client();
+    let current_exit = exit_client.get_current_exit();
 
-            // failed pings block for one second, so we should be sure it's at least reasonable
-            // to expect the pings to work before issuing them.
-            let reachable = match have_route {
-                true => KI.ping_check_v6(&exit.1.id.mesh_ip)?,
-                false => false,
-            };
-            let tunnel_working = match (have_route, selected) {
-                (true, true) => is_tunnel_working(&exit.1, current_exit),
-                _ => false,
-            };
+    for exit in exit_client.exits.clone().into_iter() {
+        let selected = is_selected(&exit.1, current_exit);
+        let have_route = do_we_have_route(&exit.1.id.mesh_ip, route_table_sample)?;
 
-            output.push(ExitInfo {
-                nickname: exit.0,
-                exit_settings: exit.1.clone(),
-                is_selected: selected,
-                have_route: have_route,
-                is_reachable: reachable,
-                is_tunnel_working: tunnel_working,
-            })
-        }
+        // failed pings block for one second, so we should be sure it's at least reasonable
+        // to expect the pings to work before issuing them.
+        let reachable = match have_route {
+            true => KI.ping_check_v6(&exit.1.id.mesh_ip)?,
+            false => false,
+        };
+        let tunnel_working = match (have_route, selected) {
+            (true, true) => is_tunnel_working(&exit.1, current_exit),
+            _ => false,
+        };
 
-        Ok(output)
+        output.push(ExitInfo {
+            nickname: exit.0,
+            exit_settings: exit.1.clone(),
+            is_selected: selected,
+            have_route: have_route,
+            is_reachable: reachable,
+            is_tunnel_working: tunnel_working,
+        })
     }
+
+    Ok(output)
 }
@@ -9,13 +9,16 @@ use log::LevelFilter;
 use reqwest;
 
 use althea_types::ExitState;
+use num256::Int256;
+use rita_client::budget::{BudgetKeeper, BudgetStatus, GetBudget};
 use rita_client::dashboard::exitinfo::{ExitInfo, GetExitInfo};
 use rita_client::dashboard::interfaces::{GetInterfaces, InterfaceMode, InterfaceToSet};
 use rita_client::dashboard::nodeinfo::{GetNodeInfo, NodeInfo};
 use rita_client::dashboard::wifi::{GetWifiConfig, WifiInterface, WifiPass, WifiSSID};
 use rita_client::exit_manager::exit_setup_request;
+use rita_client::rita_loop::Tick;
 use rita_common::dashboard::Dashboard;
-use settings::{ExitServer, RitaClientSettings, RitaCommonSettings};
+use settings::{BudgetSettings, ExitServer, RitaClientSettings, RitaCommonSettings};
 use KI;
 use SETTING;
 
@@ -383,6 +386,48 @@ pub fn add_exits(
     Box::new(future::ok(HttpResponse::Ok().json(exits.clone())))
 }
 
+pub fn get_budget(_req: HttpRequest) -> Box<Future<Item = Json<BudgetStatus>, Error = Error>> {
+    debug!("/budget GET hit");
+    BudgetKeeper::from_registry()
+        .send(GetBudget {})
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+/// Replaces the caps, warning thresholds and cutoff mode, where the budget is saved can't be
+/// changed from here
+pub fn set_budget(
+    new_budget: Json<BudgetSettings>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    debug!("/budget POST hit with {:?}", new_budget);
+    let mut new_budget = new_budget.into_inner();
+
+    let negative = |cap: &Option<Int256>| match *cap {
+        Some(ref cap) => *cap < Int256::from(0),
+        None => false,
+    };
+    if negative(&new_budget.daily_cap) || negative(&new_budget.monthly_cap) {
+        let mut ret = HashMap::new();
+        ret.insert("error".to_owned(), "Caps can't be negative".to_owned());
+        return Box::new(future::ok(
+            HttpResponse::new(StatusCode::BAD_REQUEST)
+                .into_builder()
+                .json(ret),
+        ));
+    }
+
+    {
+        let mut budget = SETTING.get_budget_mut();
+        new_budget.budget_file = budget.budget_file.clone();
+        *budget = new_budget.clone();
+    }
+    // a raised cap lifts the cutoff right away instead of on the next tick
+    BudgetKeeper::from_registry().do_send(Tick {});
+
+    Box::new(future::ok(HttpResponse::Ok().json(new_budget)))
+}
+
 pub fn exits_sync(
     list_url_json: Json<HashMap<String, String>>,
 ) -> Box<Future<Item = HttpResponse, Error = Error>> {
@@ -1,3 +1,4 @@
+pub mod budget;
 pub mod dashboard;
 pub mod exit_manager;
 pub mod rita_loop;
@@ -9,6 +9,7 @@ use std::time::{Duration, Instant};
 use actix::prelude::*;
 use actix::registry::SystemService;
 
+use rita_client::budget::BudgetKeeper;
 use rita_client::exit_manager::ExitManager;
 
 use failure::Error;
@@ -48,6 +49,8 @@ impl Handler<Tick> for RitaLoop {
                 }),
         );
 
+        BudgetKeeper::from_registry().do_send(Tick {});
+
         info!(
             "Rita Client loop completed in {}s {}ms",
             start.elapsed().as_secs(),
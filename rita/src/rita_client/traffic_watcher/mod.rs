@@ -8,19 +8,23 @@
 
 use actix::prelude::*;
 use failure::Error;
+use futures::Future;
 use ipnetwork::IpNetwork;
 use reqwest;
 
-use std::io::{Read, Write};
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::collections::VecDeque;
+use std::net::IpAddr;
 use std::time::{Duration, SystemTime};
 
 use althea_types::{Identity, RTTimestamps};
-use babel_monitor::Babel;
+use babel_monitor::{Neighbor as BabelNeighbor, Route};
 use num256::Int256;
+use rita_client::budget::{Billed, BudgetKeeper};
+use rita_common::babel_watcher::table_or_dump;
 use rita_common::debt_keeper::{DebtKeeper, TrafficUpdate};
 use rita_common::tunnel_manager::Neighbor;
 use settings::{RitaClientSettings, RitaCommonSettings};
+use BABEL;
 use KI;
 use SETTING;
 
@@ -60,38 +64,50 @@ impl Message for Watch {
 }
 
 impl Handler<Watch> for TrafficWatcher {
-    type Result = Result<(), Error>;
+    type Result = ResponseActFuture<Self, (), Error>;
 
     fn handle(&mut self, msg: Watch, _: &mut Context<Self>) -> Self::Result {
-        let stream = TcpStream::connect::<SocketAddr>(
-            format!("[::1]:{}", SETTING.get_network().babel_port).parse()?,
-        )?;
-
-        watch(
-            self,
-            Babel::new(stream),
-            msg.exit_id,
-            msg.exit_price,
-            msg.neighbors,
+        let babel_state = table_or_dump(
+            |table| Some((table.routes(), table.neighs())),
+            || {
+                BABEL.run(|babel| {
+                    babel.parse_routes().and_then(|(babel, routes)| {
+                        babel
+                            .parse_neighs()
+                            .map(|(babel, babel_neighs)| (babel, (routes, babel_neighs)))
+                    })
+                })
+            },
+        );
+
+        Box::new(
+            babel_state
+                .into_actor(self)
+                .and_then(move |(routes, babel_neighs), act, _ctx| {
+                    actix::fut::result(watch(
+                        act,
+                        &routes,
+                        &babel_neighs,
+                        msg.exit_id,
+                        msg.exit_price,
+                        msg.neighbors,
+                    ))
+                }),
         )
     }
 }
 
 /// This traffic watcher watches how much traffic we send to the exit, and how much the exit sends
 /// back to us.
-pub fn watch<T: Read + Write>(
+pub fn watch(
     history: &mut TrafficWatcher,
-    mut babel: Babel<T>,
+    routes: &VecDeque<Route>,
+    babel_neighs: &VecDeque<BabelNeighbor>,
     exit: Identity,
     exit_price: u64,
     neighbors: Vec<Neighbor>,
 ) -> Result<(), Error> {
-    babel.start_connection()?;
-
-    trace!("Getting routes");
-    let routes = babel.parse_routes()?;
     trace!("Got routes: {:?}", routes);
-    let babel_neighs = babel.parse_neighs()?;
     trace!("Got neighs: {:?}", babel_neighs);
 
     let mut exit_route = None;
@@ -240,6 +256,10 @@ pub fn watch<T: Read + Write>(
 
     info!("Total client debt of {} this round", owes_exit);
 
+    BudgetKeeper::from_registry().do_send(Billed {
+        amount: owes_exit.clone() + owes_neighbor.clone(),
+    });
+
     let exit_update = TrafficUpdate {
         from: exit.clone(),
         amount: owes_exit,
@@ -261,7 +281,9 @@ mod tests {
 
     use super::*;
     use althea_types::WgKey;
+    use babel_monitor::Babel;
     use clarity::Address;
+    use std::net::{SocketAddr, TcpStream};
     use std::str::FromStr;
 
     #[test]
@@ -269,12 +291,17 @@ mod tests {
     fn debug_babel_socket_client() {
         env_logger::init();
         let bm_stream = TcpStream::connect::<SocketAddr>("[::1]:9001".parse().unwrap()).unwrap();
+        let mut babel = Babel::new(bm_stream);
+        babel.start_connection().unwrap();
+        let routes = babel.parse_routes().unwrap();
+        let babel_neighs = babel.parse_neighs().unwrap();
         watch(
             &mut TrafficWatcher {
                 last_read_input: 0u64,
                 last_read_output: 0u64,
             },
-            Babel::new(bm_stream),
+            &routes,
+            &babel_neighs,
             Identity::new(
                 "0.0.0.0".parse().unwrap(),
                 Address::from_str("abababababababababab").unwrap(),
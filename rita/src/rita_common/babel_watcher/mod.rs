@@ -0,0 +1,322 @@
+//! The babel watcher holds a monitoring connection open to babeld and keeps a copy of its route
+//! and neighbor table current from the events babeld sends, so actors that need the routes can
+//! ask for them rather than each dumping and reparsing babel's whole table. Actors that care
+//! about individual changes can subscribe and are sent every event as it arrives.
+//!
+//! If the connection drops the table is discarded until a new connection has replayed it, in
+//! the meantime `table_or_dump` goes back to asking babel directly.
+
+use actix::prelude::*;
+use actix::SendError;
+
+use babel_monitor::{BabelEvent, BabelPool, BabelTable};
+
+use failure::Error;
+
+use futures::{future, Future};
+
+use std::time::Duration;
+
+use BABEL;
+
+/// How long to wait before reconnecting after the monitoring connection is lost, in seconds
+const RECONNECT_DELAY: u64 = 5;
+
+pub struct BabelWatcher {
+    babel: BabelPool,
+    table: BabelTable,
+    /// Whether the table has been completely replayed over the current connection
+    synced: bool,
+    subscribers: Vec<Recipient<BabelUpdate>>,
+}
+
+impl Actor for BabelWatcher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.connect(ctx);
+    }
+}
+
+impl Supervised for BabelWatcher {}
+impl SystemService for BabelWatcher {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Babel watcher started");
+    }
+}
+
+impl Default for BabelWatcher {
+    fn default() -> BabelWatcher {
+        BabelWatcher::new(BABEL.clone())
+    }
+}
+
+impl BabelWatcher {
+    pub fn new(babel: BabelPool) -> BabelWatcher {
+        BabelWatcher {
+            babel,
+            table: BabelTable::new(),
+            synced: false,
+            subscribers: Vec::new(),
+        }
+    }
+
+    fn connect(&mut self, ctx: &mut Context<Self>) {
+        self.table = BabelTable::new();
+        self.synced = false;
+        ctx.add_stream(self.babel.subscribe());
+    }
+
+    /// The local fee isn't part of the monitoring output, so it's asked for once per connection
+    fn fetch_local_fee(&mut self, ctx: &mut Context<Self>) {
+        ctx.spawn(
+            self.babel
+                .get_local_fee()
+                .into_actor(self)
+                .then(|res, act, _ctx| {
+                    match res {
+                        // a fee set while we were asking takes precedence
+                        Ok(fee) => {
+                            if act.table.local_fee().is_none() {
+                                act.table.apply(&BabelEvent::LocalFee(fee))
+                            }
+                        }
+                        Err(e) => warn!("Failed to get the local fee from babel {:?}", e),
+                    }
+                    actix::fut::ok(())
+                }),
+        );
+    }
+}
+
+impl StreamHandler<BabelEvent, Error> for BabelWatcher {
+    fn handle(&mut self, event: BabelEvent, ctx: &mut Context<Self>) {
+        trace!("Got babel event {:?}", event);
+        if let BabelEvent::Synced = event {
+            info!("Babel table synced");
+            self.synced = true;
+            if self.table.local_fee().is_none() {
+                self.fetch_local_fee(ctx);
+            }
+        }
+        self.table.apply(&event);
+
+        // only subscribers that have gone away are dropped, a full mailbox just misses this one
+        self.subscribers.retain(|subscriber| {
+            match subscriber.do_send(BabelUpdate(event.clone())) {
+                Err(SendError::Closed(_)) => false,
+                _ => true,
+            }
+        });
+    }
+
+    fn error(&mut self, err: Error, _ctx: &mut Context<Self>) -> Running {
+        warn!("Babel monitoring connection failed {:?}", err);
+        Running::Stop
+    }
+
+    fn finished(&mut self, ctx: &mut Context<Self>) {
+        warn!(
+            "Babel monitoring connection closed, reconnecting in {}s",
+            RECONNECT_DELAY
+        );
+        self.synced = false;
+        ctx.run_later(Duration::from_secs(RECONNECT_DELAY), |act, ctx| {
+            act.connect(ctx)
+        });
+    }
+}
+
+/// Sent to subscribers for every event babel reports
+#[derive(Clone)]
+pub struct BabelUpdate(pub BabelEvent);
+
+impl Message for BabelUpdate {
+    type Result = ();
+}
+
+pub struct Subscribe(pub Recipient<BabelUpdate>);
+
+impl Message for Subscribe {
+    type Result = ();
+}
+
+impl Handler<Subscribe> for BabelWatcher {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) -> Self::Result {
+        self.subscribers.push(msg.0);
+    }
+}
+
+/// Records a local fee babel has accepted, in case babel doesn't report the change itself
+pub struct SetLocalFee(pub u32);
+
+impl Message for SetLocalFee {
+    type Result = ();
+}
+
+impl Handler<SetLocalFee> for BabelWatcher {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetLocalFee, _: &mut Context<Self>) -> Self::Result {
+        self.table.apply(&BabelEvent::LocalFee(msg.0));
+    }
+}
+
+/// A copy of the table, an error if it isn't synced with babel at the moment
+pub struct GetBabelTable;
+
+impl Message for GetBabelTable {
+    type Result = Result<BabelTable, Error>;
+}
+
+impl Handler<GetBabelTable> for BabelWatcher {
+    type Result = Result<BabelTable, Error>;
+
+    fn handle(&mut self, _msg: GetBabelTable, _: &mut Context<Self>) -> Self::Result {
+        if !self.synced {
+            bail!("Not synced with babel");
+        }
+        Ok(self.table.clone())
+    }
+}
+
+/// Takes what's needed out of the watched table with `from_table`, or if the table isn't synced
+/// or `from_table` comes up empty asks babel for it with `dump`.
+pub fn table_or_dump<T, F, D>(from_table: F, dump: D) -> Box<Future<Item = T, Error = Error>>
+where
+    T: 'static,
+    F: FnOnce(&BabelTable) -> Option<T> + 'static,
+    D: FnOnce() -> Box<Future<Item = T, Error = Error>> + 'static,
+{
+    Box::new(
+        BabelWatcher::from_registry()
+            .send(GetBabelTable)
+            .from_err()
+            .and_then(move |table| -> Box<Future<Item = T, Error = Error>> {
+                match table.map(|table| from_table(&table)) {
+                    Ok(Some(res)) => Box::new(future::ok(res)),
+                    Ok(None) => {
+                        trace!("Babel table is missing data, dumping instead");
+                        dump()
+                    }
+                    Err(e) => {
+                        trace!("{}, dumping instead", e);
+                        dump()
+                    }
+                }
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::SystemRunner;
+    use babel_monitor::fake_babeld::{fake_neigh, fake_route, FakeBabeld};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use tokio::timer::Delay;
+
+    /// Keeps every event it's sent
+    struct Subscriber(Arc<Mutex<Vec<BabelEvent>>>);
+
+    impl Actor for Subscriber {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<BabelUpdate> for Subscriber {
+        type Result = ();
+
+        fn handle(&mut self, msg: BabelUpdate, _: &mut Context<Self>) -> Self::Result {
+            self.0.lock().unwrap().push(msg.0);
+        }
+    }
+
+    /// Polls the watcher until its table satisfies `done`
+    fn wait_for<F: Fn(&BabelTable) -> bool>(
+        system: &mut SystemRunner,
+        watcher: &Addr<BabelWatcher>,
+        done: F,
+    ) -> BabelTable {
+        for _ in 0..50 {
+            if let Ok(Ok(table)) = system.block_on(watcher.send(GetBabelTable)) {
+                if done(&table) {
+                    return table;
+                }
+            }
+            system
+                .block_on(Delay::new(Instant::now() + Duration::from_millis(100)))
+                .unwrap();
+        }
+        panic!("Babel watcher never caught up");
+    }
+
+    #[test]
+    fn test_table_follows_babeld() {
+        let babeld = FakeBabeld::new().unwrap();
+        babeld.set_local_fee(30);
+        babeld.set_route(fake_route(
+            "14f06d8",
+            "fd00::5/128".parse().unwrap(),
+            "fe80::1".parse().unwrap(),
+            100,
+        ));
+
+        let mut system = System::new("test_babel_watcher");
+        let watcher =
+            BabelWatcher::new(BabelPool::new(babeld.addr(), Duration::from_secs(5))).start();
+
+        let table = wait_for(&mut system, &watcher, |table| table.routes().len() == 1);
+        assert_eq!(table.local_fee(), Some(30));
+        assert_eq!(table.routes()[0].price, 100);
+
+        babeld.set_neigh(fake_neigh("14f05f0", "fe80::1".parse().unwrap(), "wg0"));
+        babeld.flush_route("14f06d8");
+        let table = wait_for(&mut system, &watcher, |table| table.routes().is_empty());
+        assert_eq!(table.neighs().len(), 1);
+
+        system.block_on(watcher.send(SetLocalFee(5))).unwrap();
+        let table = wait_for(&mut system, &watcher, |_| true);
+        assert_eq!(table.local_fee(), Some(5));
+    }
+    #[test]
+    fn test_subscriber_gets_events() {
+        let babeld = FakeBabeld::new().unwrap();
+        babeld.set_route(fake_route(
+            "14f06d8",
+            "fd00::5/128".parse().unwrap(),
+            "fe80::1".parse().unwrap(),
+            100,
+        ));
+
+        let mut system = System::new("test_babel_subscriber");
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = Subscriber(events.clone()).start();
+        let watcher =
+            BabelWatcher::new(BabelPool::new(babeld.addr(), Duration::from_secs(5))).start();
+        system
+            .block_on(watcher.send(Subscribe(subscriber.recipient())))
+            .unwrap();
+
+        wait_for(&mut system, &watcher, |table| table.routes().len() == 1);
+        babeld.flush_route("14f06d8");
+        wait_for(&mut system, &watcher, |table| table.routes().is_empty());
+
+        // the subscriber handles the events in its own time
+        for _ in 0..50 {
+            let flushed = events.lock().unwrap().iter().any(|event| match *event {
+                BabelEvent::RouteFlushed(ref id) => id == "14f06d8",
+                _ => false,
+            });
+            if flushed {
+                return;
+            }
+            system
+                .block_on(Delay::new(Instant::now() + Duration::from_millis(100)))
+                .unwrap();
+        }
+        panic!("Subscriber never got the flush");
+    }
+}
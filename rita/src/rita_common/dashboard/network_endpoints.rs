@@ -4,20 +4,27 @@ use actix_web::*;
 use clarity::Address;
 use failure::Error;
 use futures::{future, Future};
+use num256::{Int256, Uint256};
 use serde_json;
 
-use std::{
-    boxed::Box,
-    collections::HashMap,
-    net::{SocketAddr, TcpStream},
-};
+use std::{boxed::Box, collections::HashMap, net::IpAddr};
 
 use super::{Dashboard, GetOwnInfo, OwnInfo};
-use babel_monitor::Babel;
+use rita_common::babel_watcher::{BabelWatcher, SetLocalFee};
 use rita_common::debt_keeper::GetDebtsList;
-use rita_common::debt_keeper::{DebtKeeper, GetDebtsResult};
+use rita_common::debt_keeper::{history_to_csv, GetDebtsHistory};
+use rita_common::debt_keeper::{CorrectDebt, DebtAdjustment, DebtCorrection, GetDebtAdjustments};
+use rita_common::debt_keeper::{DebtKeeper, GetDebtsResult, PaymentPoliciesChanged};
 use rita_common::network_endpoints::JsonStatusResponse;
-use settings::RitaCommonSettings;
+use rita_common::route_history::{
+    GetDestinationHistory, GetRouteHistory, RouteHistory, RouteQuality,
+};
+use rita_common::traffic_reconciler::{
+    GetReconciliations, NeighborReconciliation, TrafficReconciler,
+};
+use rita_common::traffic_watcher::{AccountingGap, GetAccountingGaps, TrafficWatcher};
+use settings::{PaymentPolicy, RitaCommonSettings};
+use BABEL;
 use SETTING;
 
 pub fn get_own_info(_req: HttpRequest) -> Box<Future<Item = Json<OwnInfo>, Error = Error>> {
@@ -115,6 +122,203 @@ pub fn get_debts(
         .responder()
 }
 
+pub fn get_traffic_reconciliation(
+    _req: HttpRequest,
+) -> Box<Future<Item = Json<Vec<NeighborReconciliation>>, Error = Error>> {
+    trace!("get_traffic_reconciliation: Hit");
+    TrafficReconciler::from_registry()
+        .send(GetReconciliations {})
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+pub fn get_accounting_gaps(
+    _req: HttpRequest,
+) -> Box<Future<Item = Json<Vec<AccountingGap>>, Error = Error>> {
+    trace!("get_accounting_gaps: Hit");
+    TrafficWatcher::from_registry()
+        .send(GetAccountingGaps)
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+pub fn get_route_history(
+    _req: HttpRequest,
+) -> Box<Future<Item = Json<Vec<RouteQuality>>, Error = Error>> {
+    trace!("get_route_history: Hit");
+    RouteHistory::from_registry()
+        .send(GetRouteHistory)
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+pub fn get_destination_history(
+    path: Path<IpAddr>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let ip = path.into_inner();
+    trace!("get_destination_history: Hit for {}", ip);
+    RouteHistory::from_registry()
+        .send(GetDestinationHistory(ip))
+        .from_err()
+        .and_then(move |reply| match reply? {
+            Some(detail) => Ok(HttpResponse::Ok().json(detail)),
+            None => {
+                let mut ret = HashMap::new();
+                ret.insert("error".to_owned(), format!("No route history for {}", ip));
+                Ok(HttpResponse::new(StatusCode::NOT_FOUND)
+                    .into_builder()
+                    .json(ret))
+            }
+        })
+        .responder()
+}
+
+/// Body of `/debts/{eth_address}/forgive`
+#[derive(Deserialize, Debug)]
+pub struct ForgiveDebtRequest {
+    reason: String,
+    /// Forgive only this much, everything the neighbor owes us if left out
+    #[serde(default)]
+    amount: Option<Uint256>,
+}
+
+/// Body of `/debts/{eth_address}/adjust`
+#[derive(Deserialize, Debug)]
+pub struct AdjustDebtRequest {
+    reason: String,
+    /// Added to the debt, positive if we owe the neighbor more
+    amount: Int256,
+}
+
+/// Body of `/debts/{eth_address}/reset`
+#[derive(Deserialize, Debug)]
+pub struct ResetDebtRequest {
+    reason: String,
+}
+
+fn correct_debt(
+    eth_address: Address,
+    correction: DebtCorrection,
+    reason: String,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    DebtKeeper::from_registry()
+        .send(CorrectDebt {
+            eth_address,
+            correction,
+            reason,
+        })
+        .from_err()
+        .and_then(move |reply| match reply {
+            Ok(adjustment) => Ok(HttpResponse::Ok().json(adjustment)),
+            Err(e) => {
+                let mut ret = HashMap::new();
+                ret.insert("error".to_owned(), format!("{}", e));
+                Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
+                    .into_builder()
+                    .json(ret))
+            }
+        })
+        .responder()
+}
+
+pub fn forgive_debt(
+    path: Path<Address>,
+    body: Json<ForgiveDebtRequest>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let body = body.into_inner();
+    debug!("/debts/{}/forgive hit with {:?}", *path, body);
+    correct_debt(
+        path.into_inner(),
+        DebtCorrection::Forgive(body.amount),
+        body.reason,
+    )
+}
+
+pub fn adjust_debt(
+    path: Path<Address>,
+    body: Json<AdjustDebtRequest>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let body = body.into_inner();
+    debug!("/debts/{}/adjust hit with {:?}", *path, body);
+    correct_debt(
+        path.into_inner(),
+        DebtCorrection::Adjust(body.amount),
+        body.reason,
+    )
+}
+
+pub fn reset_debt(
+    path: Path<Address>,
+    body: Json<ResetDebtRequest>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let body = body.into_inner();
+    debug!("/debts/{}/reset hit with {:?}", *path, body);
+    correct_debt(path.into_inner(), DebtCorrection::Reset, body.reason)
+}
+
+pub fn get_debt_adjustments(
+    _req: HttpRequest,
+) -> Box<Future<Item = Json<Vec<DebtAdjustment>>, Error = Error>> {
+    trace!("get_debt_adjustments: Hit");
+    DebtKeeper::from_registry()
+        .send(GetDebtAdjustments {})
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DebtsHistoryQuery {
+    /// Unix time, only buckets starting at or after it are returned
+    since: Option<u64>,
+    /// Unix time, only buckets starting before it are returned
+    until: Option<u64>,
+    /// `json` (default) or `csv`
+    format: Option<String>,
+}
+
+pub fn get_debts_history(
+    query: Query<DebtsHistoryQuery>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    trace!("get_debts_history: Hit");
+    let query = query.into_inner();
+
+    let csv = match query.format.as_ref().map(|format| format.as_str()) {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(other) => {
+            let mut ret = HashMap::new();
+            ret.insert("error".to_owned(), format!("Unknown format {}", other));
+            return Box::new(future::ok(
+                HttpResponse::new(StatusCode::BAD_REQUEST)
+                    .into_builder()
+                    .json(ret),
+            ));
+        }
+    };
+
+    DebtKeeper::from_registry()
+        .send(GetDebtsHistory {
+            since: query.since.unwrap_or(0),
+            until: query.until.unwrap_or(u64::max_value()),
+        })
+        .from_err()
+        .and_then(move |reply| {
+            let histories = reply?;
+            if csv {
+                Ok(HttpResponse::Ok()
+                    .content_type("text/csv")
+                    .body(history_to_csv(&histories)))
+            } else {
+                Ok(HttpResponse::Ok().json(histories))
+            }
+        })
+        .responder()
+}
+
 pub fn get_dao_list(_req: HttpRequest) -> Result<Json<Vec<Address>>, Error> {
     trace!("get dao list: Hit");
     Ok(Json(SETTING.get_dao().dao_addresses.clone()))
@@ -150,152 +354,167 @@ pub fn remove_from_dao_list(path: Path<(Address)>) -> Result<Json<()>, Error> {
     Ok(Json(()))
 }
 
-pub fn get_local_fee(_req: HttpRequest) -> Box<Future<Item = HttpResponse, Error = Error>> {
-    debug!("/local_fee GET hit");
-    let mut ret = HashMap::new();
-    ret.insert("local_fee", SETTING.get_local_fee());
-
-    Box::new(future::ok(HttpResponse::Ok().json(ret)))
-}
-
-pub fn get_metric_factor(_req: HttpRequest) -> Box<Future<Item = HttpResponse, Error = Error>> {
-    debug!("/local_fee GET hit");
-    let mut ret = HashMap::new();
-    ret.insert("metric_factor", SETTING.get_metric_factor());
-
-    Box::new(future::ok(HttpResponse::Ok().json(ret)))
+pub fn get_payment_policies(
+    _req: HttpRequest,
+) -> Result<Json<HashMap<Address, PaymentPolicy>>, Error> {
+    debug!("/payment_policy GET hit");
+    Ok(Json(SETTING.get_payment().neighbor_policies.clone()))
 }
 
-pub fn set_local_fee(path: Path<u32>) -> Box<Future<Item = HttpResponse, Error = Error>> {
-    let new_fee = path.into_inner();
-    debug!("/local_fee/{} POST hit", new_fee);
-    let mut ret = HashMap::<String, String>::new();
-
-    let stream = match TcpStream::connect::<SocketAddr>(
-        format!("[::1]:{}", SETTING.get_network().babel_port)
-            .parse()
-            .unwrap(),
-    ) {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Failed to set local fee! {:?}", e);
-            ret.insert(
-                "error".to_owned(),
-                "Could not create a socket for connecting to Babel".to_owned(),
-            );
-            ret.insert("rust_error".to_owned(), format!("{:?}", e));
-
-            return Box::new(future::ok(
-                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                    .into_builder()
-                    .json(ret),
-            ));
+/// Rejects the same values the debt keeper refuses to start with in the global settings
+fn validate_payment_policy(policy: &PaymentPolicy) -> Result<(), String> {
+    if let Some(ref pay_threshold) = policy.pay_threshold {
+        if *pay_threshold < Int256::from(0) {
+            return Err("pay_threshold can't be negative".to_owned());
         }
-    };
-
-    let mut babel = Babel::new(stream);
-
-    if let Err(e) = babel.start_connection() {
-        error!("Failed to set local fee! {:?}", e);
-        ret.insert("error".to_owned(), "Could not connect to Babel".to_owned());
-        ret.insert("rust_error".to_owned(), format!("{:?}", e));
+    }
+    if let Some(ref close_fraction) = policy.close_fraction {
+        if *close_fraction <= Int256::from(0) {
+            return Err("close_fraction has to be positive".to_owned());
+        }
+    }
+    if policy.buffer_period == Some(0) {
+        return Err("buffer_period has to be at least 1".to_owned());
+    }
+    Ok(())
+}
 
+pub fn set_payment_policy(
+    path: Path<Address>,
+    policy: Json<PaymentPolicy>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let address = path.into_inner();
+    let policy = policy.into_inner();
+    debug!("/payment_policy/{} POST hit with {:?}", address, policy);
+
+    if let Err(e) = validate_payment_policy(&policy) {
+        let mut ret = HashMap::new();
+        ret.insert("error".to_owned(), e);
         return Box::new(future::ok(
-            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+            HttpResponse::new(StatusCode::BAD_REQUEST)
                 .into_builder()
                 .json(ret),
         ));
     }
 
-    if let Err(e) = babel.set_local_fee(new_fee) {
-        error!("Failed to set local fee! {:?}", e);
+    SETTING
+        .get_payment_mut()
+        .neighbor_policies
+        .insert(address, policy.clone());
+    DebtKeeper::from_registry().do_send(PaymentPoliciesChanged);
+
+    Box::new(future::ok(HttpResponse::Ok().json(policy)))
+}
+
+pub fn remove_payment_policy(
+    path: Path<Address>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let address = path.into_inner();
+    debug!("/payment_policy/{} DELETE hit", address);
+
+    let removed = SETTING.get_payment_mut().neighbor_policies.remove(&address);
+    if removed.is_none() {
+        let mut ret = HashMap::new();
         ret.insert(
             "error".to_owned(),
-            "Failed to ask Babel to set the proposed fee".to_owned(),
+            format!("No payment policy for {}", address),
         );
-        ret.insert("rust_error".to_owned(), format!("{:?}", e));
-
         return Box::new(future::ok(
-            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+            HttpResponse::new(StatusCode::NOT_FOUND)
                 .into_builder()
                 .json(ret),
         ));
-    };
-
-    // Set the value in settings only after Babel successfuly accepts the passed value
-    SETTING.set_local_fee(new_fee);
-
-    if new_fee == 0 {
-        warn!("THIS NODE IS GIVING BANDWIDTH AWAY FOR FREE. PLEASE SET local_fee TO A NON-ZERO VALUE TO DISABLE THIS WARNING.");
-        ret.insert("warning".to_owned(), "THIS NODE IS GIVING BANDWIDTH AWAY FOR FREE. PLEASE SET local_fee TO A NON-ZERO VALUE TO DISABLE THIS WARNING.".to_owned());
     }
+    DebtKeeper::from_registry().do_send(PaymentPoliciesChanged);
 
-    Box::new(future::ok(HttpResponse::Ok().json(ret)))
+    Box::new(future::ok(HttpResponse::Ok().json(())))
 }
 
-pub fn set_metric_factor(path: Path<u32>) -> Box<Future<Item = HttpResponse, Error = Error>> {
-    let new_factor = path.into_inner();
-    debug!("/metric_factor/{} POST hit", new_factor);
-    let mut ret = HashMap::<String, String>::new();
-
-    let stream = match TcpStream::connect::<SocketAddr>(
-        format!("[::1]:{}", SETTING.get_network().babel_port)
-            .parse()
-            .unwrap(),
-    ) {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Failed to set metric factor! {:?}", e);
-            ret.insert(
-                "error".to_owned(),
-                "Could not create a socket for connecting to Babel".to_owned(),
-            );
-            ret.insert("rust_error".to_owned(), format!("{:?}", e));
-
-            return Box::new(future::ok(
-                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                    .into_builder()
-                    .json(ret),
-            ));
-        }
-    };
-
-    let mut babel = Babel::new(stream);
+pub fn get_local_fee(_req: HttpRequest) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    debug!("/local_fee GET hit");
+    let mut ret = HashMap::new();
+    ret.insert("local_fee", SETTING.get_local_fee());
 
-    if let Err(e) = babel.start_connection() {
-        error!("Failed to set metric factor! {:?}", e);
-        ret.insert("error".to_owned(), "Could not connect to Babel".to_owned());
-        ret.insert("rust_error".to_owned(), format!("{:?}", e));
+    Box::new(future::ok(HttpResponse::Ok().json(ret)))
+}
 
-        return Box::new(future::ok(
-            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                .into_builder()
-                .json(ret),
-        ));
-    }
+pub fn get_metric_factor(_req: HttpRequest) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    debug!("/local_fee GET hit");
+    let mut ret = HashMap::new();
+    ret.insert("metric_factor", SETTING.get_metric_factor());
 
-    if let Err(e) = babel.set_metric_factor(new_factor) {
-        error!("Failed to set metric factor! {:?}", e);
-        ret.insert(
-            "error".to_owned(),
-            "Failed to ask Babel to set the proposed factor".to_owned(),
-        );
-        ret.insert("rust_error".to_owned(), format!("{:?}", e));
+    Box::new(future::ok(HttpResponse::Ok().json(ret)))
+}
 
-        return Box::new(future::ok(
-            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                .into_builder()
-                .json(ret),
-        ));
-    };
+pub fn set_local_fee(path: Path<u32>) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let new_fee = path.into_inner();
+    debug!("/local_fee/{} POST hit", new_fee);
 
-    // Set the value in settings only after Babel successfuly accepts the passed value
-    SETTING.set_metric_factor(new_factor);
+    Box::new(
+        BABEL
+            .set_local_fee(new_fee)
+            .then(move |res| -> Result<HttpResponse, Error> {
+                let mut ret = HashMap::<String, String>::new();
+
+                if let Err(e) = res {
+                    error!("Failed to set local fee! {:?}", e);
+                    ret.insert(
+                        "error".to_owned(),
+                        "Failed to ask Babel to set the proposed fee".to_owned(),
+                    );
+                    ret.insert("rust_error".to_owned(), format!("{:?}", e));
+
+                    return Ok(HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+                        .into_builder()
+                        .json(ret));
+                }
+
+                // Set the value in settings only after Babel successfuly accepts the passed value
+                SETTING.set_local_fee(new_fee);
+                BabelWatcher::from_registry().do_send(SetLocalFee(new_fee));
+
+                if new_fee == 0 {
+                    warn!("THIS NODE IS GIVING BANDWIDTH AWAY FOR FREE. PLEASE SET local_fee TO A NON-ZERO VALUE TO DISABLE THIS WARNING.");
+                    ret.insert("warning".to_owned(), "THIS NODE IS GIVING BANDWIDTH AWAY FOR FREE. PLEASE SET local_fee TO A NON-ZERO VALUE TO DISABLE THIS WARNING.".to_owned());
+                }
+
+                Ok(HttpResponse::Ok().json(ret))
+            }),
+    )
+}
 
-    if new_factor == 0 {
-        warn!("THIS NODE DOESN'T PAY ATTENTION TO ROUTE QUALITY - IT'LL CHOOSE THE CHEAPEST ROUTE EVEN IF IT'S THE WORST LINK AROUND. PLEASE SET metric_factor TO A NON-ZERO VALUE TO DISABLE THIS WARNING.");
-        ret.insert("warning".to_owned(), "THIS NODE DOESN'T PAY ATTENTION TO ROUTE QUALITY - IT'LL CHOOSE THE CHEAPEST ROUTE EVEN IF IT'S THE WORST LINK AROUND. PLEASE SET metric_factor TO A NON-ZERO VALUE TO DISABLE THIS WARNING.".to_owned());
-    }
+pub fn set_metric_factor(path: Path<u32>) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let new_factor = path.into_inner();
+    debug!("/metric_factor/{} POST hit", new_factor);
 
-    Box::new(future::ok(HttpResponse::Ok().json(ret)))
+    Box::new(
+        BABEL
+            .set_metric_factor(new_factor)
+            .then(move |res| -> Result<HttpResponse, Error> {
+                let mut ret = HashMap::<String, String>::new();
+
+                if let Err(e) = res {
+                    error!("Failed to set metric factor! {:?}", e);
+                    ret.insert(
+                        "error".to_owned(),
+                        "Failed to ask Babel to set the proposed factor".to_owned(),
+                    );
+                    ret.insert("rust_error".to_owned(), format!("{:?}", e));
+
+                    return Ok(HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+                        .into_builder()
+                        .json(ret));
+                }
+
+                // Set the value in settings only after Babel successfuly accepts the passed value
+                SETTING.set_metric_factor(new_factor);
+
+                if new_factor == 0 {
+                    warn!("THIS NODE DOESN'T PAY ATTENTION TO ROUTE QUALITY - IT'LL CHOOSE THE CHEAPEST ROUTE EVEN IF IT'S THE WORST LINK AROUND. PLEASE SET metric_factor TO A NON-ZERO VALUE TO DISABLE THIS WARNING.");
+                    ret.insert("warning".to_owned(), "THIS NODE DOESN'T PAY ATTENTION TO ROUTE QUALITY - IT'LL CHOOSE THE CHEAPEST ROUTE EVEN IF IT'S THE WORST LINK AROUND. PLEASE SET metric_factor TO A NON-ZERO VALUE TO DISABLE THIS WARNING.".to_owned());
+                }
+
+                Ok(HttpResponse::Ok().json(ret))
+            }),
+    )
 }
@@ -0,0 +1,175 @@
+//! Corrections an operator makes by hand to a neighbor's ledger, for instance forgiving what a
+//! neighbor ran up while an outage kept them from paying, or undoing the effects of a billing bug.
+//! Every correction is kept in a log along with the reason given for it.
+
+use std::collections::VecDeque;
+
+use althea_types::Identity;
+
+use num256::{Int256, Uint256};
+
+use super::NodeDebtData;
+
+/// How many corrections are kept in the log
+pub const MAX_ADJUSTMENTS: usize = 1000;
+
+/// A correction to a neighbor's ledger
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DebtCorrection {
+    /// Forgives up to this much of what the neighbor owes us, everything if `None`
+    Forgive(Option<Uint256>),
+    /// Adds this much to the debt, positive means we owe the neighbor more and negative that they
+    /// owe us more
+    Adjust(Int256),
+    /// Starts the neighbor's ledger over as if we had just met them, except for what we already
+    /// paid them since payments still on their way may yet fail and be taken back out of it
+    Reset,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AdjustmentKind {
+    Forgive,
+    Adjust,
+    Reset,
+}
+
+/// A logged correction, balances include the debt that's still in the buffer
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DebtAdjustment {
+    /// Unix time of the correction
+    pub time: u64,
+    pub identity: Identity,
+    pub kind: AdjustmentKind,
+    pub balance_before: Int256,
+    pub balance_after: Int256,
+    pub reason: String,
+}
+
+/// Everything on the neighbor's ledger, positive if we owe them and negative if they owe us
+pub fn balance(debt_data: &NodeDebtData) -> Int256 {
+    debt_data
+        .debt_buffer
+        .iter()
+        .fold(debt_data.debt.clone(), |total, traffic| {
+            total + traffic.clone()
+        })
+}
+
+/// Takes up to `amount` off a debt the neighbor owes us, `amount` is reduced by what was taken
+fn forgive_part(owed: &mut Int256, amount: &mut Option<Int256>) {
+    if *owed >= Int256::from(0) {
+        return;
+    }
+    match *amount {
+        None => *owed = Int256::from(0),
+        Some(ref mut left) => {
+            if *left >= -owed.clone() {
+                *left += owed.clone();
+                *owed = Int256::from(0);
+            } else {
+                *owed += left.clone();
+                *left = Int256::from(0);
+            }
+        }
+    }
+}
+
+impl DebtCorrection {
+    pub fn kind(&self) -> AdjustmentKind {
+        match *self {
+            DebtCorrection::Forgive(_) => AdjustmentKind::Forgive,
+            DebtCorrection::Adjust(_) => AdjustmentKind::Adjust,
+            DebtCorrection::Reset => AdjustmentKind::Reset,
+        }
+    }
+
+    /// Applies the correction to a neighbor's ledger entry. Forgiveness goes to the debt that's
+    /// already due first and then to the buffer, oldest first.
+    pub fn apply(&self, debt_data: &mut NodeDebtData, buffer_period: u32) {
+        match *self {
+            DebtCorrection::Forgive(ref amount) => {
+                let mut left = amount.clone().map(Int256::from);
+                forgive_part(&mut debt_data.debt, &mut left);
+                for traffic in debt_data.debt_buffer.iter_mut() {
+                    forgive_part(traffic, &mut left);
+                }
+            }
+            DebtCorrection::Adjust(ref amount) => debt_data.debt += amount.clone(),
+            DebtCorrection::Reset => {
+                let mut reset = NodeDebtData::new(buffer_period);
+                reset.total_payment_sent = debt_data.total_payment_sent.clone();
+                reset.payments_in_flight = debt_data.payments_in_flight.clone();
+                reset.last_payment = debt_data.last_payment;
+                *debt_data = reset;
+            }
+        }
+    }
+}
+
+/// Adds a correction to the log, dropping the oldest ones past `MAX_ADJUSTMENTS`
+pub fn log_adjustment(log: &mut VecDeque<DebtAdjustment>, adjustment: DebtAdjustment) {
+    log.push_back(adjustment);
+    while log.len() > MAX_ADJUSTMENTS {
+        log.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_debt_data(debt: i32, buffer: Vec<i32>) -> NodeDebtData {
+        let mut debt_data = NodeDebtData::new(buffer.len() as u32);
+        debt_data.debt = Int256::from(debt);
+        debt_data.debt_buffer = buffer.into_iter().map(Int256::from).collect();
+        debt_data
+    }
+
+    #[test]
+    fn test_forgive_everything() {
+        let mut debt_data = get_debt_data(-100, vec![-10, -20]);
+        DebtCorrection::Forgive(None).apply(&mut debt_data, 2);
+        assert_eq!(debt_data.debt, Int256::from(0));
+        assert_eq!(balance(&debt_data), Int256::from(0));
+    }
+
+    #[test]
+    fn test_forgive_part() {
+        let mut debt_data = get_debt_data(-100, vec![-10, -20]);
+        DebtCorrection::Forgive(Some(Uint256::from(115u32))).apply(&mut debt_data, 2);
+        assert_eq!(debt_data.debt, Int256::from(0));
+        assert_eq!(
+            debt_data.debt_buffer,
+            vec![Int256::from(0), Int256::from(-15)]
+                .into_iter()
+                .collect::<VecDeque<Int256>>()
+        );
+    }
+
+    #[test]
+    fn test_forgive_leaves_what_we_owe() {
+        let mut debt_data = get_debt_data(50, vec![-10]);
+        DebtCorrection::Forgive(None).apply(&mut debt_data, 1);
+        assert_eq!(debt_data.debt, Int256::from(50));
+        assert_eq!(balance(&debt_data), Int256::from(50));
+    }
+
+    #[test]
+    fn test_adjust_and_reset() {
+        let mut debt_data = get_debt_data(-100, vec![-10]);
+        DebtCorrection::Adjust(Int256::from(30)).apply(&mut debt_data, 1);
+        assert_eq!(debt_data.debt, Int256::from(-70));
+
+        debt_data.total_payment_received = Uint256::from(500u32);
+        debt_data.total_payment_sent = Uint256::from(300u32);
+        debt_data.payments_in_flight = Uint256::from(100u32);
+        DebtCorrection::Reset.apply(&mut debt_data, 3);
+        assert_eq!(debt_data.total_payment_received, Uint256::from(0u32));
+        assert_eq!(balance(&debt_data), Int256::from(0));
+        assert_eq!(debt_data.debt_buffer.len(), 3);
+        // payments still on their way have to be accounted for if they fail
+        assert_eq!(debt_data.total_payment_sent, Uint256::from(300u32));
+        assert_eq!(debt_data.payments_in_flight, Uint256::from(100u32));
+    }
+}
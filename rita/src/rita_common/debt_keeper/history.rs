@@ -0,0 +1,224 @@
+//! The debt keeper's running totals can't tell how much money moved in a given week, so every
+//! charge and payment is also added to an hourly bucket kept per neighbor. These buckets are what
+//! the dashboard's `/debts/history` endpoint serves for bookkeeping.
+
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use althea_types::Identity;
+
+use num256::Uint256;
+
+/// Length of a history bucket in seconds
+pub const HISTORY_BUCKET_SECS: u64 = 3600;
+
+/// How many buckets are kept per neighbor, 90 days worth of hourly buckets
+pub const HISTORY_MAX_BUCKETS: usize = 24 * 90;
+
+/// Something that moved money between us and a neighbor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedgerEvent {
+    /// The neighbor forwarded traffic for us, we owe them this much
+    ForwardedForUs,
+    /// We forwarded traffic for the neighbor, they owe us this much
+    ForwardedForThem,
+    PaymentSent,
+    PaymentReceived,
+    /// A payment we sent earlier was never delivered and went back onto the debt
+    PaymentFailed,
+}
+
+/// Everything that happened with a neighbor during one bucket
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LedgerBucket {
+    /// Unix time the bucket starts at, it covers `HISTORY_BUCKET_SECS` from there
+    pub start: u64,
+    pub forwarded_for_us: Uint256,
+    pub forwarded_for_them: Uint256,
+    pub payments_sent: Uint256,
+    pub payments_received: Uint256,
+    pub payments_failed: Uint256,
+}
+
+impl LedgerBucket {
+    fn new(start: u64) -> LedgerBucket {
+        LedgerBucket {
+            start,
+            forwarded_for_us: Uint256::from(0u32),
+            forwarded_for_them: Uint256::from(0u32),
+            payments_sent: Uint256::from(0u32),
+            payments_received: Uint256::from(0u32),
+            payments_failed: Uint256::from(0u32),
+        }
+    }
+
+    fn add(&mut self, event: LedgerEvent, amount: Uint256) {
+        let total = match event {
+            LedgerEvent::ForwardedForUs => &mut self.forwarded_for_us,
+            LedgerEvent::ForwardedForThem => &mut self.forwarded_for_them,
+            LedgerEvent::PaymentSent => &mut self.payments_sent,
+            LedgerEvent::PaymentReceived => &mut self.payments_received,
+            LedgerEvent::PaymentFailed => &mut self.payments_failed,
+        };
+        *total = total.clone() + amount;
+    }
+}
+
+/// The buckets of one neighbor, oldest first
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LedgerHistory {
+    buckets: VecDeque<LedgerBucket>,
+}
+
+impl LedgerHistory {
+    /// Adds `amount` to the bucket `now` falls into
+    pub fn record(&mut self, now: u64, event: LedgerEvent, amount: Uint256) {
+        let start = now - now % HISTORY_BUCKET_SECS;
+
+        let is_current = match self.buckets.back() {
+            Some(bucket) => bucket.start >= start,
+            None => false,
+        };
+        if !is_current {
+            self.buckets.push_back(LedgerBucket::new(start));
+            while self.buckets.len() > HISTORY_MAX_BUCKETS {
+                self.buckets.pop_front();
+            }
+        }
+
+        // If the clock went backwards the newest bucket takes the entry, history stays sorted
+        self.buckets.back_mut().unwrap().add(event, amount);
+    }
+
+    /// Buckets starting within `[since, until)`
+    pub fn range(&self, since: u64, until: u64) -> Vec<LedgerBucket> {
+        self.buckets
+            .iter()
+            .filter(|bucket| bucket.start >= since && bucket.start < until)
+            .cloned()
+            .collect()
+    }
+}
+
+/// The history of one neighbor as served by the dashboard
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct NeighborHistory {
+    pub identity: Identity,
+    pub buckets: Vec<LedgerBucket>,
+}
+
+/// Flattens histories into one CSV row per neighbor and bucket
+pub fn history_to_csv(histories: &[NeighborHistory]) -> String {
+    let mut csv = String::from(
+        "mesh_ip,eth_address,wg_public_key,bucket_start,forwarded_for_us,forwarded_for_them,\
+         payments_sent,payments_received,payments_failed\n",
+    );
+    for history in histories {
+        for bucket in history.buckets.iter() {
+            csv += &format!(
+                "{},{:#x},{},{},{},{},{},{},{}\n",
+                history.identity.mesh_ip,
+                history.identity.eth_address,
+                history.identity.wg_public_key,
+                bucket.start,
+                bucket.forwarded_for_us,
+                bucket.forwarded_for_them,
+                bucket.payments_sent,
+                bucket.payments_received,
+                bucket.payments_failed
+            );
+        }
+    }
+    csv
+}
+
+pub fn unix_now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_identity() -> Identity {
+        Identity {
+            eth_address: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            mesh_ip: "2001::3".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_record_buckets() {
+        let mut history = LedgerHistory::default();
+        history.record(7200, LedgerEvent::ForwardedForUs, Uint256::from(10u32));
+        history.record(7300, LedgerEvent::ForwardedForUs, Uint256::from(5u32));
+        history.record(7400, LedgerEvent::PaymentSent, Uint256::from(15u32));
+        history.record(11000, LedgerEvent::PaymentReceived, Uint256::from(3u32));
+
+        let buckets = history.range(0, u64::max_value());
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].start, 7200);
+        assert_eq!(buckets[0].forwarded_for_us, Uint256::from(15u32));
+        assert_eq!(buckets[0].payments_sent, Uint256::from(15u32));
+        assert_eq!(buckets[1].start, 10800);
+        assert_eq!(buckets[1].payments_received, Uint256::from(3u32));
+
+        assert_eq!(history.range(7200, 10800), vec![buckets[0].clone()]);
+        assert!(history.range(0, 7200).is_empty());
+    }
+
+    #[test]
+    fn test_clock_going_backwards() {
+        let mut history = LedgerHistory::default();
+        history.record(10800, LedgerEvent::ForwardedForThem, Uint256::from(1u32));
+        history.record(7200, LedgerEvent::ForwardedForThem, Uint256::from(1u32));
+
+        let buckets = history.range(0, u64::max_value());
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].forwarded_for_them, Uint256::from(2u32));
+    }
+
+    #[test]
+    fn test_old_buckets_dropped() {
+        let mut history = LedgerHistory::default();
+        for hour in 0..(HISTORY_MAX_BUCKETS as u64 + 5) {
+            history.record(
+                hour * HISTORY_BUCKET_SECS,
+                LedgerEvent::PaymentSent,
+                Uint256::from(1u32),
+            );
+        }
+
+        let buckets = history.range(0, u64::max_value());
+        assert_eq!(buckets.len(), HISTORY_MAX_BUCKETS);
+        assert_eq!(buckets[0].start, 5 * HISTORY_BUCKET_SECS);
+    }
+
+    #[test]
+    fn test_history_to_csv() {
+        let mut history = LedgerHistory::default();
+        history.record(3600, LedgerEvent::PaymentFailed, Uint256::from(7u32));
+        let histories = vec![NeighborHistory {
+            identity: get_identity(),
+            buckets: history.range(0, u64::max_value()),
+        }];
+
+        let csv = history_to_csv(&histories);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("mesh_ip,"));
+        assert_eq!(
+            lines[1],
+            "2001::3,0x0000000000000000000000000000000000000001,\
+             8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk=,3600,0,0,0,0,7"
+        );
+    }
+}
@@ -5,32 +5,54 @@
 use actix::prelude::*;
 
 use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
 
 use althea_types::{Identity, PaymentTx};
 
+use clarity::Address;
+
 use num256::{Int256, Uint256};
 
-use settings::RitaCommonSettings;
+use settings::{PaymentSettings, RitaCommonSettings};
 use SETTING;
 
 use rita_common::payment_controller;
 use rita_common::payment_controller::PaymentController;
+use rita_common::storage;
+use rita_common::tunnel_manager::{TunnelAction, TunnelManager, TunnelStateChange};
 
 use failure::Error;
 
+use std::mem;
 use std::ops::Add;
 
+mod adjustment;
+mod history;
+#[cfg(test)]
+mod simulator;
+
+use self::adjustment::{balance, log_adjustment};
+pub use self::adjustment::{AdjustmentKind, DebtAdjustment, DebtCorrection};
+pub use self::history::{history_to_csv, unix_now, LedgerBucket, NeighborHistory};
+use self::history::{LedgerEvent, LedgerHistory};
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NodeDebtData {
     pub total_payment_received: Uint256,
     pub total_payment_sent: Uint256,
     pub debt: Int256,
     pub incoming_payments: Int256,
+    /// Payments already counted in `total_payment_sent` that the payment controller hasn't
+    /// delivered yet
+    pub payments_in_flight: Uint256,
     /// Front = older
     /// Only pop from front
     /// Only push to back
     #[serde(skip_serializing)]
     pub debt_buffer: VecDeque<Int256>,
+    /// When we last paid this neighbor, or started keeping track of them if we never did
+    #[serde(skip, default = "Instant::now")]
+    pub last_payment: Instant,
 }
 
 impl NodeDebtData {
@@ -40,6 +62,7 @@ impl NodeDebtData {
             total_payment_sent: Uint256::from(0u32),
             debt: Int256::from(0),
             incoming_payments: Int256::from(0),
+            payments_in_flight: Uint256::from(0u32),
             debt_buffer: {
                 let mut buf = VecDeque::new();
                 for _ in 0..buffer_period {
@@ -47,14 +70,58 @@ impl NodeDebtData {
                 }
                 buf
             },
+            last_payment: Instant::now(),
         }
     }
 }
 
 pub type DebtData = HashMap<Identity, NodeDebtData>;
 
+/// Version of the on disk ledger format, bump this whenever `DebtKeeperSnapshot` changes in a way
+/// older files can't be read as
+const DEBTS_FILE_VERSION: u32 = 1;
+
+/// How often the ledger is written out to `debts_file`, kept fairly long because routers store it
+/// on flash
+const DEBTS_SAVE_INTERVAL: u64 = 300;
+
+/// The on disk representation of the ledger, identities can't be json object keys so the map is
+/// stored as a list
+#[derive(Debug, Serialize, Deserialize)]
+struct DebtKeeperSnapshot {
+    version: u32,
+    debts: Vec<DebtSnapshotEntry>,
+    #[serde(default)]
+    adjustments: VecDeque<DebtAdjustment>,
+}
+
+/// `NodeDebtData` does not serialize its buffer since it's not interesting to the dashboard, but
+/// it's still money owed so it has to be saved
+#[derive(Debug, Serialize, Deserialize)]
+struct DebtSnapshotEntry {
+    identity: Identity,
+    total_payment_received: Uint256,
+    total_payment_sent: Uint256,
+    debt: Int256,
+    incoming_payments: Int256,
+    /// The payment controller's queue doesn't survive a restart, so these are handed back to the
+    /// debt when the ledger is loaded
+    #[serde(default = "no_payments_in_flight")]
+    payments_in_flight: Uint256,
+    debt_buffer: VecDeque<Int256>,
+    #[serde(default)]
+    history: LedgerHistory,
+}
+
+fn no_payments_in_flight() -> Uint256 {
+    Uint256::from(0u32)
+}
+
 pub struct DebtKeeper {
     debt_data: DebtData,
+    history: HashMap<Identity, LedgerHistory>,
+    /// Corrections made by hand through the dashboard, oldest first
+    adjustments: VecDeque<DebtAdjustment>,
 }
 
 impl Actor for DebtKeeper {
@@ -63,7 +130,18 @@ impl Actor for DebtKeeper {
 
 impl Supervised for DebtKeeper {}
 impl SystemService for DebtKeeper {
-    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+    fn service_started(&mut self, ctx: &mut Context<Self>) {
+        match self.load() {
+            Ok(()) => info!("Restored debts for {} neighbors", self.debt_data.len()),
+            Err(e) => error!("Failed to restore debts, starting empty: {:?}", e),
+        }
+
+        ctx.run_interval(Duration::from_secs(DEBTS_SAVE_INTERVAL), |act, _ctx| {
+            if let Err(e) = act.save() {
+                error!("Failed to save debts: {:?}", e);
+            }
+        });
+
         info!("Debt Keeper started");
     }
 }
@@ -95,6 +173,37 @@ impl Handler<PaymentReceived> for DebtKeeper {
     }
 }
 
+/// Sent by the payment controller when it gave up on delivering a payment, the amount goes back
+/// onto the neighbor's debt so that it gets paid again later
+#[derive(Message, PartialEq, Eq, Debug)]
+pub struct PaymentFailed {
+    pub to: Identity,
+    pub amount: Uint256,
+}
+
+impl Handler<PaymentFailed> for DebtKeeper {
+    type Result = ();
+
+    fn handle(&mut self, msg: PaymentFailed, _: &mut Context<Self>) -> Self::Result {
+        self.payment_failed(&msg.to, msg.amount)
+    }
+}
+
+/// Sent by the payment controller once a payment went through
+#[derive(Message, PartialEq, Eq, Debug)]
+pub struct PaymentDelivered {
+    pub to: Identity,
+    pub amount: Uint256,
+}
+
+impl Handler<PaymentDelivered> for DebtKeeper {
+    type Result = ();
+
+    fn handle(&mut self, msg: PaymentDelivered, _: &mut Context<Self>) -> Self::Result {
+        self.payment_delivered(&msg.to, msg.amount)
+    }
+}
+
 #[derive(Message)]
 pub struct TrafficUpdate {
     pub from: Identity,
@@ -109,6 +218,20 @@ impl Handler<TrafficUpdate> for DebtKeeper {
     }
 }
 
+/// Sent when the payment policies in the settings were changed, neighbors whose buffer period
+/// changed have their debt buffer resized right away
+#[derive(Message)]
+pub struct PaymentPoliciesChanged;
+
+impl Handler<PaymentPoliciesChanged> for DebtKeeper {
+    type Result = ();
+
+    fn handle(&mut self, _msg: PaymentPoliciesChanged, _: &mut Context<Self>) -> Self::Result {
+        let payment = SETTING.get_payment().clone();
+        self.apply_policies(&payment)
+    }
+}
+
 pub struct SendUpdate;
 
 impl Message for SendUpdate {
@@ -131,19 +254,35 @@ impl Handler<SendUpdate> for DebtKeeper {
     fn handle(&mut self, _msg: SendUpdate, _ctx: &mut Context<Self>) -> Self::Result {
         trace!("sending debt keeper update");
         trace!("total debt data: {:?}", self.debt_data);
+        // checked before any debt is moved into a payment, a payment we can't send would
+        // otherwise be left in flight
+        let our_id = match SETTING.get_identity() {
+            Some(id) => id,
+            None => bail!("Identity has no mesh IP ready yet"),
+        };
         for (k, _) in self.debt_data.clone() {
             trace!("sending update for {:?}", k);
-            match self.send_update(&k) {
-                DebtAction::SuspendTunnel => {}
-                DebtAction::OpenTunnel => {}
+            let action = self.send_update(&k);
+            // The tunnel state is sent on every tick rather than only when the threshold is
+            // crossed so that a suspension or resume that failed is retried, the tunnel manager
+            // ignores updates that don't change anything
+            TunnelManager::from_registry().do_send(TunnelStateChange {
+                identity: k.clone(),
+                action: match action {
+                    DebtAction::SuspendTunnel => TunnelAction::PaymentOverdue,
+                    _ => TunnelAction::PaidOnTime,
+                },
+            });
+            match action {
+                DebtAction::SuspendTunnel | DebtAction::OpenTunnel => {}
                 DebtAction::MakePayment { to, amount } => PaymentController::from_registry()
                     .do_send(payment_controller::MakePayment(PaymentTx {
                         to,
-                        from: match SETTING.get_identity() {
-                            Some(id) => id,
-                            None => bail!("Identity has no mesh IP ready yet"),
-                        },
+                        from: our_id.clone(),
                         amount,
+                        txid: None,
+                        nonce: Uint256::from(0u32),
+                        signature: None,
                     })),
                 DebtAction::None => {}
             }
@@ -165,21 +304,143 @@ impl DebtKeeper {
 
         DebtKeeper {
             debt_data: DebtData::new(),
+            history: HashMap::new(),
+            adjustments: VecDeque::new(),
         }
     }
 
+    /// Writes the ledger to `debts_file`
+    pub fn save(&self) -> Result<(), Error> {
+        if self.debt_data.is_empty() {
+            return Ok(());
+        }
+        let path = SETTING.get_payment().debts_file.clone();
+        storage::save_json(&path, &self.snapshot())?;
+        trace!("Saved debts for {} neighbors", self.debt_data.len());
+        Ok(())
+    }
+
+    /// Restores the ledger from `debts_file` if one was saved
+    pub fn load(&mut self) -> Result<(), Error> {
+        let payment = SETTING.get_payment().clone();
+        match storage::load_json(&payment.debts_file)? {
+            Some(snapshot) => {
+                self.restore(snapshot, payment.buffer_period)?;
+                self.apply_policies(&payment);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn snapshot(&self) -> DebtKeeperSnapshot {
+        DebtKeeperSnapshot {
+            version: DEBTS_FILE_VERSION,
+            debts: self
+                .debt_data
+                .iter()
+                .map(|(identity, data)| DebtSnapshotEntry {
+                    identity: identity.clone(),
+                    total_payment_received: data.total_payment_received.clone(),
+                    total_payment_sent: data.total_payment_sent.clone(),
+                    debt: data.debt.clone(),
+                    incoming_payments: data.incoming_payments.clone(),
+                    payments_in_flight: data.payments_in_flight.clone(),
+                    debt_buffer: data.debt_buffer.clone(),
+                    history: self.history.get(identity).cloned().unwrap_or_default(),
+                })
+                .collect(),
+            adjustments: self.adjustments.clone(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: DebtKeeperSnapshot, buffer_period: u32) -> Result<(), Error> {
+        if snapshot.version != DEBTS_FILE_VERSION {
+            bail!(
+                "Unsupported debts file version {}, expected {}",
+                snapshot.version,
+                DEBTS_FILE_VERSION
+            );
+        }
+
+        self.adjustments = snapshot.adjustments;
+        for entry in snapshot.debts {
+            if entry.payments_in_flight > Uint256::from(0u32) {
+                info!(
+                    "Restoring {} of undelivered payments to {:?}",
+                    entry.payments_in_flight, entry.identity.mesh_ip
+                );
+            }
+            self.history.insert(entry.identity.clone(), entry.history);
+            self.debt_data.insert(
+                entry.identity,
+                NodeDebtData {
+                    total_payment_received: entry.total_payment_received,
+                    total_payment_sent: saturating_sub(
+                        entry.total_payment_sent,
+                        entry.payments_in_flight.clone(),
+                    ),
+                    debt: entry.debt + Int256::from(entry.payments_in_flight),
+                    incoming_payments: entry.incoming_payments,
+                    payments_in_flight: Uint256::from(0u32),
+                    debt_buffer: resize_debt_buffer(entry.debt_buffer, buffer_period),
+                    last_payment: Instant::now(),
+                },
+            );
+        }
+        Ok(())
+    }
+
     fn get_debts(&self) -> DebtData {
         self.debt_data.clone()
     }
 
+    fn record(&mut self, now: u64, ident: &Identity, event: LedgerEvent, amount: Uint256) {
+        self.history
+            .entry(ident.clone())
+            .or_insert_with(LedgerHistory::default)
+            .record(now, event, amount);
+    }
+
+    fn get_history(&self, since: u64, until: u64) -> Vec<NeighborHistory> {
+        self.history
+            .iter()
+            .map(|(identity, history)| NeighborHistory {
+                identity: identity.clone(),
+                buckets: history.range(since, until),
+            })
+            .filter(|history| !history.buckets.is_empty())
+            .collect()
+    }
+
     fn get_debt_data(&mut self, ident: &Identity) -> &mut NodeDebtData {
-        let buffer = SETTING.get_payment().buffer_period;
+        let buffer = SETTING
+            .get_payment()
+            .policy_for(&ident.eth_address)
+            .buffer_period;
         self.debt_data
             .entry(ident.clone())
             .or_insert_with(|| NodeDebtData::new(buffer))
     }
 
+    /// Fits every neighbor's debt buffer to the buffer period of its payment policy
+    fn apply_policies(&mut self, payment: &PaymentSettings) {
+        for (ident, debt_data) in self.debt_data.iter_mut() {
+            let buffer = payment.policy_for(&ident.eth_address).buffer_period;
+            if debt_data.debt_buffer.len() != buffer as usize {
+                let debt_buffer = mem::replace(&mut debt_data.debt_buffer, VecDeque::new());
+                debt_data.debt_buffer = resize_debt_buffer(debt_buffer, buffer);
+            }
+        }
+    }
+
     fn payment_received(&mut self, ident: &Identity, amount: Uint256) {
+        self.payment_received_at(unix_now(), ident, amount)
+    }
+
+    /// Like `payment_received`, but with the unix time to put the payment into the history at
+    fn payment_received_at(&mut self, now: u64, ident: &Identity, amount: Uint256) {
+        self.record(now, ident, LedgerEvent::PaymentReceived, amount.clone());
         let debt_data = self.get_debt_data(ident);
 
         let old_balance = debt_data.incoming_payments.clone();
@@ -202,7 +463,59 @@ impl DebtKeeper {
         );
     }
 
-    fn traffic_update(&mut self, ident: &Identity, mut amount: Int256) {
+    fn payment_failed(&mut self, ident: &Identity, amount: Uint256) {
+        self.payment_failed_at(unix_now(), ident, amount)
+    }
+
+    /// Like `payment_failed`, but with the unix time to put the failure into the history at
+    fn payment_failed_at(&mut self, now: u64, ident: &Identity, amount: Uint256) {
+        self.record(now, ident, LedgerEvent::PaymentFailed, amount.clone());
+        let debt_data = self.get_debt_data(ident);
+        warn!(
+            "payment of {} to {:?} failed, restoring debt",
+            amount, ident.mesh_ip
+        );
+        debt_data.payments_in_flight =
+            saturating_sub(debt_data.payments_in_flight.clone(), amount.clone());
+        if amount > debt_data.total_payment_sent {
+            error!(
+                "failed payment of {} to {:?} is more than the {} sent in total",
+                amount, ident.mesh_ip, debt_data.total_payment_sent
+            );
+        }
+        debt_data.total_payment_sent =
+            saturating_sub(debt_data.total_payment_sent.clone(), amount.clone());
+        debt_data.debt += Int256::from(amount);
+    }
+
+    fn payment_delivered(&mut self, ident: &Identity, amount: Uint256) {
+        trace!("payment of {} to {:?} delivered", amount, ident.mesh_ip);
+        let debt_data = self.get_debt_data(ident);
+        debt_data.payments_in_flight = saturating_sub(debt_data.payments_in_flight.clone(), amount);
+    }
+
+    fn traffic_update(&mut self, ident: &Identity, amount: Int256) {
+        self.traffic_update_at(unix_now(), ident, amount)
+    }
+
+    /// Like `traffic_update`, but with the unix time to put the traffic into the history at
+    fn traffic_update_at(&mut self, now: u64, ident: &Identity, mut amount: Int256) {
+        if amount < Int256::from(0) {
+            self.record(
+                now,
+                ident,
+                LedgerEvent::ForwardedForThem,
+                Uint256::from(-amount.clone()),
+            );
+        } else if amount > Int256::from(0) {
+            self.record(
+                now,
+                ident,
+                LedgerEvent::ForwardedForUs,
+                Uint256::from(amount.clone()),
+            );
+        }
+
         {
             trace!("traffic update for {} is {}", ident.mesh_ip, amount);
             let debt_data = self.get_debt_data(ident);
@@ -217,8 +530,8 @@ impl DebtKeeper {
                     debt_data.incoming_payments = Int256::from(0);
 
                     // Buffer debt in the back of the debt buffer
-                    debt_data.debt_buffer[(SETTING.get_payment().buffer_period - 1) as usize] +=
-                        amount;
+                    let back = debt_data.debt_buffer.len() - 1;
+                    debt_data.debt_buffer[back] += amount;
                 }
             } else {
                 // Immediately apply credit
@@ -234,8 +547,80 @@ impl DebtKeeper {
         trace!("total debt imbalance: {}", imbalance);
     }
 
-    /// This updates a neighbor's debt and outputs a DebtAction if one is necessary.
+    /// Applies a correction to the ledger of the neighbor with this eth address and logs it. If
+    /// it takes the neighbor back above the close threshold their tunnel has to be reopened,
+    /// `update_debt` only notices the crossing when traffic causes it.
+    fn correct_debt(
+        &mut self,
+        eth_address: &Address,
+        correction: DebtCorrection,
+        reason: String,
+        payment: &PaymentSettings,
+        now: u64,
+    ) -> Result<(DebtAdjustment, DebtAction), Error> {
+        if reason.trim().is_empty() {
+            bail!("A reason is required for debt corrections");
+        }
+        let ident = match self
+            .debt_data
+            .keys()
+            .find(|ident| ident.eth_address == *eth_address)
+        {
+            Some(ident) => ident.clone(),
+            None => bail!("No debts recorded for {}", eth_address),
+        };
+        let payment = payment.policy_for(eth_address);
+
+        let (adjustment, action) = {
+            let debt_data = self.get_debt_data(&ident);
+            let was_suspended = debt_data.debt < close_threshold(debt_data, &payment);
+            let balance_before = balance(debt_data);
+
+            correction.apply(debt_data, payment.buffer_period);
+
+            let reopened = debt_data.debt >= close_threshold(debt_data, &payment);
+            let action = if was_suspended && reopened {
+                DebtAction::OpenTunnel
+            } else {
+                DebtAction::None
+            };
+            let adjustment = DebtAdjustment {
+                time: now,
+                identity: ident.clone(),
+                kind: correction.kind(),
+                balance_before,
+                balance_after: balance(debt_data),
+                reason,
+            };
+            (adjustment, action)
+        }; // borrowck
+
+        info!(
+            "Debt of {:?} corrected by hand from {} to {}: {}",
+            ident.mesh_ip, adjustment.balance_before, adjustment.balance_after, adjustment.reason
+        );
+        log_adjustment(&mut self.adjustments, adjustment.clone());
+        Ok((adjustment, action))
+    }
+
+    /// This updates a neighbor's debt under the payment policy for that neighbor and outputs a
+    /// DebtAction if one is necessary.
     fn send_update(&mut self, ident: &Identity) -> DebtAction {
+        let payment = SETTING.get_payment().clone();
+        let action = self.update_debt(ident, &payment, Instant::now());
+        if let DebtAction::MakePayment { ref amount, .. } = action {
+            self.record(unix_now(), ident, LedgerEvent::PaymentSent, amount.clone());
+        }
+        action
+    }
+
+    fn update_debt(
+        &mut self,
+        ident: &Identity,
+        payment: &PaymentSettings,
+        now: Instant,
+    ) -> DebtAction {
+        let payment = payment.policy_for(&ident.eth_address);
         trace!("debt data: {:?}", self.debt_data);
         let debt_data = self.get_debt_data(ident);
         let debt = debt_data.debt.clone();
@@ -273,9 +658,7 @@ impl DebtKeeper {
             debt_data.incoming_payments = Int256::from(0);
         }
 
-        let close_threshold = SETTING.get_payment().close_threshold.clone()
-            - debt_data.total_payment_received.clone()
-                / SETTING.get_payment().close_fraction.clone();
+        let close_threshold = close_threshold(debt_data, &payment);
 
         if debt_data.debt < close_threshold {
             trace!(
@@ -286,7 +669,10 @@ impl DebtKeeper {
         } else if (close_threshold < debt_data.debt) && (debt < close_threshold) {
             trace!("debt is above close threshold. resuming forwarding");
             DebtAction::OpenTunnel
-        } else if debt_data.debt > SETTING.get_payment().pay_threshold {
+        } else if debt_data.debt > payment.pay_threshold
+            && (debt_data.debt >= payment.min_payment
+                || payment_interval_elapsed(debt_data.last_payment, now, &payment))
+        {
             let d = debt_data.debt.clone();
             trace!(
                 "debt is above payment threshold for {}. making payment of {}",
@@ -294,7 +680,9 @@ impl DebtKeeper {
                 d
             );
             debt_data.total_payment_sent = debt_data.total_payment_sent.clone().add(d.clone());
+            debt_data.payments_in_flight = debt_data.payments_in_flight.clone().add(d.clone());
             debt_data.debt = Int256::from(0);
+            debt_data.last_payment = now;
             DebtAction::MakePayment {
                 to: ident.clone(),
                 amount: Uint256::from(d),
@@ -305,6 +693,48 @@ impl DebtKeeper {
     }
 }
 
+/// `Uint256` panics when a subtraction would go below zero
+fn saturating_sub(a: Uint256, b: Uint256) -> Uint256 {
+    if b > a {
+        Uint256::from(0u32)
+    } else {
+        a - b
+    }
+}
+
+/// The debt below which we stop forwarding for a neighbor, neighbors that have paid us a lot in
+/// the past get more grace
+fn close_threshold(debt_data: &NodeDebtData, payment: &PaymentSettings) -> Int256 {
+    payment.close_threshold.clone()
+        - debt_data.total_payment_received.clone() / payment.close_fraction.clone()
+}
+
+/// Whether it's been `max_payment_interval` since `last_payment`, debt below `min_payment` is only
+/// paid once that's the case
+fn payment_interval_elapsed(
+    last_payment: Instant,
+    now: Instant,
+    payment: &PaymentSettings,
+) -> bool {
+    let max_interval = Duration::from_secs(payment.max_payment_interval);
+    now > last_payment && now.duration_since(last_payment) >= max_interval
+}
+
+/// Fits a saved debt buffer to the current `buffer_period`, if the period was shortened while we
+/// were down the oldest entries are folded into the front so no debt is lost, if it was lengthened
+/// the buffer is padded at the back so existing entries come due at the same time they would have
+fn resize_debt_buffer(mut buffer: VecDeque<Int256>, buffer_period: u32) -> VecDeque<Int256> {
+    let buffer_period = buffer_period as usize;
+    while buffer.len() > buffer_period && buffer.len() > 1 {
+        let oldest = buffer.pop_front().unwrap();
+        buffer[0] += oldest;
+    }
+    while buffer.len() < buffer_period {
+        buffer.push_back(Int256::from(0));
+    }
+    buffer
+}
+
 pub struct GetDebtsList;
 
 impl Message for GetDebtsList {
@@ -313,8 +743,8 @@ impl Message for GetDebtsList {
 
 #[derive(Serialize)]
 pub struct GetDebtsResult {
-    identity: Identity,
-    payment_details: NodeDebtData,
+    pub identity: Identity,
+    pub payment_details: NodeDebtData,
 }
 
 impl GetDebtsResult {
@@ -340,9 +770,82 @@ impl Handler<GetDebtsList> for DebtKeeper {
     }
 }
 
+/// Forgives, adjusts or resets the debt of the neighbor with this eth address
+pub struct CorrectDebt {
+    pub eth_address: Address,
+    pub correction: DebtCorrection,
+    pub reason: String,
+}
+
+impl Message for CorrectDebt {
+    type Result = Result<DebtAdjustment, Error>;
+}
+
+impl Handler<CorrectDebt> for DebtKeeper {
+    type Result = Result<DebtAdjustment, Error>;
+
+    fn handle(&mut self, msg: CorrectDebt, _ctx: &mut Context<Self>) -> Self::Result {
+        let payment = SETTING.get_payment().clone();
+        let (adjustment, action) = self.correct_debt(
+            &msg.eth_address,
+            msg.correction,
+            msg.reason,
+            &payment,
+            unix_now(),
+        )?;
+        if action == DebtAction::OpenTunnel {
+            TunnelManager::from_registry().do_send(TunnelStateChange {
+                identity: adjustment.identity.clone(),
+                action: TunnelAction::PaidOnTime,
+            });
+        }
+        // corrections are rare and shouldn't be lost to a crash before the next periodic save
+        if let Err(e) = self.save() {
+            error!("Failed to save debts after a correction: {:?}", e);
+        }
+        Ok(adjustment)
+    }
+}
+
+pub struct GetDebtAdjustments;
+
+impl Message for GetDebtAdjustments {
+    type Result = Result<Vec<DebtAdjustment>, Error>;
+}
+
+impl Handler<GetDebtAdjustments> for DebtKeeper {
+    type Result = Result<Vec<DebtAdjustment>, Error>;
+
+    fn handle(&mut self, _msg: GetDebtAdjustments, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.adjustments.iter().cloned().collect())
+    }
+}
+
+/// Asks for every neighbor's ledger buckets starting within `[since, until)`
+pub struct GetDebtsHistory {
+    pub since: u64,
+    pub until: u64,
+}
+
+impl Message for GetDebtsHistory {
+    type Result = Result<Vec<NeighborHistory>, Error>;
+}
+
+impl Handler<GetDebtsHistory> for DebtKeeper {
+    type Result = Result<Vec<NeighborHistory>, Error>;
+
+    fn handle(&mut self, msg: GetDebtsHistory, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.get_history(msg.since, msg.until))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use clarity::PrivateKey;
+    use serde_json;
+    use settings::PaymentPolicy;
+    use std::time::Instant;
 
     #[test]
     fn test_single_suspend() {
@@ -571,6 +1074,238 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_failed_payment_restores_debt() {
+        SETTING.get_payment_mut().pay_threshold = Int256::from(5);
+        SETTING.get_payment_mut().close_threshold = Int256::from(-10);
+        SETTING.get_payment_mut().close_fraction = Int256::from(100);
+        SETTING.get_payment_mut().buffer_period = 2;
+
+        let mut d = DebtKeeper::new();
+
+        let ident = Identity {
+            eth_address: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            mesh_ip: "2001::3".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+
+        d.traffic_update(&ident, Int256::from(100));
+
+        assert_eq!(
+            d.send_update(&ident),
+            DebtAction::MakePayment {
+                amount: Uint256::from(100u32),
+                to: ident.clone(),
+            }
+        );
+
+        d.payment_failed(&ident, Uint256::from(100u32));
+
+        assert_eq!(d.debt_data[&ident].total_payment_sent, Uint256::from(0u32));
+        assert_eq!(d.debt_data[&ident].debt, Int256::from(100));
+
+        // the restored debt gets paid again on the next update
+        assert_eq!(
+            d.send_update(&ident),
+            DebtAction::MakePayment {
+                amount: Uint256::from(100u32),
+                to: ident,
+            }
+        );
+    }
+
+    #[test]
+    fn test_undeliverable_payment_restored() {
+        SETTING.get_payment_mut().pay_threshold = Int256::from(5);
+        SETTING.get_payment_mut().close_threshold = Int256::from(-10);
+        SETTING.get_payment_mut().close_fraction = Int256::from(100);
+        SETTING.get_payment_mut().buffer_period = 2;
+
+        let mut d = DebtKeeper::new();
+        let backend = payment_controller::MockBackend::new();
+        backend.state.lock().unwrap().failures = u32::max_value();
+        let mut pc = PaymentController::with_backend(Box::new(backend.clone()));
+        let key: PrivateKey = "0xfe1b2d1a4f8b0b8a3c1e2f3d4c5b6a79887766554433221100ffeeddccbbaa99"
+            .parse()
+            .unwrap();
+
+        let ident = Identity {
+            eth_address: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            mesh_ip: "2001::3".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+        let us = Identity {
+            eth_address: key.to_public_key().unwrap(),
+            mesh_ip: "2001::4".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+
+        d.traffic_update(&ident, Int256::from(100));
+        match d.send_update(&ident) {
+            DebtAction::MakePayment { to, amount } => {
+                let pmt = PaymentTx {
+                    to,
+                    from: us,
+                    amount,
+                    txid: None,
+                    nonce: Uint256::from(0u32),
+                    signature: None,
+                };
+                pc.queue_payment(pmt, &key, Instant::now());
+            }
+            action => panic!("Expected a payment, got {:?}", action),
+        }
+        assert_eq!(d.debt_data[&ident].debt, Int256::from(0));
+
+        // keep retrying until the controller gives up and hands the payment back
+        let mut now = Instant::now();
+        let mut failed = Vec::new();
+        while failed.is_empty() {
+            failed = pc.process_queue(now).failed;
+            now += Duration::from_secs(3600);
+        }
+        for failure in failed {
+            d.payment_failed(&failure.to, failure.amount);
+        }
+
+        assert!(pc.pending_payments().is_empty());
+        assert!(backend.state.lock().unwrap().sent.is_empty());
+        assert_eq!(d.debt_data[&ident].total_payment_sent, Uint256::from(0u32));
+        assert_eq!(d.debt_data[&ident].debt, Int256::from(100));
+    }
+
+    #[test]
+    fn test_failed_payment_after_reset() {
+        let payment = PaymentSettings::default();
+        let mut d = DebtKeeper {
+            debt_data: DebtData::new(),
+            history: HashMap::new(),
+            adjustments: VecDeque::new(),
+        };
+
+        let ident = Identity {
+            eth_address: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            mesh_ip: "2001::3".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+
+        d.get_debt_data(&ident).debt = Int256::from(100);
+        match d.update_debt(&ident, &payment, Instant::now()) {
+            DebtAction::MakePayment { .. } => {}
+            action => panic!("Expected a payment, got {:?}", action),
+        }
+        d.correct_debt(
+            &ident.eth_address,
+            DebtCorrection::Reset,
+            "test".to_string(),
+            &payment,
+            0,
+        )
+        .unwrap();
+
+        // the payment queued before the reset fails afterwards
+        d.payment_failed(&ident, Uint256::from(100u32));
+        assert_eq!(d.debt_data[&ident].total_payment_sent, Uint256::from(0u32));
+        assert_eq!(d.debt_data[&ident].payments_in_flight, Uint256::from(0u32));
+        assert_eq!(d.debt_data[&ident].debt, Int256::from(100));
+
+        // more than was ever sent doesn't underflow either
+        d.payment_failed(&ident, Uint256::from(100u32));
+        assert_eq!(d.debt_data[&ident].total_payment_sent, Uint256::from(0u32));
+    }
+
+    #[test]
+    fn test_payment_batching() {
+        let mut payment = PaymentSettings::default();
+        payment.pay_threshold = Int256::from(5);
+        payment.close_threshold = Int256::from(-10);
+        payment.close_fraction = Int256::from(100);
+        payment.min_payment = Int256::from(100);
+        payment.max_payment_interval = 60;
+
+        let mut d = DebtKeeper::new();
+
+        let ident = Identity {
+            eth_address: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            mesh_ip: "2001::3".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+        let start = Instant::now();
+        d.get_debt_data(&ident).last_payment = start;
+
+        // above the payment threshold but not worth a payment yet, the debt is kept
+        d.traffic_update(&ident, Int256::from(40));
+        assert_eq!(
+            d.update_debt(&ident, &payment, start + Duration::from_secs(10)),
+            DebtAction::None
+        );
+        d.traffic_update(&ident, Int256::from(40));
+        assert_eq!(
+            d.update_debt(&ident, &payment, start + Duration::from_secs(20)),
+            DebtAction::None
+        );
+        assert_eq!(d.debt_data[&ident].debt, Int256::from(80));
+
+        // both rounds are paid together once the minimum is reached
+        d.traffic_update(&ident, Int256::from(25));
+        assert_eq!(
+            d.update_debt(&ident, &payment, start + Duration::from_secs(30)),
+            DebtAction::MakePayment {
+                to: ident.clone(),
+                amount: Uint256::from(105u32),
+            }
+        );
+        assert_eq!(d.debt_data[&ident].debt, Int256::from(0));
+        assert_eq!(
+            d.debt_data[&ident].total_payment_sent,
+            Uint256::from(105u32)
+        );
+
+        // small debt is still paid once the interval runs out
+        d.traffic_update(&ident, Int256::from(10));
+        assert_eq!(
+            d.update_debt(&ident, &payment, start + Duration::from_secs(80)),
+            DebtAction::None
+        );
+        assert_eq!(
+            d.update_debt(&ident, &payment, start + Duration::from_secs(90)),
+            DebtAction::MakePayment {
+                to: ident.clone(),
+                amount: Uint256::from(10u32),
+            }
+        );
+        assert_eq!(
+            d.debt_data[&ident].total_payment_sent,
+            Uint256::from(115u32)
+        );
+
+        // debt at or below the payment threshold is never paid
+        d.traffic_update(&ident, Int256::from(5));
+        assert_eq!(
+            d.update_debt(&ident, &payment, start + Duration::from_secs(1000)),
+            DebtAction::None
+        );
+        assert_eq!(d.debt_data[&ident].debt, Int256::from(5));
+    }
+
     #[test]
     fn test_fudge() {
         SETTING.get_payment_mut().pay_threshold = Int256::from(5);
@@ -717,4 +1452,366 @@ mod tests {
 
         assert_eq!(d.send_update(&ident), DebtAction::OpenTunnel);
     }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut d = DebtKeeper {
+            debt_data: DebtData::new(),
+            history: HashMap::new(),
+            adjustments: VecDeque::new(),
+        };
+
+        let ident = Identity {
+            eth_address: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            mesh_ip: "2001::3".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+
+        let mut data = NodeDebtData::new(3);
+        data.total_payment_received = Uint256::from(1000u32);
+        data.total_payment_sent = Uint256::from(500u32);
+        data.debt = Int256::from(-42);
+        data.incoming_payments = Int256::from(7);
+        data.debt_buffer[2] = Int256::from(-100);
+        d.debt_data.insert(ident.clone(), data);
+        d.record(
+            unix_now(),
+            &ident,
+            LedgerEvent::PaymentSent,
+            Uint256::from(500u32),
+        );
+
+        let ser = serde_json::to_string(&d.snapshot()).unwrap();
+        let snapshot: DebtKeeperSnapshot = serde_json::from_str(&ser).unwrap();
+
+        let mut restored = DebtKeeper {
+            debt_data: DebtData::new(),
+            history: HashMap::new(),
+            adjustments: VecDeque::new(),
+        };
+        restored.restore(snapshot, 3).unwrap();
+
+        let data = &restored.debt_data[&ident];
+        assert_eq!(data.total_payment_received, Uint256::from(1000u32));
+        assert_eq!(data.total_payment_sent, Uint256::from(500u32));
+        assert_eq!(data.debt, Int256::from(-42));
+        assert_eq!(data.incoming_payments, Int256::from(7));
+        assert_eq!(
+            data.debt_buffer,
+            vec![Int256::from(0), Int256::from(0), Int256::from(-100)]
+                .into_iter()
+                .collect::<VecDeque<Int256>>()
+        );
+        assert_eq!(restored.history[&ident], d.history[&ident]);
+    }
+
+    #[test]
+    fn test_snapshot_refunds_payments_in_flight() {
+        let mut d = DebtKeeper {
+            debt_data: DebtData::new(),
+            history: HashMap::new(),
+            adjustments: VecDeque::new(),
+        };
+
+        let ident = Identity {
+            eth_address: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            mesh_ip: "2001::3".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+
+        let payment = PaymentSettings::default();
+        d.get_debt_data(&ident).debt = Int256::from(100);
+        d.get_debt_data(&ident).total_payment_sent = Uint256::from(50u32);
+        match d.update_debt(&ident, &payment, Instant::now()) {
+            DebtAction::MakePayment { .. } => {}
+            action => panic!("Expected a payment, got {:?}", action),
+        }
+        assert_eq!(
+            d.debt_data[&ident].payments_in_flight,
+            Uint256::from(100u32)
+        );
+
+        // the payment controller's queue is lost, so the payment has to be made again
+        let ser = serde_json::to_string(&d.snapshot()).unwrap();
+        let snapshot: DebtKeeperSnapshot = serde_json::from_str(&ser).unwrap();
+        let mut restored = DebtKeeper {
+            debt_data: DebtData::new(),
+            history: HashMap::new(),
+            adjustments: VecDeque::new(),
+        };
+        restored.restore(snapshot, 1).unwrap();
+
+        let data = &restored.debt_data[&ident];
+        assert_eq!(data.payments_in_flight, Uint256::from(0u32));
+        assert_eq!(data.total_payment_sent, Uint256::from(50u32));
+        assert_eq!(data.debt, Int256::from(100));
+
+        // delivered payments are no longer in flight and stay paid
+        d.payment_delivered(&ident, Uint256::from(100u32));
+        assert_eq!(d.debt_data[&ident].payments_in_flight, Uint256::from(0u32));
+        assert_eq!(
+            d.debt_data[&ident].total_payment_sent,
+            Uint256::from(150u32)
+        );
+    }
+
+    #[test]
+    fn test_snapshot_without_history() {
+        let ser = r#"{"version":1,"debts":[{"identity":{"mesh_ip":"2001::3","eth_address":"0x0000000000000000000000000000000000000001","wg_public_key":"8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="},"total_payment_received":"0x0","total_payment_sent":"0x0","debt":"0","incoming_payments":"0","debt_buffer":["0"]}]}"#;
+        let snapshot: DebtKeeperSnapshot = serde_json::from_str(ser).unwrap();
+
+        let mut d = DebtKeeper {
+            debt_data: DebtData::new(),
+            history: HashMap::new(),
+            adjustments: VecDeque::new(),
+        };
+        d.restore(snapshot, 1).unwrap();
+
+        assert_eq!(d.debt_data.len(), 1);
+        assert!(d.get_history(0, u64::max_value()).is_empty());
+    }
+
+    #[test]
+    fn test_history_recorded() {
+        SETTING.get_payment_mut().pay_threshold = Int256::from(5);
+        SETTING.get_payment_mut().close_threshold = Int256::from(-10);
+        SETTING.get_payment_mut().close_fraction = Int256::from(100);
+        SETTING.get_payment_mut().buffer_period = 1;
+
+        let mut d = DebtKeeper::new();
+
+        let ident = Identity {
+            eth_address: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            mesh_ip: "2001::3".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+
+        d.traffic_update(&ident, Int256::from(100));
+        d.traffic_update(&ident, Int256::from(-30));
+        d.payment_received(&ident, Uint256::from(30u32));
+        assert_eq!(
+            d.send_update(&ident),
+            DebtAction::MakePayment {
+                to: ident.clone(),
+                amount: Uint256::from(100u32),
+            }
+        );
+
+        let history = d.get_history(0, u64::max_value());
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].identity, ident);
+        // everything above happened within one bucket unless the test ran across an hour boundary
+        let mut totals = LedgerBucket {
+            start: 0,
+            forwarded_for_us: Uint256::from(0u32),
+            forwarded_for_them: Uint256::from(0u32),
+            payments_sent: Uint256::from(0u32),
+            payments_received: Uint256::from(0u32),
+            payments_failed: Uint256::from(0u32),
+        };
+        for bucket in history[0].buckets.iter() {
+            totals.forwarded_for_us = totals.forwarded_for_us + bucket.forwarded_for_us.clone();
+            totals.forwarded_for_them =
+                totals.forwarded_for_them + bucket.forwarded_for_them.clone();
+            totals.payments_sent = totals.payments_sent + bucket.payments_sent.clone();
+            totals.payments_received = totals.payments_received + bucket.payments_received.clone();
+        }
+        assert_eq!(totals.forwarded_for_us, Uint256::from(100u32));
+        assert_eq!(totals.forwarded_for_them, Uint256::from(30u32));
+        assert_eq!(totals.payments_sent, Uint256::from(100u32));
+        assert_eq!(totals.payments_received, Uint256::from(30u32));
+    }
+
+    #[test]
+    fn test_snapshot_unknown_version() {
+        let mut d = DebtKeeper {
+            debt_data: DebtData::new(),
+            history: HashMap::new(),
+            adjustments: VecDeque::new(),
+        };
+        let snapshot = DebtKeeperSnapshot {
+            version: DEBTS_FILE_VERSION + 1,
+            debts: Vec::new(),
+            adjustments: VecDeque::new(),
+        };
+        assert!(d.restore(snapshot, 3).is_err());
+    }
+
+    #[test]
+    fn test_resize_debt_buffer() {
+        let buffer: VecDeque<Int256> = vec![Int256::from(-1), Int256::from(-2), Int256::from(-3)]
+            .into_iter()
+            .collect();
+
+        let shrunk = resize_debt_buffer(buffer.clone(), 2);
+        assert_eq!(
+            shrunk,
+            vec![Int256::from(-3), Int256::from(-3)]
+                .into_iter()
+                .collect::<VecDeque<Int256>>()
+        );
+
+        let grown = resize_debt_buffer(buffer, 4);
+        assert_eq!(
+            grown,
+            vec![
+                Int256::from(-1),
+                Int256::from(-2),
+                Int256::from(-3),
+                Int256::from(0),
+            ]
+            .into_iter()
+            .collect::<VecDeque<Int256>>()
+        );
+    }
+
+    fn get_policy_identities() -> (Identity, Identity) {
+        let backbone = Identity {
+            eth_address: "0x0000000000000000000000000000000000000002"
+                .parse()
+                .unwrap(),
+            mesh_ip: "2001::3".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+        let edge = Identity {
+            eth_address: "0x0000000000000000000000000000000000000003"
+                .parse()
+                .unwrap(),
+            mesh_ip: "2001::4".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+        (backbone, edge)
+    }
+
+    #[test]
+    fn test_neighbor_policy() {
+        let (backbone, edge) = get_policy_identities();
+        let mut payment = PaymentSettings::default();
+        payment.close_threshold = Int256::from(-10);
+        payment.neighbor_policies.insert(
+            backbone.eth_address.clone(),
+            PaymentPolicy {
+                close_threshold: Some(Int256::from(-1000)),
+                ..PaymentPolicy::default()
+            },
+        );
+
+        let mut d = DebtKeeper::new();
+        d.get_debt_data(&backbone).debt = Int256::from(-100);
+        d.get_debt_data(&edge).debt = Int256::from(-100);
+
+        // the same debt is within the backbone link's grace but not the edge neighbor's
+        assert_eq!(
+            d.update_debt(&backbone, &payment, Instant::now()),
+            DebtAction::None
+        );
+        assert_eq!(
+            d.update_debt(&edge, &payment, Instant::now()),
+            DebtAction::SuspendTunnel
+        );
+    }
+
+    #[test]
+    fn test_apply_policies() {
+        let (backbone, edge) = get_policy_identities();
+        let mut payment = PaymentSettings::default();
+        payment.buffer_period = 3;
+        payment.neighbor_policies.insert(
+            backbone.eth_address.clone(),
+            PaymentPolicy {
+                buffer_period: Some(5),
+                ..PaymentPolicy::default()
+            },
+        );
+
+        let mut d = DebtKeeper::new();
+        d.get_debt_data(&backbone);
+        d.get_debt_data(&edge);
+        d.apply_policies(&payment);
+
+        assert_eq!(d.debt_data[&backbone].debt_buffer.len(), 5);
+        assert_eq!(d.debt_data[&edge].debt_buffer.len(), 3);
+    }
+
+    #[test]
+    fn test_correct_debt() {
+        let (neighbor, stranger) = get_policy_identities();
+        let mut payment = PaymentSettings::default();
+        payment.close_threshold = Int256::from(-10);
+
+        let mut d = DebtKeeper::new();
+        d.get_debt_data(&neighbor).debt = Int256::from(-100);
+
+        assert!(d
+            .correct_debt(
+                &neighbor.eth_address,
+                DebtCorrection::Forgive(None),
+                " ".to_string(),
+                &payment,
+                1000
+            )
+            .is_err());
+        assert!(d
+            .correct_debt(
+                &stranger.eth_address,
+                DebtCorrection::Forgive(None),
+                "outage".to_string(),
+                &payment,
+                1000
+            )
+            .is_err());
+        assert!(d.adjustments.is_empty());
+
+        // forgiving a suspended neighbor reopens their tunnel
+        let (adjustment, action) = d
+            .correct_debt(
+                &neighbor.eth_address,
+                DebtCorrection::Forgive(None),
+                "outage".to_string(),
+                &payment,
+                1000,
+            )
+            .unwrap();
+        assert_eq!(action, DebtAction::OpenTunnel);
+        assert_eq!(adjustment.kind, AdjustmentKind::Forgive);
+        assert_eq!(adjustment.balance_before, Int256::from(-100));
+        assert_eq!(adjustment.balance_after, Int256::from(0));
+        assert_eq!(d.debt_data[&neighbor].debt, Int256::from(0));
+
+        let (adjustment, action) = d
+            .correct_debt(
+                &neighbor.eth_address,
+                DebtCorrection::Adjust(Int256::from(-5)),
+                "billing bug".to_string(),
+                &payment,
+                2000,
+            )
+            .unwrap();
+        assert_eq!(action, DebtAction::None);
+        assert_eq!(adjustment.balance_after, Int256::from(-5));
+
+        // the log survives a restart
+        let ser = serde_json::to_string(&d.snapshot()).unwrap();
+        let snapshot: DebtKeeperSnapshot = serde_json::from_str(&ser).unwrap();
+        let mut restored = DebtKeeper::new();
+        restored.restore(snapshot, 1).unwrap();
+        assert_eq!(restored.adjustments, d.adjustments);
+        assert_eq!(restored.adjustments[1].reason, "billing bug");
+    }
 }
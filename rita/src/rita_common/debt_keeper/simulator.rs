@@ -0,0 +1,561 @@
+//! A deterministic simulation of the economics between several nodes, each running its own
+//! `DebtKeeper` and `PaymentController` on a virtual clock. Traffic is given as flows of bytes per
+//! tick that one node sends through a neighbor at that neighbor's price, payments go through a
+//! `MockBackend` and are handed straight to the recipient. The actor system isn't involved and every
+//! node runs its updates with its own `PaymentSettings`, so scenarios can be used to try out values
+//! for `close_fraction` or `buffer_period` before they ship. `DebtKeeper::new()` still checks the
+//! global `SETTING`, so the defaults there have to stay valid.
+
+use std::collections::{BTreeSet, HashSet};
+use std::mem;
+use std::time::{Duration, Instant};
+
+use althea_types::{Identity, PaymentTx};
+
+use clarity::PrivateKey;
+
+use num256::{Int256, Uint256};
+
+use settings::PaymentSettings;
+
+use rita_common::payment_controller::{IncomingPayment, MockBackend, PaymentController};
+
+use super::adjustment::balance;
+use super::{DebtAction, DebtKeeper, LedgerEvent, NodeDebtData};
+
+/// Seconds of virtual time between two debt keeper updates, the same as the rita loop
+const TICK: u64 = 5;
+
+/// Unix time the virtual clock starts at, fixed so that histories come out the same every run
+const SIM_EPOCH: u64 = 1_500_000_000;
+
+struct SimNode {
+    identity: Identity,
+    key: PrivateKey,
+    payment: PaymentSettings,
+    /// What this node charges per byte it forwards
+    price: u64,
+    keeper: DebtKeeper,
+    controller: PaymentController,
+    backend: MockBackend,
+    /// Other nodes this one keeps a ledger for, sorted so that updates happen in a fixed order
+    neighbors: BTreeSet<usize>,
+    /// Payments this node decides on are dropped instead of sent
+    freeloader: bool,
+}
+
+/// `from` sends `bytes` every tick through its neighbor `via`
+#[derive(Debug, Clone, Copy)]
+struct Flow {
+    from: usize,
+    via: usize,
+    bytes: u64,
+}
+
+/// `node` changed the state of its tunnel to `neighbor` during `tick`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimEvent {
+    pub tick: u64,
+    pub node: usize,
+    pub neighbor: usize,
+}
+
+/// Two neighbors whose ledgers don't agree once payments still being delivered are accounted for.
+/// Balances are each side's view, positive if it owes the other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedgerMismatch {
+    pub a: usize,
+    pub b: usize,
+    pub a_balance: Int256,
+    pub b_balance: Int256,
+    pub in_flight: Uint256,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimReport {
+    pub ticks: u64,
+    /// Nodes that stopped forwarding for a neighbor
+    pub suspensions: Vec<SimEvent>,
+    /// Nodes that started forwarding for a neighbor again
+    pub reopenings: Vec<SimEvent>,
+    /// What each node charged its neighbors for forwarding, by node index
+    pub billed: Vec<Uint256>,
+    /// Payments each node got delivered to its neighbors
+    pub paid: Vec<Uint256>,
+    /// Payments delivered to each node
+    pub received: Vec<Uint256>,
+    /// Payments the payment controllers gave up on and put back onto the ledger
+    pub failed_payments: u64,
+    pub mismatches: Vec<LedgerMismatch>,
+}
+
+pub struct Simulation {
+    nodes: Vec<SimNode>,
+    flows: Vec<Flow>,
+    /// (node, neighbor) pairs where node stopped forwarding for neighbor
+    suspended: HashSet<(usize, usize)>,
+    now: Instant,
+    /// The virtual clock as unix time, what the debt keepers' histories are stamped with
+    unix_now: u64,
+    report: SimReport,
+}
+
+/// A node's position towards a neighbor, positive if it owes the neighbor. Payments the neighbor
+/// made ahead of time count as well.
+fn position(debt_data: &NodeDebtData) -> Int256 {
+    balance(debt_data) + debt_data.incoming_payments.clone()
+}
+
+impl Simulation {
+    pub fn new() -> Simulation {
+        Simulation {
+            nodes: Vec::new(),
+            flows: Vec::new(),
+            suspended: HashSet::new(),
+            now: Instant::now(),
+            unix_now: SIM_EPOCH,
+            report: SimReport {
+                ticks: 0,
+                suspensions: Vec::new(),
+                reopenings: Vec::new(),
+                billed: Vec::new(),
+                paid: Vec::new(),
+                received: Vec::new(),
+                failed_payments: 0,
+                mismatches: Vec::new(),
+            },
+        }
+    }
+
+    /// Adds a node charging `price` per byte and returns its index
+    pub fn add_node(&mut self, price: u64, payment: PaymentSettings) -> usize {
+        let index = self.nodes.len();
+        let key: PrivateKey = format!("0x{:064x}", index + 1).parse().unwrap();
+        let identity = Identity {
+            eth_address: key.to_public_key().unwrap(),
+            mesh_ip: format!("2001::{:x}", index + 1).parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+        let backend = MockBackend::new();
+        self.nodes.push(SimNode {
+            identity,
+            key,
+            payment,
+            price,
+            keeper: DebtKeeper::new(),
+            controller: PaymentController::with_backend(Box::new(backend.clone())),
+            backend,
+            neighbors: BTreeSet::new(),
+            freeloader: false,
+        });
+        self.report.billed.push(Uint256::from(0u32));
+        self.report.paid.push(Uint256::from(0u32));
+        self.report.received.push(Uint256::from(0u32));
+        index
+    }
+
+    /// Has `from` send `bytes` every tick through `via`, replacing any earlier flow between them.
+    /// The two become neighbors if they weren't yet.
+    pub fn set_traffic(&mut self, from: usize, via: usize, bytes: u64) {
+        self.connect(from, via);
+        self.connect(via, from);
+        self.flows
+            .retain(|flow| !(flow.from == from && flow.via == via));
+        self.flows.push(Flow { from, via, bytes });
+    }
+
+    /// Replaces the payment settings of a node, buffers are resized before the next tick
+    pub fn set_payment(&mut self, node: usize, payment: PaymentSettings) {
+        self.nodes[node].payment = payment;
+    }
+
+    /// From now on `node` keeps track of what it owes but never actually pays it
+    pub fn stop_paying(&mut self, node: usize) {
+        self.nodes[node].freeloader = true;
+    }
+
+    /// The next `count` deliveries attempted by `node` fail
+    pub fn fail_payments(&mut self, node: usize, count: u32) {
+        self.nodes[node].backend.state.lock().unwrap().failures = count;
+    }
+
+    fn connect(&mut self, node: usize, neighbor: usize) {
+        if !self.nodes[node].neighbors.insert(neighbor) {
+            return;
+        }
+        let ident = self.nodes[neighbor].identity.clone();
+        let node = &mut self.nodes[node];
+        let mut debt_data =
+            NodeDebtData::new(node.payment.policy_for(&ident.eth_address).buffer_period);
+        debt_data.last_payment = self.now;
+        node.keeper.debt_data.insert(ident, debt_data);
+    }
+
+    pub fn run(&mut self, ticks: u64) {
+        for node in self.nodes.iter_mut() {
+            node.keeper.apply_policies(&node.payment);
+        }
+        for _ in 0..ticks {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        self.now += Duration::from_secs(TICK);
+        self.unix_now += TICK;
+        self.report.ticks += 1;
+
+        for flow in self.flows.clone() {
+            if self.suspended.contains(&(flow.via, flow.from)) {
+                continue;
+            }
+            let amount = flow.bytes * self.nodes[flow.via].price;
+            let from_ident = self.nodes[flow.from].identity.clone();
+            let via_ident = self.nodes[flow.via].identity.clone();
+            self.nodes[flow.from].keeper.traffic_update_at(
+                self.unix_now,
+                &via_ident,
+                Int256::from(amount),
+            );
+            self.nodes[flow.via].keeper.traffic_update_at(
+                self.unix_now,
+                &from_ident,
+                -Int256::from(amount),
+            );
+            self.report.billed[flow.via] =
+                self.report.billed[flow.via].clone() + Uint256::from(amount);
+        }
+
+        for index in 0..self.nodes.len() {
+            self.update_node(index);
+        }
+
+        for index in 0..self.nodes.len() {
+            self.deliver_payments(index);
+        }
+    }
+
+    /// Runs the debt keeper update of every neighbor of a node and acts on the outcome
+    fn update_node(&mut self, index: usize) {
+        let neighbors: Vec<usize> = self.nodes[index].neighbors.iter().cloned().collect();
+        for neighbor in neighbors {
+            let ident = self.nodes[neighbor].identity.clone();
+            let node = &mut self.nodes[index];
+            match node.keeper.update_debt(&ident, &node.payment, self.now) {
+                DebtAction::SuspendTunnel => {
+                    if self.suspended.insert((index, neighbor)) {
+                        self.report.suspensions.push(SimEvent {
+                            tick: self.report.ticks,
+                            node: index,
+                            neighbor,
+                        });
+                    }
+                }
+                DebtAction::OpenTunnel => {
+                    if self.suspended.remove(&(index, neighbor)) {
+                        self.report.reopenings.push(SimEvent {
+                            tick: self.report.ticks,
+                            node: index,
+                            neighbor,
+                        });
+                    }
+                }
+                DebtAction::MakePayment { to, amount } => {
+                    if node.freeloader {
+                        continue;
+                    }
+                    node.keeper.record(
+                        self.unix_now,
+                        &to,
+                        LedgerEvent::PaymentSent,
+                        amount.clone(),
+                    );
+                    let pmt = PaymentTx {
+                        to,
+                        from: node.identity.clone(),
+                        amount,
+                        txid: None,
+                        nonce: Uint256::from(0u32),
+                        signature: None,
+                    };
+                    node.controller.queue_payment(pmt, &node.key, self.now);
+                }
+                DebtAction::None => {}
+            }
+        }
+    }
+
+    /// Lets a node's payment controller attempt its deliveries and hands whatever went through to
+    /// the recipients
+    fn deliver_payments(&mut self, index: usize) {
+        let delivered: Vec<PaymentTx> = {
+            let node = &mut self.nodes[index];
+            let result = node.controller.process_queue(self.now);
+            for delivered in result.delivered {
+                node.keeper
+                    .payment_delivered(&delivered.to, delivered.amount);
+            }
+            for failed in result.failed {
+                node.keeper
+                    .payment_failed_at(self.unix_now, &failed.to, failed.amount);
+                self.report.failed_payments += 1;
+            }
+            let mut state = node.backend.state.lock().unwrap();
+            mem::replace(&mut state.sent, Vec::new())
+        }; // borrowck
+
+        for pmt in delivered {
+            let recipient = self
+                .nodes
+                .iter()
+                .position(|node| node.identity == pmt.to)
+                .expect("Payment to a node outside the simulation");
+            self.report.paid[index] = self.report.paid[index].clone() + pmt.amount.clone();
+            self.report.received[recipient] =
+                self.report.received[recipient].clone() + pmt.amount.clone();
+
+            let unix_now = self.unix_now;
+            let node = &mut self.nodes[recipient];
+            let receipt = node
+                .controller
+                .payment_received(IncomingPayment::Tx(pmt))
+                .expect("Payment rejected by the mock backend");
+            if let Some(credit) = receipt.credit {
+                node.keeper
+                    .payment_received_at(unix_now, &credit.from, credit.amount);
+            }
+        }
+    }
+
+    /// What `node` still has queued for `neighbor`
+    fn in_flight(&self, node: usize, neighbor: usize) -> Uint256 {
+        let to = &self.nodes[neighbor].identity;
+        self.nodes[node]
+            .controller
+            .pending_payments()
+            .iter()
+            .filter(|pending| pending.tx.to == *to)
+            .fold(Uint256::from(0u32), |total, pending| {
+                total + pending.tx.amount.clone()
+            })
+    }
+
+    /// Compares the ledgers on both sides of every link. Debt one side recorded as paid must
+    /// either be in the other's books or still be queued for delivery.
+    fn check_ledgers(&self) -> Vec<LedgerMismatch> {
+        let mut mismatches = Vec::new();
+        for a in 0..self.nodes.len() {
+            for &b in self.nodes[a].neighbors.iter().filter(|&&b| b > a) {
+                let a_balance = position(&self.nodes[a].keeper.debt_data[&self.nodes[b].identity]);
+                let b_balance = position(&self.nodes[b].keeper.debt_data[&self.nodes[a].identity]);
+                let in_flight = self.in_flight(a, b) + self.in_flight(b, a);
+                let total = a_balance.clone() + b_balance.clone() + Int256::from(in_flight.clone());
+                if total != Int256::from(0) {
+                    mismatches.push(LedgerMismatch {
+                        a,
+                        b,
+                        a_balance,
+                        b_balance,
+                        in_flight,
+                    });
+                }
+            }
+        }
+        mismatches
+    }
+
+    /// Everything that happened since the simulation started, ledgers are compared as they are now
+    pub fn report(&self) -> SimReport {
+        let mut report = self.report.clone();
+        report.mismatches = self.check_ledgers();
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Node b forwards 1000 bytes a tick for node a at a price of 10
+    fn get_pair(payment: PaymentSettings) -> (Simulation, usize, usize) {
+        let mut sim = Simulation::new();
+        let a = sim.add_node(10, payment.clone());
+        let b = sim.add_node(10, payment);
+        sim.set_traffic(a, b, 1000);
+        (sim, a, b)
+    }
+
+    fn get_lenient_settings() -> PaymentSettings {
+        PaymentSettings {
+            close_threshold: Int256::from(-10_000_000),
+            ..PaymentSettings::default()
+        }
+    }
+
+    #[test]
+    fn test_honest_pair() {
+        let (mut sim, a, b) = get_pair(PaymentSettings::default());
+        sim.run(100);
+        let report = sim.report();
+
+        assert_eq!(report.ticks, 100);
+        assert_eq!(report.suspensions, Vec::new());
+        assert_eq!(report.mismatches, Vec::new());
+        assert_eq!(report.billed[b], Uint256::from(1_000_000u32));
+        assert_eq!(report.paid[a], report.billed[b]);
+        assert_eq!(report.received[b], report.billed[b]);
+    }
+
+    #[test]
+    fn test_history_on_virtual_clock() {
+        let (mut sim, a, b) = get_pair(PaymentSettings::default());
+        // a bit over two hours, so the history spans three buckets
+        sim.run(1500);
+
+        let history = sim.nodes[a].keeper.get_history(0, u64::max_value());
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].identity, sim.nodes[b].identity);
+        let starts: Vec<u64> = history[0]
+            .buckets
+            .iter()
+            .map(|bucket| bucket.start)
+            .collect();
+        assert_eq!(starts, vec![1_499_997_600, 1_500_001_200, 1_500_004_800]);
+
+        let sent = history[0]
+            .buckets
+            .iter()
+            .fold(Uint256::from(0u32), |total, bucket| {
+                total + bucket.payments_sent.clone()
+            });
+        assert_eq!(sent, sim.report().paid[a]);
+    }
+
+    #[test]
+    fn test_freeloader_suspended() {
+        let (mut sim, a, b) = get_pair(PaymentSettings::default());
+        sim.stop_paying(a);
+        sim.run(20);
+        let report = sim.report();
+
+        assert_eq!(
+            report.suspensions,
+            vec![SimEvent {
+                tick: 4,
+                node: b,
+                neighbor: a,
+            }]
+        );
+        // no more traffic is forwarded once the tunnel is suspended
+        assert_eq!(report.billed[b], Uint256::from(40_000u32));
+        assert_eq!(report.received[b], Uint256::from(0u32));
+        assert_eq!(
+            report.mismatches,
+            vec![LedgerMismatch {
+                a,
+                b,
+                a_balance: Int256::from(0),
+                b_balance: Int256::from(-40_000),
+                in_flight: Uint256::from(0u32),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_buffer_period_delays_suspension() {
+        let suspended_at = |buffer_period| {
+            let (mut sim, a, _b) = get_pair(PaymentSettings {
+                buffer_period,
+                ..PaymentSettings::default()
+            });
+            sim.stop_paying(a);
+            sim.run(50);
+            sim.report().suspensions[0].tick
+        };
+        assert_eq!(suspended_at(3), 4);
+        assert_eq!(suspended_at(6), 7);
+    }
+
+    #[test]
+    fn test_close_fraction_grace() {
+        // b lets a run up more debt the more a paid in the past
+        let suspended_at = |close_fraction| {
+            let (mut sim, a, b) = get_pair(PaymentSettings::default());
+            sim.run(100);
+            sim.set_payment(
+                b,
+                PaymentSettings {
+                    close_fraction: Int256::from(close_fraction),
+                    ..PaymentSettings::default()
+                },
+            );
+            sim.stop_paying(a);
+            sim.run(100);
+            sim.report().suspensions[0].tick
+        };
+        assert!(suspended_at(10) > suspended_at(100));
+    }
+
+    #[test]
+    fn test_payment_retried() {
+        let (mut sim, a, b) = get_pair(get_lenient_settings());
+        sim.fail_payments(a, 3);
+        sim.run(5);
+        assert_eq!(sim.report().mismatches, Vec::new());
+        assert_eq!(sim.report().paid[a], Uint256::from(0u32));
+
+        sim.run(15);
+        let report = sim.report();
+        assert_eq!(report.suspensions, Vec::new());
+        assert_eq!(report.failed_payments, 0);
+        assert_eq!(report.mismatches, Vec::new());
+        assert_eq!(report.paid[a], report.billed[b]);
+    }
+
+    #[test]
+    fn test_payment_given_up() {
+        let (mut sim, a, b) = get_pair(get_lenient_settings());
+        // enough failures for the payment controller to give up on the first payment
+        sim.fail_payments(a, 10);
+        sim.run(300);
+        let report = sim.report();
+
+        assert_eq!(report.suspensions, Vec::new());
+        assert_eq!(report.failed_payments, 1);
+        assert_eq!(report.mismatches, Vec::new());
+        assert_eq!(report.paid[a], report.billed[b]);
+    }
+
+    #[test]
+    fn test_line_deterministic() {
+        let scenario = || {
+            let mut sim = Simulation::new();
+            let a = sim.add_node(10, PaymentSettings::default());
+            let b = sim.add_node(20, PaymentSettings::default());
+            let c = sim.add_node(5, PaymentSettings::default());
+            sim.set_traffic(a, b, 1000);
+            sim.set_traffic(b, c, 3000);
+            sim.set_traffic(c, b, 200);
+            sim.stop_paying(a);
+            sim.fail_payments(b, 1);
+            sim.run(50);
+            (sim.report(), a, b)
+        };
+        let (report, a, b) = scenario();
+
+        // a is cut off by b, b and c keep paying each other
+        assert_eq!(report.suspensions.len(), 1);
+        assert_eq!(report.suspensions[0].node, b);
+        assert_eq!(report.suspensions[0].neighbor, a);
+        assert_eq!(report.reopenings, Vec::new());
+        assert_eq!(report.mismatches.len(), 1);
+        assert_eq!((report.mismatches[0].a, report.mismatches[0].b), (a, b));
+        assert!(report.paid[b] > Uint256::from(0u32));
+        assert!(report.paid[c] > Uint256::from(0u32));
+        assert_eq!(report.received[a], Uint256::from(0u32));
+
+        assert_eq!(scenario().0, report);
+    }
+}
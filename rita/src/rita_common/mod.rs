@@ -1,10 +1,15 @@
+pub mod babel_watcher;
 pub mod dao_manager;
 pub mod dashboard;
 pub mod debt_keeper;
 pub mod http_client;
 pub mod network_endpoints;
+pub mod payment_channel;
 pub mod payment_controller;
 pub mod peer_listener;
 pub mod rita_loop;
+pub mod route_history;
+pub mod storage;
+pub mod traffic_reconciler;
 pub mod traffic_watcher;
 pub mod tunnel_manager;
@@ -1,22 +1,25 @@
 //! Network endptoints for common Rita functionality (such as exchanging hello messages)
 
-use althea_types::{LocalIdentity, PaymentTx};
+use althea_types::{ChannelUpdate, LocalIdentity, PaymentTx};
 
 use actix::registry::SystemService;
+use actix_web::http::StatusCode;
 use actix_web::*;
 
-use futures::Future;
+use futures::{future, Future};
 
 use failure::Error;
 
 use settings::RitaCommonSettings;
 use SETTING;
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
 
 use rita_common;
-use rita_common::payment_controller::PaymentController;
+use rita_common::payment_controller::{ChannelUpdateReceived, PaymentController};
 use rita_common::peer_listener::Peer;
+use rita_common::traffic_reconciler::{TrafficReconciler, TrafficReport, TrafficReportReceived};
 use rita_common::tunnel_manager::{IdentityCallback, TunnelManager};
 
 use std::boxed::Box;
@@ -48,8 +51,97 @@ pub fn make_payments(
         .send(rita_common::payment_controller::PaymentReceived(
             pmt.0.clone(),
         )).from_err()
-        .and_then(|_| Ok(HttpResponse::Ok().into()))
-        .responder()
+        .and_then(|res| match res {
+            Ok(()) => Ok(HttpResponse::Ok().into()),
+            Err(e) => {
+                warn!("Rejected payment with {:?}", e);
+                let mut ret = HashMap::new();
+                ret.insert("error".to_owned(), format!("{}", e));
+                Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
+                    .into_builder()
+                    .json(ret))
+            }
+        }).responder()
+}
+
+pub fn channel_update(
+    update: (Json<ChannelUpdate>, HttpRequest),
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    info!(
+        "Got channel update from {:?}",
+        update.1.connection_info().remote()
+    );
+    trace!("Received channel update: {:?}", update.0);
+    PaymentController::from_registry()
+        .send(ChannelUpdateReceived(update.0.into_inner()))
+        .from_err()
+        .and_then(|res| match res {
+            Ok(response) => {
+                if response.accepted {
+                    Ok(HttpResponse::Ok().json(response))
+                } else {
+                    Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
+                        .into_builder()
+                        .json(response))
+                }
+            }
+            Err(e) => {
+                error!("Failed to handle channel update {:?}", e);
+                let mut ret = HashMap::new();
+                ret.insert("error".to_owned(), format!("{}", e));
+                Ok(HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+                    .into_builder()
+                    .json(ret))
+            }
+        }).responder()
+}
+
+/// Reports are only taken from the neighbor they claim to be from, so nobody can skew the
+/// reconciliation with someone else by sending reports in their name. `remote` has to be the
+/// address of the socket, forwarding headers are set by the sender and prove nothing.
+fn check_report_source(remote: Option<SocketAddr>, report: &TrafficReport) -> Result<(), Error> {
+    let remote = match remote {
+        Some(remote) => remote,
+        None => bail!("Can't tell where the traffic report came from"),
+    };
+    if remote.ip() != report.from.mesh_ip {
+        bail!(
+            "Traffic report for {} was sent from {}",
+            report.from.mesh_ip,
+            remote.ip()
+        );
+    }
+    Ok(())
+}
+
+pub fn traffic_report(
+    report: (Json<TrafficReport>, HttpRequest),
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    trace!("Got traffic report from {:?}", report.1.peer_addr());
+    if let Err(e) = check_report_source(report.1.peer_addr(), &report.0) {
+        warn!("Rejected traffic report with {:?}", e);
+        let mut ret = HashMap::new();
+        ret.insert("error".to_owned(), format!("{}", e));
+        return Box::new(future::ok(
+            HttpResponse::new(StatusCode::BAD_REQUEST)
+                .into_builder()
+                .json(ret),
+        ));
+    }
+    TrafficReconciler::from_registry()
+        .send(TrafficReportReceived(report.0.into_inner()))
+        .from_err()
+        .and_then(|res| match res {
+            Ok(()) => Ok(HttpResponse::Ok().into()),
+            Err(e) => {
+                trace!("Rejected traffic report with {:?}", e);
+                let mut ret = HashMap::new();
+                ret.insert("error".to_owned(), format!("{}", e));
+                Ok(HttpResponse::new(StatusCode::BAD_REQUEST)
+                    .into_builder()
+                    .json(ret))
+            }
+        }).responder()
 }
 
 pub fn hello_response(
@@ -101,3 +193,43 @@ pub fn version(_req: HttpRequest) -> String {
         env!("GIT_HASH")
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use althea_types::Identity;
+
+    fn get_report() -> TrafficReport {
+        TrafficReport {
+            from: Identity {
+                mesh_ip: "fd00::2".parse().unwrap(),
+                eth_address: "0x0101010101010101010101010101010101010101"
+                    .parse()
+                    .unwrap(),
+                wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                    .parse()
+                    .unwrap(),
+            },
+            ticks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_report_source() {
+        let report = get_report();
+        let remote = |addr: &str| addr.parse::<SocketAddr>().ok();
+        assert!(check_report_source(remote("[fd00::2]:4876"), &report).is_ok());
+        assert!(check_report_source(remote("[fd00::3]:4876"), &report).is_err());
+        assert!(check_report_source(None, &report).is_err());
+    }
+
+    #[test]
+    fn test_forwarded_report_source() {
+        let report = get_report();
+        // a header claiming the report comes from the neighbor it names
+        let req = TestRequest::with_header("Forwarded", "for=[fd00::2]:4876").finish();
+        assert_eq!(req.connection_info().remote(), Some("[fd00::2]:4876"));
+        assert!(check_report_source(req.peer_addr(), &report).is_err());
+    }
+}
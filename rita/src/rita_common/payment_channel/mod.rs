@@ -0,0 +1,613 @@
+//! Off-chain payment channels with our neighbors. Every payment is a new channel state with a
+//! higher nonce that the payer signs and proposes, once the payee has checked and counter-signed
+//! it the state is final and gets uploaded to the bounty hunter, which can enforce it on chain.
+//!
+//! There are no on-chain deposits yet, so a party's balance is the running total it has been paid
+//! over the channel rather than what's left of a deposit. The payer may only ever increase the
+//! payee's balance, nothing else in a proposal is allowed to change.
+//!
+//! This module only holds the channel logic, the payment controller does the talking.
+
+use althea_types::{ChannelState, ChannelUpdate, ChannelUpdateResponse};
+
+use clarity::{Address, PrivateKey};
+
+use num256::Uint256;
+
+use std::collections::HashMap;
+
+use failure::Error;
+
+/// Version of the on disk channel format, see `PaymentChannels::snapshot`
+pub const CHANNELS_FILE_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChannelsSnapshot {
+    pub version: u32,
+    pub channels: Vec<ChannelState>,
+}
+
+#[derive(Debug, Clone)]
+struct Channel {
+    /// The last state signed by both of us
+    latest: ChannelState,
+    /// A state we proposed and haven't heard back about yet
+    pending: Option<ChannelState>,
+}
+
+#[derive(Default)]
+pub struct PaymentChannels {
+    /// Keyed by the counterparty's address
+    channels: HashMap<Address, Channel>,
+}
+
+impl PaymentChannels {
+    pub fn new() -> PaymentChannels {
+        PaymentChannels {
+            channels: HashMap::new(),
+        }
+    }
+
+    fn channel(&mut self, ours: &Address, theirs: &Address) -> &mut Channel {
+        self.channels
+            .entry(theirs.clone())
+            .or_insert_with(|| Channel {
+                latest: ChannelState::new(ours, theirs),
+                pending: None,
+            })
+    }
+
+    /// The last fully signed state of the channel with `theirs`
+    pub fn latest(&mut self, ours: &Address, theirs: &Address) -> ChannelState {
+        self.channel(ours, theirs).latest.clone()
+    }
+
+    /// Builds and signs the state paying `amount` to `theirs`, replacing any earlier proposal
+    /// that was never answered
+    pub fn propose_payment(
+        &mut self,
+        ours: &Address,
+        key: &PrivateKey,
+        theirs: &Address,
+        amount: &Uint256,
+    ) -> ChannelState {
+        let channel = self.channel(ours, theirs);
+
+        let mut proposal = channel.latest.clone();
+        proposal.nonce = proposal.nonce.clone() + Uint256::from(1u32);
+        proposal.credit(theirs, amount);
+        proposal.signature_a = None;
+        proposal.signature_b = None;
+        proposal.sign(key, ours);
+
+        channel.pending = Some(proposal.clone());
+        proposal
+    }
+
+    /// Forgets our last proposal after failing to deliver it. If it did arrive we'll learn about
+    /// it when the counterparty rejects the retry.
+    pub fn abandon_proposal(&mut self, theirs: &Address) {
+        if let Some(channel) = self.channels.get_mut(theirs) {
+            channel.pending = None;
+        }
+    }
+
+    /// Handles the counterparty's answer to our last proposal, returns the fully signed state if
+    /// the payment went through
+    pub fn handle_response(
+        &mut self,
+        ours: &Address,
+        theirs: &Address,
+        response: ChannelUpdateResponse,
+    ) -> Result<ChannelState, Error> {
+        let channel = self.channel(ours, theirs);
+        let pending = match channel.pending.take() {
+            Some(pending) => pending,
+            None => bail!("No proposal pending for channel with {}", theirs),
+        };
+
+        // The parties have to be checked along with the id, any two keys can produce a fully
+        // signed state that claims our channel id
+        let state = response.state;
+        if state.channel_id != channel.latest.channel_id
+            || state.address_a != channel.latest.address_a
+            || state.address_b != channel.latest.address_b
+            || !state.is_signed_by(ours)
+            || !state.is_fully_signed()
+        {
+            bail!("Invalid channel state in response from {}", theirs);
+        }
+
+        if response.accepted {
+            if state.fingerprint() != pending.fingerprint() {
+                bail!("{} counter-signed a state we didn't propose", theirs);
+            }
+            channel.latest = state.clone();
+            return Ok(state);
+        }
+
+        // They have a state we don't, either we lost it in a restart or our last proposal went
+        // through and only the reply to it got lost. We signed it ourselves so it's safe to adopt.
+        if state.nonce > channel.latest.nonce {
+            info!(
+                "Catching up channel with {} from nonce {} to {}",
+                theirs, channel.latest.nonce, state.nonce
+            );
+            channel.latest = state.clone();
+        }
+
+        if state.fingerprint() == pending.fingerprint() {
+            Ok(state)
+        } else {
+            bail!(
+                "{} rejected channel update: {}",
+                theirs,
+                response
+                    .error
+                    .unwrap_or_else(|| "no reason given".to_string())
+            )
+        }
+    }
+
+    /// Checks and counter-signs an update from a neighbor paying us, returns the fully signed
+    /// state and the amount we were paid. Anything other than the next nonce with only our
+    /// balance increased is rejected, if we are behind the neighbor has to send the state we
+    /// missed along.
+    pub fn receive_update(
+        &mut self,
+        ours: &Address,
+        key: &PrivateKey,
+        update: &ChannelUpdate,
+    ) -> Result<(ChannelState, Uint256), Error> {
+        let theirs = &update.from.eth_address;
+        let state = &update.state;
+        let channel = self.channel(ours, theirs);
+
+        // If both sides propose at once only one proposal can win, the one from the lower address
+        if channel.pending.is_some() && ours.as_bytes() < theirs.as_bytes() {
+            bail!("A proposal of our own is in flight");
+        }
+
+        // The payer knows a state we don't, we lost it in a restart. We signed it ourselves so
+        // it's safe to adopt, just like the payer does in `handle_response`.
+        if let Some(ref known) = update.latest {
+            if known.nonce > channel.latest.nonce
+                && known.channel_id == channel.latest.channel_id
+                && known.address_a == channel.latest.address_a
+                && known.address_b == channel.latest.address_b
+                && known.is_signed_by(ours)
+                && known.is_fully_signed()
+            {
+                info!(
+                    "Catching up channel with {} from nonce {} to {}",
+                    theirs, channel.latest.nonce, known.nonce
+                );
+                channel.latest = known.clone();
+            }
+        }
+        let latest = channel.latest.clone();
+
+        if state.channel_id != latest.channel_id
+            || state.address_a != latest.address_a
+            || state.address_b != latest.address_b
+        {
+            bail!("Update for channel {} is not ours", state.channel_id);
+        }
+        if state.nonce != latest.nonce.clone() + Uint256::from(1u32) {
+            bail!(
+                "Update nonce {} does not follow our nonce {}",
+                state.nonce,
+                latest.nonce
+            );
+        }
+        if state.balance_of(theirs) != latest.balance_of(theirs) {
+            bail!("Update changes the balance of the payer");
+        }
+        if state.balance_of(ours) < latest.balance_of(ours) {
+            bail!("Update reduces our balance");
+        }
+        if !state.is_signed_by(theirs) {
+            bail!("Update is not signed by {}", theirs);
+        }
+
+        let amount = state.balance_of(ours) - latest.balance_of(ours);
+
+        let mut signed = state.clone();
+        signed.sign(key, ours);
+        channel.latest = signed.clone();
+        // whatever we proposed before is based on a state that's now outdated
+        channel.pending = None;
+
+        Ok((signed, amount))
+    }
+
+    /// The latest states of all channels, for saving to disk
+    pub fn snapshot(&self) -> ChannelsSnapshot {
+        ChannelsSnapshot {
+            version: CHANNELS_FILE_VERSION,
+            channels: self
+                .channels
+                .values()
+                .map(|channel| channel.latest.clone())
+                .collect(),
+        }
+    }
+
+    /// Restores channels saved with `snapshot`, states that aren't ours or aren't fully signed
+    /// are skipped
+    pub fn restore(&mut self, ours: &Address, snapshot: ChannelsSnapshot) -> Result<(), Error> {
+        if snapshot.version != CHANNELS_FILE_VERSION {
+            bail!(
+                "Unsupported channels file version {}, expected {}",
+                snapshot.version,
+                CHANNELS_FILE_VERSION
+            );
+        }
+
+        for state in snapshot.channels {
+            if !state.is_party(ours)
+                || (state.nonce > Uint256::from(0u32) && !state.is_fully_signed())
+            {
+                warn!("Skipping invalid saved channel state {:?}", state);
+                continue;
+            }
+            let theirs = if state.address_a == *ours {
+                state.address_b.clone()
+            } else {
+                state.address_a.clone()
+            };
+            self.channels.insert(
+                theirs,
+                Channel {
+                    latest: state,
+                    pending: None,
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_types::Identity;
+
+    struct Node {
+        key: PrivateKey,
+        address: Address,
+        identity: Identity,
+        channels: PaymentChannels,
+    }
+
+    fn get_node(key: &str, mesh_ip: &str) -> Node {
+        let key: PrivateKey = key.parse().unwrap();
+        let address = key.to_public_key().unwrap();
+        Node {
+            key,
+            address: address.clone(),
+            identity: Identity {
+                eth_address: address,
+                mesh_ip: mesh_ip.parse().unwrap(),
+                wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                    .parse()
+                    .unwrap(),
+            },
+            channels: PaymentChannels::new(),
+        }
+    }
+
+    fn get_nodes() -> (Node, Node) {
+        (
+            get_node(
+                "0xfe1b2d1a4f8b0b8a3c1e2f3d4c5b6a79887766554433221100ffeeddccbbaa99",
+                "2001::3",
+            ),
+            get_node(
+                "0x1111111111111111111111111111111111111111111111111111111111111111",
+                "2001::4",
+            ),
+        )
+    }
+
+    /// One full round of `payer` paying `payee` over the channel, with the payee answering the
+    /// way the contact endpoint would
+    fn pay(payer: &mut Node, payee: &mut Node, amount: u32) -> Result<ChannelState, Error> {
+        let proposal = payer.channels.propose_payment(
+            &payer.address,
+            &payer.key,
+            &payee.address,
+            &Uint256::from(amount),
+        );
+        let update = ChannelUpdate {
+            from: payer.identity.clone(),
+            state: proposal,
+            latest: Some(payer.channels.latest(&payer.address, &payee.address)),
+        };
+        let response = match payee
+            .channels
+            .receive_update(&payee.address, &payee.key, &update)
+        {
+            Ok((state, paid)) => {
+                assert_eq!(paid, Uint256::from(amount));
+                ChannelUpdateResponse {
+                    accepted: true,
+                    state,
+                    error: None,
+                }
+            }
+            Err(e) => ChannelUpdateResponse {
+                accepted: false,
+                state: payee.channels.latest(&payee.address, &payer.address),
+                error: Some(format!("{}", e)),
+            },
+        };
+        payer
+            .channels
+            .handle_response(&payer.address, &payee.address, response)
+    }
+
+    #[test]
+    fn test_payments_both_ways() {
+        let (mut a, mut b) = get_nodes();
+
+        pay(&mut a, &mut b, 100).unwrap();
+        pay(&mut b, &mut a, 30).unwrap();
+        let state = pay(&mut a, &mut b, 5).unwrap();
+
+        assert_eq!(state.nonce, Uint256::from(3u32));
+        assert_eq!(state.balance_of(&b.address), Uint256::from(105u32));
+        assert_eq!(state.balance_of(&a.address), Uint256::from(30u32));
+        assert!(state.is_fully_signed());
+        assert_eq!(a.channels.latest(&a.address, &b.address), state);
+        assert_eq!(b.channels.latest(&b.address, &a.address), state);
+    }
+
+    #[test]
+    fn test_lost_reply() {
+        let (mut a, mut b) = get_nodes();
+
+        let proposal =
+            a.channels
+                .propose_payment(&a.address, &a.key, &b.address, &Uint256::from(100u32));
+        b.channels
+            .receive_update(
+                &b.address,
+                &b.key,
+                &ChannelUpdate {
+                    from: a.identity.clone(),
+                    state: proposal.clone(),
+                    latest: None,
+                },
+            )
+            .unwrap();
+
+        // the reply never arrives, the retry is rejected but shows the payment went through
+        let response = ChannelUpdateResponse {
+            accepted: false,
+            state: b.channels.latest(&b.address, &a.address),
+            error: Some("out of sync".to_string()),
+        };
+        let state = a
+            .channels
+            .handle_response(&a.address, &b.address, response)
+            .unwrap();
+        assert_eq!(state.balance_of(&b.address), Uint256::from(100u32));
+    }
+
+    #[test]
+    fn test_catch_up_after_restart() {
+        let (mut a, mut b) = get_nodes();
+
+        pay(&mut a, &mut b, 100).unwrap();
+        pay(&mut a, &mut b, 100).unwrap();
+
+        // a loses its channel states
+        a.channels = PaymentChannels::new();
+        assert!(pay(&mut a, &mut b, 50).is_err());
+        // but learned the latest state from the rejection, so the retry goes through
+        let state = pay(&mut a, &mut b, 50).unwrap();
+        assert_eq!(state.nonce, Uint256::from(3u32));
+        assert_eq!(state.balance_of(&b.address), Uint256::from(250u32));
+    }
+
+    #[test]
+    fn test_payee_catch_up_after_restart() {
+        let (mut a, mut b) = get_nodes();
+
+        pay(&mut a, &mut b, 100).unwrap();
+        pay(&mut a, &mut b, 100).unwrap();
+
+        // b loses its channel states, the update carries the state it missed
+        b.channels = PaymentChannels::new();
+        let state = pay(&mut a, &mut b, 50).unwrap();
+        assert_eq!(state.nonce, Uint256::from(3u32));
+        assert_eq!(state.balance_of(&b.address), Uint256::from(250u32));
+        assert_eq!(b.channels.latest(&b.address, &a.address), state);
+    }
+
+    #[test]
+    fn test_reject_forged_catch_up() {
+        let (mut a, mut b) = get_nodes();
+        pay(&mut a, &mut b, 100).unwrap();
+
+        // a state b never signed can't be used to skip ahead
+        let mut forged = a.channels.latest(&a.address, &b.address);
+        forged.nonce = Uint256::from(10u32);
+        forged.signature_a = None;
+        forged.signature_b = None;
+        forged.sign(&a.key, &a.address);
+        let mut state = forged.clone();
+        state.nonce = Uint256::from(11u32);
+        state.credit(&b.address, &Uint256::from(1u32));
+        state.signature_a = None;
+        state.signature_b = None;
+        state.sign(&a.key, &a.address);
+        let update = ChannelUpdate {
+            from: a.identity.clone(),
+            state,
+            latest: Some(forged),
+        };
+        assert!(b
+            .channels
+            .receive_update(&b.address, &b.key, &update)
+            .is_err());
+        assert_eq!(
+            b.channels.latest(&b.address, &a.address).nonce,
+            Uint256::from(1u32)
+        );
+    }
+
+    #[test]
+    fn test_reject_forged_catch_up_response() {
+        let (mut a, mut b) = get_nodes();
+        let c = get_node(
+            "0x2222222222222222222222222222222222222222222222222222222222222222",
+            "2001::5",
+        );
+        pay(&mut a, &mut b, 100).unwrap();
+
+        // b answers a's next proposal with a state between itself and another key of its own
+        // that claims the id of the channel with a
+        a.channels
+            .propose_payment(&a.address, &a.key, &b.address, &Uint256::from(50u32));
+        let mut forged = ChannelState::new(&b.address, &c.address);
+        forged.channel_id = a.channels.latest(&a.address, &b.address).channel_id;
+        forged.nonce = Uint256::from(10u32);
+        forged.credit(&b.address, &Uint256::from(1_000_000u32));
+        forged.sign(&b.key, &b.address);
+        forged.sign(&c.key, &c.address);
+        assert!(forged.is_fully_signed());
+
+        let response = ChannelUpdateResponse {
+            accepted: false,
+            state: forged,
+            error: Some("out of sync".to_string()),
+        };
+        assert!(a
+            .channels
+            .handle_response(&a.address, &b.address, response)
+            .is_err());
+        let latest = a.channels.latest(&a.address, &b.address);
+        assert_eq!(latest.nonce, Uint256::from(1u32));
+        assert_eq!(latest.balance_of(&b.address), Uint256::from(100u32));
+    }
+
+    #[test]
+    fn test_reject_bad_updates() {
+        let (mut a, mut b) = get_nodes();
+        pay(&mut a, &mut b, 100).unwrap();
+
+        let good =
+            a.channels
+                .propose_payment(&a.address, &a.key, &b.address, &Uint256::from(10u32));
+
+        // the payer tries to credit itself as well
+        let mut state = good.clone();
+        state.credit(&a.address, &Uint256::from(10u32));
+        state.sign(&a.key, &a.address);
+        let update = ChannelUpdate {
+            from: a.identity.clone(),
+            state,
+            latest: None,
+        };
+        assert!(b
+            .channels
+            .receive_update(&b.address, &b.key, &update)
+            .is_err());
+
+        // skipping a nonce
+        let mut state = good.clone();
+        state.nonce = Uint256::from(5u32);
+        state.sign(&a.key, &a.address);
+        let update = ChannelUpdate {
+            from: a.identity.clone(),
+            state,
+            latest: None,
+        };
+        assert!(b
+            .channels
+            .receive_update(&b.address, &b.key, &update)
+            .is_err());
+
+        // not signed by the payer
+        let mut state = good.clone();
+        state.signature_a = None;
+        state.signature_b = None;
+        let update = ChannelUpdate {
+            from: a.identity.clone(),
+            state,
+            latest: None,
+        };
+        assert!(b
+            .channels
+            .receive_update(&b.address, &b.key, &update)
+            .is_err());
+
+        // nothing was accepted so the good update still goes through
+        let update = ChannelUpdate {
+            from: a.identity.clone(),
+            state: good,
+            latest: None,
+        };
+        let (_, paid) = b
+            .channels
+            .receive_update(&b.address, &b.key, &update)
+            .unwrap();
+        assert_eq!(paid, Uint256::from(10u32));
+    }
+
+    #[test]
+    fn test_simultaneous_proposals() {
+        let (a, b) = get_nodes();
+        let (mut low, mut high) = if a.address.as_bytes() < b.address.as_bytes() {
+            (a, b)
+        } else {
+            (b, a)
+        };
+
+        let from_low = low.channels.propose_payment(
+            &low.address,
+            &low.key,
+            &high.address,
+            &Uint256::from(10u32),
+        );
+        let from_high = high.channels.propose_payment(
+            &high.address,
+            &high.key,
+            &low.address,
+            &Uint256::from(20u32),
+        );
+
+        let update = ChannelUpdate {
+            from: high.identity.clone(),
+            state: from_high,
+            latest: None,
+        };
+        assert!(low
+            .channels
+            .receive_update(&low.address, &low.key, &update)
+            .is_err());
+
+        let update = ChannelUpdate {
+            from: low.identity.clone(),
+            state: from_low,
+            latest: None,
+        };
+        let (state, _) = high
+            .channels
+            .receive_update(&high.address, &high.key, &update)
+            .unwrap();
+        assert_eq!(state.balance_of(&high.address), Uint256::from(10u32));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let (mut a, mut b) = get_nodes();
+        let state = pay(&mut a, &mut b, 100).unwrap();
+
+        let mut restored = PaymentChannels::new();
+        restored.restore(&a.address, a.channels.snapshot()).unwrap();
+        assert_eq!(restored.latest(&a.address, &b.address), state);
+    }
+}
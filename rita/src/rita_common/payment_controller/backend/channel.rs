@@ -0,0 +1,225 @@
+//! Payments as co-signed updates of a payment channel with each neighbor, see `payment_channel`
+//! for the protocol itself. Every fully signed state is handed to the bounty hunter.
+
+use althea_types::{ChannelState, ChannelUpdate, ChannelUpdateResponse, PaymentTx};
+
+use clarity::{Address, PrivateKey};
+
+use failure::Error;
+
+use num256::Int256;
+
+use reqwest::{Client, StatusCode};
+
+use std::time::Duration;
+
+use settings::RitaCommonSettings;
+use SETTING;
+
+use super::{IncomingPayment, PaymentBackend, Receipt};
+use rita_common::debt_keeper;
+use rita_common::payment_channel::PaymentChannels;
+use rita_common::storage;
+
+pub struct ChannelBackend {
+    reqwest_client: Client,
+    channels: PaymentChannels,
+}
+
+fn get_key() -> Result<PrivateKey, Error> {
+    SETTING
+        .get_payment()
+        .eth_private_key
+        .clone()
+        .ok_or(format_err!("No eth private key configured"))
+}
+
+impl ChannelBackend {
+    /// Starts out with the channels saved in `channels_file`
+    pub fn new() -> Self {
+        let mut backend = ChannelBackend {
+            reqwest_client: Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .unwrap(),
+            channels: PaymentChannels::new(),
+        };
+        if let Err(e) = backend.load() {
+            error!("Failed to restore payment channels: {:?}", e);
+        }
+        backend
+    }
+
+    fn load(&mut self) -> Result<(), Error> {
+        let ours = get_key()?.to_public_key()?;
+        let path = SETTING.get_payment().channels_file.clone();
+        if let Some(snapshot) = storage::load_json(&path)? {
+            self.channels.restore(&ours, snapshot)?;
+        }
+        Ok(())
+    }
+
+    /// Checks and counter-signs a channel update from a neighbor paying us, a rejected update is
+    /// answered with our latest state so the neighbor can catch up
+    fn channel_update_received(&mut self, update: ChannelUpdate) -> Result<Receipt, Error> {
+        let key = get_key()?;
+        let us = SETTING
+            .get_identity()
+            .ok_or(format_err!("No mesh IP available for Identity yet"))?;
+
+        match self.channels.receive_update(&us.eth_address, &key, &update) {
+            Ok((state, amount)) => {
+                trace!(
+                    "channel payment of {} received from {:?}",
+                    amount,
+                    update.from.mesh_ip
+                );
+                self.persist();
+                self.upload_channel_state(&state);
+                Ok(Receipt {
+                    credit: Some(debt_keeper::PaymentReceived {
+                        from: update.from,
+                        amount,
+                    }),
+                    response: Some(ChannelUpdateResponse {
+                        accepted: true,
+                        state,
+                        error: None,
+                    }),
+                })
+            }
+            Err(e) => {
+                warn!(
+                    "Rejected channel update from {:?}: {:?}",
+                    update.from.mesh_ip, e
+                );
+                Ok(Receipt {
+                    credit: None,
+                    response: Some(ChannelUpdateResponse {
+                        accepted: false,
+                        state: self
+                            .channels
+                            .latest(&us.eth_address, &update.from.eth_address),
+                        error: Some(format!("{}", e)),
+                    }),
+                })
+            }
+        }
+    }
+
+    /// Saves the channels right after every state both sides signed rather than waiting for the
+    /// periodic save, losing one after a restart leaves the channel out of sync with the neighbor
+    fn persist(&self) {
+        if let Err(e) = self.save() {
+            error!("Failed to save payment channels: {:?}", e);
+        }
+    }
+
+    /// Hands a fully signed channel state to the bounty hunter for safekeeping, failures are only
+    /// logged since the next state supersedes this one anyway
+    fn upload_channel_state(&self, state: &ChannelState) {
+        let bounty_url = format!(
+            "http://[{}]:{}/upload_channel_state",
+            SETTING.get_network().bounty_ip,
+            SETTING.get_network().bounty_port
+        );
+        match self.reqwest_client.post(&bounty_url).json(state).send() {
+            Ok(ref r) if r.status() == StatusCode::OK => {
+                trace!("Uploaded channel state {:?}", state.nonce)
+            }
+            Ok(r) => warn!("Bounty hunter rejected channel state with {}", r.status()),
+            Err(e) => warn!("Failed to upload channel state {:?}", e),
+        }
+    }
+}
+
+impl PaymentBackend for ChannelBackend {
+    /// Pays a neighbor by proposing the next state of our channel with them and waiting for them
+    /// to counter-sign it
+    fn make_payment(&mut self, pmt: &PaymentTx) -> Result<(), Error> {
+        let key = get_key()?;
+        let us = SETTING
+            .get_identity()
+            .ok_or(format_err!("No mesh IP available for Identity yet"))?;
+        let theirs = pmt.to.eth_address.clone();
+
+        let latest = self.channels.latest(&us.eth_address, &theirs);
+        let proposal = self
+            .channels
+            .propose_payment(&us.eth_address, &key, &theirs, &pmt.amount);
+        trace!(
+            "proposing channel state {:?} to {:?}",
+            proposal,
+            pmt.to.mesh_ip
+        );
+
+        let neighbor_url = format!(
+            "http://[{}]:{}/channel_update",
+            pmt.to.mesh_ip,
+            SETTING.get_network().rita_contact_port
+        );
+        let response = self
+            .reqwest_client
+            .post(&neighbor_url)
+            .json(&ChannelUpdate {
+                from: us.clone(),
+                state: proposal,
+                latest: Some(latest),
+            }).send()
+            .and_then(|mut r| r.json::<ChannelUpdateResponse>());
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                self.channels.abandon_proposal(&theirs);
+                return Err(e.into());
+            }
+        };
+
+        // a rejection may still have caught us up, so this is saved either way
+        let result = self
+            .channels
+            .handle_response(&us.eth_address, &theirs, response);
+        self.persist();
+        let state = result?;
+        self.upload_channel_state(&state);
+        Ok(())
+    }
+
+    fn confirm_receipt(&mut self, incoming: IncomingPayment) -> Result<Receipt, Error> {
+        match incoming {
+            IncomingPayment::ChannelUpdate(update) => self.channel_update_received(update),
+            IncomingPayment::Tx(pmt) => bail!(
+                "Got a plain payment from {:?} but only channel payments are accepted",
+                pmt.from.mesh_ip
+            ),
+        }
+    }
+
+    /// What neighbors have paid us over all channels minus what we've paid them
+    fn balance(&self) -> Int256 {
+        let ours: Address = match get_key().and_then(|key| Ok(key.to_public_key()?)) {
+            Ok(ours) => ours,
+            Err(_) => return Int256::from(0),
+        };
+        let mut balance = Int256::from(0);
+        for state in self.channels.snapshot().channels {
+            let theirs = if state.address_a == ours {
+                state.address_b.clone()
+            } else {
+                state.address_a.clone()
+            };
+            balance = balance + Int256::from(state.balance_of(&ours))
+                - Int256::from(state.balance_of(&theirs));
+        }
+        balance
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let snapshot = self.channels.snapshot();
+        if snapshot.channels.is_empty() {
+            return Ok(());
+        }
+        let path = SETTING.get_payment().channels_file.clone();
+        storage::save_json(&path, &snapshot)
+    }
+}
@@ -0,0 +1,440 @@
+//! The placeholder backend, payments are signed `PaymentTx` messages posted straight to the
+//! neighbor and nothing ever settles them. Bounty hunters are kept up to date with a balance that
+//! is just as made up.
+
+use althea_types::{Identity, PaymentTx};
+
+use clarity::Address;
+
+use failure::Error;
+
+use num256::{Int256, Uint256};
+
+use reqwest::{Client, StatusCode};
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use serde_json;
+use settings::RitaCommonSettings;
+use SETTING;
+
+use super::{IncomingPayment, PaymentBackend, Receipt};
+use rita_common::debt_keeper;
+use rita_common::payment_controller::PaymentControllerError;
+use rita_common::storage;
+
+/// How many txids we remember per sender for recognizing retried payments
+const RECEIVED_TXID_HISTORY: usize = 100;
+
+/// This updates a "bounty hunter" with the current balance and the last `PaymentTx`.
+/// Bounty hunters are servers which store and possibly enforce the current state of
+/// a channel. Currently they are actually just showing a completely insecure
+/// "fake" balance as a stand-in for the real thing.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BountyUpdate {
+    pub from: Identity,
+    pub balance: Int256,
+    pub tx: PaymentTx,
+}
+
+/// What was last credited from a sender
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ReceivedPayments {
+    /// Nonce of the last payment, anything at or below it is a replay unless its txid is known
+    nonce: Uint256,
+    /// Txids of the most recent payments, a retry of one of them is acknowledged without credit
+    txids: VecDeque<u64>,
+}
+
+pub struct DummyBackend {
+    reqwest_client: Client,
+    balance: Int256,
+    /// By sender, saved to `payment_nonces_file` whenever it changes
+    received: HashMap<Address, ReceivedPayments>,
+    nonces_file: String,
+}
+
+impl DummyBackend {
+    /// Starts out with the nonces saved in `payment_nonces_file`
+    pub fn new() -> Self {
+        DummyBackend::with_nonces_file(&SETTING.get_payment().payment_nonces_file)
+    }
+
+    /// Starts out with the nonces saved at `path` and saves them there
+    pub fn with_nonces_file(path: &str) -> Self {
+        let mut backend = DummyBackend {
+            reqwest_client: Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .unwrap(),
+            balance: Int256::from(0i64),
+            received: HashMap::new(),
+            nonces_file: path.to_string(),
+        };
+        if let Err(e) = backend.load_nonces() {
+            error!("Failed to restore payment nonces: {:?}", e);
+        }
+        backend
+    }
+
+    fn load_nonces(&mut self) -> Result<(), Error> {
+        if let Some(received) = storage::load_json(&self.nonces_file)? {
+            self.received = received;
+        }
+        Ok(())
+    }
+
+    fn save_nonces(&self) -> Result<(), Error> {
+        storage::save_json(&self.nonces_file, &self.received)
+    }
+
+    /// Checks an incoming payment, returns false if we already credited it, meaning the sender is
+    /// retrying a payment whose response got lost, and an error if it should be rejected
+    fn validate_payment(&mut self, pmt: &PaymentTx, our_address: &Address) -> Result<bool, Error> {
+        if !pmt.verify() {
+            bail!(
+                "Payment from {:?} is not signed by {}",
+                pmt.from.mesh_ip,
+                pmt.from.eth_address
+            );
+        }
+        if pmt.to.eth_address != *our_address {
+            bail!("Payment is addressed to {}, not us", pmt.to.eth_address);
+        }
+
+        if let Some(received) = self.received.get(&pmt.from.eth_address) {
+            if let Some(txid) = pmt.txid {
+                if received.txids.contains(&txid) {
+                    return Ok(false);
+                }
+            }
+            if pmt.nonce <= received.nonce {
+                bail!(
+                    "Replayed payment from {:?}, nonce {} is not above {}",
+                    pmt.from.mesh_ip,
+                    pmt.nonce,
+                    received.nonce
+                );
+            }
+        }
+
+        let received = self
+            .received
+            .entry(pmt.from.eth_address.clone())
+            .or_insert_with(|| ReceivedPayments {
+                nonce: pmt.nonce.clone(),
+                txids: VecDeque::new(),
+            });
+        received.nonce = pmt.nonce.clone();
+        if let Some(txid) = pmt.txid {
+            received.txids.push_back(txid);
+            if received.txids.len() > RECEIVED_TXID_HISTORY {
+                received.txids.pop_front();
+            }
+        }
+        Ok(true)
+    }
+
+    fn update_bounty_actual(&self, update: BountyUpdate) -> Result<(), Error> {
+        trace!("Sending bounty hunter update: {:?}", update);
+        let bounty_url = format!(
+            "http://[{}]:{}/update",
+            SETTING.get_network().bounty_ip,
+            SETTING.get_network().bounty_port
+        );
+
+        let mut r = self
+            .reqwest_client
+            .post(&bounty_url)
+            .body(serde_json::to_string(&update)?)
+            .send()?;
+
+        if r.status() == StatusCode::OK {
+            Ok(())
+        } else {
+            trace!("Unsuccessfully in sending update to bounty hunter");
+            trace!(
+                "Received error from bounty hunter: {:?}",
+                r.text().unwrap_or(String::from("No message received"))
+            );
+            Err(Error::from(PaymentControllerError::BountyError(
+                String::from(format!(
+                    "Received error from bounty hunter: {:?}",
+                    r.text().unwrap_or(String::from("No message received"))
+                )),
+            )))
+        }
+    }
+
+    fn update_bounty(&self, update: BountyUpdate) -> Result<(), Error> {
+        match self.update_bounty_actual(update) {
+            Ok(()) => {}
+            Err(err) => warn!("Bounty hunter returned error {:?}, ignoring", err),
+        };
+        Ok(())
+    }
+
+    /// This gets called when a payment from a counterparty has arrived, and updates
+    /// the balance in memory and sends an update to the "bounty hunter". Returns `None` for
+    /// payments that were already credited.
+    fn payment_received(
+        &mut self,
+        pmt: PaymentTx,
+    ) -> Result<Option<debt_keeper::PaymentReceived>, Error> {
+        let our_id = SETTING
+            .get_identity()
+            .ok_or(format_err!("No mesh IP available for Identity yet"))?;
+        if !self.validate_payment(&pmt, &our_id.eth_address)? {
+            info!(
+                "payment {:?} from {:?} was already credited, ignoring",
+                pmt.txid, pmt.from.mesh_ip
+            );
+            return Ok(None);
+        }
+        // saved right away rather than periodically, a replay window after a crash is money lost
+        if let Err(e) = self.save_nonces() {
+            error!("Failed to save payment nonces: {:?}", e);
+        }
+
+        trace!("current balance: {:?}", self.balance);
+        trace!(
+            "payment of {:?} received from {:?}: {:?}",
+            pmt.amount,
+            pmt.from.mesh_ip,
+            pmt
+        );
+
+        self.balance = self.balance.clone() + Int256::from(pmt.amount.clone());
+
+        trace!("current balance: {:?}", self.balance);
+
+        self.update_bounty(BountyUpdate {
+            from: our_id,
+            tx: pmt.clone(),
+            balance: self.balance.clone(),
+        })?;
+        Ok(Some(debt_keeper::PaymentReceived {
+            from: pmt.from,
+            amount: pmt.amount.clone(),
+        }))
+    }
+}
+
+impl Default for DummyBackend {
+    fn default() -> DummyBackend {
+        DummyBackend::new()
+    }
+}
+
+impl PaymentBackend for DummyBackend {
+    /// Sends the PaymentTx to the `mesh_ip` in its `to` field
+    fn make_payment(&mut self, pmt: &PaymentTx) -> Result<(), Error> {
+        trace!("current balance: {:?}", self.balance);
+
+        trace!(
+            "sending payment of {:?} to {:?}: {:?}",
+            pmt.amount,
+            pmt.to.mesh_ip,
+            pmt
+        );
+
+        let neighbor_url = format!(
+            "http://[{}]:{}/make_payment",
+            pmt.to.mesh_ip,
+            SETTING.get_network().rita_contact_port
+        );
+
+        let mut r = self.reqwest_client.post(&neighbor_url).json(pmt).send()?;
+
+        if r.status() == StatusCode::OK {
+            self.balance = self.balance.clone() - Int256::from(pmt.amount.clone());
+            self.update_bounty(BountyUpdate {
+                from: SETTING
+                    .get_identity()
+                    .ok_or(format_err!("No mesh IP available for Identity yet"))?,
+                tx: pmt.clone(),
+                balance: self.balance.clone(),
+            })?;
+            Ok(())
+        } else {
+            trace!("Unsuccessfully paid");
+            trace!(
+                "Received error from payee: {:?}",
+                r.text().unwrap_or(String::from("No message received"))
+            );
+            Err(Error::from(PaymentControllerError::PaymentSendingError(
+                String::from(format!(
+                    "Received error from payee: {:?}",
+                    r.text().unwrap_or(String::from("No message received"))
+                )),
+            )))
+        }
+    }
+
+    fn confirm_receipt(&mut self, incoming: IncomingPayment) -> Result<Receipt, Error> {
+        match incoming {
+            IncomingPayment::Tx(pmt) => Ok(Receipt {
+                credit: self.payment_received(pmt)?,
+                response: None,
+            }),
+            IncomingPayment::ChannelUpdate(update) => bail!(
+                "Got a channel update from {:?} but payment channels are not in use",
+                update.from.mesh_ip
+            ),
+        }
+    }
+
+    fn balance(&self) -> Int256 {
+        self.balance.clone()
+    }
+
+    /// Updates the bounty hunter with our current balance
+    fn update(&mut self) -> Result<(), Error> {
+        let our_id = SETTING
+            .get_identity()
+            .ok_or(format_err!("No mesh IP available for Identity yet"))?;
+        self.update_bounty(BountyUpdate {
+            from: our_id.clone(),
+            tx: PaymentTx {
+                from: our_id.clone(),
+                to: our_id.clone(),
+                amount: Uint256::from(0u32),
+                txid: None,
+                nonce: Uint256::from(0u32),
+                signature: None,
+            },
+            balance: self.balance.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clarity::PrivateKey;
+    use std::env;
+    use std::fs;
+
+    /// A backend saving its nonces to a file of its own under the temp dir, which is removed
+    /// first so nothing is left over from an earlier run
+    fn get_test_backend(name: &str) -> (DummyBackend, String) {
+        let path = env::temp_dir().join(format!("rita-dummy-backend-{}.json", name));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+        (DummyBackend::with_nonces_file(&path), path)
+    }
+
+    fn get_test_key() -> PrivateKey {
+        "0xfe1b2d1a4f8b0b8a3c1e2f3d4c5b6a79887766554433221100ffeeddccbbaa99"
+            .parse()
+            .unwrap()
+    }
+
+    /// Payments from the test key with increasing nonces, signed the way the sending side would
+    fn get_signed_payments(txids: Vec<Option<u64>>) -> Vec<PaymentTx> {
+        let from = Identity {
+            eth_address: get_test_key().to_public_key().unwrap(),
+            mesh_ip: "2001::3".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+        let to = Identity {
+            eth_address: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            mesh_ip: "2001::4".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+        txids
+            .into_iter()
+            .enumerate()
+            .map(|(i, txid)| {
+                let mut pmt = PaymentTx {
+                    to: to.clone(),
+                    from: from.clone(),
+                    amount: Uint256::from(100u32),
+                    txid,
+                    nonce: Uint256::from(i as u64 + 1),
+                    signature: None,
+                };
+                pmt.sign(&get_test_key());
+                pmt
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_duplicate_txid() {
+        let pmts = get_signed_payments(vec![Some(1), Some(2)]);
+        let us = pmts[0].to.eth_address.clone();
+
+        let (mut backend, _) = get_test_backend("duplicate-txid");
+        assert!(backend.validate_payment(&pmts[0], &us).unwrap());
+        assert!(backend.validate_payment(&pmts[1], &us).unwrap());
+        // a retry of an already credited payment is accepted but not credited again
+        assert!(!backend.validate_payment(&pmts[0], &us).unwrap());
+        assert!(!backend.validate_payment(&pmts[1], &us).unwrap());
+    }
+
+    #[test]
+    fn test_replayed_nonce() {
+        let pmts = get_signed_payments(vec![None, None]);
+        let us = pmts[0].to.eth_address.clone();
+
+        let (mut backend, _) = get_test_backend("replayed-nonce");
+        assert!(backend.validate_payment(&pmts[1], &us).unwrap());
+        // older nonce from the same sender
+        assert!(backend.validate_payment(&pmts[0], &us).is_err());
+        // without a txid a resend can't be told apart from a replay
+        assert!(backend.validate_payment(&pmts[1], &us).is_err());
+    }
+
+    #[test]
+    fn test_replay_after_restart() {
+        let pmts = get_signed_payments(vec![Some(1), Some(2)]);
+        let us = pmts[0].to.eth_address.clone();
+
+        let (mut backend, path) = get_test_backend("replay-after-restart");
+        assert!(backend.validate_payment(&pmts[0], &us).unwrap());
+        backend.save_nonces().unwrap();
+
+        // a retry of the credited payment is still recognized and acknowledged without credit
+        let mut backend = DummyBackend::with_nonces_file(&path);
+        assert!(!backend.validate_payment(&pmts[0], &us).unwrap());
+        assert!(backend.validate_payment(&pmts[1], &us).unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_forged_payment() {
+        let mut pmt = get_signed_payments(vec![Some(1)]).remove(0);
+        let us = pmt.to.eth_address.clone();
+        let (mut backend, _) = get_test_backend("forged-payment");
+
+        let mut unsigned = pmt.clone();
+        unsigned.signature = None;
+        assert!(backend.validate_payment(&unsigned, &us).is_err());
+
+        let mut tampered = pmt.clone();
+        tampered.amount = Uint256::from(1_000_000u32);
+        assert!(backend.validate_payment(&tampered, &us).is_err());
+
+        let other: Address = "0x0000000000000000000000000000000000000002"
+            .parse()
+            .unwrap();
+        assert!(backend.validate_payment(&pmt, &other).is_err());
+
+        pmt.signature = None;
+        pmt.sign(
+            &"0x1111111111111111111111111111111111111111111111111111111111111111"
+                .parse()
+                .unwrap(),
+        );
+        assert!(backend.validate_payment(&pmt, &us).is_err());
+    }
+}
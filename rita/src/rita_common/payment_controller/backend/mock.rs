@@ -0,0 +1,80 @@
+//! A backend for tests, payments are recorded instead of sent and deliveries can be made to fail
+
+use failure::Error;
+
+use num256::Int256;
+
+use std::sync::{Arc, Mutex};
+
+use althea_types::PaymentTx;
+
+use super::{IncomingPayment, PaymentBackend, Receipt};
+use rita_common::debt_keeper;
+
+#[derive(Debug)]
+pub struct MockBackendState {
+    /// Payments that were delivered, in order
+    pub sent: Vec<PaymentTx>,
+    /// Delivery attempts, successful or not
+    pub attempts: u32,
+    /// How many of the next delivery attempts fail
+    pub failures: u32,
+    pub balance: Int256,
+}
+
+/// Clones share their state, so a test can keep one to look into the one it handed out
+#[derive(Clone)]
+pub struct MockBackend {
+    pub state: Arc<Mutex<MockBackendState>>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        MockBackend {
+            state: Arc::new(Mutex::new(MockBackendState {
+                sent: Vec::new(),
+                attempts: 0,
+                failures: 0,
+                balance: Int256::from(0),
+            })),
+        }
+    }
+}
+
+impl PaymentBackend for MockBackend {
+    fn make_payment(&mut self, pmt: &PaymentTx) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.attempts += 1;
+        if state.failures > 0 {
+            state.failures -= 1;
+            bail!("Mock delivery failure");
+        }
+        state.balance = state.balance.clone() - Int256::from(pmt.amount.clone());
+        state.sent.push(pmt.clone());
+        Ok(())
+    }
+
+    fn confirm_receipt(&mut self, incoming: IncomingPayment) -> Result<Receipt, Error> {
+        match incoming {
+            IncomingPayment::Tx(pmt) => {
+                if !pmt.verify() {
+                    bail!("Payment is not signed by its sender");
+                }
+                let mut state = self.state.lock().unwrap();
+                state.balance = state.balance.clone() + Int256::from(pmt.amount.clone());
+                Ok(Receipt {
+                    credit: Some(debt_keeper::PaymentReceived {
+                        from: pmt.from,
+                        amount: pmt.amount,
+                    }),
+                    response: None,
+                })
+            }
+            IncomingPayment::ChannelUpdate(_) => bail!("The mock backend has no channels"),
+        }
+    }
+
+    fn balance(&self) -> Int256 {
+        self.state.lock().unwrap().balance.clone()
+    }
+}
@@ -0,0 +1,71 @@
+//! Ways of actually moving money between neighbors. The payment controller decides what gets paid
+//! and queues, signs and retries payments, a `PaymentBackend` delivers them and checks the ones
+//! that arrive. Which backend is used is picked by `PaymentSettings.backend`.
+
+use althea_types::{ChannelUpdate, ChannelUpdateResponse, PaymentTx};
+
+use failure::Error;
+
+use num256::Int256;
+
+use settings::{PaymentBackendKind, RitaCommonSettings};
+use SETTING;
+
+use rita_common::debt_keeper;
+
+mod channel;
+mod dummy;
+#[cfg(test)]
+mod mock;
+
+pub use self::channel::ChannelBackend;
+pub use self::dummy::{BountyUpdate, DummyBackend};
+#[cfg(test)]
+pub use self::mock::{MockBackend, MockBackendState};
+
+/// A payment arriving from a neighbor, in whatever form the sending backend uses
+#[derive(Debug, Clone)]
+pub enum IncomingPayment {
+    Tx(PaymentTx),
+    ChannelUpdate(ChannelUpdate),
+}
+
+/// What came out of checking an incoming payment
+#[derive(Debug, Default)]
+pub struct Receipt {
+    /// Who to credit with how much, `None` if there's nothing new to credit, for example because
+    /// the sender retried a payment we already have
+    pub credit: Option<debt_keeper::PaymentReceived>,
+    /// The answer for the sender if the backend has one, channel updates get our latest state back
+    pub response: Option<ChannelUpdateResponse>,
+}
+
+pub trait PaymentBackend {
+    /// Delivers a signed payment, an error means it may not have arrived and it will be retried
+    fn make_payment(&mut self, pmt: &PaymentTx) -> Result<(), Error>;
+
+    /// Checks a payment from a neighbor, an error means it was rejected
+    fn confirm_receipt(&mut self, incoming: IncomingPayment) -> Result<Receipt, Error>;
+
+    /// Our balance as far as this backend can tell
+    fn balance(&self) -> Int256;
+
+    /// Called periodically for anything the backend has to keep up with
+    fn update(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called periodically to persist whatever has to survive a restart
+    fn save(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+pub fn backend_from_settings() -> Box<PaymentBackend> {
+    let kind = SETTING.get_payment().backend;
+    info!("Using the {:?} payment backend", kind);
+    match kind {
+        PaymentBackendKind::Dummy => Box::new(DummyBackend::new()),
+        PaymentBackendKind::Channel => Box::new(ChannelBackend::new()),
+    }
+}
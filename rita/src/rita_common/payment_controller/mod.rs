@@ -2,24 +2,34 @@
 
 use actix::prelude::*;
 
-use althea_types::{Identity, PaymentTx};
+use althea_types::{ChannelUpdate, ChannelUpdateResponse, PaymentTx};
+
+use clarity::PrivateKey;
 
 use num256::{Int256, Uint256};
 
-use reqwest::{Client, StatusCode};
+use rand::random;
 
-use std::time::Duration;
+use std::cmp;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use settings::RitaCommonSettings;
 use SETTING;
 
-use reqwest;
 use rita_common::debt_keeper;
 use rita_common::debt_keeper::DebtKeeper;
-use serde_json;
 
 use failure::Error;
 
+mod backend;
+
+pub use self::backend::{
+    BountyUpdate, ChannelBackend, DummyBackend, IncomingPayment, PaymentBackend, Receipt,
+};
+#[cfg(test)]
+pub use self::backend::{MockBackend, MockBackendState};
+
 #[derive(Debug, Fail)]
 pub enum PaymentControllerError {
     #[fail(display = "Payment Sending Error: {:?}", _0)]
@@ -28,9 +38,61 @@ pub enum PaymentControllerError {
     BountyError(String),
 }
 
+/// How many times delivery of a payment is attempted before it's handed back to the debt keeper
+const MAX_PAYMENT_ATTEMPTS: u32 = 10;
+
+/// Delay before the first retry of a failed payment, doubled with every further failure
+const PAYMENT_RETRY_BASE: u64 = 5;
+
+/// Upper bound on the delay between retries
+const PAYMENT_RETRY_MAX: u64 = 300;
+
+/// How often the backend gets to persist its state
+const BACKEND_SAVE_INTERVAL: u64 = 300;
+
+/// Delay before the next delivery attempt of a payment that failed `attempts` times
+fn payment_retry_backoff(attempts: u32) -> Duration {
+    let exponent = cmp::min(attempts.saturating_sub(1), 16);
+    Duration::from_secs(cmp::min(
+        PAYMENT_RETRY_BASE * 2u64.pow(exponent),
+        PAYMENT_RETRY_MAX,
+    ))
+}
+
+/// A payment that has been deducted from the debt keeper's ledger but not yet delivered
+#[derive(Debug, Clone)]
+pub struct PendingPayment {
+    pub tx: PaymentTx,
+    pub attempts: u32,
+    pub next_attempt: Instant,
+}
+
+/// What became of the payments handled by one pass over the outgoing queue
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct QueueResult {
+    pub delivered: Vec<debt_keeper::PaymentDelivered>,
+    /// Payments that ran out of attempts and have been dropped from the queue
+    pub failed: Vec<debt_keeper::PaymentFailed>,
+}
+
+impl QueueResult {
+    /// Lets the debt keeper know which of its payments are settled
+    fn report(self) {
+        for delivered in self.delivered {
+            DebtKeeper::from_registry().do_send(delivered);
+        }
+        for failed in self.failed {
+            DebtKeeper::from_registry().do_send(failed);
+        }
+    }
+}
+
 pub struct PaymentController {
-    pub reqwest_client: Client,
-    pub balance: Int256,
+    backend: Box<PaymentBackend>,
+    /// Payments waiting to be delivered, in the order they were made
+    outgoing: VecDeque<PendingPayment>,
+    /// Nonce of the last payment we signed
+    last_nonce: Uint256,
 }
 
 impl Actor for PaymentController {
@@ -38,22 +100,59 @@ impl Actor for PaymentController {
 }
 impl Supervised for PaymentController {}
 impl SystemService for PaymentController {
-    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+    fn service_started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(Duration::from_secs(BACKEND_SAVE_INTERVAL), |act, _ctx| {
+            if let Err(e) = act.backend.save() {
+                error!("Failed to save payment backend state: {:?}", e);
+            }
+        });
+
         info!("Payment Controller started");
     }
 }
 
-#[derive(Message)]
 pub struct PaymentReceived(pub PaymentTx);
 
+impl Message for PaymentReceived {
+    type Result = Result<(), Error>;
+}
+
 impl Handler<PaymentReceived> for PaymentController {
-    type Result = ();
+    type Result = Result<(), Error>;
 
     fn handle(&mut self, msg: PaymentReceived, _: &mut Context<Self>) -> Self::Result {
-        DebtKeeper::from_registry().do_send(self.payment_received(msg.0).unwrap());
+        let receipt = self.payment_received(IncomingPayment::Tx(msg.0))?;
+        if let Some(credit) = receipt.credit {
+            DebtKeeper::from_registry().do_send(credit);
+        }
+        Ok(())
     }
 }
 
+/// A channel update from a neighbor paying us
+pub struct ChannelUpdateReceived(pub ChannelUpdate);
+
+impl Message for ChannelUpdateReceived {
+    type Result = Result<ChannelUpdateResponse, Error>;
+}
+
+impl Handler<ChannelUpdateReceived> for PaymentController {
+    type Result = Result<ChannelUpdateResponse, Error>;
+
+    fn handle(&mut self, msg: ChannelUpdateReceived, _: &mut Context<Self>) -> Self::Result {
+        let receipt = self.payment_received(IncomingPayment::ChannelUpdate(msg.0))?;
+        if let Some(credit) = receipt.credit {
+            DebtKeeper::from_registry().do_send(credit);
+        }
+        match receipt.response {
+            Some(response) => Ok(response),
+            None => bail!("Payment backend had no answer for the channel update"),
+        }
+    }
+}
+
+/// Queues a payment for delivery, it's retried with backoff until it goes through or runs out of
+/// attempts in which case the debt keeper is told to put the amount back on the ledger
 #[derive(Message, Clone)]
 pub struct MakePayment(pub PaymentTx);
 
@@ -61,13 +160,21 @@ impl Handler<MakePayment> for PaymentController {
     type Result = ();
 
     fn handle(&mut self, msg: MakePayment, _ctx: &mut Context<Self>) -> Self::Result {
-        match self.make_payment(msg.clone().0) {
-            Ok(()) => {}
-            Err(err) => {
-                warn!("got error from make payment {:?}, retrying", err);
-                // ctx.notify_later(msg, Duration::from_secs(5));
+        let key = SETTING.get_payment().eth_private_key.clone();
+        match key {
+            Some(key) => self.queue_payment(msg.0, &key, Instant::now()),
+            None => {
+                error!(
+                    "No eth private key configured, can't sign payment {:?}",
+                    msg.0
+                );
+                DebtKeeper::from_registry().do_send(debt_keeper::PaymentFailed {
+                    to: msg.0.to,
+                    amount: msg.0.amount,
+                });
             }
         }
+        self.process_queue(Instant::now()).report();
     }
 }
 
@@ -78,6 +185,8 @@ impl Handler<PaymentControllerUpdate> for PaymentController {
     type Result = ();
 
     fn handle(&mut self, _msg: PaymentControllerUpdate, _ctx: &mut Context<Self>) -> Self::Result {
+        self.process_queue(Instant::now()).report();
+
         match self.update() {
             Ok(()) => {}
             Err(err) => {
@@ -97,24 +206,10 @@ impl Message for GetOwnBalance {
 impl Handler<GetOwnBalance> for PaymentController {
     type Result = Result<Int256, Error>;
     fn handle(&mut self, _msg: GetOwnBalance, _: &mut Context<Self>) -> Self::Result {
-        Ok(self.balance.clone())
+        Ok(self.backend.balance())
     }
 }
 
-/// This updates a "bounty hunter" with the current balance and the last `PaymentTx`.
-/// Bounty hunters are servers which store and possibly enforce the current state of
-/// a channel. Currently they are actually just showing a completely insecure
-/// "fake" balance as a stand-in for the real thing.
-#[derive(Serialize, Deserialize, Debug)]
-pub struct BountyUpdate {
-    pub from: Identity,
-    pub balance: Int256,
-    pub tx: PaymentTx,
-}
-
-#[cfg(test)]
-extern crate mockito;
-
 impl Default for PaymentController {
     fn default() -> PaymentController {
         PaymentController::new()
@@ -122,157 +217,275 @@ impl Default for PaymentController {
 }
 
 impl PaymentController {
+    /// Uses the backend picked in `PaymentSettings`
     pub fn new() -> Self {
+        PaymentController::with_backend(backend::backend_from_settings())
+    }
+
+    pub fn with_backend(backend: Box<PaymentBackend>) -> Self {
         PaymentController {
-            reqwest_client: reqwest::Client::builder()
-                .timeout(Duration::from_secs(5))
-                .build()
-                .unwrap(),
-            balance: Int256::from(0i64),
+            backend,
+            outgoing: VecDeque::new(),
+            last_nonce: Uint256::from(0u32),
         }
     }
 
-    fn update_bounty_actual(&self, update: BountyUpdate) -> Result<(), Error> {
-        trace!("Sending bounty hunter update: {:?}", update);
-        let bounty_url = if cfg!(not(test)) {
-            format!(
-                "http://[{}]:{}/update",
-                SETTING.get_network().bounty_ip,
-                SETTING.get_network().bounty_port
-            )
-        } else {
-            String::from("http://127.0.0.1:1234/update") //TODO: This is mockito::SERVER_URL, but don't want to include the crate in a non-test build just for that string
+    /// Nonces are based on the clock so that they keep increasing across restarts without having
+    /// to be stored, but never go backwards if the clock does
+    fn next_nonce(&mut self) -> Uint256 {
+        let millis = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() * 1000 + u64::from(d.subsec_millis()),
+            Err(_) => 0,
         };
+        let nonce = cmp::max(
+            Uint256::from(millis),
+            self.last_nonce.clone() + Uint256::from(1u32),
+        );
+        self.last_nonce = nonce.clone();
+        nonce
+    }
 
-        let mut r = self
-            .reqwest_client
-            .post(&bounty_url)
-            .body(serde_json::to_string(&update)?)
-            .send()?;
-
-        if r.status() == StatusCode::OK {
-            Ok(())
-        } else {
-            trace!("Unsuccessfully in sending update to bounty hunter");
-            trace!(
-                "Received error from bounty hunter: {:?}",
-                r.text().unwrap_or(String::from("No message received"))
-            );
-            Err(Error::from(PaymentControllerError::BountyError(
-                String::from(format!(
-                    "Received error from bounty hunter: {:?}",
-                    r.text().unwrap_or(String::from("No message received"))
-                )),
-            )))
+    /// Adds a payment to the back of the outgoing queue, giving it a txid if it doesn't have one
+    /// and signing it. Retries send the exact same signed payment.
+    pub fn queue_payment(&mut self, mut pmt: PaymentTx, key: &PrivateKey, now: Instant) {
+        if pmt.txid.is_none() {
+            pmt.txid = Some(random());
         }
+        pmt.nonce = self.next_nonce();
+        pmt.sign(key);
+        trace!("queueing payment {:?}", pmt);
+        self.outgoing.push_back(PendingPayment {
+            tx: pmt,
+            attempts: 0,
+            next_attempt: now,
+        });
     }
 
-    fn update_bounty(&self, update: BountyUpdate) -> Result<(), Error> {
-        match self.update_bounty_actual(update) {
-            Ok(()) => {}
-            Err(err) => warn!("Bounty hunter returned error {:?}, ignoring", err),
-        };
-        Ok(())
+    /// Attempts delivery of every queued payment that is due. Payments to the same neighbor are
+    /// delivered strictly in order, so one waiting on a retry holds back the ones behind it.
+    pub fn process_queue(&mut self, now: Instant) -> QueueResult {
+        let mut result = QueueResult::default();
+        let mut held_back = HashSet::new();
+        let mut remaining = VecDeque::new();
+
+        while let Some(mut pending) = self.outgoing.pop_front() {
+            if held_back.contains(&pending.tx.to) || pending.next_attempt > now {
+                held_back.insert(pending.tx.to.clone());
+                remaining.push_back(pending);
+                continue;
+            }
+
+            match self.backend.make_payment(&pending.tx) {
+                Ok(()) => result.delivered.push(debt_keeper::PaymentDelivered {
+                    to: pending.tx.to,
+                    amount: pending.tx.amount,
+                }),
+                Err(e) => {
+                    pending.attempts += 1;
+                    if pending.attempts >= MAX_PAYMENT_ATTEMPTS {
+                        error!(
+                            "giving up on payment {:?} after {} attempts, last error {:?}",
+                            pending.tx, pending.attempts, e
+                        );
+                        result.failed.push(debt_keeper::PaymentFailed {
+                            to: pending.tx.to,
+                            amount: pending.tx.amount,
+                        });
+                    } else {
+                        let backoff = payment_retry_backoff(pending.attempts);
+                        warn!(
+                            "payment {:?} failed with {:?}, retrying in {}s",
+                            pending.tx,
+                            e,
+                            backoff.as_secs()
+                        );
+                        pending.next_attempt = now + backoff;
+                        held_back.insert(pending.tx.to.clone());
+                        remaining.push_back(pending);
+                    }
+                }
+            }
+        }
+
+        self.outgoing = remaining;
+        result
     }
 
-    /// This gets called when a payment from a counterparty has arrived, and updates
-    /// the balance in memory and sends an update to the "bounty hunter".
-    pub fn payment_received(
-        &mut self,
-        pmt: PaymentTx,
-    ) -> Result<debt_keeper::PaymentReceived, Error> {
-        trace!("current balance: {:?}", self.balance);
-        trace!(
-            "payment of {:?} received from {:?}: {:?}",
-            pmt.amount,
-            pmt.from.mesh_ip,
-            pmt
-        );
+    /// Payments queued but not delivered yet
+    pub fn pending_payments(&self) -> Vec<PendingPayment> {
+        self.outgoing.iter().cloned().collect()
+    }
 
-        self.balance = self.balance.clone() + Int256::from(pmt.amount.clone());
-
-        trace!("current balance: {:?}", self.balance);
-
-        self.update_bounty(BountyUpdate {
-            from: SETTING
-                .get_identity()
-                .ok_or(format_err!("No mesh IP available for Identity yet"))?,
-            tx: pmt.clone(),
-            balance: self.balance.clone(),
-        })?;
-        Ok(debt_keeper::PaymentReceived {
-            from: pmt.from,
-            amount: pmt.amount.clone(),
-        })
+    /// Checks a payment that arrived from a neighbor with the backend
+    pub fn payment_received(&mut self, incoming: IncomingPayment) -> Result<Receipt, Error> {
+        trace!("payment received: {:?}", incoming);
+        self.backend.confirm_receipt(incoming)
     }
 
-    /// This should be called on a regular interval to update the bounty hunter of a node's current
-    /// balance as well as to log the current balance
+    /// This should be called on a regular interval to let the backend keep up with whatever it
+    /// needs to, like updating the bounty hunter, as well as to log the current balance
     pub fn update(&mut self) -> Result<(), Error> {
-        let our_id = SETTING
-            .get_identity()
-            .ok_or(format_err!("No mesh IP available for Identity yet"))?;
-        self.update_bounty(BountyUpdate {
-            from: our_id.clone(),
-            tx: PaymentTx {
-                from: our_id.clone(),
-                to: our_id.clone(),
-                amount: Uint256::from(0u32),
-            },
-            balance: self.balance.clone(),
-        })?;
-        info!("Balance update: {:?}", self.balance);
+        self.backend.update()?;
+        info!("Balance update: {:?}", self.backend.balance());
         Ok(())
     }
+}
 
-    /// This is called by the other modules in Rita to make payments. It sends a
-    /// PaymentTx to the `mesh_ip` in its `to` field.
-    pub fn make_payment(&mut self, pmt: PaymentTx) -> Result<(), Error> {
-        trace!("current balance: {:?}", self.balance);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_types::Identity;
+
+    fn get_test_key() -> PrivateKey {
+        "0xfe1b2d1a4f8b0b8a3c1e2f3d4c5b6a79887766554433221100ffeeddccbbaa99"
+            .parse()
+            .unwrap()
+    }
 
-        trace!(
-            "sending payment of {:?} to {:?}: {:?}",
-            pmt.amount,
-            pmt.to.mesh_ip,
-            pmt
+    fn get_test_payment(txid: Option<u64>) -> PaymentTx {
+        let from = Identity {
+            eth_address: get_test_key().to_public_key().unwrap(),
+            mesh_ip: "2001::3".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+        let to = Identity {
+            eth_address: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            mesh_ip: "2001::4".parse().unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        };
+        PaymentTx {
+            to,
+            from,
+            amount: Uint256::from(100u32),
+            txid,
+            nonce: Uint256::from(0u32),
+            signature: None,
+        }
+    }
+
+    fn get_test_controller() -> (PaymentController, MockBackend) {
+        let backend = MockBackend::new();
+        let pc = PaymentController::with_backend(Box::new(backend.clone()));
+        (pc, backend)
+    }
+
+    #[test]
+    fn test_payment_retry_backoff() {
+        assert_eq!(payment_retry_backoff(1), Duration::from_secs(5));
+        assert_eq!(payment_retry_backoff(2), Duration::from_secs(10));
+        assert_eq!(payment_retry_backoff(3), Duration::from_secs(20));
+        assert_eq!(payment_retry_backoff(7), Duration::from_secs(300));
+        assert_eq!(payment_retry_backoff(1000), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_queue_assigns_txid() {
+        let (mut pc, _backend) = get_test_controller();
+        pc.queue_payment(get_test_payment(None), &get_test_key(), Instant::now());
+        pc.queue_payment(get_test_payment(Some(7)), &get_test_key(), Instant::now());
+
+        let pending = pc.pending_payments();
+        assert!(pending[0].tx.txid.is_some());
+        assert_eq!(pending[1].tx.txid, Some(7));
+        assert!(pending[0].tx.verify());
+        assert!(pending[1].tx.verify());
+        assert!(pending[0].tx.nonce < pending[1].tx.nonce);
+    }
+
+    #[test]
+    fn test_queue_not_due() {
+        let (mut pc, _backend) = get_test_controller();
+        let now = Instant::now();
+        pc.queue_payment(
+            get_test_payment(Some(1)),
+            &get_test_key(),
+            now + Duration::from_secs(10),
         );
+        pc.queue_payment(get_test_payment(Some(2)), &get_test_key(), now);
+
+        // the first payment isn't due yet and holds back the second one to the same neighbor
+        assert_eq!(pc.process_queue(now).failed, Vec::new());
+        let pending = pc.pending_payments();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].tx.txid, Some(1));
+        assert_eq!(pending[0].attempts, 0);
+        assert_eq!(pending[1].tx.txid, Some(2));
+        assert_eq!(pending[1].attempts, 0);
+    }
 
-        let neighbor_url = if cfg!(not(test)) {
-            format!(
-                "http://[{}]:{}/make_payment",
-                pmt.to.mesh_ip,
-                SETTING.get_network().rita_contact_port
-            )
-        } else {
-            String::from("http://127.0.0.1:1234/make_payment")
-        };
+    #[test]
+    fn test_queue_delivered() {
+        let (mut pc, backend) = get_test_controller();
+        let now = Instant::now();
+        pc.queue_payment(get_test_payment(Some(1)), &get_test_key(), now);
+        pc.queue_payment(get_test_payment(Some(2)), &get_test_key(), now);
+
+        let result = pc.process_queue(now);
+        assert_eq!(result.failed, Vec::new());
+        assert_eq!(result.delivered.len(), 2);
+        assert!(pc.pending_payments().is_empty());
+
+        let state = backend.state.lock().unwrap();
+        let txids: Vec<Option<u64>> = state.sent.iter().map(|pmt| pmt.txid).collect();
+        assert_eq!(txids, vec![Some(1), Some(2)]);
+        assert_eq!(state.balance, Int256::from(-200));
+    }
 
-        trace!("current balance: {:?}", self.balance);
-
-        let mut r = self.reqwest_client.post(&neighbor_url).json(&pmt).send()?;
-
-        if r.status() == StatusCode::OK {
-            self.balance = self.balance.clone() - Int256::from(pmt.amount.clone());
-            self.update_bounty(BountyUpdate {
-                from: SETTING
-                    .get_identity()
-                    .ok_or(format_err!("No mesh IP available for Identity yet"))?,
-                tx: pmt,
-                balance: self.balance.clone(),
-            })?;
-            Ok(())
-        } else {
-            trace!("Unsuccessfully paid");
-            trace!(
-                "Received error from payee: {:?}",
-                r.text().unwrap_or(String::from("No message received"))
-            );
-            Err(Error::from(PaymentControllerError::PaymentSendingError(
-                String::from(format!(
-                    "Received error from payee: {:?}",
-                    r.text().unwrap_or(String::from("No message received"))
-                )),
-            )))
+    #[test]
+    fn test_queue_retries_in_order() {
+        let (mut pc, backend) = get_test_controller();
+        backend.state.lock().unwrap().failures = 1;
+        let now = Instant::now();
+        pc.queue_payment(get_test_payment(Some(1)), &get_test_key(), now);
+        pc.queue_payment(get_test_payment(Some(2)), &get_test_key(), now);
+
+        // the failed first payment holds back the second one to the same neighbor
+        assert_eq!(pc.process_queue(now).failed, Vec::new());
+        assert_eq!(backend.state.lock().unwrap().attempts, 1);
+        assert_eq!(pc.pending_payments()[0].attempts, 1);
+
+        assert_eq!(
+            pc.process_queue(now + payment_retry_backoff(1)).failed,
+            Vec::new()
+        );
+        assert!(pc.pending_payments().is_empty());
+        let txids: Vec<Option<u64>> = backend
+            .state
+            .lock()
+            .unwrap()
+            .sent
+            .iter()
+            .map(|pmt| pmt.txid)
+            .collect();
+        assert_eq!(txids, vec![Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn test_queue_gives_up() {
+        let (mut pc, backend) = get_test_controller();
+        backend.state.lock().unwrap().failures = MAX_PAYMENT_ATTEMPTS;
+        let mut now = Instant::now();
+        pc.queue_payment(get_test_payment(Some(1)), &get_test_key(), now);
+
+        for attempt in 1..MAX_PAYMENT_ATTEMPTS {
+            assert_eq!(pc.process_queue(now).failed, Vec::new());
+            now += payment_retry_backoff(attempt);
         }
+        let pmt = get_test_payment(None);
+        assert_eq!(
+            pc.process_queue(now).failed,
+            vec![debt_keeper::PaymentFailed {
+                to: pmt.to,
+                amount: pmt.amount,
+            }]
+        );
+        assert!(pc.pending_payments().is_empty());
+        assert!(backend.state.lock().unwrap().sent.is_empty());
     }
 }
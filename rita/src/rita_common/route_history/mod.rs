@@ -0,0 +1,609 @@
+//! Babel only tells us about the routes it has right now, so a link that keeps dropping out or a
+//! destination whose route bounces between neighbors looks fine at any single moment. The route
+//! history subscribes to the babel watcher and keeps, for every destination, what the installed
+//! route looked like whenever babel changed it over the last hour along with every time its next
+//! hop changed.
+//!
+//! A destination is flapping when its next hop changed at least `route_flap_threshold` times in
+//! the last `route_flap_window` seconds, both in `NetworkSettings`. Losing the route entirely and
+//! getting it back counts as a change each way.
+
+use actix::prelude::*;
+
+use babel_monitor::{BabelEvent, BabelTable, Route};
+
+use failure::Error;
+
+use ipnetwork::IpNetwork;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use settings::{RitaCommonSettings, MAX_ROUTE_FLAP_THRESHOLD, MAX_ROUTE_FLAP_WINDOW};
+use SETTING;
+
+use rita_common::babel_watcher::{BabelUpdate, BabelWatcher, GetBabelTable, Subscribe};
+use rita_common::debt_keeper::unix_now;
+
+/// How long route changes are collected before the destinations they belong to are observed, in
+/// seconds. Babel reports switching between two routes as two changes with the old route
+/// uninstalled first, observed one at a time that would look like the route was lost.
+const SETTLE_TIME: u64 = 1;
+
+/// How long samples and next hop changes are kept, in seconds. `route_flap_window` is limited
+/// to this when the settings are loaded.
+const HISTORY_LENGTH: u64 = MAX_ROUTE_FLAP_WINDOW;
+
+/// How many next hop changes are kept per destination at most, so a route flapping on every
+/// sample doesn't grow its history without bound. `route_flap_threshold` is limited to this.
+const MAX_CHANGES: usize = MAX_ROUTE_FLAP_THRESHOLD as usize;
+
+/// The installed route to a destination after babel changed it
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub struct RouteSample {
+    pub time: u64,
+    pub via: IpAddr,
+    pub metric: u16,
+    pub price: u32,
+    pub full_path_rtt: f32,
+}
+
+/// The next hop to a destination changed, `None` means there was no installed route
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub struct NextHopChange {
+    pub time: u64,
+    pub from: Option<IpAddr>,
+    pub to: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub struct Stats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+impl Stats {
+    /// `None` if there are no values
+    fn from_values<I: Iterator<Item = f64>>(values: I) -> Option<Stats> {
+        let mut count = 0;
+        let mut stats = Stats {
+            min: ::std::f64::INFINITY,
+            max: ::std::f64::NEG_INFINITY,
+            mean: 0.0,
+        };
+        for value in values {
+            count += 1;
+            stats.min = stats.min.min(value);
+            stats.max = stats.max.max(value);
+            stats.mean += value;
+        }
+        if count == 0 {
+            return None;
+        }
+        stats.mean /= f64::from(count);
+        Some(stats)
+    }
+}
+
+/// A summary of the history of one destination
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteQuality {
+    pub destination: String,
+    pub next_hop: Option<IpAddr>,
+    /// How many samples the statistics are taken over
+    pub sample_count: usize,
+    pub metric: Option<Stats>,
+    pub price: Option<Stats>,
+    pub full_path_rtt: Option<Stats>,
+    /// Next hop changes within the flap window
+    pub recent_changes: usize,
+    pub flapping: bool,
+}
+
+/// The summary of a destination along with everything it was taken from, oldest first
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteHistoryDetail {
+    pub quality: RouteQuality,
+    pub samples: Vec<RouteSample>,
+    pub changes: Vec<NextHopChange>,
+}
+
+#[derive(Debug, Clone)]
+struct DestinationHistory {
+    next_hop: Option<IpAddr>,
+    flapping: bool,
+    samples: VecDeque<RouteSample>,
+    changes: VecDeque<NextHopChange>,
+}
+
+impl DestinationHistory {
+    fn new(next_hop: Option<IpAddr>) -> DestinationHistory {
+        DestinationHistory {
+            next_hop,
+            flapping: false,
+            samples: VecDeque::new(),
+            changes: VecDeque::new(),
+        }
+    }
+
+    /// Records the installed route to this destination, `None` if there isn't one
+    fn observe(&mut self, route: Option<&Route>, now: u64) {
+        let next_hop = route.map(|route| route.neigh_ip);
+        if next_hop != self.next_hop {
+            self.changes.push_back(NextHopChange {
+                time: now,
+                from: self.next_hop,
+                to: next_hop,
+            });
+            while self.changes.len() > MAX_CHANGES {
+                self.changes.pop_front();
+            }
+            self.next_hop = next_hop;
+        }
+
+        if let Some(route) = route {
+            self.samples.push_back(RouteSample {
+                time: now,
+                via: route.neigh_ip,
+                metric: route.metric,
+                price: route.price,
+                full_path_rtt: route.full_path_rtt,
+            });
+        }
+
+        self.prune(now);
+    }
+
+    /// Drops samples and changes that have left the history
+    fn prune(&mut self, now: u64) {
+        let cutoff = now.saturating_sub(HISTORY_LENGTH);
+        while self
+            .samples
+            .front()
+            .map(|s| s.time < cutoff)
+            .unwrap_or(false)
+        {
+            self.samples.pop_front();
+        }
+        while self
+            .changes
+            .front()
+            .map(|c| c.time < cutoff)
+            .unwrap_or(false)
+        {
+            self.changes.pop_front();
+        }
+    }
+
+    /// Unreachable and with nothing left in its history
+    fn is_stale(&self) -> bool {
+        self.next_hop.is_none() && self.samples.is_empty() && self.changes.is_empty()
+    }
+
+    fn recent_changes(&self, now: u64, window: u64) -> usize {
+        let cutoff = now.saturating_sub(window);
+        self.changes.iter().filter(|c| c.time >= cutoff).count()
+    }
+
+    fn quality(
+        &self,
+        destination: &IpNetwork,
+        now: u64,
+        window: u64,
+        threshold: u32,
+    ) -> RouteQuality {
+        let recent_changes = self.recent_changes(now, window);
+        RouteQuality {
+            destination: destination.to_string(),
+            next_hop: self.next_hop,
+            sample_count: self.samples.len(),
+            metric: Stats::from_values(self.samples.iter().map(|s| f64::from(s.metric))),
+            price: Stats::from_values(self.samples.iter().map(|s| f64::from(s.price))),
+            full_path_rtt: Stats::from_values(
+                self.samples.iter().map(|s| f64::from(s.full_path_rtt)),
+            ),
+            recent_changes,
+            flapping: recent_changes as u64 >= u64::from(threshold),
+        }
+    }
+}
+
+pub struct RouteHistory {
+    destinations: HashMap<IpNetwork, DestinationHistory>,
+    /// Babel's routes as the babel watcher reported them
+    table: BabelTable,
+    /// Destinations whose routes changed since they were last observed
+    changed: HashSet<IpNetwork>,
+}
+
+impl Actor for RouteHistory {
+    type Context = Context<Self>;
+}
+
+impl Supervised for RouteHistory {}
+impl SystemService for RouteHistory {
+    fn service_started(&mut self, ctx: &mut Context<Self>) {
+        BabelWatcher::from_registry().do_send(Subscribe(ctx.address().recipient()));
+
+        info!("Route History started");
+    }
+}
+
+impl Default for RouteHistory {
+    fn default() -> RouteHistory {
+        RouteHistory::new()
+    }
+}
+
+impl RouteHistory {
+    pub fn new() -> Self {
+        RouteHistory {
+            destinations: HashMap::new(),
+            table: BabelTable::new(),
+            changed: HashSet::new(),
+        }
+    }
+
+    /// Takes over the babel watcher's table once it has been replayed, routes that went away
+    /// while the watcher was reconnecting are only noticed here
+    fn resync(&mut self, ctx: &mut Context<Self>) {
+        ctx.spawn(
+            BabelWatcher::from_registry()
+                .send(GetBabelTable)
+                .into_actor(self)
+                .then(|res, act, _ctx| {
+                    match res {
+                        Ok(Ok(table)) => {
+                            let routes = table.routes();
+                            act.table = table;
+                            act.changed.clear();
+                            act.record(&routes, unix_now());
+                        }
+                        Ok(Err(e)) => warn!("Failed to get the babel table {:?}", e),
+                        Err(e) => warn!("Failed to get the babel table {:?}", e),
+                    }
+                    actix::fut::ok(())
+                }),
+        );
+    }
+
+    /// Applies a change babel reported to the table, returns whether it was a route change
+    fn apply(&mut self, event: &BabelEvent) -> bool {
+        let prefix = match *event {
+            BabelEvent::RouteUpdated(ref route) => Some(route.prefix),
+            BabelEvent::RouteFlushed(ref id) => self.table.route(id).map(|route| route.prefix),
+            _ => None,
+        };
+        self.table.apply(event);
+        match prefix {
+            Some(prefix) => {
+                self.changed.insert(prefix);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Observes the destinations whose routes changed since the last time
+    fn record_changed(&mut self, now: u64) {
+        let routes = self.table.routes();
+        let changed: Vec<IpNetwork> = self.changed.drain().collect();
+        for destination in changed {
+            let installed = routes
+                .iter()
+                .find(|route| route.prefix == destination && route.installed);
+            self.observe(destination, installed, now);
+        }
+    }
+
+    /// Observes every destination we know of or have a route to
+    fn record(&mut self, routes: &VecDeque<Route>, now: u64) {
+        let mut installed: HashMap<IpNetwork, &Route> = HashMap::new();
+        for route in routes.iter() {
+            if route.installed {
+                installed.insert(route.prefix, route);
+            }
+        }
+
+        let mut destinations: HashSet<IpNetwork> = self.destinations.keys().cloned().collect();
+        destinations.extend(installed.keys().cloned());
+        for destination in destinations {
+            self.observe(destination, installed.get(&destination).cloned(), now);
+        }
+    }
+
+    fn observe(&mut self, destination: IpNetwork, installed: Option<&Route>, now: u64) {
+        let network = SETTING.get_network();
+        let stale = {
+            let history = self
+                .destinations
+                .entry(destination)
+                .or_insert_with(|| DestinationHistory::new(installed.map(|route| route.neigh_ip)));
+            history.observe(installed, now);
+
+            let quality = history.quality(
+                &destination,
+                now,
+                network.route_flap_window,
+                network.route_flap_threshold,
+            );
+            if quality.flapping && !history.flapping {
+                warn!(
+                    "Route to {} is flapping, its next hop changed {} times in {}s",
+                    destination, quality.recent_changes, network.route_flap_window
+                );
+            }
+            history.flapping = quality.flapping;
+            history.is_stale()
+        };
+        if stale {
+            self.destinations.remove(&destination);
+        }
+    }
+
+    /// Destinations are only observed when their routes change, so what has left the history
+    /// since is dropped before anything is reported
+    fn prune(&mut self, now: u64) {
+        for history in self.destinations.values_mut() {
+            history.prune(now);
+        }
+        self.destinations.retain(|_, history| !history.is_stale());
+    }
+
+    fn summaries(&self, now: u64, window: u64, threshold: u32) -> Vec<RouteQuality> {
+        let mut summaries: Vec<RouteQuality> = self
+            .destinations
+            .iter()
+            .map(|(destination, history)| history.quality(destination, now, window, threshold))
+            .collect();
+        summaries.sort_by(|a, b| a.destination.cmp(&b.destination));
+        summaries
+    }
+
+    /// The history of the most specific destination containing `ip`
+    fn detail(
+        &self,
+        ip: IpAddr,
+        now: u64,
+        window: u64,
+        threshold: u32,
+    ) -> Option<RouteHistoryDetail> {
+        self.destinations
+            .iter()
+            .filter(|&(destination, _)| destination.contains(ip))
+            .max_by_key(|&(destination, _)| destination.prefix())
+            .map(|(destination, history)| RouteHistoryDetail {
+                quality: history.quality(destination, now, window, threshold),
+                samples: history.samples.iter().cloned().collect(),
+                changes: history.changes.iter().cloned().collect(),
+            })
+    }
+}
+
+impl Handler<BabelUpdate> for RouteHistory {
+    type Result = ();
+
+    fn handle(&mut self, msg: BabelUpdate, ctx: &mut Context<Self>) -> Self::Result {
+        if let BabelEvent::Synced = msg.0 {
+            self.resync(ctx);
+            return;
+        }
+
+        let idle = self.changed.is_empty();
+        if self.apply(&msg.0) && idle {
+            ctx.run_later(Duration::from_secs(SETTLE_TIME), |act, _ctx| {
+                act.record_changed(unix_now())
+            });
+        }
+    }
+}
+
+pub struct GetRouteHistory;
+
+impl Message for GetRouteHistory {
+    type Result = Result<Vec<RouteQuality>, Error>;
+}
+
+impl Handler<GetRouteHistory> for RouteHistory {
+    type Result = Result<Vec<RouteQuality>, Error>;
+
+    fn handle(&mut self, _msg: GetRouteHistory, _: &mut Context<Self>) -> Self::Result {
+        let now = unix_now();
+        self.prune(now);
+        let network = SETTING.get_network();
+        Ok(self.summaries(now, network.route_flap_window, network.route_flap_threshold))
+    }
+}
+
+/// The history of the route to an address, `None` if there's no route to it in the history
+pub struct GetDestinationHistory(pub IpAddr);
+
+impl Message for GetDestinationHistory {
+    type Result = Result<Option<RouteHistoryDetail>, Error>;
+}
+
+impl Handler<GetDestinationHistory> for RouteHistory {
+    type Result = Result<Option<RouteHistoryDetail>, Error>;
+
+    fn handle(&mut self, msg: GetDestinationHistory, _: &mut Context<Self>) -> Self::Result {
+        let now = unix_now();
+        self.prune(now);
+        let network = SETTING.get_network();
+        Ok(self.detail(
+            msg.0,
+            now,
+            network.route_flap_window,
+            network.route_flap_threshold,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use babel_monitor::fake_babeld::fake_route;
+
+    fn get_route(prefix: &str, via: &str, price: u32) -> Route {
+        fake_route(
+            "14f06d8",
+            prefix.parse().unwrap(),
+            via.parse().unwrap(),
+            price,
+        )
+    }
+
+    fn get_routes(routes: Vec<Route>) -> VecDeque<Route> {
+        routes.into_iter().collect()
+    }
+
+    #[test]
+    fn test_stats() {
+        assert_eq!(Stats::from_values(Vec::<f64>::new().into_iter()), None);
+        assert_eq!(
+            Stats::from_values(vec![1.0, 5.0, 3.0].into_iter()),
+            Some(Stats {
+                min: 1.0,
+                max: 5.0,
+                mean: 3.0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_next_hop_changes() {
+        let mut history = RouteHistory::new();
+        let destination: IpNetwork = "fd00::5/128".parse().unwrap();
+        let first = "fe80::1".parse().unwrap();
+        let second = "fe80::2".parse().unwrap();
+
+        history.record(
+            &get_routes(vec![get_route("fd00::5/128", "fe80::1", 10)]),
+            100,
+        );
+        let mut uninstalled = get_route("fd00::5/128", "fe80::2", 20);
+        uninstalled.installed = false;
+        history.record(
+            &get_routes(vec![
+                get_route("fd00::5/128", "fe80::1", 30),
+                uninstalled.clone(),
+            ]),
+            110,
+        );
+        // babel switching to the other route is a change, a route we don't use changing isn't
+        uninstalled.installed = true;
+        history.record(&get_routes(vec![uninstalled]), 120);
+        history.record(&get_routes(vec![]), 130);
+
+        let dest = &history.destinations[&destination];
+        assert_eq!(dest.samples.len(), 3);
+        assert_eq!(
+            dest.changes.iter().cloned().collect::<Vec<NextHopChange>>(),
+            vec![
+                NextHopChange {
+                    time: 120,
+                    from: Some(first),
+                    to: Some(second),
+                },
+                NextHopChange {
+                    time: 130,
+                    from: Some(second),
+                    to: None,
+                },
+            ]
+        );
+
+        let quality = dest.quality(&destination, 130, 600, 2);
+        assert_eq!(quality.next_hop, None);
+        assert_eq!(quality.recent_changes, 2);
+        assert!(quality.flapping);
+        assert_eq!(quality.price.unwrap().mean, 20.0);
+        assert!(!dest.quality(&destination, 130, 600, 3).flapping);
+        // the changes have left the window
+        assert!(!dest.quality(&destination, 800, 600, 2).flapping);
+    }
+
+    #[test]
+    fn test_old_history_dropped() {
+        let mut history = RouteHistory::new();
+        history.record(
+            &get_routes(vec![get_route("fd00::5/128", "fe80::1", 10)]),
+            100,
+        );
+        history.record(&get_routes(vec![]), 110);
+        assert_eq!(history.destinations.len(), 1);
+
+        history.record(&get_routes(vec![]), 110 + HISTORY_LENGTH + 1);
+        assert!(history.destinations.is_empty());
+    }
+
+    #[test]
+    fn test_detail_picks_most_specific() {
+        let mut history = RouteHistory::new();
+        history.record(
+            &get_routes(vec![
+                get_route("fd00::/8", "fe80::1", 10),
+                get_route("fd00::5/128", "fe80::2", 20),
+            ]),
+            100,
+        );
+
+        let detail = history
+            .detail("fd00::5".parse().unwrap(), 100, 600, 4)
+            .unwrap();
+        assert_eq!(detail.quality.destination, "fd00::5/128");
+        assert_eq!(detail.samples.len(), 1);
+
+        let detail = history
+            .detail("fd00::6".parse().unwrap(), 100, 600, 4)
+            .unwrap();
+        assert_eq!(detail.quality.destination, "fd00::/8");
+        assert!(history
+            .detail("10.0.0.1".parse().unwrap(), 100, 600, 4)
+            .is_none());
+    }
+    #[test]
+    fn test_route_switch_from_events() {
+        let mut history = RouteHistory::new();
+        let destination: IpNetwork = "fd00::5/128".parse().unwrap();
+        let mut first = fake_route("1", destination, "fe80::1".parse().unwrap(), 10);
+        let mut second = fake_route("2", destination, "fe80::2".parse().unwrap(), 20);
+        second.installed = false;
+
+        assert!(history.apply(&BabelEvent::RouteUpdated(first.clone())));
+        assert!(history.apply(&BabelEvent::RouteUpdated(second.clone())));
+        assert!(!history.apply(&BabelEvent::LocalFee(5)));
+        history.record_changed(100);
+
+        // babel reports the old route uninstalled before the new one installed
+        first.installed = false;
+        second.installed = true;
+        history.apply(&BabelEvent::RouteUpdated(first));
+        history.apply(&BabelEvent::RouteUpdated(second));
+        history.record_changed(110);
+
+        history.apply(&BabelEvent::RouteFlushed("2".to_string()));
+        history.record_changed(120);
+        // nothing changed since
+        history.record_changed(130);
+
+        let dest = &history.destinations[&destination];
+        assert_eq!(dest.samples.len(), 2);
+        assert_eq!(
+            dest.changes.iter().cloned().collect::<Vec<NextHopChange>>(),
+            vec![
+                NextHopChange {
+                    time: 110,
+                    from: Some("fe80::1".parse().unwrap()),
+                    to: Some("fe80::2".parse().unwrap()),
+                },
+                NextHopChange {
+                    time: 120,
+                    from: Some("fe80::2".parse().unwrap()),
+                    to: None,
+                },
+            ]
+        );
+    }
+}
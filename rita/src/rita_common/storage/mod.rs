@@ -0,0 +1,84 @@
+//! Helpers for persisting actor state to disk as JSON. Writes go to a temporary file next to the
+//! target which is synced and then renamed over it, so a crash or power loss in the middle of a
+//! write leaves the previous copy intact instead of a truncated file.
+
+use failure::Error;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+
+/// Atomically replaces the contents of `path` with `value` serialized as JSON
+pub fn save_json<T: Serialize>(path: &str, value: &T) -> Result<(), Error> {
+    let tmp_path = format!("{}.tmp", path);
+    let ser = serde_json::to_vec(value)?;
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&ser)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, path)?;
+
+    // the rename itself is only durable once the containing directory is synced
+    if let Some(parent) = Path::new(path).parent() {
+        if let Ok(dir) = File::open(parent) {
+            dir.sync_all()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads a value previously written by `save_json`, returns `None` if nothing has been saved yet
+pub fn load_json<T: DeserializeOwned>(path: &str) -> Result<Option<T>, Error> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+
+    Ok(Some(serde_json::from_str(&contents)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::env;
+
+    #[test]
+    fn test_save_load_round_trip() {
+        let path = env::temp_dir().join("rita-storage-test-round-trip.json");
+        let path = path.to_str().unwrap();
+
+        let mut value = HashMap::new();
+        value.insert("a".to_string(), 1u32);
+        value.insert("b".to_string(), 2u32);
+
+        save_json(path, &value).unwrap();
+        // overwriting must replace the previous copy
+        value.insert("c".to_string(), 3u32);
+        save_json(path, &value).unwrap();
+
+        let loaded: HashMap<String, u32> = load_json(path).unwrap().unwrap();
+        assert_eq!(loaded, value);
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_missing() {
+        let path = env::temp_dir().join("rita-storage-test-missing.json");
+        let loaded: Option<u32> = load_json(path.to_str().unwrap()).unwrap();
+        assert_eq!(loaded, None);
+    }
+}
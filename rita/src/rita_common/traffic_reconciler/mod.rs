@@ -0,0 +1,496 @@
+//! Both ends of a tunnel bill each other from their own counters, so nothing stops one side from
+//! charging for traffic that never crossed the link, or a broken counter from going unnoticed. The
+//! traffic reconciler keeps the bytes sent to and received from every neighbor each tick, and
+//! every few minutes sends those counts to the neighbor over the contact port. When the counts
+//! of a neighbor come in they're compared to ours over the same stretch of time, what they sent
+//! should be what we received and the other way around.
+//!
+//! Ticks on both sides don't start at the same time and some packets are lost on the way, so the
+//! counts are only flagged when they're further apart than the tolerance in `NetworkSettings`.
+//! This relies on both clocks being roughly right.
+
+use actix::prelude::*;
+use actix_web::client;
+
+use althea_types::Identity;
+
+use failure::Error;
+
+use futures::Future;
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use settings::RitaCommonSettings;
+use SETTING;
+
+use rita_common::debt_keeper::unix_now;
+
+/// How often our counts are sent to every neighbor, in seconds
+const RECONCILE_INTERVAL: u64 = 300;
+
+/// How long our own counts are kept around to compare reports to, long enough to cover a report
+/// that took a while to arrive or a neighbor whose clock is a bit off
+const TICK_RETENTION: u64 = 3 * RECONCILE_INTERVAL;
+
+/// How many disagreements are kept per neighbor
+const MAX_DISAGREEMENTS: usize = 20;
+
+/// Bytes that went over the tunnel with a neighbor during the tick ending at `time`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TickCount {
+    pub time: u64,
+    pub sent: u64,
+    pub received: u64,
+}
+
+/// What a neighbor sends us, its counts for our tunnel, oldest first. The first tick only marks
+/// where the report starts and is not compared.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrafficReport {
+    pub from: Identity,
+    pub ticks: Vec<TickCount>,
+}
+
+/// The outcome of comparing one report to our own counts
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Reconciliation {
+    /// Unix time of the comparison
+    pub time: u64,
+    pub window_start: u64,
+    pub window_end: u64,
+    pub we_sent: u64,
+    pub they_received: u64,
+    pub they_sent: u64,
+    pub we_received: u64,
+    pub agrees: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NeighborReconciliation {
+    pub identity: Identity,
+    pub checks: u64,
+    pub disagreements: u64,
+    pub last: Option<Reconciliation>,
+    /// The latest disagreements, oldest first
+    pub recent_disagreements: VecDeque<Reconciliation>,
+}
+
+impl NeighborReconciliation {
+    fn new(identity: Identity) -> NeighborReconciliation {
+        NeighborReconciliation {
+            identity,
+            checks: 0,
+            disagreements: 0,
+            last: None,
+            recent_disagreements: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, reconciliation: Reconciliation) {
+        self.checks += 1;
+        if !reconciliation.agrees {
+            self.disagreements += 1;
+            self.recent_disagreements.push_back(reconciliation.clone());
+            while self.recent_disagreements.len() > MAX_DISAGREEMENTS {
+                self.recent_disagreements.pop_front();
+            }
+        }
+        self.last = Some(reconciliation);
+    }
+}
+
+/// Whether two counts of the same traffic are within the tolerance band
+fn within_tolerance(ours: u64, theirs: u64, tolerance_percent: u8, tolerance_bytes: u64) -> bool {
+    let difference = cmp::max(ours, theirs) - cmp::min(ours, theirs);
+    difference <= tolerance_bytes
+        || difference.saturating_mul(100)
+            <= cmp::max(ours, theirs).saturating_mul(u64::from(tolerance_percent))
+}
+
+/// Compares a neighbor's counts to ours over the time their report covers. Returns `None` if
+/// there's nothing to compare, because the report is too short or we weren't counting for the
+/// whole of it.
+pub fn reconcile(
+    ours: &VecDeque<TickCount>,
+    theirs: &[TickCount],
+    tolerance_percent: u8,
+    tolerance_bytes: u64,
+    now: u64,
+) -> Option<Reconciliation> {
+    if theirs.len() < 2 {
+        return None;
+    }
+    let window_start = theirs[0].time;
+    let window_end = theirs[theirs.len() - 1].time;
+    match ours.front() {
+        Some(oldest) if oldest.time <= window_start => {}
+        _ => return None,
+    }
+
+    let ours_in_window: Vec<&TickCount> = ours
+        .iter()
+        .filter(|tick| tick.time > window_start && tick.time <= window_end)
+        .collect();
+    // ticks on both sides end at different times so the counts may be one apart, more than that
+    // means one of us missed some
+    let their_ticks = theirs.len() - 1;
+    if ours_in_window.len() + 1 < their_ticks || their_ticks + 1 < ours_in_window.len() {
+        return None;
+    }
+
+    let we_sent = ours_in_window.iter().map(|tick| tick.sent).sum();
+    let we_received = ours_in_window.iter().map(|tick| tick.received).sum();
+    let they_sent = theirs[1..].iter().map(|tick| tick.sent).sum();
+    let they_received = theirs[1..].iter().map(|tick| tick.received).sum();
+
+    Some(Reconciliation {
+        time: now,
+        window_start,
+        window_end,
+        we_sent,
+        they_received,
+        they_sent,
+        we_received,
+        agrees: within_tolerance(we_sent, they_received, tolerance_percent, tolerance_bytes)
+            && within_tolerance(they_sent, we_received, tolerance_percent, tolerance_bytes),
+    })
+}
+
+pub struct TrafficReconciler {
+    /// Our counts for every neighbor, oldest first
+    ticks: HashMap<Identity, VecDeque<TickCount>>,
+    reconciliations: HashMap<Identity, NeighborReconciliation>,
+}
+
+impl Actor for TrafficReconciler {
+    type Context = Context<Self>;
+}
+
+impl Supervised for TrafficReconciler {}
+impl SystemService for TrafficReconciler {
+    fn service_started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(Duration::from_secs(RECONCILE_INTERVAL), |act, _ctx| {
+            act.send_reports(unix_now());
+        });
+
+        info!("Traffic Reconciler started");
+    }
+}
+
+impl Default for TrafficReconciler {
+    fn default() -> TrafficReconciler {
+        TrafficReconciler::new()
+    }
+}
+
+impl TrafficReconciler {
+    pub fn new() -> Self {
+        TrafficReconciler {
+            ticks: HashMap::new(),
+            reconciliations: HashMap::new(),
+        }
+    }
+
+    fn record_tick(&mut self, time: u64, counts: HashMap<Identity, (u64, u64)>) {
+        for (identity, (sent, received)) in counts {
+            self.ticks
+                .entry(identity)
+                .or_insert_with(VecDeque::new)
+                .push_back(TickCount {
+                    time,
+                    sent,
+                    received,
+                });
+        }
+        for ticks in self.ticks.values_mut() {
+            while ticks
+                .front()
+                .map(|tick| tick.time + TICK_RETENTION < time)
+                .unwrap_or(false)
+            {
+                ticks.pop_front();
+            }
+        }
+        self.ticks.retain(|_, ticks| !ticks.is_empty());
+    }
+
+    /// Our report for every neighbor we have counts for, covering the last `RECONCILE_INTERVAL`
+    /// plus the tick before it as the starting point
+    fn reports(&self, us: &Identity, now: u64) -> Vec<(Identity, TrafficReport)> {
+        let mut reports = Vec::new();
+        for (identity, ticks) in self.ticks.iter() {
+            let first = ticks
+                .iter()
+                .rposition(|tick| tick.time + RECONCILE_INTERVAL <= now)
+                .unwrap_or(0);
+            let report: Vec<TickCount> = ticks.iter().skip(first).cloned().collect();
+            if report.len() < 2 {
+                continue;
+            }
+            reports.push((
+                identity.clone(),
+                TrafficReport {
+                    from: us.clone(),
+                    ticks: report,
+                },
+            ));
+        }
+        reports
+    }
+
+    fn send_reports(&self, now: u64) {
+        let us = match SETTING.get_identity() {
+            Some(id) => id,
+            None => return,
+        };
+        let port = SETTING.get_network().rita_contact_port;
+
+        for (identity, report) in self.reports(&us, now) {
+            let url = format!("http://[{}]:{}/traffic_report", identity.mesh_ip, port);
+            let request = match client::post(&url).json(&report) {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!("Failed to build traffic report for {}: {:?}", url, e);
+                    continue;
+                }
+            };
+            let mesh_ip = identity.mesh_ip;
+            Arbiter::spawn(
+                request
+                    .send()
+                    .timeout(Duration::from_secs(5))
+                    .then(move |res| {
+                        if let Err(e) = res {
+                            warn!("Failed to send traffic report to {}: {:?}", mesh_ip, e);
+                        }
+                        Ok(())
+                    }),
+            );
+        }
+    }
+
+    fn report_received(&mut self, report: TrafficReport, now: u64) -> Result<(), Error> {
+        let ours = match self.ticks.get(&report.from) {
+            Some(ticks) => ticks,
+            None => bail!("No tunnel with {:?} to reconcile", report.from.mesh_ip),
+        };
+        let network = SETTING.get_network();
+        let reconciliation = match reconcile(
+            ours,
+            &report.ticks,
+            network.reconcile_tolerance_percent,
+            network.reconcile_tolerance_bytes,
+            now,
+        ) {
+            Some(reconciliation) => reconciliation,
+            None => {
+                trace!(
+                    "Traffic report from {:?} doesn't overlap our counts",
+                    report.from.mesh_ip
+                );
+                return Ok(());
+            }
+        };
+
+        if !reconciliation.agrees {
+            warn!(
+                "Traffic counts of {:?} disagree with ours between {} and {}: we sent {} and they \
+                 received {}, they sent {} and we received {}",
+                report.from.mesh_ip,
+                reconciliation.window_start,
+                reconciliation.window_end,
+                reconciliation.we_sent,
+                reconciliation.they_received,
+                reconciliation.they_sent,
+                reconciliation.we_received
+            );
+        }
+        self.reconciliations
+            .entry(report.from.clone())
+            .or_insert_with(|| NeighborReconciliation::new(report.from))
+            .record(reconciliation);
+        Ok(())
+    }
+}
+
+/// Sent by the traffic watcher every tick with the bytes sent to and received from each neighbor
+#[derive(Message)]
+pub struct RecordTick {
+    pub counts: HashMap<Identity, (u64, u64)>,
+}
+
+impl Handler<RecordTick> for TrafficReconciler {
+    type Result = ();
+
+    fn handle(&mut self, msg: RecordTick, _: &mut Context<Self>) -> Self::Result {
+        self.record_tick(unix_now(), msg.counts)
+    }
+}
+
+pub struct TrafficReportReceived(pub TrafficReport);
+
+impl Message for TrafficReportReceived {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<TrafficReportReceived> for TrafficReconciler {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: TrafficReportReceived, _: &mut Context<Self>) -> Self::Result {
+        self.report_received(msg.0, unix_now())
+    }
+}
+
+pub struct GetReconciliations;
+
+impl Message for GetReconciliations {
+    type Result = Result<Vec<NeighborReconciliation>, Error>;
+}
+
+impl Handler<GetReconciliations> for TrafficReconciler {
+    type Result = Result<Vec<NeighborReconciliation>, Error>;
+
+    fn handle(&mut self, _msg: GetReconciliations, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.reconciliations.values().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_ticks(start: u64, counts: &[(u64, u64)]) -> Vec<TickCount> {
+        counts
+            .iter()
+            .enumerate()
+            .map(|(i, &(sent, received))| TickCount {
+                time: start + 5 * i as u64,
+                sent,
+                received,
+            })
+            .collect()
+    }
+
+    fn get_identity() -> Identity {
+        Identity {
+            mesh_ip: "2001::3".parse().unwrap(),
+            eth_address: "0x0101010101010101010101010101010101010101"
+                .parse()
+                .unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_within_tolerance() {
+        assert!(within_tolerance(1000, 1000, 5, 0));
+        assert!(within_tolerance(1000, 960, 5, 0));
+        assert!(!within_tolerance(1000, 940, 5, 0));
+        assert!(within_tolerance(1000, 940, 5, 100));
+        assert!(within_tolerance(0, 100, 5, 100));
+        assert!(!within_tolerance(0, 101, 5, 100));
+    }
+
+    #[test]
+    fn test_reconcile_agrees() {
+        // our ticks end two seconds after theirs
+        let ours: VecDeque<TickCount> =
+            get_ticks(97, &[(0, 0), (100, 50), (100, 50), (100, 50), (100, 50)])
+                .into_iter()
+                .collect();
+        let theirs = get_ticks(100, &[(0, 0), (50, 100), (50, 100), (50, 100)]);
+
+        let reconciliation = reconcile(&ours, &theirs, 5, 0, 200).unwrap();
+        assert_eq!(reconciliation.window_start, 100);
+        assert_eq!(reconciliation.window_end, 115);
+        assert_eq!(reconciliation.we_sent, 300);
+        assert_eq!(reconciliation.they_received, 300);
+        assert_eq!(reconciliation.they_sent, 150);
+        assert_eq!(reconciliation.we_received, 150);
+        assert!(reconciliation.agrees);
+    }
+
+    #[test]
+    fn test_reconcile_disagrees() {
+        let ours: VecDeque<TickCount> = get_ticks(100, &[(0, 0), (100, 50), (100, 50)])
+            .into_iter()
+            .collect();
+        // they claim to have sent us twice what we got
+        let theirs = get_ticks(100, &[(0, 0), (100, 100), (100, 100)]);
+
+        let reconciliation = reconcile(&ours, &theirs, 5, 10, 200).unwrap();
+        assert_eq!(reconciliation.they_sent, 200);
+        assert_eq!(reconciliation.we_received, 100);
+        assert!(!reconciliation.agrees);
+    }
+
+    #[test]
+    fn test_reconcile_no_overlap() {
+        let ours: VecDeque<TickCount> = get_ticks(200, &[(0, 0), (100, 50), (100, 50)])
+            .into_iter()
+            .collect();
+        // we weren't counting yet when their report starts
+        let theirs = get_ticks(190, &[(0, 0), (50, 100), (50, 100), (50, 100)]);
+        assert_eq!(reconcile(&ours, &theirs, 5, 0, 300), None);
+        // too short to compare anything
+        assert_eq!(reconcile(&ours, &theirs[..1], 5, 0, 300), None);
+
+        // we missed ticks in the middle of theirs
+        let ours: VecDeque<TickCount> = vec![
+            TickCount {
+                time: 100,
+                sent: 0,
+                received: 0,
+            },
+            TickCount {
+                time: 130,
+                sent: 600,
+                received: 300,
+            },
+        ]
+        .into_iter()
+        .collect();
+        let theirs = get_ticks(100, &[(0, 0); 7]);
+        assert_eq!(reconcile(&ours, &theirs, 5, 0, 300), None);
+    }
+
+    #[test]
+    fn test_record_tick_and_report() {
+        let mut reconciler = TrafficReconciler::new();
+        let ident = get_identity();
+        for i in 0..300 {
+            let mut counts = HashMap::new();
+            counts.insert(ident.clone(), (100, 50));
+            reconciler.record_tick(1000 + 5 * i, counts);
+        }
+        let now = 1000 + 5 * 299;
+
+        // only the last TICK_RETENTION seconds are kept
+        let ticks = &reconciler.ticks[&ident];
+        assert_eq!(ticks.front().unwrap().time, now - TICK_RETENTION);
+
+        let reports = reconciler.reports(&ident, now);
+        assert_eq!(reports.len(), 1);
+        let report = &reports[0].1;
+        assert_eq!(report.ticks[0].time, now - RECONCILE_INTERVAL);
+        assert_eq!(report.ticks.len() as u64, RECONCILE_INTERVAL / 5 + 1);
+
+        // a neighbor reporting exactly what we counted agrees
+        let mirrored: Vec<TickCount> = report
+            .ticks
+            .iter()
+            .map(|tick| TickCount {
+                time: tick.time,
+                sent: tick.received,
+                received: tick.sent,
+            })
+            .collect();
+        let reconciliation = reconcile(ticks, &mirrored, 5, 0, now).unwrap();
+        assert!(reconciliation.agrees);
+        assert_eq!(reconciliation.we_sent, 100 * RECONCILE_INTERVAL / 5);
+    }
+}
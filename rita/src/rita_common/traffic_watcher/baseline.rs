@@ -0,0 +1,305 @@
+//! The traffic counters are zeroed every time they are read, so each read holds what was counted
+//! since the one before. The baseline keeps the time of the previous read of every set, which
+//! bounds how much traffic a counter can plausibly hold: a set that was restored with old counters
+//! shows up as far more than the link could have carried since. Rather than billing that in one
+//! enormous round, or silently losing the counts of a set that couldn't be read, those events are
+//! recorded as accounting gaps.
+//!
+//! The sets don't survive a reboot and the baseline doesn't have to either, so it's kept under
+//! /tmp by default. After a restart of rita alone the previous read times are picked up again.
+
+use failure::Error;
+
+use rita_common::storage;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+/// How many gaps are kept for the dashboard
+const MAX_GAPS: usize = 200;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GapKind {
+    /// The set couldn't be read. Depending on how far reading got before it failed, what was
+    /// counted since the previous read is either lost or billed with the next read.
+    ReadFailed,
+    /// The counter held more than `max_counter_rate` allows since the previous read, it was not
+    /// billed
+    Jump,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AccountingGap {
+    /// Seconds since the unix epoch
+    pub time: u64,
+    /// The set the counter belongs to
+    pub target: String,
+    /// When the set was read before, seconds since the unix epoch
+    pub since: u64,
+    /// The counter's destination and interface, both `None` if the whole set is affected
+    pub ip: Option<IpAddr>,
+    pub interface: Option<String>,
+    pub kind: GapKind,
+    /// Bytes that were left unbilled, unknown for failed reads
+    pub unbilled: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct BaselineSnapshot {
+    /// When each set was last read
+    times: HashMap<String, u64>,
+    gaps: Vec<AccountingGap>,
+}
+
+#[derive(Debug, Default)]
+pub struct CounterBaseline {
+    /// When the counters were set up, what a set that was never read has counted since
+    started: u64,
+    /// When each set was last read, seconds since the unix epoch
+    times: HashMap<String, u64>,
+    gaps: VecDeque<AccountingGap>,
+}
+
+impl CounterBaseline {
+    pub fn new(started: u64) -> CounterBaseline {
+        CounterBaseline {
+            started,
+            ..CounterBaseline::default()
+        }
+    }
+
+    /// Loads the baseline saved at `path`, an empty baseline starting at `started` if nothing was
+    /// saved yet
+    pub fn load(path: &str, started: u64) -> Result<CounterBaseline, Error> {
+        let snapshot: BaselineSnapshot = match storage::load_json(path)? {
+            Some(snapshot) => snapshot,
+            None => return Ok(CounterBaseline::new(started)),
+        };
+
+        Ok(CounterBaseline {
+            started,
+            times: snapshot.times,
+            gaps: snapshot.gaps.into_iter().collect(),
+        })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        let snapshot = BaselineSnapshot {
+            times: self.times.clone(),
+            gaps: self.gaps.iter().cloned().collect(),
+        };
+        storage::save_json(path, &snapshot)
+    }
+
+    /// Most recent gaps last
+    pub fn gaps(&self) -> Vec<AccountingGap> {
+        self.gaps.iter().cloned().collect()
+    }
+
+    fn record_gap(&mut self, gap: AccountingGap) {
+        warn!("Traffic accounting gap {:?}", gap);
+        if self.gaps.len() >= MAX_GAPS {
+            self.gaps.pop_front();
+        }
+        self.gaps.push_back(gap);
+    }
+
+    fn since(&self, target: &str) -> u64 {
+        self.times.get(target).cloned().unwrap_or(self.started)
+    }
+
+    /// Takes what a set's counters held when they were read and zeroed and returns the part that
+    /// can be billed. `max_rate` is in bytes per second.
+    pub fn advance(
+        &mut self,
+        target: &str,
+        counters: HashMap<(IpAddr, String), u64>,
+        now: u64,
+        max_rate: u64,
+    ) -> HashMap<(IpAddr, String), u64> {
+        let since = self.since(target);
+        let max_bytes = max_rate.saturating_mul(now.saturating_sub(since).max(1));
+        let mut billed = HashMap::new();
+        let mut gaps = Vec::new();
+
+        for ((ip, interface), bytes) in counters {
+            if bytes > max_bytes {
+                gaps.push(AccountingGap {
+                    time: now,
+                    target: target.to_string(),
+                    since,
+                    ip: Some(ip),
+                    interface: Some(interface),
+                    kind: GapKind::Jump,
+                    unbilled: Some(bytes),
+                });
+            } else {
+                billed.insert((ip, interface), bytes);
+            }
+        }
+
+        for gap in gaps {
+            self.record_gap(gap);
+        }
+        self.times.insert(target.to_string(), now);
+        billed
+    }
+
+    /// Records that a set couldn't be read. The time of the previous read is kept, if the counters
+    /// weren't zeroed they are checked against the whole time since then next round.
+    pub fn read_failed(&mut self, target: &str, now: u64) {
+        let since = self.since(target);
+        self.record_gap(AccountingGap {
+            time: now,
+            target: target.to_string(),
+            since,
+            ip: None,
+            interface: None,
+            kind: GapKind::ReadFailed,
+            unbilled: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn key(ip: &str, iface: &str) -> (IpAddr, String) {
+        (ip.parse().unwrap(), iface.to_string())
+    }
+
+    fn counts(values: &[(&str, &str, u64)]) -> HashMap<(IpAddr, String), u64> {
+        values
+            .iter()
+            .map(|&(ip, iface, bytes)| (key(ip, iface), bytes))
+            .collect()
+    }
+
+    #[test]
+    fn test_counts_are_billed() {
+        let mut baseline = CounterBaseline::new(100);
+        let billed = baseline.advance(
+            "rita_input",
+            counts(&[("fd00::1", "wg0", 4000), ("10.0.0.1", "wg1", 500)]),
+            105,
+            1000,
+        );
+        assert_eq!(billed.get(&key("fd00::1", "wg0")), Some(&4000));
+        assert_eq!(billed.get(&key("10.0.0.1", "wg1")), Some(&500));
+
+        let billed = baseline.advance("rita_input", counts(&[("fd00::1", "wg0", 100)]), 110, 1000);
+        assert_eq!(billed.len(), 1);
+        assert_eq!(billed.get(&key("fd00::1", "wg0")), Some(&100));
+        assert!(baseline.gaps().is_empty());
+    }
+
+    #[test]
+    fn test_targets_are_independent() {
+        let mut baseline = CounterBaseline::new(100);
+        baseline.advance("rita_input", HashMap::new(), 200, 1000);
+        // rita_output was never read, so it may have counted 105 seconds worth
+        let billed = baseline.advance(
+            "rita_output",
+            counts(&[("fd00::1", "wg0", 100_000)]),
+            205,
+            1000,
+        );
+        assert_eq!(billed.get(&key("fd00::1", "wg0")), Some(&100_000));
+        assert!(baseline.gaps().is_empty());
+    }
+
+    #[test]
+    fn test_phantom_jump_is_not_billed() {
+        let mut baseline = CounterBaseline::new(100);
+        // a restored set, far more than 5 seconds at 1000 bytes per second
+        let billed = baseline.advance(
+            "rita_input",
+            counts(&[("fd00::1", "wg0", 50_000_000), ("fd00::2", "wg0", 4000)]),
+            105,
+            1000,
+        );
+        assert_eq!(billed.len(), 1);
+        assert_eq!(billed.get(&key("fd00::2", "wg0")), Some(&4000));
+
+        let gaps = baseline.gaps();
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0].kind, GapKind::Jump);
+        assert_eq!(gaps[0].ip, Some(key("fd00::1", "").0));
+        assert_eq!(gaps[0].interface, Some("wg0".to_string()));
+        assert_eq!(gaps[0].since, 100);
+        assert_eq!(gaps[0].time, 105);
+        assert_eq!(gaps[0].unbilled, Some(50_000_000));
+    }
+
+    #[test]
+    fn test_read_failed_is_reported() {
+        let mut baseline = CounterBaseline::new(100);
+        baseline.advance("rita_input", HashMap::new(), 105, 1000);
+        baseline.read_failed("rita_input", 110);
+
+        let gaps = baseline.gaps();
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0].kind, GapKind::ReadFailed);
+        assert_eq!(gaps[0].since, 105);
+        assert_eq!(gaps[0].ip, None);
+        assert_eq!(gaps[0].unbilled, None);
+
+        // the next read may hold everything since the last successful one
+        let billed = baseline.advance(
+            "rita_input",
+            counts(&[("fd00::1", "wg0", 10_000)]),
+            115,
+            1000,
+        );
+        assert_eq!(billed.get(&key("fd00::1", "wg0")), Some(&10_000));
+        assert_eq!(baseline.gaps().len(), 1);
+    }
+
+    #[test]
+    fn test_gaps_are_bounded() {
+        let mut baseline = CounterBaseline::new(0);
+        for i in 0..(MAX_GAPS as u64 + 10) {
+            baseline.read_failed("rita_input", i);
+        }
+        let gaps = baseline.gaps();
+        assert_eq!(gaps.len(), MAX_GAPS);
+        // oldest dropped first
+        assert_eq!(gaps[0].time, 10);
+        assert_eq!(gaps[MAX_GAPS - 1].time, MAX_GAPS as u64 + 9);
+    }
+
+    #[test]
+    fn test_save_load_round_trip() {
+        let path = env::temp_dir().join("rita-counter-baseline-test.json");
+        let path = path.to_str().unwrap();
+
+        let mut baseline = CounterBaseline::new(100);
+        baseline.advance("rita_input", counts(&[("fd00::1", "wg0", 700)]), 105, 1000);
+        baseline.read_failed("rita_output", 105);
+        baseline.save(path).unwrap();
+
+        // rita restarted a while later, the sets kept counting in the meantime
+        let mut loaded = CounterBaseline::load(path, 500).unwrap();
+        assert_eq!(loaded.gaps(), baseline.gaps());
+        let billed = loaded.advance(
+            "rita_input",
+            counts(&[("fd00::1", "wg0", 300_000)]),
+            505,
+            1000,
+        );
+        assert_eq!(billed.get(&key("fd00::1", "wg0")), Some(&300_000));
+        assert_eq!(loaded.gaps().len(), 1);
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let path = env::temp_dir().join("rita-counter-baseline-missing.json");
+        let baseline = CounterBaseline::load(path.to_str().unwrap(), 100).unwrap();
+        assert!(baseline.gaps().is_empty());
+        assert!(baseline.times.is_empty());
+        assert_eq!(baseline.started, 100);
+    }
+}
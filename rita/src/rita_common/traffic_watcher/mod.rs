@@ -1,5 +1,5 @@
 //! Traffic watcher monitors system traffic by interfacing with KernelInterface to create and check
-//! iptables and ipset counters on each per hop tunnel (the WireGuard tunnel between two devices). These counts
+//! iptables and ipset counters (IPv4 and IPv6) on each per hop tunnel (the WireGuard tunnel between two devices). These counts
 //! are then stored and used to compute amounts for bills.
 
 use actix::prelude::*;
@@ -10,25 +10,38 @@ use KI;
 
 use althea_types::Identity;
 
-use babel_monitor::Babel;
+use babel_monitor::Route;
 
+use rita_common::babel_watcher::table_or_dump;
 use rita_common::debt_keeper;
 use rita_common::debt_keeper::DebtKeeper;
+use rita_common::traffic_reconciler::{RecordTick, TrafficReconciler};
 
 use num256::Int256;
 
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
 
 use ipnetwork::IpNetwork;
 
-use settings::RitaCommonSettings;
+use settings::{CounterBackendKind, RitaCommonSettings};
+use BABEL;
 use SETTING;
 
 use failure::Error;
+use futures::Future;
 
-pub struct TrafficWatcher;
+mod baseline;
+
+pub use self::baseline::{AccountingGap, CounterBaseline, GapKind};
+
+pub struct TrafficWatcher {
+    /// Picked from the settings when the traffic watcher starts, the counters it set up are the
+    /// ones that have to be read
+    counter_backend: CounterBackendKind,
+    /// When each set was last read, saved after every round
+    baseline: CounterBaseline,
+}
 
 impl Actor for TrafficWatcher {
     type Context = Context<Self>;
@@ -38,10 +51,23 @@ impl Supervised for TrafficWatcher {}
 
 impl SystemService for TrafficWatcher {
     fn service_started(&mut self, _ctx: &mut Context<Self>) {
-        KI.init_counter(&FilterTarget::Input).unwrap();
-        KI.init_counter(&FilterTarget::Output).unwrap();
-        KI.init_counter(&FilterTarget::ForwardInput).unwrap();
-        KI.init_counter(&FilterTarget::ForwardOutput).unwrap();
+        self.counter_backend = SETTING.get_network().counter_backend;
+        info!("Counting traffic with {:?}", self.counter_backend);
+
+        init_counter(self.counter_backend, &FilterTarget::Input).unwrap();
+        init_counter(self.counter_backend, &FilterTarget::Output).unwrap();
+        init_counter(self.counter_backend, &FilterTarget::ForwardInput).unwrap();
+        init_counter(self.counter_backend, &FilterTarget::ForwardOutput).unwrap();
+
+        let path = SETTING.get_network().counter_baseline_file.clone();
+        let now = debt_keeper::unix_now();
+        self.baseline = match CounterBaseline::load(&path, now) {
+            Ok(baseline) => baseline,
+            Err(e) => {
+                error!("Failed to load counter baseline from {} {:?}", path, e);
+                CounterBaseline::new(now)
+            }
+        };
 
         info!("Traffic Watcher started");
     }
@@ -49,7 +75,54 @@ impl SystemService for TrafficWatcher {
 
 impl Default for TrafficWatcher {
     fn default() -> TrafficWatcher {
-        TrafficWatcher {}
+        TrafficWatcher {
+            counter_backend: CounterBackendKind::default(),
+            baseline: CounterBaseline::new(0),
+        }
+    }
+}
+
+fn init_counter(backend: CounterBackendKind, target: &FilterTarget) -> Result<(), Error> {
+    match backend {
+        CounterBackendKind::Ipset => KI.init_counter(target),
+        CounterBackendKind::Nftables => KI.init_nft_counter(target),
+    }
+}
+
+fn read_counters(
+    backend: CounterBackendKind,
+    target: &FilterTarget,
+) -> Result<HashMap<(IpAddr, String), u64>, Error> {
+    match backend {
+        CounterBackendKind::Ipset => KI.read_counters(target),
+        CounterBackendKind::Nftables => KI.read_nft_counters(target),
+    }
+}
+
+/// Reads and zeroes a set's counters and returns what can be billed of them. A set that can't be
+/// read is recorded in the baseline and counts as empty, so the other sets are still billed.
+fn read_target(
+    backend: CounterBackendKind,
+    target: &FilterTarget,
+    baseline: &mut CounterBaseline,
+    now: u64,
+) -> HashMap<(IpAddr, String), u64> {
+    match read_counters(backend, target) {
+        Ok(counters) => baseline.advance(
+            target.set_name(),
+            counters,
+            now,
+            SETTING.get_network().max_counter_rate,
+        ),
+        Err(e) => {
+            warn!(
+                "Error getting {} counters {:?} traffic may have gone unaccounted!",
+                target.set_name(),
+                e
+            );
+            baseline.read_failed(target.set_name(), now);
+            HashMap::new()
+        }
     }
 }
 
@@ -69,14 +142,61 @@ impl Message for Watch {
 }
 
 impl Handler<Watch> for TrafficWatcher {
-    type Result = Result<(), Error>;
+    type Result = ResponseActFuture<Self, (), Error>;
 
     fn handle(&mut self, msg: Watch, _: &mut Context<Self>) -> Self::Result {
-        let stream = TcpStream::connect::<SocketAddr>(
-            format!("[::1]:{}", SETTING.get_network().babel_port).parse()?,
-        )?;
+        let babel_state = table_or_dump(
+            |table| {
+                table
+                    .local_fee()
+                    .map(|local_fee| (table.routes(), local_fee))
+            },
+            || {
+                BABEL.run(|babel| {
+                    babel.parse_routes().and_then(|(babel, routes)| {
+                        babel
+                            .get_local_fee()
+                            .map(|(babel, local_fee)| (babel, (routes, local_fee)))
+                    })
+                })
+            },
+        );
+
+        Box::new(
+            babel_state
+                .into_actor(self)
+                .and_then(move |(routes, local_fee), act, _ctx| {
+                    let res = watch(
+                        &routes,
+                        local_fee,
+                        &msg.neighbors,
+                        act.counter_backend,
+                        &mut act.baseline,
+                    );
+
+                    let path = SETTING.get_network().counter_baseline_file.clone();
+                    if let Err(e) = act.baseline.save(&path) {
+                        error!("Failed to save counter baseline to {} {:?}", path, e);
+                    }
+
+                    actix::fut::result(res)
+                }),
+        )
+    }
+}
+
+/// Failed reads and counter jumps that were reported instead of billed, oldest first
+pub struct GetAccountingGaps;
+
+impl Message for GetAccountingGaps {
+    type Result = Result<Vec<AccountingGap>, Error>;
+}
+
+impl Handler<GetAccountingGaps> for TrafficWatcher {
+    type Result = Result<Vec<AccountingGap>, Error>;
 
-        watch(Babel::new(stream), &msg.neighbors)
+    fn handle(&mut self, _msg: GetAccountingGaps, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.baseline.gaps())
     }
 }
 
@@ -85,13 +205,15 @@ impl Handler<Watch> for TrafficWatcher {
 /// It also gathers the price to each destination from Babel and uses this information
 /// to calculate how much each neighbor owes. It returns a list of how much each neighbor owes.
 ///
-/// This first time this is run, it will create the rules and then immediately read and zero them.
-/// (should return 0)
-pub fn watch<T: Read + Write>(mut babel: Babel<T>, neighbors: &Vec<Neighbor>) -> Result<(), Error> {
-    babel.start_connection()?;
-
-    trace!("Getting routes");
-    let routes = babel.parse_routes()?;
+/// The counters are zeroed as they are read, `baseline` holds back anything more than the time
+/// since the previous read allows and records sets that couldn't be read.
+pub fn watch(
+    routes: &VecDeque<Route>,
+    local_fee: u32,
+    neighbors: &Vec<Neighbor>,
+    counter_backend: CounterBackendKind,
+    baseline: &mut CounterBaseline,
+) -> Result<(), Error> {
     trace!("Got routes: {:?}", routes);
 
     let mut identities: HashMap<IpAddr, Identity> = HashMap::new();
@@ -108,14 +230,17 @@ pub fn watch<T: Read + Write>(mut babel: Babel<T>, neighbors: &Vec<Neighbor>) ->
     }
 
     let mut destinations = HashMap::new();
-    let local_fee = babel.get_local_fee().unwrap();
-
-    for route in &routes {
-        // Only ip6
-        if let IpNetwork::V6(ref ip) = route.prefix {
-            // Only host addresses and installed routes
-            if ip.prefix() == 128 && route.installed {
-                destinations.insert(IpAddr::V6(ip.ip()), Int256::from(route.price + local_fee));
+
+    for route in routes {
+        // Only host addresses and installed routes, the counters hold both address families
+        let host = match route.prefix {
+            IpNetwork::V6(ref ip) if ip.prefix() == 128 => Some(IpAddr::V6(ip.ip())),
+            IpNetwork::V4(ref ip) if ip.prefix() == 32 => Some(IpAddr::V4(ip.ip())),
+            _ => None,
+        };
+        if let Some(host) = host {
+            if route.installed {
+                destinations.insert(host, Int256::from(route.price + local_fee));
             }
         }
     }
@@ -128,53 +253,21 @@ pub fn watch<T: Read + Write>(mut babel: Babel<T>, neighbors: &Vec<Neighbor>) ->
         Int256::from(0),
     );
 
+    let now = debt_keeper::unix_now();
+
     trace!("Getting input counters");
-    let input_counters = match KI.read_counters(&FilterTarget::Input) {
-        Ok(res) => res,
-        Err(e) => {
-            warn!(
-                "Error getting input counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
-        }
-    };
+    let input_counters = read_target(counter_backend, &FilterTarget::Input, baseline, now);
     trace!("Got input counters: {:?}", input_counters);
 
     trace!("Getting ouput counters");
-    let output_counters = match KI.read_counters(&FilterTarget::Output) {
-        Ok(res) => res,
-        Err(e) => {
-            warn!(
-                "Error getting output counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
-        }
-    };
+    let output_counters = read_target(counter_backend, &FilterTarget::Output, baseline, now);
     trace!("Got output counters: {:?}", output_counters);
 
     trace!("Getting fwd counters");
-    let fwd_input_counters = match KI.read_counters(&FilterTarget::ForwardInput) {
-        Ok(res) => res,
-        Err(e) => {
-            warn!(
-                "Error getting input counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
-        }
-    };
-    let fwd_output_counters = match KI.read_counters(&FilterTarget::ForwardOutput) {
-        Ok(res) => res,
-        Err(e) => {
-            warn!(
-                "Error getting input counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
-        }
-    };
+    let fwd_input_counters =
+        read_target(counter_backend, &FilterTarget::ForwardInput, baseline, now);
+    let fwd_output_counters =
+        read_target(counter_backend, &FilterTarget::ForwardOutput, baseline, now);
 
     info!(
         "Got fwd counters: {:?}",
@@ -216,6 +309,31 @@ pub fn watch<T: Read + Write>(mut babel: Babel<T>, neighbors: &Vec<Neighbor>) ->
     }
     info!("Total output of {} bytes this round", total_out);
 
+    // Bytes that went over each tunnel, the neighbor's counts are later compared to these
+    let mut tunnel_counts: HashMap<Identity, (u64, u64)> = HashMap::new();
+    for ident in identities.values() {
+        tunnel_counts.insert(ident.clone(), (0, 0));
+    }
+    for (&(_, ref interface), bytes) in total_output_counters.iter() {
+        if let Some(counts) = if_to_id
+            .get(interface)
+            .and_then(|id| tunnel_counts.get_mut(id))
+        {
+            counts.0 += bytes;
+        }
+    }
+    for (&(_, ref interface), bytes) in total_input_counters.iter() {
+        if let Some(counts) = if_to_id
+            .get(interface)
+            .and_then(|id| tunnel_counts.get_mut(id))
+        {
+            counts.1 += bytes;
+        }
+    }
+    TrafficReconciler::from_registry().do_send(RecordTick {
+        counts: tunnel_counts,
+    });
+
     // Flow counters should debit your neighbor which you received the packet from
     // Destination counters should credit your neighbor which you sent the packet to
 
@@ -319,12 +437,24 @@ mod tests {
     extern crate env_logger;
 
     use super::*;
+    use babel_monitor::Babel;
+    use std::net::{SocketAddr, TcpStream};
 
     #[test]
     #[ignore]
     fn debug_babel_socket_common() {
         env_logger::init();
         let bm_stream = TcpStream::connect::<SocketAddr>("[::1]:9001".parse().unwrap()).unwrap();
-        watch(Babel::new(bm_stream), &Vec::new()).unwrap();
+        let mut babel = Babel::new(bm_stream);
+        babel.start_connection().unwrap();
+        let routes = babel.parse_routes().unwrap();
+        let local_fee = babel.get_local_fee().unwrap();
+        watch(
+            &routes,
+            local_fee,
+            &Vec::new(),
+            CounterBackendKind::default(),
+            &mut CounterBaseline::new(debt_keeper::unix_now()),
+        ).unwrap();
     }
 }
@@ -5,7 +5,7 @@
 //! then into TunnelManager to open a tunnel for them.
 
 use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use std::time::{Duration, Instant};
 
@@ -19,13 +19,15 @@ use althea_types::LocalIdentity;
 
 use KI;
 
-use babel_monitor::{Babel, Route};
+use babel_monitor::Route;
 
 use rita_common;
+use rita_common::babel_watcher::table_or_dump;
 use rita_common::http_client::Hello;
 use rita_common::peer_listener::Peer;
 
 use settings::RitaCommonSettings;
+use BABEL;
 use SETTING;
 
 use failure::Error;
@@ -34,7 +36,6 @@ use failure::Error;
 use actix::actors::mocker::Mocker;
 use ipnetwork::IpNetwork;
 use std::fmt;
-use std::io::{Read, Write};
 
 #[cfg(test)]
 type HTTPClient = Mocker<rita_common::http_client::HTTPClient>;
@@ -63,6 +64,10 @@ pub enum TunnelAction {
     MembershipConfirmed,
     /// Membership expired for an identity
     MembershipExpired,
+    /// The neighbor's debt fell below the close threshold
+    PaymentOverdue,
+    /// The neighbor paid enough to get back above the close threshold
+    PaidOnTime,
 }
 
 impl fmt::Display for TunnelAction {
@@ -96,6 +101,28 @@ fn test_tunnel_state() {
     assert_eq!(TunnelState::Registered.to_string(), "Registered");
 }
 
+/// Whether the neighbor on the other end of a tunnel is paying for the traffic we forward,
+/// tracked separately from TunnelState since registration and payment are independent
+#[derive(PartialEq, Debug, Clone)]
+pub enum PaymentState {
+    /// Forwarding normally (default)
+    Paid,
+    /// Forwarding is suspended until the debt is paid off
+    Overdue,
+}
+
+impl fmt::Display for PaymentState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[test]
+fn test_payment_state() {
+    assert_eq!(PaymentState::Paid.to_string(), "Paid");
+    assert_eq!(PaymentState::Overdue.to_string(), "Overdue");
+}
+
 #[derive(Debug, Clone)]
 pub struct Tunnel {
     pub ip: IpAddr,              // Tunnel endpoint
@@ -105,6 +132,7 @@ pub struct Tunnel {
     pub neigh_id: LocalIdentity, // the identity of the counterparty tunnel
     pub last_contact: Instant,   // When's the last we heard from the other end of this tunnel?
     state: TunnelState,
+    payment_state: PaymentState,
 }
 
 impl Tunnel {
@@ -124,6 +152,9 @@ impl Tunnel {
             last_contact: Instant::now(),
             // By default new tunnels are in Registered state
             state: TunnelState::Registered,
+            // New tunnels start out forwarding, if the neighbor is still behind the next debt
+            // keeper update will suspend it again
+            payment_state: PaymentState::Paid,
         }
     }
 
@@ -146,21 +177,44 @@ impl Tunnel {
     }
 
     /// Register this tunnel into Babel monitor
-    pub fn monitor<T: Read + Write>(&self, stream: T) -> Result<(), Error> {
+    pub fn monitor(&self) -> Box<Future<Item = (), Error = Error>> {
         info!("Monitoring tunnel {}", self.iface_name);
-        let mut babel = Babel::new(stream);
-        babel.start_connection()?;
-        babel.monitor(&self.iface_name)?;
-        Ok(())
+        BABEL.monitor(&self.iface_name)
     }
 
-    pub fn unmonitor<T: Read + Write>(&self, stream: T) -> Result<(), Error> {
+    pub fn unmonitor(&self) -> Box<Future<Item = (), Error = Error>> {
         warn!("Unmonitoring tunnel {}", self.iface_name);
-        let mut babel = Babel::new(stream);
-        babel.start_connection()?;
-        babel.unmonitor(&self.iface_name)?;
+        BABEL.unmonitor(&self.iface_name)
+    }
+
+    /// Stop forwarding traffic for this neighbor
+    fn suspend(&mut self) -> Result<(), Error> {
+        info!("Suspending forwarding on tunnel {}", self.iface_name);
+        KI.suspend_tunnel(&self.iface_name)?;
+        self.payment_state = PaymentState::Overdue;
+        Ok(())
+    }
+
+    /// Resume forwarding traffic for this neighbor
+    fn resume(&mut self) -> Result<(), Error> {
+        info!("Resuming forwarding on tunnel {}", self.iface_name);
+        KI.resume_tunnel(&self.iface_name)?;
+        self.payment_state = PaymentState::Paid;
         Ok(())
     }
+
+    /// Tunnel interface names get reused, so a suspended tunnel must have its rules removed
+    /// before the interface goes away
+    fn release_suspension(&mut self) {
+        if self.payment_state == PaymentState::Overdue {
+            if let Err(e) = self.resume() {
+                error!(
+                    "Failed to remove suspension for deleted tunnel {}: {:?}",
+                    self.iface_name, e
+                );
+            }
+        }
+    }
 }
 
 pub struct TunnelManager {
@@ -174,6 +228,9 @@ impl Actor for TunnelManager {
 impl Supervised for TunnelManager {}
 impl SystemService for TunnelManager {
     fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        if let Err(e) = KI.init_tunnel_suspension() {
+            error!("Failed to set up tunnel suspension rules: {:?}", e);
+        }
         info!("Tunnel manager started");
     }
 }
@@ -217,7 +274,7 @@ impl Message for IdentityCallback {
 impl Handler<IdentityCallback> for TunnelManager {
     type Result = Option<(Tunnel, bool)>;
 
-    fn handle(&mut self, msg: IdentityCallback, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: IdentityCallback, ctx: &mut Context<Self>) -> Self::Result {
         let our_port = match msg.our_port {
             Some(port) => port,
             _ => match self.get_port() {
@@ -229,7 +286,7 @@ impl Handler<IdentityCallback> for TunnelManager {
             },
         };
 
-        let res = self.open_tunnel(msg.local_identity, msg.peer, our_port);
+        let res = self.open_tunnel(msg.local_identity, msg.peer, our_port, ctx);
         match res {
             Ok(res) => Some(res),
             Err(e) => {
@@ -262,39 +319,34 @@ impl Message for GetPhyIpFromMeshIp {
     type Result = Result<IpAddr, Error>;
 }
 
-fn make_babel_stream() -> Result<TcpStream, Error> {
-    let stream = TcpStream::connect::<SocketAddr>(
-        format!("[::1]:{}", SETTING.get_network().babel_port).parse()?,
-    )?;
-    Ok(stream)
-}
-
 impl Handler<GetPhyIpFromMeshIp> for TunnelManager {
-    type Result = Result<IpAddr, Error>;
+    type Result = ResponseFuture<IpAddr, Error>;
 
     fn handle(&mut self, mesh_ip: GetPhyIpFromMeshIp, _: &mut Context<Self>) -> Self::Result {
-        let mut babel = Babel::new(make_babel_stream()?);
-        babel.start_connection()?;
-        let routes = babel.parse_routes()?;
-
-        let mut route_to_des: Option<Route> = None;
-
-        for route in routes {
-            // Only ip6
-            if let IpNetwork::V6(ref ip) = route.prefix {
-                // Only host addresses and installed routes
-                if ip.prefix() == 128 && route.installed {
-                    if IpAddr::V6(ip.ip()) == mesh_ip.0 {
-                        route_to_des = Some(route.clone());
+        Box::new(
+            table_or_dump(|table| Some(table.routes()), || BABEL.parse_routes()).and_then(
+                move |routes| {
+                    let mut route_to_des: Option<Route> = None;
+
+                    for route in routes {
+                        // Only ip6
+                        if let IpNetwork::V6(ref ip) = route.prefix {
+                            // Only host addresses and installed routes
+                            if ip.prefix() == 128 && route.installed {
+                                if IpAddr::V6(ip.ip()) == mesh_ip.0 {
+                                    route_to_des = Some(route.clone());
+                                }
+                            }
+                        }
                     }
-                }
-            }
-        }
 
-        match route_to_des {
-            Some(route) => Ok(KI.get_wg_remote_ip(&route.iface)?),
-            None => bail!("No route found for mesh ip: {:?}", mesh_ip),
-        }
+                    match route_to_des {
+                        Some(route) => Ok(KI.get_wg_remote_ip(&route.iface)?),
+                        None => bail!("No route found for mesh ip: {:?}", mesh_ip),
+                    }
+                },
+            ),
+        )
     }
 }
 
@@ -305,14 +357,21 @@ pub struct Neighbor {
     pub identity: LocalIdentity,
     pub iface_name: String,
     pub tunnel_ip: IpAddr,
+    pub payment_state: PaymentState,
 }
 
 impl Neighbor {
-    fn new(identity: LocalIdentity, iface_name: String, tunnel_ip: IpAddr) -> Neighbor {
+    fn new(
+        identity: LocalIdentity,
+        iface_name: String,
+        tunnel_ip: IpAddr,
+        payment_state: PaymentState,
+    ) -> Neighbor {
         Neighbor {
             identity,
             iface_name,
             tunnel_ip,
+            payment_state,
         }
     }
 }
@@ -331,6 +390,7 @@ impl Handler<GetNeighbors> for TunnelManager {
                     tunnel.neigh_id.clone(),
                     tunnel.iface_name.clone(),
                     tunnel.ip,
+                    tunnel.payment_state.clone(),
                 ));
             }
         }
@@ -347,21 +407,7 @@ impl Message for TriggerGC {
 
 impl Handler<TriggerGC> for TunnelManager {
     type Result = Result<(), Error>;
-    fn handle(&mut self, msg: TriggerGC, _ctx: &mut Context<Self>) -> Self::Result {
-        let stream = match make_babel_stream() {
-            Ok(stream) => stream,
-            Err(e) => {
-                warn!("Tunnel GC failed to open babel stream with {:?}", e);
-                return Err(e);
-            }
-        };
-        let mut babel = Babel::new(stream);
-        let res = babel.start_connection();
-        if res.is_err() {
-            warn!("Failed to start Babel RPC connection! {:?}", res);
-            bail!("Failed to start Babel RPC connection!");
-        }
-
+    fn handle(&mut self, msg: TriggerGC, ctx: &mut Context<Self>) -> Self::Result {
         let mut good: HashMap<Identity, HashMap<u32, Tunnel>> = HashMap::new();
         let mut timed_out: HashMap<Identity, HashMap<u32, Tunnel>> = HashMap::new();
         // Split entries into good and timed out rebuilding the double hashmap strucutre
@@ -410,15 +456,27 @@ impl Handler<TriggerGC> for TunnelManager {
         self.tunnels = good;
 
         for (_ident, tunnels) in timed_out {
-            for (_ifidx, tunnel) in tunnels {
+            for (_ifidx, mut tunnel) in tunnels {
                 // In the same spirit, we return the port to the free port pool only after tunnel
                 // deletion goes well.
-                let res = babel.unmonitor(&tunnel.iface_name);
-                if res.is_err() {
-                    warn!("Failed to unmonitor {} with {:?}", tunnel.iface_name, res);
-                }
-                KI.del_interface(&tunnel.iface_name)?;
-                self.free_ports.push(tunnel.listen_port);
+                tunnel.release_suspension();
+                ctx.spawn(
+                    tunnel
+                        .unmonitor()
+                        .into_actor(self)
+                        .then(move |res, act, _ctx| {
+                            if let Err(e) = res {
+                                warn!("Failed to unmonitor {} with {:?}", tunnel.iface_name, e);
+                            }
+                            match KI.del_interface(&tunnel.iface_name) {
+                                Ok(_) => act.free_ports.push(tunnel.listen_port),
+                                Err(e) => {
+                                    warn!("Failed to delete {} with {:?}", tunnel.iface_name, e)
+                                }
+                            }
+                            actix::fut::ok(())
+                        }),
+                );
             }
         }
 
@@ -621,6 +679,39 @@ impl TunnelManager {
         contact_neighbor(peer, our_port)
     }
 
+    /// Takes down a tunnel babel couldn't be told about, unless it was replaced or collected in
+    /// the meantime
+    fn close_tunnel(&mut self, tunnel: &Tunnel) {
+        let key = &tunnel.neigh_id.global;
+        let current = self
+            .tunnels
+            .get(key)
+            .and_then(|tunnels| tunnels.get(&tunnel.listen_ifidx))
+            .map(|current| current.iface_name == tunnel.iface_name)
+            .unwrap_or(false);
+        if !current {
+            return;
+        }
+
+        let (mut removed, size) = {
+            let tunnels = self.tunnels.get_mut(key).unwrap();
+            let removed = tunnels.remove(&tunnel.listen_ifidx).unwrap();
+            (removed, tunnels.len())
+        };
+        if size == 0 {
+            self.tunnels.remove(key);
+        }
+
+        removed.release_suspension();
+        match KI.del_interface(&removed.iface_name) {
+            Ok(_) => self.free_ports.push(removed.listen_port),
+            Err(e) => warn!(
+                "We failed to delete the interface {:?} with {:?} it's now orphaned",
+                removed.iface_name, e
+            ),
+        }
+    }
+
     /// Given a LocalIdentity, connect to the neighbor over wireguard
     /// return the tunnel object and if already had a tunnel
     pub fn open_tunnel(
@@ -628,6 +719,7 @@ impl TunnelManager {
         their_localid: LocalIdentity,
         peer: Peer,
         our_port: u16,
+        ctx: &mut Context<Self>,
     ) -> Result<(Tunnel, bool), Error> {
         trace!("getting existing tunnel or opening a new one");
         // ifidx must be a part of the key so that we can open multiple tunnels
@@ -689,7 +781,7 @@ impl TunnelManager {
                 );
                 // Unwrapping is safe because we confirm membership. This is done
                 // in a separate scope to limit surface of borrow checker.
-                let (tunnel, size) = {
+                let (mut tunnel, size) = {
                     // Find tunnels by identity
                     let tunnels = self.tunnels.get_mut(&key).unwrap();
                     // Find tunnel by interface index
@@ -705,6 +797,7 @@ impl TunnelManager {
                 }
 
                 // Remove interface
+                tunnel.release_suspension();
                 let res = KI.del_interface(&tunnel.iface_name);
                 if res.is_err() {
                     warn!(
@@ -738,24 +831,31 @@ impl TunnelManager {
                 return Err(e);
             }
         }
-        match tunnel.monitor(make_babel_stream()?) {
-            Ok(_) => {
-                let new_key = tunnel.neigh_id.global.clone();
-                // Add a tunnel to internal map based on identity, and interface index.
-                self.tunnels
-                    .entry(new_key)
-                    .or_insert(HashMap::new())
-                    .insert(tunnel.listen_ifidx.clone(), tunnel.clone());
-                Ok((tunnel, return_bool))
-            }
-            Err(e) => {
-                error!(
-                    "Unable to execute babel monitor on tunnel {:?}: {}",
-                    tunnel, e
-                );
-                Err(e)
-            }
-        }
+        let new_key = tunnel.neigh_id.global.clone();
+        // Add a tunnel to internal map based on identity, and interface index.
+        self.tunnels
+            .entry(new_key)
+            .or_insert(HashMap::new())
+            .insert(tunnel.listen_ifidx.clone(), tunnel.clone());
+        // Babel is told about the tunnel in the background, if that fails the tunnel is taken
+        // down again so that the next hello sets it up from scratch
+        let monitored = tunnel.clone();
+        ctx.spawn(
+            tunnel
+                .monitor()
+                .into_actor(self)
+                .then(move |res, act, _ctx| {
+                    if let Err(e) = res {
+                        error!(
+                            "Unable to execute babel monitor on tunnel {:?}: {}",
+                            monitored, e
+                        );
+                        act.close_tunnel(&monitored);
+                    }
+                    actix::fut::ok(())
+                }),
+        );
+        Ok((tunnel, return_bool))
     }
 }
 
@@ -768,11 +868,48 @@ impl Message for TunnelStateChange {
     type Result = Result<(), Error>;
 }
 
-// Called by DAOManager to notify TunnelManager about the registration state of a given peer
+/// Tells babel to start or stop monitoring a tunnel, its state only changes once babel did so
+fn change_registration(
+    ctx: &mut Context<TunnelManager>,
+    identity: &Identity,
+    ifidx: u32,
+    tunnel: &Tunnel,
+    state: TunnelState,
+) {
+    let babel = match state {
+        TunnelState::Registered => tunnel.monitor(),
+        TunnelState::NotRegistered => tunnel.unmonitor(),
+    };
+    let identity = identity.clone();
+    let iface_name = tunnel.iface_name.clone();
+    ctx.spawn(
+        actix::fut::wrap_future::<_, TunnelManager>(babel).then(move |res, act, _ctx| {
+            match res {
+                Ok(()) => {
+                    if let Some(tunnel) = act
+                        .tunnels
+                        .get_mut(&identity)
+                        .and_then(|tunnels| tunnels.get_mut(&ifidx))
+                    {
+                        tunnel.state = state;
+                    }
+                }
+                Err(e) => error!(
+                    "Failed to change the registration of tunnel {} with {:?}",
+                    iface_name, e
+                ),
+            }
+            actix::fut::ok(())
+        }),
+    );
+}
+
+// Called by DAOManager to notify TunnelManager about the registration state of a given peer and
+// by DebtKeeper to suspend or resume forwarding for a peer based on their debt
 impl Handler<TunnelStateChange> for TunnelManager {
     type Result = Result<(), Error>;
 
-    fn handle(&mut self, msg: TunnelStateChange, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: TunnelStateChange, ctx: &mut Context<Self>) -> Self::Result {
         trace!(
             "Tunnel state change request for {:?} with action {:?}",
             msg.identity,
@@ -781,7 +918,7 @@ impl Handler<TunnelStateChange> for TunnelManager {
         // Find a tunnel
         match self.tunnels.get_mut(&msg.identity) {
             Some(tunnels) => {
-                for (_, tunnel) in tunnels.iter_mut() {
+                for (ifidx, tunnel) in tunnels.iter_mut() {
                     trace!("Handle action {} on tunnel {:?}", msg.action, tunnel);
                     match msg.action {
                         TunnelAction::MembershipConfirmed => {
@@ -791,10 +928,13 @@ impl Handler<TunnelStateChange> for TunnelManager {
                                 tunnel
                             );
                             match tunnel.state {
-                                TunnelState::NotRegistered => {
-                                    tunnel.monitor(make_babel_stream()?)?;
-                                    tunnel.state = TunnelState::Registered;
-                                }
+                                TunnelState::NotRegistered => change_registration(
+                                    ctx,
+                                    &msg.identity,
+                                    *ifidx,
+                                    tunnel,
+                                    TunnelState::Registered,
+                                ),
                                 TunnelState::Registered => {
                                     trace!("Tunnel {:?} already in registered state", tunnel);
                                     continue;
@@ -804,23 +944,57 @@ impl Handler<TunnelStateChange> for TunnelManager {
                         TunnelAction::MembershipExpired => {
                             trace!("Membership for identity {:?} is expired", msg.identity);
                             match tunnel.state {
-                                TunnelState::Registered => {
-                                    tunnel.unmonitor(make_babel_stream()?)?;
-                                    tunnel.state = TunnelState::NotRegistered;
-                                }
+                                TunnelState::Registered => change_registration(
+                                    ctx,
+                                    &msg.identity,
+                                    *ifidx,
+                                    tunnel,
+                                    TunnelState::NotRegistered,
+                                ),
                                 TunnelState::NotRegistered => {
                                     trace!("Tunnel {:?} already in not registered state.", tunnel);
                                     continue;
                                 }
                             }
                         }
+                        // Errors are logged rather than returned so that one bad tunnel doesn't
+                        // keep the rest from being handled, the state is only changed on success
+                        // so a failed suspension or resume is retried on the next debt keeper
+                        // update
+                        TunnelAction::PaymentOverdue => match tunnel.payment_state {
+                            PaymentState::Paid => {
+                                if let Err(e) = tunnel.suspend() {
+                                    error!("Failed to suspend tunnel {:?}: {:?}", tunnel, e);
+                                }
+                            }
+                            PaymentState::Overdue => {
+                                trace!("Tunnel {:?} already suspended", tunnel);
+                                continue;
+                            }
+                        },
+                        TunnelAction::PaidOnTime => match tunnel.payment_state {
+                            PaymentState::Overdue => {
+                                if let Err(e) = tunnel.resume() {
+                                    error!("Failed to resume tunnel {:?}: {:?}", tunnel, e);
+                                }
+                            }
+                            PaymentState::Paid => {
+                                trace!("Tunnel {:?} already forwarding", tunnel);
+                                continue;
+                            }
+                        },
                     }
                 }
             }
-            None => {
+            None => match msg.action {
+                // the debt keeper tracks everyone we have ever exchanged traffic with, not all of
+                // them are still neighbors
+                TunnelAction::PaymentOverdue | TunnelAction::PaidOnTime => {
+                    trace!("No tunnel to change for identity {:?}", msg.identity)
+                }
                 // TODO: This should probably return error
-                warn!("Couldn't find tunnel for identity {:?}", msg.identity);
-            }
+                _ => warn!("Couldn't find tunnel for identity {:?}", msg.identity),
+            },
         }
         Ok(())
     }
@@ -14,23 +14,25 @@ use althea_kernel_interface::KI;
 
 use althea_types::Identity;
 
-use babel_monitor::Babel;
+use babel_monitor::Route;
 
+use rita_common::babel_watcher::table_or_dump;
 use rita_common::debt_keeper;
 use rita_common::debt_keeper::DebtKeeper;
 
 use num256::Int256;
 
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
 
 use ipnetwork::IpNetwork;
 
 use settings::{RitaCommonSettings, RitaExitSettings};
+use BABEL;
 use SETTING;
 
 use failure::Error;
+use futures::Future;
 
 pub struct TrafficWatcher {
     last_seen_bytes: HashMap<WgKey, WgUsage>,
@@ -67,27 +69,43 @@ impl Message for Watch {
 }
 
 impl Handler<Watch> for TrafficWatcher {
-    type Result = Result<(), Error>;
+    type Result = ResponseActFuture<Self, (), Error>;
 
     fn handle(&mut self, msg: Watch, _: &mut Context<Self>) -> Self::Result {
-        let stream = TcpStream::connect::<SocketAddr>(
-            format!("[::1]:{}", SETTING.get_network().babel_port).parse()?,
-        )?;
+        let babel_state = table_or_dump(
+            |table| {
+                table
+                    .local_fee()
+                    .map(|local_fee| (table.routes(), local_fee))
+            },
+            || {
+                BABEL.run(|babel| {
+                    babel.parse_routes().and_then(|(babel, routes)| {
+                        babel
+                            .get_local_fee()
+                            .map(|(babel, local_fee)| (babel, (routes, local_fee)))
+                    })
+                })
+            },
+        );
 
-        watch(&mut self.last_seen_bytes, Babel::new(stream), msg.0)
+        Box::new(
+            babel_state
+                .into_actor(self)
+                .and_then(move |(routes, local_fee), act, _ctx| {
+                    actix::fut::result(watch(&mut act.last_seen_bytes, &routes, local_fee, msg.0))
+                }),
+        )
     }
 }
 
 /// This traffic watcher watches how much traffic each we send and receive from each client.
-pub fn watch<T: Read + Write>(
+pub fn watch(
     usage_history: &mut HashMap<WgKey, WgUsage>,
-    mut babel: Babel<T>,
+    routes: &VecDeque<Route>,
+    local_fee: u32,
     clients: Vec<Identity>,
 ) -> Result<(), Error> {
-    babel.start_connection()?;
-
-    trace!("Getting routes");
-    let routes = babel.parse_routes()?;
     info!("Got routes: {:?}", routes);
 
     let mut identities: HashMap<WgKey, Identity> = HashMap::new();
@@ -109,12 +127,9 @@ pub fn watch<T: Read + Write>(
 
     // insert ourselves as a destination, don't think this is actually needed
     let mut destinations = HashMap::new();
-    destinations.insert(
-        our_id.wg_public_key,
-        Int256::from(babel.get_local_fee().unwrap()),
-    );
+    destinations.insert(our_id.wg_public_key, Int256::from(local_fee));
 
-    for route in &routes {
+    for route in routes {
         // Only ip6
         if let IpNetwork::V6(ref ip) = route.prefix {
             // Only host addresses and installed routes
@@ -282,12 +297,18 @@ mod tests {
     extern crate env_logger;
 
     use super::*;
+    use babel_monitor::Babel;
+    use std::net::{SocketAddr, TcpStream};
 
     #[test]
     #[ignore]
     fn debug_babel_socket_client() {
         env_logger::init();
         let bm_stream = TcpStream::connect::<SocketAddr>("[::1]:9001".parse().unwrap()).unwrap();
-        watch(&mut HashMap::new(), Babel::new(bm_stream), Vec::new()).unwrap();
+        let mut babel = Babel::new(bm_stream);
+        babel.start_connection().unwrap();
+        let routes = babel.parse_routes().unwrap();
+        let local_fee = babel.get_local_fee().unwrap();
+        watch(&mut HashMap::new(), &routes, local_fee, Vec::new()).unwrap();
     }
 }
@@ -97,6 +97,54 @@ fn default_metric_factor() -> u32 {
     1_900u32
 }
 
+fn default_reconcile_tolerance_percent() -> u8 {
+    5
+}
+
+fn default_reconcile_tolerance_bytes() -> u64 {
+    1_000_000
+}
+
+fn default_counter_baseline_file() -> String {
+    "/tmp/rita-counters.json".to_string()
+}
+
+fn default_max_counter_rate() -> u64 {
+    // 1 Gbit/s
+    125_000_000
+}
+
+/// The route history keeps an hour of next hop changes, a longer flap window can't be judged
+pub const MAX_ROUTE_FLAP_WINDOW: u64 = 3600;
+
+/// The route history keeps at most this many next hop changes per destination, a higher flap
+/// threshold could never be reached
+pub const MAX_ROUTE_FLAP_THRESHOLD: u32 = 100;
+
+fn default_route_flap_window() -> u64 {
+    600 // 10 minutes
+}
+
+fn default_route_flap_threshold() -> u32 {
+    4
+}
+
+/// How the traffic watcher counts the bytes going to each destination over each tunnel
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CounterBackendKind {
+    /// ipsets with counters matched from ip6tables rules
+    Ipset,
+    /// A dynamic nftables set with a counter on every element, for systems without iptables
+    Nftables,
+}
+
+impl Default for CounterBackendKind {
+    fn default() -> CounterBackendKind {
+        CounterBackendKind::Ipset
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct NetworkSettings {
     /// The static IP used on mesh interfaces
@@ -154,6 +202,35 @@ pub struct NetworkSettings {
     /// The name of the device or router model
     #[serde(skip_serializing_if = "Option::is_none")]
     pub device: Option<String>,
+    /// Byte counts a neighbor reports for our shared tunnel are taken to agree with ours if they
+    /// differ by no more than this percentage
+    #[serde(default = "default_reconcile_tolerance_percent")]
+    pub reconcile_tolerance_percent: u8,
+    /// Or by no more than this many bytes, so that links with little traffic aren't flagged over
+    /// ticks that started at slightly different times on either side
+    #[serde(default = "default_reconcile_tolerance_bytes")]
+    pub reconcile_tolerance_bytes: u64,
+    /// How traffic is counted, picked when rita starts
+    #[serde(default)]
+    pub counter_backend: CounterBackendKind,
+    /// Where the time every traffic counter set was last read is saved along with the accounting
+    /// gaps. It's written every round and the sets are gone after a reboot anyway, so this
+    /// belongs on a tmpfs.
+    #[serde(default = "default_counter_baseline_file")]
+    pub counter_baseline_file: String,
+    /// A counter holding more than this many bytes per second since the previous read is assumed
+    /// to be restored or corrupted rather than traffic and is reported instead of billed
+    #[serde(default = "default_max_counter_rate")]
+    pub max_counter_rate: u64,
+    /// A route whose next hop changed `route_flap_threshold` times within this many seconds is
+    /// reported as flapping. At most `MAX_ROUTE_FLAP_WINDOW`, larger values are lowered to it
+    /// when the settings are loaded.
+    #[serde(default = "default_route_flap_window")]
+    pub route_flap_window: u64,
+    /// Between 1 and `MAX_ROUTE_FLAP_THRESHOLD`, values outside are moved to the nearest limit
+    /// when the settings are loaded
+    #[serde(default = "default_route_flap_threshold")]
+    pub route_flap_threshold: u32,
 }
 
 impl Default for NetworkSettings {
@@ -179,6 +256,36 @@ impl Default for NetworkSettings {
             is_gateway: false,
             tunnel_timeout_seconds: default_tunnel_timeout(),
             device: None,
+            reconcile_tolerance_percent: default_reconcile_tolerance_percent(),
+            reconcile_tolerance_bytes: default_reconcile_tolerance_bytes(),
+            counter_backend: CounterBackendKind::default(),
+            counter_baseline_file: default_counter_baseline_file(),
+            max_counter_rate: default_max_counter_rate(),
+            route_flap_window: default_route_flap_window(),
+            route_flap_threshold: default_route_flap_threshold(),
+        }
+    }
+}
+
+impl NetworkSettings {
+    /// Moves the route flap settings within the limits the route history can judge
+    fn clamp_route_flap(&mut self) {
+        if self.route_flap_window > MAX_ROUTE_FLAP_WINDOW {
+            warn!(
+                "route_flap_window {} is longer than the route history, using {}",
+                self.route_flap_window, MAX_ROUTE_FLAP_WINDOW
+            );
+            self.route_flap_window = MAX_ROUTE_FLAP_WINDOW;
+        }
+        if self.route_flap_threshold == 0 {
+            warn!("route_flap_threshold 0 would call every route flapping, using 1");
+            self.route_flap_threshold = 1;
+        } else if self.route_flap_threshold > MAX_ROUTE_FLAP_THRESHOLD {
+            warn!(
+                "route_flap_threshold {} is more changes than the route history keeps, using {}",
+                self.route_flap_threshold, MAX_ROUTE_FLAP_THRESHOLD
+            );
+            self.route_flap_threshold = MAX_ROUTE_FLAP_THRESHOLD;
         }
     }
 }
@@ -229,6 +336,36 @@ impl Default for LoggingSettings {
     }
 }
 
+/// The payment backends the payment controller can use
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentBackendKind {
+    /// Payments are plain signed messages sent straight to the neighbor, nothing settles them
+    Dummy,
+    /// Payments are co-signed updates of a payment channel with the neighbor
+    Channel,
+}
+
+impl Default for PaymentBackendKind {
+    fn default() -> PaymentBackendKind {
+        PaymentBackendKind::Dummy
+    }
+}
+
+/// Payment settings for a single neighbor, anything left unset is taken from `PaymentSettings`.
+/// Trusted backbone links can be given more grace than untrusted edge neighbors this way.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct PaymentPolicy {
+    #[serde(default)]
+    pub pay_threshold: Option<Int256>,
+    #[serde(default)]
+    pub close_threshold: Option<Int256>,
+    #[serde(default)]
+    pub close_fraction: Option<Int256>,
+    #[serde(default)]
+    pub buffer_period: Option<u32>,
+}
+
 /// This struct is used by both rita and rita_exit to configure the dummy payment controller and
 /// debt keeper
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
@@ -244,6 +381,50 @@ pub struct PaymentSettings {
     pub buffer_period: u32,
     /// Our own eth private key we do not store address, instead it is derived from here
     pub eth_private_key: Option<PrivateKey>,
+    /// Debt above `pay_threshold` is held back and paid in one go once it reaches this amount,
+    /// so that we don't make a payment every round
+    #[serde(default = "default_min_payment")]
+    pub min_payment: Int256,
+    /// Seconds after which debt above `pay_threshold` is paid even if it's below `min_payment`
+    #[serde(default = "default_max_payment_interval")]
+    pub max_payment_interval: u64,
+    /// Where the debt keeper periodically snapshots its ledger so that it survives restarts
+    #[serde(default = "default_debts_file")]
+    pub debts_file: String,
+    /// How payments are actually made to and received from neighbors
+    #[serde(default)]
+    pub backend: PaymentBackendKind,
+    /// Where the latest state of every payment channel is periodically saved
+    #[serde(default = "default_channels_file")]
+    pub channels_file: String,
+    /// Where the dummy backend keeps the nonce and the recent txids of the payments from every
+    /// neighbor, so that old payments can't be replayed and retried ones are recognized after a
+    /// restart
+    #[serde(default = "default_payment_nonces_file")]
+    pub payment_nonces_file: String,
+    /// Overrides of the settings above for individual neighbors, by eth address
+    #[serde(default)]
+    pub neighbor_policies: HashMap<Address, PaymentPolicy>,
+}
+
+fn default_min_payment() -> Int256 {
+    Int256::from(0)
+}
+
+fn default_max_payment_interval() -> u64 {
+    3600
+}
+
+fn default_debts_file() -> String {
+    "/etc/rita-debts.json".to_string()
+}
+
+fn default_channels_file() -> String {
+    "/etc/rita-channels.json".to_string()
+}
+
+fn default_payment_nonces_file() -> String {
+    "/etc/rita-payment-nonces.json".to_string()
 }
 
 impl Default for PaymentSettings {
@@ -258,7 +439,37 @@ impl Default for PaymentSettings {
                     .parse()
                     .expect("Failed to create default dummy PrivateKey"),
             ),
+            min_payment: default_min_payment(),
+            max_payment_interval: default_max_payment_interval(),
+            debts_file: default_debts_file(),
+            backend: PaymentBackendKind::default(),
+            channels_file: default_channels_file(),
+            payment_nonces_file: default_payment_nonces_file(),
+            neighbor_policies: HashMap::new(),
+        }
+    }
+}
+
+impl PaymentSettings {
+    /// The settings that apply to the neighbor with this eth address, that is these settings
+    /// with the neighbor's policy applied on top
+    pub fn policy_for(&self, address: &Address) -> PaymentSettings {
+        let mut settings = self.clone();
+        if let Some(policy) = self.neighbor_policies.get(address) {
+            if let Some(ref pay_threshold) = policy.pay_threshold {
+                settings.pay_threshold = pay_threshold.clone();
+            }
+            if let Some(ref close_threshold) = policy.close_threshold {
+                settings.close_threshold = close_threshold.clone();
+            }
+            if let Some(ref close_fraction) = policy.close_fraction {
+                settings.close_fraction = close_fraction.clone();
+            }
+            if let Some(buffer_period) = policy.buffer_period {
+                settings.buffer_period = buffer_period;
+            }
         }
+        settings
     }
 }
 
@@ -315,6 +526,46 @@ impl ExitClientSettings {
     }
 }
 
+fn default_budget_warn_at() -> Vec<u8> {
+    vec![50, 80, 95]
+}
+
+fn default_budget_file() -> String {
+    "/etc/rita-budget.json".to_string()
+}
+
+/// Spending limits for a client, counting both the exit's bill and what we pay neighbors
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct BudgetSettings {
+    /// The most we want to spend in a UTC day, unlimited if unset
+    #[serde(default)]
+    pub daily_cap: Option<Int256>,
+    /// The most we want to spend in a UTC calendar month, unlimited if unset
+    #[serde(default)]
+    pub monthly_cap: Option<Int256>,
+    /// Percentages of a cap at which a warning is raised, each one once per period
+    #[serde(default = "default_budget_warn_at")]
+    pub warn_at: Vec<u8>,
+    /// Stop routing LAN traffic through the exit tunnel while a cap is exceeded
+    #[serde(default)]
+    pub cutoff: bool,
+    /// Where the spending of the current periods is saved so that it survives restarts
+    #[serde(default = "default_budget_file")]
+    pub budget_file: String,
+}
+
+impl Default for BudgetSettings {
+    fn default() -> Self {
+        BudgetSettings {
+            daily_cap: None,
+            monthly_cap: None,
+            warn_at: default_budget_warn_at(),
+            cutoff: false,
+            budget_file: default_budget_file(),
+        }
+    }
+}
+
 // in seconds
 fn default_cache_timeout() -> u64 {
     600
@@ -358,6 +609,8 @@ pub struct RitaSettingsStruct {
     log: LoggingSettings,
     network: NetworkSettings,
     exit_client: ExitClientSettings,
+    #[serde(default)]
+    budget: BudgetSettings,
     #[serde(skip)]
     future: bool,
     /// What we charge other nodes
@@ -733,6 +986,12 @@ pub trait RitaClientSettings {
     fn get_log_mut<'ret, 'me: 'ret>(
         &'me self,
     ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, LoggingSettings>;
+    fn get_budget<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockReadGuardRef<'ret, RitaSettingsStruct, BudgetSettings>;
+    fn get_budget_mut<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, BudgetSettings>;
 }
 
 impl RitaClientSettings for Arc<RwLock<RitaSettingsStruct>> {
@@ -770,6 +1029,18 @@ impl RitaClientSettings for Arc<RwLock<RitaSettingsStruct>> {
     ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, LoggingSettings> {
         RwLockWriteGuardRefMut::new(self.write().unwrap()).map_mut(|g| &mut g.log)
     }
+
+    fn get_budget<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockReadGuardRef<'ret, RitaSettingsStruct, BudgetSettings> {
+        RwLockReadGuardRef::new(self.read().unwrap()).map(|g| &g.budget)
+    }
+
+    fn get_budget_mut<'ret, 'me: 'ret>(
+        &'me self,
+    ) -> RwLockWriteGuardRefMut<'ret, RitaSettingsStruct, BudgetSettings> {
+        RwLockWriteGuardRefMut::new(self.write().unwrap()).map_mut(|g| &mut g.budget)
+    }
 }
 
 pub trait RitaExitSettings {
@@ -863,7 +1134,8 @@ impl RitaSettingsStruct {
     pub fn new(file_name: &str) -> Result<Self, Error> {
         let mut s = Config::new();
         s.merge(config::File::with_name(file_name).required(false))?;
-        let settings: Self = s.try_into()?;
+        let mut settings: Self = s.try_into()?;
+        settings.network.clamp_route_flap();
 
         Ok(settings)
     }
@@ -871,7 +1143,8 @@ impl RitaSettingsStruct {
     pub fn new_watched(file_name: &str) -> Result<Arc<RwLock<Self>>, Error> {
         let mut s = Config::new();
         s.merge(config::File::with_name(file_name).required(false))?;
-        let settings: Self = s.clone().try_into()?;
+        let mut settings: Self = s.clone().try_into()?;
+        settings.network.clamp_route_flap();
 
         let settings = Arc::new(RwLock::new(settings));
 
@@ -891,14 +1164,16 @@ impl RitaExitSettingsStruct {
     pub fn new(file_name: &str) -> Result<Self, Error> {
         let mut s = Config::new();
         s.merge(config::File::with_name(file_name).required(false))?;
-        let settings: Self = s.try_into()?;
+        let mut settings: Self = s.try_into()?;
+        settings.network.clamp_route_flap();
         Ok(settings)
     }
 
     pub fn new_watched(file_name: &str) -> Result<Arc<RwLock<Self>>, Error> {
         let mut s = Config::new();
         s.merge(config::File::with_name(file_name).required(false))?;
-        let settings: Self = s.clone().try_into()?;
+        let mut settings: Self = s.clone().try_into()?;
+        settings.network.clamp_route_flap();
 
         let settings = Arc::new(RwLock::new(settings));
 
@@ -951,4 +1226,63 @@ mod tests {
         RitaExitSettingsStruct::new("example_exit.toml").unwrap();
     }
 
+    #[test]
+    fn test_policy_for() {
+        let backbone: Address = "0x0202020202020202020202020202020202020202"
+            .parse()
+            .unwrap();
+        let edge: Address = "0x0303030303030303030303030303030303030303"
+            .parse()
+            .unwrap();
+        let mut payment = PaymentSettings::default();
+        payment.neighbor_policies.insert(
+            backbone.clone(),
+            PaymentPolicy {
+                close_threshold: Some((-1000000).into()),
+                buffer_period: Some(10),
+                ..PaymentPolicy::default()
+            },
+        );
+
+        let settings = payment.policy_for(&backbone);
+        assert_eq!(settings.close_threshold, Int256::from(-1000000));
+        assert_eq!(settings.buffer_period, 10);
+        assert_eq!(settings.pay_threshold, payment.pay_threshold);
+        assert_eq!(settings.close_fraction, payment.close_fraction);
+
+        assert_eq!(payment.policy_for(&edge), payment);
+    }
+
+    #[test]
+    fn test_clamp_route_flap() {
+        let mut network = NetworkSettings {
+            route_flap_window: MAX_ROUTE_FLAP_WINDOW + 1,
+            route_flap_threshold: 0,
+            ..NetworkSettings::default()
+        };
+        network.clamp_route_flap();
+        assert_eq!(network.route_flap_window, MAX_ROUTE_FLAP_WINDOW);
+        assert_eq!(network.route_flap_threshold, 1);
+
+        network.route_flap_threshold = MAX_ROUTE_FLAP_THRESHOLD + 1;
+        network.clamp_route_flap();
+        assert_eq!(network.route_flap_threshold, MAX_ROUTE_FLAP_THRESHOLD);
+
+        let mut network = NetworkSettings::default();
+        network.clamp_route_flap();
+        assert_eq!(network, NetworkSettings::default());
+    }
+
+    #[test]
+    fn test_settings_example_policies() {
+        let settings = RitaSettingsStruct::new("example.toml").unwrap();
+        let backbone: Address = "0x0202020202020202020202020202020202020202"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            settings.payment.neighbor_policies[&backbone].buffer_period,
+            Some(6)
+        );
+    }
+
 }